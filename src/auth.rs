@@ -0,0 +1,361 @@
+//! Authenticated pages for detecting changes made outside the driver.
+//!
+//! [`Authenticated`] stores a MAC of each page in its spare area, computed with a
+//! keyed [`Mac`] such as HMAC-SHA256 or CMAC-AES over the page address, the erase counter of its
+//! block, the data and the spare bytes from [`SPARE_META`] that the layers above use. Every read
+//! checks the MAC and returns [`NandFlashErrorKind::Integrity`] when it does not match, bit
+//! errors are still reported by the layers below as [`NandFlashErrorKind::BlockFail`].
+//!
+//! Binding the address stops pages being moved and the erase counter stops a page of an
+//! earlier erase being put back into a block written since. The counters are kept in RAM and
+//! learnt from the pages when a block is first used, [`Authenticated::set_counter`] restores
+//! them from trusted storage to also detect a whole block being replaced by an older copy.
+//! Erased pages have no MAC and read back erased.
+//!
+//! The record ends at [`SPARE_USER_SIZE`], below the parity of the on chip ECC, so it works with
+//! the ECC enabled and is left alone by software ECC layers whose spare area is smaller still.
+use digest::{Mac, OutputSizeUser};
+
+use crate::{
+    mem::{PAGE_SIZE, SPARE_META, SPARE_SIZE, SPARE_USER_SIZE},
+    traits::{
+        check_erase, check_page, check_read, check_write, BlockStatus, EccStatus, ErrorType,
+        NandFlash, NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+/// Bytes of the MAC kept, longer outputs are truncated
+pub const TAG_LEN: usize = 16;
+/// Spare bytes before [`SPARE_USER_SIZE`] holding the erase counter and the MAC
+pub const RECORD_LEN: usize = 4 + TAG_LEN;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// Device has more blocks than the counters can hold
+    TooManyBlocks,
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+            Error::TooManyBlocks => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// `F` with the pages authenticated by `M`, for devices of up to `BLOCKS` blocks
+pub struct Authenticated<F, M, const BLOCKS: usize> {
+    flash: F,
+    /// Keyed MAC, cloned for each page
+    mac: M,
+    /// Erase counter of each block, [None] until learnt
+    counters: [Option<u32>; BLOCKS],
+    page: [u8; PAGE_SIZE],
+    spare: [u8; SPARE_SIZE],
+}
+
+type AResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+impl<F, M, const BLOCKS: usize> Authenticated<F, M, BLOCKS>
+where
+    F: SpareNandFlash,
+    M: Mac + Clone,
+{
+    /// End of the record, the spare bytes after it may hold ECC parity and are not used
+    const RECORD_END: usize = if F::SPARE_SIZE < SPARE_USER_SIZE {
+        F::SPARE_SIZE
+    } else {
+        SPARE_USER_SIZE
+    };
+    /// Start of the record in the spare area, the authenticated spare bytes end here
+    const RECORD_START: usize = Self::RECORD_END - RECORD_LEN;
+
+    /// Authenticate the pages of `flash` with `mac`, already keyed
+    pub fn new(flash: F, mac: M) -> AResult<Self, F> {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::SPARE_SIZE <= SPARE_SIZE);
+        assert!(Self::RECORD_END >= SPARE_META + RECORD_LEN);
+        assert!(<M as OutputSizeUser>::output_size() >= TAG_LEN);
+        if flash.capacity() / F::ERASE_SIZE as u64 > BLOCKS as u64 {
+            return Err(Error::TooManyBlocks);
+        }
+        Ok(Self {
+            flash,
+            mac,
+            counters: [None; BLOCKS],
+            page: [0; PAGE_SIZE],
+            spare: [0; SPARE_SIZE],
+        })
+    }
+
+    /// Erase counter of `block`, [None] if not known yet
+    pub fn counter(&self, block: usize) -> Option<u32> {
+        self.counters[block]
+    }
+
+    /// Set the erase counter of `block`, pages written with another counter then fail to
+    /// authenticate
+    pub fn set_counter(&mut self, block: usize, counter: u32) {
+        self.counters[block] = Some(counter);
+    }
+
+    fn block(offset: u64) -> usize {
+        (offset / F::ERASE_SIZE as u64) as usize
+    }
+
+    /// MAC over the page at `offset` in the buffers
+    fn mac(&self, offset: u64, counter: u32) -> M {
+        let mut mac = self.mac.clone();
+        mac.update(&offset.to_le_bytes());
+        mac.update(&counter.to_le_bytes());
+        mac.update(&self.page[..F::WRITE_SIZE]);
+        mac.update(&self.spare[SPARE_META..Self::RECORD_START]);
+        mac
+    }
+
+    /// Erase counter of `block`, learnt from the first programmed page if not known.
+    /// A block with no programmed pages starts at 0.
+    fn load_counter(&mut self, block: usize) -> AResult<u32, F> {
+        if let Some(counter) = self.counters[block] {
+            return Ok(counter);
+        }
+        let start = (block * F::ERASE_SIZE) as u64;
+        let record = Self::RECORD_START..Self::RECORD_END;
+        let mut counter = 0;
+        for address in (start..start + F::ERASE_SIZE as u64).step_by(F::WRITE_SIZE) {
+            self.flash
+                .read_page(address, &mut [], &mut self.spare[..Self::RECORD_END])
+                .map_err(Error::Flash)?;
+            let record = &self.spare[record.clone()];
+            if record.iter().any(|b| *b != 0xFF) {
+                counter = u32::from_le_bytes(record[..4].try_into().unwrap());
+                break;
+            }
+        }
+        self.counters[block] = Some(counter);
+        Ok(counter)
+    }
+
+    /// Read the page at `offset` into the buffers and check its MAC
+    fn load(&mut self, offset: u64) -> AResult<(), F> {
+        self.flash
+            .read_page(
+                offset,
+                &mut self.page[..F::WRITE_SIZE],
+                &mut self.spare[..Self::RECORD_END],
+            )
+            .map_err(Error::Flash)?;
+        let record = &self.spare[Self::RECORD_START..Self::RECORD_END];
+        if record.iter().all(|b| *b == 0xFF) {
+            // never programmed, or only its bad block marker
+            let erased = |bytes: &[u8]| bytes.iter().all(|b| *b == 0xFF);
+            if erased(&self.page[..F::WRITE_SIZE])
+                && erased(&self.spare[SPARE_META..Self::RECORD_START])
+            {
+                return Ok(());
+            }
+            return Err(Error::Nand(NandFlashErrorKind::Integrity(Some(offset))));
+        }
+        let counter = u32::from_le_bytes(record[..4].try_into().unwrap());
+        let block = Self::block(offset);
+        let tag = &self.spare[Self::RECORD_START + 4..Self::RECORD_END];
+        let valid = self.mac(offset, counter).verify_truncated_left(tag).is_ok();
+        if !valid || self.counters[block].is_some_and(|c| c != counter) {
+            return Err(Error::Nand(NandFlashErrorKind::Integrity(Some(offset))));
+        }
+        self.counters[block] = Some(counter);
+        Ok(())
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F, M, const BLOCKS: usize> ErrorType for Authenticated<F, M, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    type Error = Error<F::Error>;
+}
+
+impl<F, M, const BLOCKS: usize> ReadNandFlash for Authenticated<F, M, BLOCKS>
+where
+    F: SpareNandFlash,
+    M: Mac + Clone,
+{
+    const READ_SIZE: usize = F::WRITE_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        for (address, page) in (offset..)
+            .step_by(F::WRITE_SIZE)
+            .zip(bytes.chunks_exact_mut(F::WRITE_SIZE))
+        {
+            self.load(address)?;
+            page.copy_from_slice(&self.page[..F::WRITE_SIZE]);
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.flash.capacity()
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        self.flash.block_status(address).map_err(Error::Flash)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.flash.ecc_status()
+    }
+}
+
+impl<F, M, const BLOCKS: usize> NandFlash for Authenticated<F, M, BLOCKS>
+where
+    F: SpareNandFlash,
+    M: Mac + Clone,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    /// Each erased block moves on to the next erase counter
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        for address in (from..to).step_by(F::ERASE_SIZE) {
+            let block = Self::block(address);
+            let counter = self.load_counter(block)?.wrapping_add(1);
+            self.counters[block] = None;
+            self.flash
+                .erase(address, address + F::ERASE_SIZE as u64)
+                .map_err(Error::Flash)?;
+            self.counters[block] = Some(counter);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        for (address, page) in (offset..)
+            .step_by(F::WRITE_SIZE)
+            .zip(bytes.chunks_exact(F::WRITE_SIZE))
+        {
+            self.write_page(address, page, &[])?;
+        }
+        Ok(())
+    }
+}
+
+impl<F, M, const BLOCKS: usize> SpareNandFlash for Authenticated<F, M, BLOCKS>
+where
+    F: SpareNandFlash,
+    M: Mac + Clone,
+{
+    const SPARE_SIZE: usize = Self::RECORD_START;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.load(offset)?;
+        data.copy_from_slice(&self.page[..data.len()]);
+        spare.copy_from_slice(&self.spare[..spare.len()]);
+        Ok(())
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let counter = self.load_counter(Self::block(offset))?;
+        let page = &mut self.page[..F::WRITE_SIZE];
+        page.fill(0xFF);
+        page[..data.len()].copy_from_slice(data);
+        let raw = &mut self.spare[..Self::RECORD_END];
+        raw.fill(0xFF);
+        raw[..spare.len()].copy_from_slice(spare);
+        let tag = self.mac(offset, counter).finalize().into_bytes();
+        let record = &mut self.spare[Self::RECORD_START..Self::RECORD_END];
+        record[..4].copy_from_slice(&counter.to_le_bytes());
+        record[4..].copy_from_slice(&tag[..TAG_LEN]);
+        self.flash
+            .write_page(
+                offset,
+                &self.page[..F::WRITE_SIZE],
+                &self.spare[..Self::RECORD_END],
+            )
+            .map_err(Error::Flash)
+    }
+
+    /// Bad block markers are written without a MAC
+    fn mark_bad(&mut self, address: u64) -> Result<(), Self::Error> {
+        self.flash.mark_bad(address).map_err(Error::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::*;
+    use crate::mock::RamFlash;
+
+    type TestAuth = Authenticated<RamFlash, Hmac<Sha256>, 4>;
+
+    fn authenticated(flash: RamFlash) -> TestAuth {
+        Authenticated::new(flash, Hmac::new_from_slice(b"key").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn record_survives_ecc_parity() {
+        let mut auth = authenticated(RamFlash::new(4));
+        let data = [0x5A; PAGE_SIZE];
+        let meta = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let mut spare = [0xFF; SPARE_META + 6];
+        spare[SPARE_META..].copy_from_slice(&meta);
+        auth.write_page(0, &data, &spare).unwrap();
+
+        // the chip writes its parity over the spare bytes from SPARE_USER_SIZE on
+        let mut flash = auth.into_inner();
+        let mut parity = [0xFF; SPARE_SIZE];
+        parity[SPARE_USER_SIZE..].fill(0x00);
+        flash.write_page(0, &[], &parity).unwrap();
+
+        let mut auth = authenticated(flash);
+        let (mut read, mut read_spare) = ([0; PAGE_SIZE], [0; SPARE_META + 6]);
+        auth.read_page(0, &mut read, &mut read_spare).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(read_spare[SPARE_META..], meta);
+    }
+
+    #[test]
+    fn changed_page_fails_to_authenticate() {
+        let mut auth = authenticated(RamFlash::new(4));
+        auth.write(0, &[0x5A; PAGE_SIZE]).unwrap();
+        let mut flash = auth.into_inner();
+        let mut data = [0xFF; PAGE_SIZE];
+        data[100] = 0x00;
+        flash.write_page(0, &data, &[]).unwrap();
+        let mut auth = authenticated(flash);
+        let mut read = [0; PAGE_SIZE];
+        assert!(matches!(
+            auth.read(0, &mut read),
+            Err(Error::Nand(NandFlashErrorKind::Integrity(Some(0))))
+        ));
+    }
+}
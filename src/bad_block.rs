@@ -0,0 +1,733 @@
+//! Logical device that hides bad blocks.
+//!
+//! Blocks marked bad are skipped when the device is opened, giving a contiguous logical
+//! address space over the remaining good blocks. The last `reserve` blocks are held back as
+//! replacements for blocks that fail to erase or program at runtime. A failed block is marked
+//! bad and tagged with its replacement so the same mapping is rebuilt on the next scan.
+//!
+//! The tag is programmed into the spare area of the last page of the failed block, on its own
+//! rather than on top of the page holding the marker, as a second program of a page reads as
+//! uncorrectable with on chip ECC enabled. A block that fails part way through being written
+//! has not used its last page yet. If the tag cannot be read the block is still taken as bad,
+//! but its replacement is lost, so keep a table where that matters.
+//!
+//! Opened with [`BadBlockDevice::with_table`] the block states are kept in an on-flash
+//! [bad block table](crate::bbt) instead, which is loaded in place of the scan and rewritten
+//! whenever a block is retired.
+use crate::{
+    bbt::{self, BAD, BBT_BLOCKS, GOOD, MIRROR, PRIMARY, TABLE, WORN},
+    mem::{PAGE_SIZE, SPARE_SIZE},
+    traits::{
+        check_erase, check_page, check_read, check_write, BlockStatus, EccStatus, ErrorType,
+        NandFlash, NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+/// Spare bytes written to the last page of a retired block: tag, replacement and its
+/// complement
+const TAG: [u8; 3] = [0x00, b'R', b'B'];
+const TAG_LEN: usize = TAG.len() + 4;
+/// Longest chain of replacements followed when building the map
+const MAX_CHAIN: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// No good replacement blocks left
+    NoReplacement,
+    /// Device has more blocks than the map can hold
+    TooManyBlocks,
+    /// Not enough good blocks left to hold both copies of the bad block table
+    NoTableBlocks,
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+            _ => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Returns true if the error is an erase or program failure of the block
+fn is_block_fail<E: NandFlashError>(e: &E) -> bool {
+    matches!(e.kind(), NandFlashErrorKind::BlockFail(_))
+}
+
+/// Maps logical blocks onto the good physical blocks of `F`, for devices of up to `N` blocks.
+pub struct BadBlockDevice<F, const N: usize> {
+    flash: F,
+    /// Physical block of each logical block
+    map: [u16; N],
+    /// State of each physical block, see [`bbt`]
+    state: [u16; N],
+    /// Number of logical blocks
+    blocks: usize,
+    /// First block of the replacement pool
+    pool: usize,
+    /// Physical blocks managed, the pool runs up to here
+    end: usize,
+    /// Version of the on-flash table, if one is used
+    table: Option<u32>,
+    /// Worst ECC status over the blocks of the last read
+    ecc: EccStatus,
+}
+
+type BResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+impl<F, const N: usize> BadBlockDevice<F, N>
+where
+    F: SpareNandFlash,
+{
+    /// Scan `flash` for bad blocks, holding back the last `reserve` blocks as replacements
+    pub fn new(flash: F, reserve: usize) -> BResult<Self, F> {
+        let end = (flash.capacity() / F::ERASE_SIZE as u64) as usize;
+        let mut device = Self::empty(flash, end, reserve)?;
+        device.scan()?;
+        device.build_map();
+        Ok(device)
+    }
+
+    /// Load the bad block table from the last [`BBT_BLOCKS`] blocks of `flash`, holding back
+    /// the `reserve` blocks before them as replacements.
+    /// If no valid table is found the device is scanned and a new table written.
+    ///
+    /// Each replacement adds to the table, returns [`Error::TooManyBlocks`] if the table would
+    /// not fit a page with every block of the pool in use.
+    pub fn with_table(flash: F, reserve: usize) -> BResult<Self, F> {
+        let physical = (flash.capacity() / F::ERASE_SIZE as u64) as usize;
+        let end = physical.saturating_sub(BBT_BLOCKS);
+        // a pool block replaces at most one block, so the pool bounds the replacements
+        if bbt::max_table_len(physical, reserve) > F::WRITE_SIZE {
+            return Err(Error::TooManyBlocks);
+        }
+        let mut device = Self::empty(flash, physical, reserve + BBT_BLOCKS)?;
+        device.end = end;
+        let mut page = [0; PAGE_SIZE];
+        let page = &mut page[..F::WRITE_SIZE];
+        // Find the newest valid copy
+        let mut newest: Option<(u32, usize)> = None;
+        let mut copies = 0;
+        for block in end..physical {
+            device.state[block] = if device.is_good(block)? { TABLE } else { BAD };
+            if device.state[block] == BAD {
+                continue;
+            }
+            device
+                .flash
+                .read_page(Self::address(block), page, &mut [])
+                .map_err(Error::Flash)?;
+            match (bbt::version(page, physical), newest) {
+                (Some(version), Some((v, _))) if version == v => copies += 1,
+                (Some(version), Some((v, _))) if version < v => {}
+                (Some(version), _) => {
+                    newest = Some((version, block));
+                    copies = 1;
+                }
+                (None, _) => {}
+            }
+        }
+        match newest {
+            Some((version, block)) => {
+                device
+                    .flash
+                    .read_page(Self::address(block), page, &mut [])
+                    .map_err(Error::Flash)?;
+                bbt::decode(page, &mut device.state[..physical]);
+                device.table = Some(version);
+                device.build_map();
+                // bring an outdated or missing copy up to date
+                if copies < 2 {
+                    device.store_table()?;
+                }
+            }
+            None => {
+                device.scan()?;
+                device.build_map();
+                device.table = Some(0);
+                device.store_table()?;
+            }
+        }
+        Ok(device)
+    }
+
+    fn empty(flash: F, end: usize, reserve: usize) -> BResult<Self, F> {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::SPARE_SIZE >= TAG_LEN);
+        if end > N || end >= TABLE as usize {
+            return Err(Error::TooManyBlocks);
+        }
+        Ok(Self {
+            flash,
+            map: [0; N],
+            state: [GOOD; N],
+            blocks: 0,
+            pool: end.saturating_sub(reserve),
+            end,
+            table: None,
+            ecc: EccStatus::NoErrors,
+        })
+    }
+
+    /// Read the state of each block from the markers and replacement tags
+    fn scan(&mut self) -> BResult<(), F> {
+        for block in 0..self.end {
+            self.state[block] = if self.is_good(block)? {
+                GOOD
+            } else {
+                self.read_tag(block)?.unwrap_or(BAD)
+            };
+        }
+        Ok(())
+    }
+
+    /// Map each good or replaced block before the pool to the next logical block
+    fn build_map(&mut self) {
+        self.blocks = 0;
+        for block in 0..self.pool {
+            if let Some(physical) = self.resolve(block) {
+                self.map[self.blocks] = physical as u16;
+                self.blocks += 1;
+            }
+        }
+    }
+
+    /// Follow the replacements from `block` to a good block
+    fn resolve(&self, mut block: usize) -> Option<usize> {
+        for _ in 0..MAX_CHAIN {
+            match self.state[block] {
+                GOOD => return Some(block),
+                BAD | WORN | TABLE => return None,
+                next if (self.pool..self.end).contains(&(next as usize)) => block = next as usize,
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    fn is_good(&mut self, block: usize) -> BResult<bool, F> {
+        let status = self
+            .flash
+            .block_status(Self::address(block))
+            .map_err(Error::Flash)?;
+        Ok(status != BlockStatus::Failed)
+    }
+
+    /// Byte address of the page holding the tag of a retired block
+    fn tag_address(block: usize) -> u64 {
+        Self::address(block + 1) - F::WRITE_SIZE as u64
+    }
+
+    /// Read the replacement recorded in a retired block, [None] if it has no readable tag
+    fn read_tag(&mut self, block: usize) -> BResult<Option<u16>, F> {
+        let mut tag = [0; TAG_LEN];
+        match self
+            .flash
+            .read_page(Self::tag_address(block), &mut [], &mut tag)
+        {
+            Ok(()) => {}
+            Err(e) if is_block_fail(&e) => return Ok(None),
+            Err(e) => return Err(Error::Flash(e)),
+        }
+        let (marker, next) = tag.split_at(TAG.len());
+        let next = [next[0], next[1], !next[2], !next[3]];
+        if marker != TAG || next[..2] != next[2..] {
+            return Ok(None);
+        }
+        Ok(Some(u16::from_be_bytes([next[0], next[1]])))
+    }
+
+    /// Write both copies of the table with the next version number
+    fn store_table(&mut self) -> BResult<(), F> {
+        let Some(version) = self.table else {
+            return Ok(());
+        };
+        let version = version.wrapping_add(1);
+        let physical = self.end + BBT_BLOCKS;
+        let mut page = [0; PAGE_SIZE];
+        let page = &mut page[..F::WRITE_SIZE];
+        let mut block = self.end;
+        for pattern in [PRIMARY, MIRROR] {
+            bbt::encode(&self.state[..physical], pattern, version, page);
+            loop {
+                while block < physical && self.state[block] != TABLE {
+                    block += 1;
+                }
+                if block == physical {
+                    return Err(Error::NoTableBlocks);
+                }
+                let address = Self::address(block);
+                let result = self
+                    .flash
+                    .erase(address, address + F::ERASE_SIZE as u64)
+                    .and_then(|_| self.flash.write_page(address, page, &[]));
+                block += 1;
+                match result {
+                    Ok(()) => break,
+                    Err(e) if is_block_fail(&e) => {
+                        self.state[block - 1] = WORN;
+                        let _ = self.flash.mark_bad(address);
+                        // the state changed, encode again
+                        bbt::encode(&self.state[..physical], pattern, version, page);
+                    }
+                    Err(e) => return Err(Error::Flash(e)),
+                }
+            }
+        }
+        self.table = Some(version);
+        Ok(())
+    }
+
+    /// Byte address of a physical block
+    fn address(block: usize) -> u64 {
+        block as u64 * F::ERASE_SIZE as u64
+    }
+
+    /// Check `address` falls within a logical block
+    fn check_address(&self, address: u64) -> BResult<(), F> {
+        if address >= self.capacity() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        Ok(())
+    }
+
+    /// Physical byte address of a logical address
+    fn translate(&self, address: u64) -> u64 {
+        let block = (address / F::ERASE_SIZE as u64) as usize;
+        Self::address(self.map[block] as usize) + address % F::ERASE_SIZE as u64
+    }
+
+    /// Replace the physical block behind `logical`, copying its first `pages` pages across.
+    /// Pages of the failed block that are uncorrectable are left erased in the replacement.
+    /// The failed block is tagged with its replacement and marked bad.
+    fn retire(&mut self, logical: usize, pages: usize) -> BResult<(), F> {
+        let old = self.map[logical] as usize;
+        let new = self.replacement()?;
+        let mut data = [0; PAGE_SIZE];
+        let mut spare = [0; SPARE_SIZE];
+        let (data, spare) = (&mut data[..F::WRITE_SIZE], &mut spare[..F::SPARE_SIZE]);
+        for page in 0..pages {
+            let offset = (page * F::WRITE_SIZE) as u64;
+            match self
+                .flash
+                .read_page(Self::address(old) + offset, data, spare)
+            {
+                Ok(()) => {}
+                // the data is already lost, the rest of the block can still be saved
+                Err(e) if is_block_fail(&e) => continue,
+                Err(e) => return Err(Error::Flash(e)),
+            }
+            // leave erased pages alone so they can still be programmed
+            if data.iter().chain(spare.iter()).all(|b| *b == 0xFF) {
+                continue;
+            }
+            self.flash
+                .write_page(Self::address(new) + offset, data, spare)
+                .map_err(Error::Flash)?;
+        }
+        let [hi, lo] = (new as u16).to_be_bytes();
+        let mut tag = [0; TAG_LEN];
+        tag[..TAG.len()].copy_from_slice(&TAG);
+        tag[TAG.len()..].copy_from_slice(&[hi, lo, !hi, !lo]);
+        // The block is failing, the writes may not take but the state in RAM is still correct
+        let _ = self.flash.write_page(Self::tag_address(old), &[], &tag);
+        let _ = self.flash.mark_bad(Self::address(old));
+        self.state[old] = new as u16;
+        self.map[logical] = new as u16;
+        self.store_table()
+    }
+
+    /// Find and erase an unused good block from the pool
+    fn replacement(&mut self) -> BResult<usize, F> {
+        for block in self.pool..self.end {
+            if self.state[block] != GOOD || self.map[..self.blocks].contains(&(block as u16)) {
+                continue;
+            }
+            let address = Self::address(block);
+            match self.flash.erase(address, address + F::ERASE_SIZE as u64) {
+                Ok(()) => return Ok(block),
+                Err(e) if is_block_fail(&e) => {
+                    self.state[block] = WORN;
+                    let _ = self.flash.mark_bad(address);
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+        Err(Error::NoReplacement)
+    }
+
+    /// Number of logical blocks
+    pub fn logical_blocks(&self) -> usize {
+        self.blocks
+    }
+
+    /// Physical block behind logical block `logical`
+    pub fn physical_block(&self, logical: usize) -> Option<usize> {
+        self.map[..self.blocks].get(logical).map(|b| *b as usize)
+    }
+
+    /// Number of good replacement blocks left in the pool
+    pub fn replacements_left(&self) -> usize {
+        (self.pool..self.end)
+            .filter(|b| self.state[*b] == GOOD && !self.map[..self.blocks].contains(&(*b as u16)))
+            .count()
+    }
+
+    /// Version of the on-flash bad block table, if one is used
+    pub fn table_version(&self) -> Option<u32> {
+        self.table
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F, const N: usize> ErrorType for BadBlockDevice<F, N>
+where
+    F: SpareNandFlash,
+{
+    type Error = Error<F::Error>;
+}
+
+impl<F, const N: usize> ReadNandFlash for BadBlockDevice<F, N>
+where
+    F: SpareNandFlash,
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let mut offset = offset;
+        let mut bytes = bytes;
+        self.ecc = EccStatus::NoErrors;
+        // Split the read at block boundaries as they may not be physically contiguous
+        while !bytes.is_empty() {
+            let len = (F::ERASE_SIZE - (offset % F::ERASE_SIZE as u64) as usize).min(bytes.len());
+            let (chunk, rest) = bytes.split_at_mut(len);
+            self.flash
+                .read(self.translate(offset), chunk)
+                .map_err(Error::Flash)?;
+            self.ecc = self.ecc.max(self.flash.ecc_status());
+            offset += len as u64;
+            bytes = rest;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        Self::address(self.blocks)
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        self.check_address(address)?;
+        self.flash
+            .block_status(self.translate(address))
+            .map_err(Error::Flash)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.ecc
+    }
+}
+
+impl<F, const N: usize> NandFlash for BadBlockDevice<F, N>
+where
+    F: SpareNandFlash,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        for address in (from..to).step_by(F::ERASE_SIZE) {
+            let physical = self.translate(address);
+            match self.flash.erase(physical, physical + F::ERASE_SIZE as u64) {
+                Ok(()) => {}
+                // the replacement is erased when it is taken from the pool
+                Err(e) if is_block_fail(&e) => {
+                    self.retire((address / F::ERASE_SIZE as u64) as usize, 0)?
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        for (i, page) in bytes.chunks_exact(F::WRITE_SIZE).enumerate() {
+            self.write_page(offset + (i * F::WRITE_SIZE) as u64, page, &[])?;
+        }
+        Ok(())
+    }
+}
+
+impl<F, const N: usize> SpareNandFlash for BadBlockDevice<F, N>
+where
+    F: SpareNandFlash,
+{
+    const SPARE_SIZE: usize = F::SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.flash
+            .read_page(self.translate(offset), data, spare)
+            .map_err(Error::Flash)?;
+        self.ecc = self.flash.ecc_status();
+        Ok(())
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let logical = (offset / F::ERASE_SIZE as u64) as usize;
+        let page = (offset % F::ERASE_SIZE as u64) as usize / F::WRITE_SIZE;
+        loop {
+            match self.flash.write_page(self.translate(offset), data, spare) {
+                Ok(()) => return Ok(()),
+                // move the pages written so far and try again on the replacement
+                Err(e) if is_block_fail(&e) => self.retire(logical, page)?,
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+    }
+
+    /// Retire the block behind `address`, moving its contents to a replacement
+    fn mark_bad(&mut self, address: u64) -> Result<(), Self::Error> {
+        self.check_address(address)?;
+        let pages = F::ERASE_SIZE / F::WRITE_SIZE;
+        self.retire((address / F::ERASE_SIZE as u64) as usize, pages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mem::{BLOCK_SIZE, PAGES_PER_BLOCK},
+        mock::RamFlash,
+    };
+
+    const BLOCKS: usize = 16;
+    type Device = BadBlockDevice<RamFlash, BLOCKS>;
+
+    fn page(block: usize, page: usize) -> [u8; PAGE_SIZE] {
+        [(block * PAGES_PER_BLOCK + page) as u8; PAGE_SIZE]
+    }
+
+    fn address(block: usize, page: usize) -> u64 {
+        (block * BLOCK_SIZE + page * PAGE_SIZE) as u64
+    }
+
+    /// Program the first `pages` pages of logical `block`
+    fn fill(device: &mut Device, block: usize, pages: usize) {
+        for i in 0..pages {
+            device
+                .write_page(address(block, i), &page(block, i), &[])
+                .unwrap();
+        }
+    }
+
+    /// Check the first `pages` pages of logical `block`, skipping those in `lost`
+    fn check(device: &mut Device, block: usize, pages: usize, lost: &[usize]) {
+        let mut data = [0; PAGE_SIZE];
+        for i in 0..pages {
+            device
+                .read_page(address(block, i), &mut data, &mut [])
+                .unwrap();
+            if lost.contains(&i) {
+                assert!(data.iter().all(|b| *b == 0xFF));
+            } else {
+                assert_eq!(data, page(block, i));
+            }
+        }
+    }
+
+    #[test]
+    fn scan_skips_bad_blocks() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.mark_bad(address(2, 0)).unwrap();
+        let device = Device::new(flash, 4).unwrap();
+        assert_eq!(device.logical_blocks(), BLOCKS - 4 - 1);
+        assert_eq!(device.physical_block(1), Some(1));
+        assert_eq!(device.physical_block(2), Some(3));
+        assert_eq!(device.replacements_left(), 4);
+    }
+
+    #[test]
+    fn program_failure_remaps_block() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.fail_program = Some(address(1, 3));
+        let mut device = Device::new(flash, 4).unwrap();
+        fill(&mut device, 1, 5);
+        check(&mut device, 1, 5, &[]);
+        assert_eq!(device.physical_block(1), Some(BLOCKS - 4));
+        assert_eq!(device.replacements_left(), 3);
+
+        // the mapping is rebuilt from the tag
+        let mut flash = device.into_inner();
+        assert_eq!(flash.block_status(address(1, 0)), Ok(BlockStatus::Failed));
+        let mut device = Device::new(flash, 4).unwrap();
+        assert_eq!(device.logical_blocks(), BLOCKS - 4);
+        assert_eq!(device.physical_block(1), Some(BLOCKS - 4));
+        assert_eq!(device.replacements_left(), 3);
+        check(&mut device, 1, 5, &[]);
+    }
+
+    #[test]
+    fn erase_failure_remaps_block() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.fail_erase.push(2);
+        let mut device = Device::new(flash, 4).unwrap();
+        device.erase(address(2, 0), address(3, 0)).unwrap();
+        assert_eq!(device.physical_block(2), Some(BLOCKS - 4));
+        fill(&mut device, 2, 2);
+
+        let mut flash = device.into_inner();
+        flash.fail_erase.clear();
+        let mut device = Device::new(flash, 4).unwrap();
+        assert_eq!(device.physical_block(2), Some(BLOCKS - 4));
+        check(&mut device, 2, 2, &[]);
+    }
+
+    #[test]
+    fn uncorrectable_page_of_failing_block_is_skipped() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.fail_program = Some(address(1, 3));
+        let mut device = Device::new(flash, 4).unwrap();
+        fill(&mut device, 1, 2);
+        device.flash.uncorrectable.push(address(1, 1));
+        // the write that found the failing block still goes through
+        device.write_page(address(1, 2), &page(1, 2), &[]).unwrap();
+        device.write_page(address(1, 3), &page(1, 3), &[]).unwrap();
+        check(&mut device, 1, 4, &[1]);
+    }
+
+    #[test]
+    fn unreadable_marker_page_keeps_the_mapping() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.fail_program = Some(address(1, 3));
+        let mut device = Device::new(flash, 4).unwrap();
+        fill(&mut device, 1, 5);
+        // the marker was a second program of the first page
+        let mut flash = device.into_inner();
+        flash.uncorrectable.push(address(1, 0));
+        let mut device = Device::new(flash, 4).unwrap();
+        assert_eq!(device.physical_block(1), Some(BLOCKS - 4));
+        check(&mut device, 1, 5, &[]);
+    }
+
+    #[test]
+    fn unreadable_tag_leaves_block_bad() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.fail_program = Some(address(1, 3));
+        let mut device = Device::new(flash, 4).unwrap();
+        fill(&mut device, 1, 5);
+        let mut flash = device.into_inner();
+        flash.uncorrectable.push(address(1, PAGES_PER_BLOCK - 1));
+        let device = Device::new(flash, 4).unwrap();
+        assert_eq!(device.logical_blocks(), BLOCKS - 4 - 1);
+        assert_eq!(device.physical_block(1), Some(2));
+    }
+
+    #[test]
+    fn table_is_versioned_and_mirrored() {
+        let mut device = Device::with_table(RamFlash::new(BLOCKS), 2).unwrap();
+        assert_eq!(device.table_version(), Some(1));
+        let pool = BLOCKS - BBT_BLOCKS - 2;
+        assert_eq!(device.logical_blocks(), pool);
+        device.flash.fail_program = Some(address(3, 0));
+        fill(&mut device, 3, 1);
+        assert_eq!(device.table_version(), Some(2));
+        assert_eq!(device.physical_block(3), Some(pool));
+
+        // the table is used instead of a scan, which would not find the tag
+        let mut flash = device.into_inner();
+        flash.uncorrectable.push(address(3, PAGES_PER_BLOCK - 1));
+        let mut device = Device::with_table(flash, 2).unwrap();
+        assert_eq!(device.table_version(), Some(2));
+        assert_eq!(device.physical_block(3), Some(pool));
+        check(&mut device, 3, 1, &[]);
+
+        // a lost primary is restored from the mirror with a new version
+        let mut flash = device.into_inner();
+        let primary = address(BLOCKS - BBT_BLOCKS, 0);
+        flash.erase(primary, primary + BLOCK_SIZE as u64).unwrap();
+        let device = Device::with_table(flash, 2).unwrap();
+        assert_eq!(device.table_version(), Some(3));
+        assert_eq!(device.physical_block(3), Some(pool));
+        let mut flash = device.into_inner();
+        let mirror = address(BLOCKS - BBT_BLOCKS + 1, 0);
+        flash.erase(mirror, mirror + BLOCK_SIZE as u64).unwrap();
+        let device = Device::with_table(flash, 2).unwrap();
+        assert_eq!(device.table_version(), Some(4));
+        assert_eq!(device.physical_block(3), Some(pool));
+    }
+
+    #[test]
+    fn newest_table_copy_wins() {
+        let mut device = Device::with_table(RamFlash::new(BLOCKS), 2).unwrap();
+        let mut old = [0; PAGE_SIZE];
+        let primary = address(BLOCKS - BBT_BLOCKS, 0);
+        device.flash.read_page(primary, &mut old, &mut []).unwrap();
+        device.flash.fail_program = Some(address(3, 0));
+        fill(&mut device, 3, 1);
+        let version = device.table_version();
+
+        // power lost after the primary was rewritten, the mirror still has the old version
+        let mut flash = device.into_inner();
+        let mirror = address(BLOCKS - BBT_BLOCKS + 1, 0);
+        flash.erase(mirror, mirror + BLOCK_SIZE as u64).unwrap();
+        let mut states = [GOOD; BLOCKS];
+        bbt::decode(&old, &mut states);
+        let mut stale = [0; PAGE_SIZE];
+        bbt::encode(&states, bbt::MIRROR, 1, &mut stale);
+        flash.write_page(mirror, &stale, &[]).unwrap();
+        let device = Device::with_table(flash, 2).unwrap();
+        assert_eq!(device.physical_block(3), Some(BLOCKS - BBT_BLOCKS - 2));
+        assert!(device.table_version() > version);
+    }
+
+    #[test]
+    fn failed_table_block_is_skipped() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.fail_erase.push(BLOCKS - BBT_BLOCKS);
+        let device = Device::with_table(flash, 2).unwrap();
+        let mut flash = device.into_inner();
+        flash.fail_erase.clear();
+        assert_eq!(
+            flash.block_status(address(BLOCKS - BBT_BLOCKS, 0)),
+            Ok(BlockStatus::Failed)
+        );
+        let device = Device::with_table(flash, 2).unwrap();
+        assert_eq!(device.table_version(), Some(1));
+    }
+
+    #[test]
+    fn reserve_too_large_for_table() {
+        // every pool block would add a replacement to the table page
+        let reserve = (PAGE_SIZE - bbt::max_table_len(BLOCKS, 0)) / 4 + 1;
+        assert!(matches!(
+            Device::with_table(RamFlash::new(BLOCKS), reserve),
+            Err(Error::TooManyBlocks)
+        ));
+        assert!(Device::with_table(RamFlash::new(BLOCKS), reserve - 1).is_ok());
+    }
+}
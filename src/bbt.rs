@@ -0,0 +1,161 @@
+//! On-flash bad block table.
+//!
+//! Like the Linux MTD table, two copies are kept in the last [`BBT_BLOCKS`] blocks of the
+//! device: a primary marked with the `Bbt0` pattern and a mirror marked `1tbB`. Each copy
+//! carries a version number and the copy with the highest version and a valid CRC is used.
+//!
+//! A table is a single page: pattern, version, block count and replacement count, then two
+//! bits per block (`11` good, `00` factory bad, `01` worn out, `10` reserved for the table),
+//! then a pair of block numbers for each worn block that was replaced, then the CRC.
+use crate::crc::crc32;
+
+/// Blocks at the end of the device set aside for the table copies
+pub const BBT_BLOCKS: usize = 4;
+
+/// Pattern of the primary copy
+pub const PRIMARY: [u8; 4] = *b"Bbt0";
+/// Pattern of the mirror copy
+pub const MIRROR: [u8; 4] = *b"1tbB";
+
+const HEADER: usize = 12;
+
+/// Per block state kept in RAM, any other value is the block replacing a worn block
+pub(crate) const GOOD: u16 = 0xFFFF;
+pub(crate) const BAD: u16 = 0xFFFE;
+pub(crate) const WORN: u16 = 0xFFFD;
+pub(crate) const TABLE: u16 = 0xFFFC;
+
+fn bits(state: u16) -> u8 {
+    match state {
+        GOOD => 0b11,
+        BAD => 0b00,
+        TABLE => 0b10,
+        _ => 0b01,
+    }
+}
+
+fn is_replaced(state: u16) -> bool {
+    !matches!(state, GOOD | BAD | WORN | TABLE)
+}
+
+/// Bytes needed to store a table of `states`
+pub(crate) fn table_len(states: &[u16]) -> usize {
+    let replaced = states.iter().filter(|s| is_replaced(**s)).count();
+    max_table_len(states.len(), replaced)
+}
+
+/// Bytes needed to store a table of `blocks` blocks with up to `replaced` replacements
+pub(crate) fn max_table_len(blocks: usize, replaced: usize) -> usize {
+    HEADER + blocks.div_ceil(4) + replaced * 4 + 4
+}
+
+/// Encode `states` into `page`, which must be at least [`table_len`] bytes
+pub(crate) fn encode(states: &[u16], pattern: [u8; 4], version: u32, page: &mut [u8]) {
+    page.fill(0xFF);
+    let replaced = states.iter().filter(|s| is_replaced(**s)).count();
+    page[0..4].copy_from_slice(&pattern);
+    page[4..8].copy_from_slice(&version.to_le_bytes());
+    page[8..10].copy_from_slice(&(states.len() as u16).to_le_bytes());
+    page[10..12].copy_from_slice(&(replaced as u16).to_le_bytes());
+    let (bitmap, rest) = page[HEADER..].split_at_mut(states.len().div_ceil(4));
+    bitmap.fill(0);
+    for (block, state) in states.iter().enumerate() {
+        bitmap[block / 4] |= bits(*state) << ((block % 4) * 2);
+    }
+    let pairs = states.iter().enumerate().filter(|(_, s)| is_replaced(**s));
+    for ((block, state), pair) in pairs.zip(rest.chunks_exact_mut(4)) {
+        pair[..2].copy_from_slice(&(block as u16).to_le_bytes());
+        pair[2..].copy_from_slice(&state.to_le_bytes());
+    }
+    let len = table_len(states);
+    let crc = crc32(&page[..len - 4]);
+    page[len - 4..len].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Check `page` holds a valid table for `blocks` blocks, returning its version
+pub(crate) fn version(page: &[u8], blocks: usize) -> Option<u32> {
+    if page[0..4] != PRIMARY && page[0..4] != MIRROR {
+        return None;
+    }
+    let stored = u16::from_le_bytes([page[8], page[9]]) as usize;
+    let replaced = u16::from_le_bytes([page[10], page[11]]) as usize;
+    let len = max_table_len(stored, replaced);
+    if stored != blocks || len > page.len() {
+        return None;
+    }
+    let crc = u32::from_le_bytes(page[len - 4..len].try_into().ok()?);
+    if crc != crc32(&page[..len - 4]) {
+        return None;
+    }
+    Some(u32::from_le_bytes(page[4..8].try_into().ok()?))
+}
+
+/// Decode a table checked by [`version`] into `states`
+pub(crate) fn decode(page: &[u8], states: &mut [u16]) {
+    let replaced = u16::from_le_bytes([page[10], page[11]]) as usize;
+    let (bitmap, rest) = page[HEADER..].split_at(states.len().div_ceil(4));
+    for (block, state) in states.iter_mut().enumerate() {
+        *state = match (bitmap[block / 4] >> ((block % 4) * 2)) & 0b11 {
+            0b11 => GOOD,
+            0b00 => BAD,
+            0b10 => TABLE,
+            _ => WORN,
+        };
+    }
+    for pair in rest.chunks_exact(4).take(replaced) {
+        let block = u16::from_le_bytes([pair[0], pair[1]]) as usize;
+        if let Some(state) = states.get_mut(block) {
+            *state = u16::from_le_bytes([pair[2], pair[3]]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 10 blocks: two factory bad, one worn out and replaced, two holding the table
+    const STATES: [u16; 10] = [GOOD, BAD, 7, GOOD, WORN, GOOD, BAD, GOOD, TABLE, TABLE];
+
+    #[test]
+    fn round_trip() {
+        let mut page = [0; 64];
+        encode(&STATES, PRIMARY, 42, &mut page);
+        assert_eq!(&page[..4], b"Bbt0");
+        assert_eq!(version(&page, STATES.len()), Some(42));
+        let mut states = [0; STATES.len()];
+        decode(&page, &mut states);
+        assert_eq!(states, STATES);
+
+        encode(&STATES, MIRROR, 43, &mut page);
+        assert_eq!(&page[..4], b"1tbB");
+        assert_eq!(version(&page, STATES.len()), Some(43));
+    }
+
+    #[test]
+    fn length_is_bounded_by_replacements() {
+        assert_eq!(table_len(&STATES), max_table_len(STATES.len(), 1));
+        let replaced = [3; 9];
+        assert_eq!(table_len(&replaced), max_table_len(9, 9));
+        assert!(table_len(&STATES) < max_table_len(STATES.len(), 2));
+    }
+
+    #[test]
+    fn invalid_tables_are_rejected() {
+        let mut page = [0; 64];
+        encode(&STATES, PRIMARY, 1, &mut page);
+        // for another device size
+        assert_eq!(version(&page, STATES.len() + 1), None);
+        // damaged
+        let mut damaged = page;
+        damaged[HEADER] ^= 1;
+        assert_eq!(version(&damaged, STATES.len()), None);
+        // no pattern
+        let mut damaged = page;
+        damaged[0] = b'X';
+        assert_eq!(version(&damaged, STATES.len()), None);
+        // longer than the page
+        assert_eq!(version(&page[..table_len(&STATES) - 1], STATES.len()), None);
+        assert_eq!(version(&[0xFF; 64], STATES.len()), None);
+    }
+}
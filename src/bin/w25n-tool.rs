@@ -0,0 +1,443 @@
+//! Inspect and edit raw W25N images on the host.
+//!
+//! Images are the files of [`NandImage`], every page followed by its spare area. The OTP area
+//! is kept in a separate file of [`otp::AREA_PAGES`] raw pages, as read from a device with
+//! [`w25n::W25N::read_otp`].
+//!
+//! `build` makes factory images from a manifest, see [`w25n::factory`].
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    ops::Range,
+    process::ExitCode,
+};
+
+use w25n::{
+    ecc::{Bch, EccCode, Hamming, SoftEcc},
+    factory::{self, Manifest},
+    image::{NandImage, RAW_PAGE_SIZE},
+    mem::{BLOCK_SIZE, PAGES_PER_BLOCK, PAGE_SIZE},
+    otp::{self, ParameterPage},
+    partition::PartitionTable,
+    traits::{BlockStatus, EccStatus, NandFlash, ReadNandFlash, SpareNandFlash},
+};
+
+type Result<T> = core::result::Result<T, Box<dyn Error>>;
+
+const USAGE: &str = "usage: w25n-tool <command> [args]
+
+commands:
+  dump <image> <page> [count]          hex dump pages and their spare areas
+  bad <image>                          list factory bad blocks
+  params <otp>                         show the parameter page and unique ID
+  otp <otp>                            hex dump the programmed OTP pages
+  erase <image> <block> [count]        erase blocks
+  program <image> <offset> <file>      program a file from a page aligned byte offset
+  extract <image> <partition> <file>   copy the good blocks of a partition to a file
+  insert <image> <partition> <file>    erase a partition and program a file into its good blocks
+  verify <image> [hamming|bch4|bch8]   check the software ECC of every page
+  compare <image> <image>              list the pages that differ
+  build <manifest> <image> <out>       build a factory image and its programming manifest
+
+A partition is a block range such as 16..80 or a name from the partition table, which is
+read from blocks 0..2 unless given as --table <first>..<end>.
+Numbers can be given in hex with a 0x prefix.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match run(&args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[&str]) -> Result<ExitCode> {
+    match args {
+        ["dump", image, page, rest @ ..] => {
+            let count = match rest {
+                [] => 1,
+                [count] => number(count)?,
+                _ => return usage(),
+            };
+            dump(image, number(page)?, count)?
+        }
+        ["bad", image] => bad(image)?,
+        ["params", file] => params(file)?,
+        ["otp", file] => otp_pages(file)?,
+        ["erase", image, block, rest @ ..] => {
+            let count = match rest {
+                [] => 1,
+                [count] => number(count)?,
+                _ => return usage(),
+            };
+            erase(image, number(block)?, count)?
+        }
+        ["program", image, offset, file] => program(image, number(offset)?, file)?,
+        ["extract", image, partition, file, rest @ ..] => {
+            extract(image, partition, file, table(rest)?)?
+        }
+        ["insert", image, partition, file, rest @ ..] => {
+            insert(image, partition, file, table(rest)?)?
+        }
+        ["verify", image] => return verify(image, "hamming"),
+        ["verify", image, code] => return verify(image, code),
+        ["compare", a, b] => return compare(a, b),
+        ["build", manifest, image, out] => build(manifest, image, out)?,
+        _ => return usage(),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn usage() -> Result<ExitCode> {
+    eprintln!("{USAGE}");
+    Ok(ExitCode::from(2))
+}
+
+/// Decimal or 0x prefixed hex number
+fn number(arg: &str) -> Result<usize> {
+    let parsed = match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number '{arg}'").into())
+}
+
+/// Block range written as first..end
+fn range(arg: &str) -> Option<Range<usize>> {
+    let (first, end) = arg.split_once("..")?;
+    let range = number(first).ok()?..number(end).ok()?;
+    (!range.is_empty()).then_some(range)
+}
+
+/// Blocks holding the partition table, from an optional --table argument
+fn table(args: &[&str]) -> Result<Range<usize>> {
+    match args {
+        [] => Ok(0..2),
+        ["--table", blocks] => {
+            range(blocks).ok_or_else(|| format!("invalid range '{blocks}'").into())
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+/// Blocks of a partition given as a range or by name
+fn partition(
+    image: &mut NandImage<fs::File>,
+    arg: &str,
+    table: Range<usize>,
+) -> Result<Range<usize>> {
+    if let Some(blocks) = range(arg) {
+        return Ok(blocks);
+    }
+    let table = PartitionTable::<64>::load(image, table)
+        .map_err(|e| format!("reading partition table: {e:?}"))?
+        .ok_or("no partition table found")?;
+    let entry = table
+        .find(arg)
+        .ok_or_else(|| format!("no partition named '{arg}'"))?;
+    Ok(entry.blocks())
+}
+
+/// Good blocks of `blocks`, skipping factory bad blocks
+fn good_blocks(image: &mut NandImage<fs::File>, blocks: Range<usize>) -> Result<Vec<usize>> {
+    if blocks.end > image.blocks() {
+        return Err(format!(
+            "blocks {blocks:?} beyond the {} in the image",
+            image.blocks()
+        )
+        .into());
+    }
+    let mut good = Vec::new();
+    for block in blocks {
+        if image.block_status((block * BLOCK_SIZE) as u64)? != BlockStatus::Failed {
+            good.push(block);
+        }
+    }
+    Ok(good)
+}
+
+/// Hex dump with identical lines collapsed to '*'
+fn hex(out: &mut impl Write, base: usize, bytes: &[u8]) -> io::Result<()> {
+    let mut previous: Option<&[u8]> = None;
+    let mut skipping = false;
+    for (i, line) in bytes.chunks(16).enumerate() {
+        if previous == Some(line) {
+            if !skipping {
+                writeln!(out, "*")?;
+                skipping = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        skipping = false;
+        write!(out, "{:08x} ", base + i * 16)?;
+        for byte in line {
+            write!(out, " {byte:02x}")?;
+        }
+        let text: String = line
+            .iter()
+            .map(|b| {
+                if b.is_ascii_graphic() {
+                    *b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(out, "  |{text}|")?;
+    }
+    Ok(())
+}
+
+fn dump(image: &str, page: usize, count: usize) -> Result<()> {
+    let mut image = NandImage::open(image)?;
+    let mut raw = [0; RAW_PAGE_SIZE];
+    let mut out = io::stdout().lock();
+    for page in page..page + count {
+        image.read_raw(page as u32, &mut raw)?;
+        writeln!(
+            out,
+            "page {page:#x} (block {}, page {}):",
+            page / PAGES_PER_BLOCK,
+            page % PAGES_PER_BLOCK
+        )?;
+        hex(&mut out, 0, &raw[..PAGE_SIZE])?;
+        writeln!(out, "spare:")?;
+        hex(&mut out, PAGE_SIZE, &raw[PAGE_SIZE..])?;
+    }
+    Ok(())
+}
+
+fn bad(image: &str) -> Result<()> {
+    let mut image = NandImage::open(image)?;
+    let blocks = image.blocks();
+    let good = good_blocks(&mut image, 0..blocks)?;
+    let mut bad = 0;
+    for block in (0..blocks).filter(|b| !good.contains(b)) {
+        println!("{block:#06x} at {:#010x}", block * BLOCK_SIZE);
+        bad += 1;
+    }
+    println!("{bad} bad blocks of {blocks}");
+    Ok(())
+}
+
+/// Contents of an OTP area file
+fn otp_area(file: &str) -> Result<Vec<u8>> {
+    let area = fs::read(file)?;
+    let len = otp::AREA_PAGES as usize * RAW_PAGE_SIZE;
+    if area.len() != len {
+        return Err(format!("OTP file is {} bytes, expected {len}", area.len()).into());
+    }
+    Ok(area)
+}
+
+fn otp_page(area: &[u8], page: u32) -> &[u8] {
+    &area[page as usize * RAW_PAGE_SIZE..(page as usize + 1) * RAW_PAGE_SIZE]
+}
+
+fn params(file: &str) -> Result<()> {
+    let area = otp_area(file)?;
+    match otp::unique_id(otp_page(&area, otp::UNIQUE_ID_PAGE)) {
+        Some(id) => {
+            let id: String = id.iter().map(|b| format!("{b:02x}")).collect();
+            println!("unique id:          {id}");
+        }
+        None => println!("unique id:          invalid"),
+    }
+    let p = ParameterPage::parse(otp_page(&area, otp::PARAMETER_PAGE))
+        .ok_or("no copy of the parameter page has a valid CRC")?;
+    println!(
+        "manufacturer:       {} ({:#04x})",
+        p.manufacturer(),
+        p.jedec_id
+    );
+    println!("model:              {}", p.model());
+    println!("revision:           {:#06x}", p.revision);
+    println!(
+        "page size:          {} + {} spare",
+        p.page_size, p.spare_size
+    );
+    println!("pages per block:    {}", p.pages_per_block);
+    println!(
+        "blocks per unit:    {} x {} units",
+        p.blocks_per_unit, p.units
+    );
+    println!("bits per cell:      {}", p.bits_per_cell);
+    println!("max bad blocks:     {}", p.max_bad_blocks);
+    println!("endurance:          {} cycles", p.endurance);
+    println!("programs per page:  {}", p.programs_per_page);
+    println!("ecc bits:           {}", p.ecc_bits);
+    println!("tPROG max:          {} us", p.max_program_us);
+    println!("tBE max:            {} us", p.max_erase_us);
+    println!("tRD max:            {} us", p.max_read_us);
+    Ok(())
+}
+
+fn otp_pages(file: &str) -> Result<()> {
+    let area = otp_area(file)?;
+    let mut out = io::stdout().lock();
+    for page in otp::OTP_PAGES {
+        let raw = otp_page(&area, page);
+        if raw.iter().all(|b| *b == 0xFF) {
+            writeln!(out, "otp page {}: erased", page - otp::OTP_PAGES.start)?;
+        } else {
+            writeln!(out, "otp page {}:", page - otp::OTP_PAGES.start)?;
+            hex(&mut out, 0, raw)?;
+        }
+    }
+    Ok(())
+}
+
+fn erase(image: &str, block: usize, count: usize) -> Result<()> {
+    let mut image = NandImage::open(image)?;
+    image.erase(
+        (block * BLOCK_SIZE) as u64,
+        ((block + count) * BLOCK_SIZE) as u64,
+    )?;
+    image.flush()?;
+    Ok(())
+}
+
+/// File contents padded with 0xFF to whole pages
+fn pages(file: &str) -> Result<Vec<u8>> {
+    let mut data = fs::read(file)?;
+    data.resize(data.len().next_multiple_of(PAGE_SIZE), 0xFF);
+    Ok(data)
+}
+
+fn program(image: &str, offset: usize, file: &str) -> Result<()> {
+    let mut image = NandImage::open(image)?;
+    image.write(offset as u64, &pages(file)?)?;
+    image.flush()?;
+    Ok(())
+}
+
+fn extract(image: &str, name: &str, file: &str, table: Range<usize>) -> Result<()> {
+    let mut image = NandImage::open(image)?;
+    let blocks = partition(&mut image, name, table)?;
+    let mut data = vec![0; BLOCK_SIZE];
+    let mut out = io::BufWriter::new(fs::File::create(file)?);
+    for block in good_blocks(&mut image, blocks)? {
+        image.read((block * BLOCK_SIZE) as u64, &mut data)?;
+        out.write_all(&data)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn insert(image: &str, name: &str, file: &str, table: Range<usize>) -> Result<()> {
+    let mut image = NandImage::open(image)?;
+    let blocks = partition(&mut image, name, table)?;
+    let good = good_blocks(&mut image, blocks)?;
+    let data = pages(file)?;
+    if data.len() > good.len() * BLOCK_SIZE {
+        return Err(format!("{file} does not fit in the {} good blocks", good.len()).into());
+    }
+    for block in &good {
+        let address = (block * BLOCK_SIZE) as u64;
+        image.erase(address, address + BLOCK_SIZE as u64)?;
+    }
+    for (block, chunk) in good.iter().zip(data.chunks(BLOCK_SIZE)) {
+        image.write((block * BLOCK_SIZE) as u64, chunk)?;
+    }
+    image.flush()?;
+    Ok(())
+}
+
+fn verify(image: &str, code: &str) -> Result<ExitCode> {
+    let image = NandImage::open(image)?;
+    match code {
+        "hamming" => verify_with(SoftEcc::new(image, Hamming)),
+        "bch4" => verify_with(SoftEcc::new(image, Bch::<4>::new())),
+        "bch8" => verify_with(SoftEcc::new(image, Bch::<8>::new())),
+        _ => Err(format!("unknown code '{code}'").into()),
+    }
+}
+
+fn verify_with<C: EccCode>(mut flash: SoftEcc<NandImage<fs::File>, C>) -> Result<ExitCode> {
+    let mut page = [0; PAGE_SIZE];
+    let (mut corrected, mut failed) = (0, 0);
+    for address in (0..flash.capacity()).step_by(PAGE_SIZE) {
+        let result = flash.read_page(address, &mut page, &mut []);
+        let index = address / PAGE_SIZE as u64;
+        match (result, flash.ecc_status()) {
+            (Err(_), _) | (_, EccStatus::Uncorrectable) => {
+                println!("page {index:#x}: uncorrectable");
+                failed += 1;
+            }
+            (Ok(()), EccStatus::Corrected(bits)) => {
+                println!("page {index:#x}: corrected {} bits", bits.unwrap_or(0));
+                corrected += 1;
+            }
+            (Ok(()), EccStatus::NoErrors) => {}
+        }
+    }
+    println!("{corrected} pages corrected, {failed} uncorrectable");
+    Ok(if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn compare(a: &str, b: &str) -> Result<ExitCode> {
+    let mut a = NandImage::open(a)?;
+    let mut b = NandImage::open(b)?;
+    if a.blocks() != b.blocks() {
+        println!(
+            "images differ in size: {} and {} blocks",
+            a.blocks(),
+            b.blocks()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+    let (mut raw_a, mut raw_b) = ([0; RAW_PAGE_SIZE], [0; RAW_PAGE_SIZE]);
+    let mut differ = 0;
+    for page in 0..(a.blocks() * PAGES_PER_BLOCK) as u32 {
+        a.read_raw(page, &mut raw_a)?;
+        b.read_raw(page, &mut raw_b)?;
+        let data = raw_a[..PAGE_SIZE] != raw_b[..PAGE_SIZE];
+        let spare = raw_a[PAGE_SIZE..] != raw_b[PAGE_SIZE..];
+        let what = match (data, spare) {
+            (false, false) => continue,
+            (true, false) => "data",
+            (false, true) => "spare",
+            (true, true) => "data and spare",
+        };
+        println!("page {page:#x}: {what} differ");
+        differ += 1;
+    }
+    println!("{differ} pages differ");
+    Ok(if differ == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn build(manifest: &str, image: &str, out: &str) -> Result<()> {
+    let manifest = Manifest::load(manifest)?;
+    let image = NandImage::create(image, manifest.blocks)?;
+    let (mut image, build) = factory::build(&manifest, image)?;
+    image.flush()?;
+    let mut file = io::BufWriter::new(fs::File::create(out)?);
+    build.write_manifest(&mut file)?;
+    file.flush()?;
+    for region in &build.regions {
+        println!(
+            "{:<16} blocks {:#06x}..{:#06x}: {} to program {}, {} spare",
+            region.name,
+            region.blocks.start,
+            region.blocks.end,
+            region.program,
+            region.placement.name(),
+            region.blocks.len() - region.required
+        );
+    }
+    Ok(())
+}
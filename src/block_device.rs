@@ -0,0 +1,207 @@
+//! 512 byte sector block device for FAT filesystems and USB mass storage.
+//!
+//! [`FtlBlockDevice`] implements the embedded-sdmmc [`BlockDevice`] trait, blocks of
+//! [`Block::LEN`] bytes addressed by index through a shared reference, so it can back a FAT
+//! volume manager or a mass storage class directly.
+//!
+//! It splits each [`Ftl`] page into 512 byte sectors. The page last touched is held in a
+//! cache: sector writes are merged into it and it is only written to the FTL when another page
+//! is needed or on [`FtlBlockDevice::flush`]. Writes are not durable until flushed, so flush
+//! when the host ejects or syncs.
+use core::cell::RefCell;
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+
+use crate::{
+    ftl::{self, Ftl},
+    mem::PAGE_SIZE,
+    traits::{ErrorType, SpareNandFlash},
+};
+
+/// Bytes in a block device sector
+pub const SECTOR_SIZE: usize = Block::LEN;
+
+struct Cache<F, const SECTORS: usize, const BLOCKS: usize> {
+    ftl: Ftl<F, SECTORS, BLOCKS>,
+    page: [u8; PAGE_SIZE],
+    /// FTL sector held in `page`
+    cached: Option<u32>,
+    dirty: bool,
+}
+
+type CResult<T, F> = Result<T, ftl::Error<<F as ErrorType>::Error>>;
+
+impl<F, const SECTORS: usize, const BLOCKS: usize> Cache<F, SECTORS, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    fn flush(&mut self) -> CResult<(), F> {
+        if let (Some(sector), true) = (self.cached, self.dirty) {
+            let size = self.ftl.sector_size();
+            self.ftl.write(sector, &self.page[..size])?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Make `sector` the cached page, reading it unless it is about to be overwritten
+    fn load(&mut self, sector: u32, overwrite: bool) -> CResult<(), F> {
+        if self.cached == Some(sector) {
+            return Ok(());
+        }
+        self.flush()?;
+        self.cached = None;
+        if !overwrite {
+            let size = self.ftl.sector_size();
+            self.ftl.read(sector, &mut self.page[..size])?;
+        }
+        self.cached = Some(sector);
+        Ok(())
+    }
+}
+
+/// 512 byte sectors over the pages of an [`Ftl`]
+pub struct FtlBlockDevice<F, const SECTORS: usize, const BLOCKS: usize> {
+    cache: RefCell<Cache<F, SECTORS, BLOCKS>>,
+    /// 512 byte sectors in each FTL sector
+    per_page: u32,
+}
+
+impl<F, const SECTORS: usize, const BLOCKS: usize> FtlBlockDevice<F, SECTORS, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    pub fn new(ftl: Ftl<F, SECTORS, BLOCKS>) -> Self {
+        let per_page = (ftl.sector_size() / SECTOR_SIZE) as u32;
+        Self {
+            cache: RefCell::new(Cache {
+                ftl,
+                page: [0; PAGE_SIZE],
+                cached: None,
+                dirty: false,
+            }),
+            per_page,
+        }
+    }
+
+    /// Write the cached page to the FTL
+    pub fn flush(&self) -> CResult<(), F> {
+        self.cache.borrow_mut().flush()
+    }
+
+    /// Flush and return the FTL
+    pub fn into_inner(self) -> CResult<Ftl<F, SECTORS, BLOCKS>, F> {
+        let mut cache = self.cache.into_inner();
+        cache.flush()?;
+        Ok(cache.ftl)
+    }
+
+    fn check(&self, start: u32, count: usize) -> CResult<(), F> {
+        let total = self.cache.borrow().ftl.sectors() as u64 * self.per_page as u64;
+        if start as u64 + count as u64 > total {
+            return Err(ftl::Error::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// FTL sector and byte offset in it of a 512 byte sector
+    fn locate(&self, index: u32) -> (u32, usize) {
+        (
+            index / self.per_page,
+            (index % self.per_page) as usize * SECTOR_SIZE,
+        )
+    }
+}
+
+impl<F, const SECTORS: usize, const BLOCKS: usize> BlockDevice
+    for FtlBlockDevice<F, SECTORS, BLOCKS>
+where
+    F: SpareNandFlash,
+    F::Error: 'static,
+{
+    type Error = ftl::Error<F::Error>;
+
+    fn read(&self, blocks: &mut [Block], start: BlockIdx) -> Result<(), Self::Error> {
+        let start = start.0;
+        self.check(start, blocks.len())?;
+        let mut cache = self.cache.borrow_mut();
+        for (index, block) in (start..).zip(blocks) {
+            let (sector, offset) = self.locate(index);
+            cache.load(sector, false)?;
+            block
+                .contents
+                .copy_from_slice(&cache.page[offset..offset + SECTOR_SIZE]);
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), Self::Error> {
+        let start = start.0;
+        self.check(start, blocks.len())?;
+        let mut cache = self.cache.borrow_mut();
+        for (i, (index, block)) in (start..).zip(blocks).enumerate() {
+            let (sector, offset) = self.locate(index);
+            // skip reading a page the rest of the write covers completely
+            let whole = offset == 0 && blocks.len() - i >= self.per_page as usize;
+            cache.load(sector, whole)?;
+            cache.page[offset..offset + SECTOR_SIZE].copy_from_slice(&block.contents);
+            cache.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        Ok(BlockCount(
+            self.cache.borrow().ftl.sectors() * self.per_page,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ftl::FtlConfig, mock::RamFlash};
+
+    type TestDevice = FtlBlockDevice<RamFlash, 128, 8>;
+
+    fn device(flash: RamFlash) -> TestDevice {
+        FtlBlockDevice::new(Ftl::mount(flash, FtlConfig::default()).unwrap())
+    }
+
+    fn block(fill: u8) -> Block {
+        Block {
+            contents: [fill; Block::LEN],
+        }
+    }
+
+    #[test]
+    fn sectors_survive_flush_and_remount() {
+        let dev = device(RamFlash::new(8));
+        let per_page = PAGE_SIZE / Block::LEN;
+        assert_eq!(
+            dev.num_blocks().unwrap(),
+            BlockCount((128 * per_page) as u32)
+        );
+
+        // a partial page, then a write spanning into the next page
+        dev.write(&[block(1)], BlockIdx(1)).unwrap();
+        let blocks: [Block; 5] = core::array::from_fn(|i| block(10 + i as u8));
+        dev.write(&blocks, BlockIdx(2)).unwrap();
+
+        let dev = device(dev.into_inner().unwrap().into_inner());
+        let mut read: [Block; 8] = core::array::from_fn(|_| Block::new());
+        dev.read(&mut read, BlockIdx(0)).unwrap();
+        assert_eq!(read[0].contents, [0xFF; Block::LEN]);
+        assert_eq!(read[1].contents, [1; Block::LEN]);
+        for (i, b) in read[2..7].iter().enumerate() {
+            assert_eq!(b.contents, [10 + i as u8; Block::LEN]);
+        }
+        assert_eq!(read[7].contents, [0xFF; Block::LEN]);
+
+        let end = BlockIdx((128 * per_page) as u32);
+        assert!(matches!(
+            dev.read(&mut read[..1], end),
+            Err(ftl::Error::OutOfBounds)
+        ));
+    }
+}
@@ -1,3 +1,5 @@
+// Full instruction set from the datasheet, not all are used by the driver yet
+#![allow(dead_code)]
 pub const RESET: u8 = 0xFF;
 pub const JEDEC: u8 = 0x9F;
 pub const READ_REG: u8 = 0x05;
@@ -0,0 +1,363 @@
+//! Several devices joined into one linear address space.
+//!
+//! [`Concat`] places its devices one after the other, so a board with several chips on separate
+//! chip selects appears as a single device. The devices can differ in size as long as each is a
+//! whole number of blocks. Reads, writes and erases that cross from one device into the next
+//! are split between them, the single page operations of [`SpareNandFlash`] never cross.
+use crate::{
+    mem::{PAGE_SIZE, SPARE_SIZE},
+    traits::{
+        check_erase, check_page, check_read, check_write, BlockStatus, EccStatus, ErrorType,
+        NandFlash, NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from one of the devices
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+        }
+    }
+}
+
+/// `N` devices of type `F` one after the other
+pub struct Concat<F, const N: usize> {
+    devices: [F; N],
+    /// Worst ECC status over the devices of the last read
+    ecc: EccStatus,
+}
+
+impl<F, const N: usize> Concat<F, N>
+where
+    F: NandFlash,
+{
+    pub fn new(devices: [F; N]) -> Self {
+        assert!(N > 0);
+        for device in &devices {
+            assert!(device.capacity().is_multiple_of(F::ERASE_SIZE as u64));
+        }
+        Self {
+            devices,
+            ecc: EccStatus::NoErrors,
+        }
+    }
+
+    /// Device holding `address` and the address within it
+    fn locate(&self, mut address: u64) -> (usize, u64) {
+        for (i, device) in self.devices.iter().enumerate() {
+            if address < device.capacity() {
+                return (i, address);
+            }
+            address -= device.capacity();
+        }
+        (N - 1, self.devices[N - 1].capacity() + address)
+    }
+
+    /// Split the range from `offset` of `len` bytes at device boundaries, calling `op` with the
+    /// device, the address within it and the range of bytes it covers
+    fn split(
+        &mut self,
+        offset: u64,
+        len: usize,
+        mut op: impl FnMut(&mut F, u64, core::ops::Range<usize>) -> Result<(), F::Error>,
+    ) -> Result<(), Error<F::Error>> {
+        let (mut index, mut address) = self.locate(offset);
+        let mut done = 0;
+        while done < len {
+            let device = &mut self.devices[index];
+            let n = ((device.capacity() - address) as usize).min(len - done);
+            op(device, address, done..done + n).map_err(Error::Flash)?;
+            done += n;
+            index += 1;
+            address = 0;
+        }
+        Ok(())
+    }
+
+    /// The devices
+    pub fn devices(&mut self) -> &mut [F; N] {
+        &mut self.devices
+    }
+
+    /// Return the devices
+    pub fn into_inner(self) -> [F; N] {
+        self.devices
+    }
+}
+
+impl<F, const N: usize> ErrorType for Concat<F, N>
+where
+    F: NandFlash,
+{
+    type Error = Error<F::Error>;
+}
+
+impl<F, const N: usize> ReadNandFlash for Concat<F, N>
+where
+    F: NandFlash,
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let mut ecc = EccStatus::NoErrors;
+        let result = self.split(offset, bytes.len(), |device, address, range| {
+            let result = device.read(address, &mut bytes[range]);
+            ecc = ecc.max(device.ecc_status());
+            result
+        });
+        self.ecc = ecc;
+        result
+    }
+
+    fn read_continuous(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let mut ecc = EccStatus::NoErrors;
+        let result = self.split(offset, bytes.len(), |device, address, range| {
+            let result = device.read_continuous(address, &mut bytes[range]);
+            ecc = ecc.max(device.ecc_status());
+            result
+        });
+        self.ecc = ecc;
+        result
+    }
+
+    fn capacity(&self) -> u64 {
+        self.devices.iter().map(|d| d.capacity()).sum()
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        if address >= self.capacity() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        let (index, address) = self.locate(address);
+        self.devices[index]
+            .block_status(address)
+            .map_err(Error::Flash)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.ecc
+    }
+}
+
+impl<F, const N: usize> NandFlash for Concat<F, N>
+where
+    F: NandFlash,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.split(from, (to - from) as usize, |device, address, range| {
+            device.erase(address, address + range.len() as u64)
+        })
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.split(offset, bytes.len(), |device, address, range| {
+            device.write(address, &bytes[range])
+        })
+    }
+}
+
+impl<F, const N: usize> SpareNandFlash for Concat<F, N>
+where
+    F: SpareNandFlash,
+{
+    const SPARE_SIZE: usize = F::SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let (index, address) = self.locate(offset);
+        let device = &mut self.devices[index];
+        let result = device.read_page(address, data, spare);
+        self.ecc = device.ecc_status();
+        result.map_err(Error::Flash)
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let (index, address) = self.locate(offset);
+        self.devices[index]
+            .write_page(address, data, spare)
+            .map_err(Error::Flash)
+    }
+
+    /// Copies within a device use its own copy, between devices the page goes through a buffer
+    fn copy_page(&mut self, from: u64, to: u64, spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, from, 0, spare.len())?;
+        check_page(self, to, 0, 0)?;
+        let ((src, from), (dst, to)) = (self.locate(from), self.locate(to));
+        if src == dst {
+            return self.devices[src]
+                .copy_page(from, to, spare)
+                .map_err(Error::Flash);
+        }
+        let mut data = [0; PAGE_SIZE];
+        let mut old = [0; SPARE_SIZE];
+        let (data, old) = (&mut data[..F::WRITE_SIZE], &mut old[..F::SPARE_SIZE]);
+        self.devices[src]
+            .read_page(from, data, old)
+            .map_err(Error::Flash)?;
+        old[..spare.len()].copy_from_slice(spare);
+        self.devices[dst]
+            .write_page(to, data, old)
+            .map_err(Error::Flash)
+    }
+
+    fn mark_bad(&mut self, address: u64) -> Result<(), Self::Error> {
+        if address >= self.capacity() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        let (index, address) = self.locate(address);
+        self.devices[index].mark_bad(address).map_err(Error::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::BLOCK_SIZE;
+    use crate::mock::RamFlash;
+
+    fn pattern(seed: u8) -> [u8; 4 * PAGE_SIZE] {
+        core::array::from_fn(|i| (i / 7) as u8 ^ seed)
+    }
+
+    /// Devices of two and three blocks
+    fn concat() -> Concat<RamFlash, 2> {
+        Concat::new([RamFlash::new(2), RamFlash::new(3)])
+    }
+
+    #[test]
+    fn access_crosses_unequal_devices() {
+        let mut flash = concat();
+        assert_eq!(flash.capacity(), 5 * BLOCK_SIZE as u64);
+        let boundary = 2 * BLOCK_SIZE;
+        let start = (boundary - 2 * PAGE_SIZE) as u64;
+        let data = pattern(1);
+        flash.write(start, &data).unwrap();
+        flash.devices()[1].corrected.push(PAGE_SIZE as u64);
+
+        let mut read = [0; 4 * PAGE_SIZE];
+        flash.read(start, &mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(flash.ecc_status(), EccStatus::Corrected(Some(1)));
+        read.fill(0);
+        flash.read_continuous(start, &mut read).unwrap();
+        assert_eq!(read, data);
+
+        let [first, second] = flash.devices();
+        let mut page = [0; 2 * PAGE_SIZE];
+        first.read(start, &mut page).unwrap();
+        assert_eq!(page[..], data[..2 * PAGE_SIZE]);
+        second.read(0, &mut page).unwrap();
+        assert_eq!(page[..], data[2 * PAGE_SIZE..]);
+
+        // the last block of the first device and the first of the second
+        flash.write(0, &data).unwrap();
+        flash.write((3 * BLOCK_SIZE) as u64, &data).unwrap();
+        flash
+            .erase(BLOCK_SIZE as u64, 3 * BLOCK_SIZE as u64)
+            .unwrap();
+        for (address, erased) in [
+            (0, false),
+            (BLOCK_SIZE, true),
+            (boundary, true),
+            (3 * BLOCK_SIZE, false),
+        ] {
+            flash.read(address as u64, &mut read).unwrap();
+            assert_eq!(read.iter().all(|b| *b == 0xFF), erased, "{address}");
+        }
+
+        assert!(matches!(
+            flash.read(5 * BLOCK_SIZE as u64 - PAGE_SIZE as u64, &mut read),
+            Err(Error::Nand(NandFlashErrorKind::OutOfBounds))
+        ));
+        assert!(matches!(
+            flash.erase(4 * BLOCK_SIZE as u64, 6 * BLOCK_SIZE as u64),
+            Err(Error::Nand(NandFlashErrorKind::OutOfBounds))
+        ));
+    }
+
+    #[test]
+    fn bad_blocks_of_each_device() {
+        let mut flash = concat();
+        flash.mark_bad((4 * BLOCK_SIZE + PAGE_SIZE) as u64).unwrap();
+        assert_eq!(
+            flash.block_status(4 * BLOCK_SIZE as u64).unwrap(),
+            BlockStatus::Failed
+        );
+        assert_eq!(
+            flash.block_status(BLOCK_SIZE as u64).unwrap(),
+            BlockStatus::MarkedOk
+        );
+        assert_eq!(
+            flash.devices()[1]
+                .block_status(2 * BLOCK_SIZE as u64)
+                .unwrap(),
+            BlockStatus::Failed
+        );
+        assert!(matches!(
+            flash.mark_bad(5 * BLOCK_SIZE as u64),
+            Err(Error::Nand(NandFlashErrorKind::OutOfBounds))
+        ));
+    }
+
+    #[test]
+    fn copy_page_between_devices() {
+        let mut flash = concat();
+        let data = pattern(2);
+        let mut spare = [0xFF; SPARE_SIZE];
+        spare[4..8].copy_from_slice(b"meta");
+        flash
+            .write_page(PAGE_SIZE as u64, &data[..PAGE_SIZE], &spare)
+            .unwrap();
+
+        // across devices the page goes through a buffer, new spare bytes replace the old
+        let to = (3 * BLOCK_SIZE) as u64;
+        flash.copy_page(PAGE_SIZE as u64, to, &[0xFF; 4]).unwrap();
+        // within a device it uses the device's own copy
+        flash
+            .copy_page(
+                PAGE_SIZE as u64,
+                2 * PAGE_SIZE as u64,
+                b"\xFF\xFF\xFF\xFFnew!",
+            )
+            .unwrap();
+        assert_eq!(flash.devices()[0].copies, 1);
+        assert_eq!(flash.devices()[1].copies, 0);
+
+        let (mut page, mut read) = ([0; PAGE_SIZE], [0; SPARE_SIZE]);
+        flash.read_page(to, &mut page, &mut read).unwrap();
+        assert_eq!((&page[..], read), (&data[..PAGE_SIZE], spare));
+        flash
+            .read_page(2 * PAGE_SIZE as u64, &mut page, &mut read)
+            .unwrap();
+        assert_eq!((&page[..], &read[4..8]), (&data[..PAGE_SIZE], &b"new!"[..]));
+    }
+}
@@ -0,0 +1,317 @@
+//! Bringing a device into a known configuration.
+//!
+//! [`W25NConfig`] collects the settings of Status Register-1 and -2 that the driver otherwise
+//! leaves to the caller, and [`W25N::init`] applies them after a reset:
+//!
+//! ```ignore
+//! let config = W25NConfig::new().protection(false, 0b0010);
+//! flash.init(&config, &mut delay)?;
+//! ```
+//!
+//! The device is always left in Buffer Read Mode (BUF=1), which the driver's reads rely on.
+//! [`crate::traits::ReadNandFlash::read_continuous`] switches to Continuous Read Mode for its
+//! own transfer and back.
+//!
+//! Every register written is read back, so a device held in reset, a locked register or a
+//! different part is reported by [`InitError`] instead of showing up as failed writes later.
+use embedded_hal::{delay::DelayNs, spi::SpiDevice};
+
+use crate::{
+    registers::{Status1, Status2},
+    w25n::RESET_TIME_US,
+    Error, VerifyPolicy, W25N,
+};
+
+/// Status register compared after writing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    /// Status Register-1, protection
+    Protection,
+    /// Status Register-2, configuration
+    Configuration,
+}
+
+/// Step of [`W25N::init`] that failed
+#[derive(Debug)]
+pub enum InitError<SPI>
+where
+    SPI: SpiDevice,
+{
+    /// Sending the reset or waiting for it to finish failed
+    Reset(Error<SPI>),
+    /// Reading the JEDEC ID failed
+    Jedec(Error<SPI>),
+    /// The JEDEC ID read is not the one expected
+    UnexpectedDevice { manufacturer: u8, device: u16 },
+    /// Writing or reading a status register failed
+    Register(Register, Error<SPI>),
+    /// A status register read back differently than written, e.g. locked by SRP or /WP
+    Readback {
+        register: Register,
+        written: u8,
+        read: u8,
+    },
+}
+
+/// Settings applied by [`W25N::init`], defaults are those of the device after power on
+/// except that no blocks are protected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct W25NConfig {
+    ecc: bool,
+    driver_strength: u8,
+    hold_disable: bool,
+    top_bottom: bool,
+    block_protect: u8,
+    jedec: Option<(u8, u16)>,
+    verify: Option<VerifyPolicy>,
+}
+
+impl Default for W25NConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl W25NConfig {
+    pub fn new() -> Self {
+        Self {
+            ecc: true,
+            driver_strength: 0,
+            hold_disable: false,
+            top_bottom: false,
+            block_protect: 0,
+            jedec: Some((0xEF, 0xAA22)),
+            verify: None,
+        }
+    }
+
+    /// Enable the on chip ECC (ECC-E), on by default
+    pub fn ecc(mut self, enable: bool) -> Self {
+        self.ecc = enable;
+        self
+    }
+
+    /// Output driver strength, ODS-1 and ODS-0 from 0 (the strongest, default) to 3
+    pub fn driver_strength(mut self, ods: u8) -> Self {
+        assert!(ods <= 3);
+        self.driver_strength = ods;
+        self
+    }
+
+    /// Disable the /HOLD function of the /HOLD pin (H-DIS)
+    pub fn hold_disable(mut self, disable: bool) -> Self {
+        self.hold_disable = disable;
+        self
+    }
+
+    /// Protect the blocks selected by the TB and BP3-0 bits, see the datasheet for the
+    /// ranges. No blocks are protected by default.
+    pub fn protection(mut self, top_bottom: bool, block_protect: u8) -> Self {
+        assert!(block_protect <= 0xF);
+        self.top_bottom = top_bottom;
+        self.block_protect = block_protect;
+        self
+    }
+
+    /// JEDEC manufacturer and device ID the device must have, [None] to accept any device.
+    /// A W25N02KV by default.
+    pub fn expect_jedec(mut self, jedec: Option<(u8, u16)>) -> Self {
+        self.jedec = jedec;
+        self
+    }
+
+    /// Verify written pages with `policy`, see [`W25N::set_verify`]
+    pub fn verify(mut self, policy: Option<VerifyPolicy>) -> Self {
+        self.verify = policy;
+        self
+    }
+}
+
+impl<SPI> W25N<SPI>
+where
+    SPI: SpiDevice,
+{
+    /// Reset the device, check its JEDEC ID and apply `config`, reading back each register.
+    /// `delay` waits out the reset, during which the device ignores commands.
+    pub fn init(
+        &mut self,
+        config: &W25NConfig,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), InitError<SPI>> {
+        self.reset_device().map_err(InitError::Reset)?;
+        delay.delay_us(RESET_TIME_US);
+        self.wait_for_operation().map_err(InitError::Reset)?;
+
+        if let Some((manufacturer, device)) = config.jedec {
+            let jedec = self.jedec().map_err(InitError::Jedec)?;
+            if (jedec.manufacturer, jedec.device) != (manufacturer, device) {
+                return Err(InitError::UnexpectedDevice {
+                    manufacturer: jedec.manufacturer,
+                    device: jedec.device,
+                });
+            }
+        }
+
+        let register = |register| move |e| InitError::Register(register, e);
+        let protection = register(Register::Protection);
+        let status = self
+            .read_status_1()
+            .map_err(protection)?
+            .with_tb(config.top_bottom)
+            .with_bp(config.block_protect);
+        let written = u8::from(status);
+        self.write_status_1(Status1::from_bytes([written]))
+            .map_err(protection)?;
+        let read = u8::from(self.read_status_1().map_err(protection)?);
+        if read != written {
+            return Err(InitError::Readback {
+                register: Register::Protection,
+                written,
+                read,
+            });
+        }
+
+        let configuration = register(Register::Configuration);
+        let status = self
+            .read_status_2()
+            .map_err(configuration)?
+            .with_ecc_e(config.ecc)
+            .with_buf(true)
+            .with_osd(config.driver_strength)
+            .with_h_dis(config.hold_disable)
+            .with_otp_e(false);
+        let written = u8::from(status);
+        self.write_status_2(Status2::from_bytes([written]))
+            .map_err(configuration)?;
+        let read = u8::from(self.read_status_2().map_err(configuration)?);
+        if read != written {
+            return Err(InitError::Readback {
+                register: Register::Configuration,
+                written,
+                read,
+            });
+        }
+
+        self.set_verify(config.verify);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        commands::{
+            ENABLE_RESET, JEDEC, READ_REG, RESET_DEVICE, STATUS_REGISTER_1, STATUS_REGISTER_2,
+            STATUS_REGISTER_3, WRITE_REG,
+        },
+        mock::RecordingSpi,
+    };
+
+    /// Delay that adds up the time waited
+    #[derive(Default)]
+    struct Delay {
+        ns: u64,
+    }
+
+    impl DelayNs for Delay {
+        fn delay_ns(&mut self, ns: u32) {
+            self.ns += ns as u64;
+        }
+    }
+
+    fn init<'a>(
+        spi: &'a mut RecordingSpi,
+        config: &W25NConfig,
+    ) -> Result<(), InitError<&'a mut RecordingSpi>> {
+        let mut flash = W25N::new(spi, 0.into());
+        flash.init(config, &mut Delay::default())
+    }
+
+    /// Status Register-2 value init writes with the default configuration
+    fn configuration() -> u8 {
+        Status2::new().with_ecc_e(true).with_buf(true).into()
+    }
+
+    #[test]
+    fn reset_then_configure() {
+        let mut spi = RecordingSpi {
+            status_2: configuration(),
+            ..Default::default()
+        };
+        let config = W25NConfig::new()
+            .expect_jedec(None)
+            .verify(Some(VerifyPolicy::Error));
+        let mut delay = Delay::default();
+        let mut flash = W25N::new(&mut spi, 0.into());
+        flash.init(&config, &mut delay).unwrap();
+        assert_eq!(flash.verify_policy(), Some(VerifyPolicy::Error));
+        assert!(delay.ns >= RESET_TIME_US as u64 * 1000);
+
+        let sent: Vec<&[u8]> = spi.transactions.iter().map(|t| &t[..]).collect();
+        let written = [configuration()];
+        let expected: [&[u8]; 9] = [
+            &[ENABLE_RESET],
+            &[RESET_DEVICE],
+            &[READ_REG, STATUS_REGISTER_3, 0],
+            &[READ_REG, STATUS_REGISTER_1, 0],
+            &[WRITE_REG, STATUS_REGISTER_1, 0],
+            &[READ_REG, STATUS_REGISTER_1, 0],
+            &[READ_REG, STATUS_REGISTER_2, 0],
+            &[&[WRITE_REG, STATUS_REGISTER_2][..], &written].concat(),
+            &[READ_REG, STATUS_REGISTER_2, 0],
+        ];
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn unexpected_device() {
+        // the mock reads the JEDEC ID as 0xFF
+        let mut spi = RecordingSpi::default();
+        let result = init(&mut spi, &W25NConfig::new());
+        assert!(matches!(
+            result,
+            Err(InitError::UnexpectedDevice {
+                manufacturer: 0xFF,
+                device: 0xFFFF
+            })
+        ));
+        assert_eq!(spi.commands(JEDEC).len(), 1);
+        assert!(spi.commands(WRITE_REG).is_empty());
+    }
+
+    #[test]
+    fn readback_mismatch() {
+        // the mock reads Status Register-1 as 0
+        let mut spi = RecordingSpi {
+            status_2: configuration(),
+            ..Default::default()
+        };
+        let config = W25NConfig::new()
+            .expect_jedec(None)
+            .protection(true, 0b0010);
+        let written = Status1::new().with_tb(true).with_bp(0b0010).into();
+        assert!(matches!(
+            init(&mut spi, &config),
+            Err(InitError::Readback {
+                register: Register::Protection,
+                written: w,
+                read: 0,
+            }) if w == written
+        ));
+
+        let mut spi = RecordingSpi::default();
+        let config = W25NConfig::new().expect_jedec(None);
+        assert!(matches!(
+            init(&mut spi, &config),
+            Err(InitError::Readback {
+                register: Register::Configuration,
+                written: w,
+                read: 0,
+            }) if w == configuration()
+        ));
+    }
+}
@@ -0,0 +1,877 @@
+//! Software ECC for use with the on chip ECC disabled.
+//!
+//! [`SoftEcc`] splits each page into [`CHUNK_SIZE`] byte chunks and stores the parity of each
+//! at the end of the spare area. The spare bytes from [`SPARE_META`] up to the parity form one
+//! more chunk, so metadata of the layers above is protected as well. The bytes before
+//! [`SPARE_META`] hold the bad block marker and are left unprotected, like factory markers.
+//!
+//! Parity is computed over the inverted data and stored inverted, so an erased chunk has
+//! erased parity and erased pages read back clean.
+//!
+//! Two codes are provided: [`Hamming`] corrects one bit per chunk for 3 bytes of parity and
+//! [`Bch`] corrects `T` bits per chunk for `13 * T + 1` bits of parity.
+//!
+//! Put [`SoftEcc`] directly on the raw device, below the bad block layer, so retirement tags
+//! and tables are written raw as factory markers are.
+use crate::{
+    mem::{PAGE_SIZE, SPARE_META, SPARE_SIZE},
+    traits::{
+        check_page, check_read, check_write, BlockStatus, EccStatus, ErrorType, NandFlash,
+        NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+/// Bytes covered by one set of parity
+pub const CHUNK_SIZE: usize = 512;
+
+/// Error correcting code applied to each chunk
+pub trait EccCode {
+    /// Parity bytes per chunk
+    const PARITY: usize;
+
+    /// Compute the parity of `data`, at most [`CHUNK_SIZE`] bytes
+    fn encode(&self, data: &[u8], parity: &mut [u8]);
+
+    /// Correct `data` in place against `parity`, returning the number of bits corrected or
+    /// [None] if there are too many errors
+    fn correct(&self, data: &mut [u8], parity: &[u8]) -> Option<u8>;
+}
+
+/// Single bit correcting, double bit detecting Hamming code over up to 512 bytes.
+///
+/// Each of the 12 bits of a bit's address gets a pair of parities, over the bits with that
+/// address bit set and clear. A single flipped data bit flips exactly one of each pair and the
+/// flipped halves spell its address.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Hamming;
+
+impl Hamming {
+    /// Parities over the set and clear halves of each address bit, 12 bits each
+    fn parities(data: &[u8]) -> (u32, u32) {
+        let (mut rows, mut columns, mut total) = (0u32, 0u8, 0u32);
+        for (i, byte) in data.iter().enumerate() {
+            let byte = !byte;
+            columns ^= byte;
+            if byte.count_ones() & 1 == 1 {
+                rows ^= i as u32;
+                total ^= 1;
+            }
+        }
+        let column = |mask: u8| (columns & mask).count_ones() & 1;
+        let ones = rows << 3 | column(0xF0) << 2 | column(0xCC) << 1 | column(0xAA);
+        // the clear half is the total parity less the set half
+        let zeros = ones ^ if total == 1 { 0xFFF } else { 0 };
+        (ones, zeros)
+    }
+}
+
+impl EccCode for Hamming {
+    const PARITY: usize = 3;
+
+    fn encode(&self, data: &[u8], parity: &mut [u8]) {
+        let (ones, zeros) = Self::parities(data);
+        let bits = !(ones | zeros << 12);
+        parity[..3].copy_from_slice(&bits.to_le_bytes()[..3]);
+    }
+
+    fn correct(&self, data: &mut [u8], parity: &[u8]) -> Option<u8> {
+        let (ones, zeros) = Self::parities(data);
+        let stored = !u32::from_le_bytes([parity[0], parity[1], parity[2], 0]) & 0xFF_FFFF;
+        let syndrome = stored ^ (ones | zeros << 12);
+        let (ones, zeros) = (syndrome & 0xFFF, syndrome >> 12);
+        match syndrome.count_ones() {
+            0 => Some(0),
+            // a flipped parity bit, the data is good
+            1 => Some(1),
+            12 if ones ^ zeros == 0xFFF => {
+                let (byte, bit) = ((ones >> 3) as usize, ones & 7);
+                *data.get_mut(byte)? ^= 1 << bit;
+                Some(1)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Largest correction strength of [`Bch`].
+///
+/// [`SoftEcc`] needs the parity of every chunk of a page and of the metadata chunk to fit in
+/// the spare area, on the W25N's 2048 + 128 byte pages that allows `T` up to 14. Larger codes
+/// fail to compile when used with it.
+pub const BCH_MAX_T: usize = 16;
+/// Bits per Galois field element
+const M: usize = 13;
+/// Primitive polynomial of GF(2^13), x^13 + x^4 + x^3 + x + 1
+const POLY: u32 = 0x201B;
+/// Order of the multiplicative group
+const N: u32 = (1 << M) - 1;
+/// 32 bit words holding the parity register
+const WORDS: usize = (M * BCH_MAX_T).div_ceil(32);
+
+fn gf_mul(mut a: u32, mut b: u32) -> u32 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 == 1 {
+            product ^= a;
+        }
+        b >>= 1;
+        a <<= 1;
+        if a & (1 << M) != 0 {
+            a ^= POLY;
+        }
+    }
+    product
+}
+
+fn gf_pow(mut base: u32, mut exp: u32) -> u32 {
+    let mut result = 1;
+    exp %= N;
+    while exp != 0 {
+        if exp & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+fn gf_inv(a: u32) -> u32 {
+    gf_pow(a, N - 1)
+}
+
+/// Binary BCH code over GF(2^13) correcting `T` bits in up to 512 bytes.
+///
+/// The generator polynomial is built in [`Bch::new`]. Encoding is a bit serial division by
+/// it. Decoding takes the syndromes from the remainder, finds the error locator with
+/// Berlekamp-Massey and its roots with a Chien search, so only reads with errors pay for it.
+///
+/// One more bit holds the parity of the whole codeword. It raises the distance of the code to
+/// `2 * T + 2`, so `T + 1` bit errors are reported as uncorrectable instead of being
+/// miscorrected.
+#[derive(Debug, Clone, Copy)]
+pub struct Bch<const T: usize> {
+    /// Generator polynomial less its leading term, bit `k` is the coefficient of `x^k`
+    generator: [u32; WORDS],
+}
+
+impl<const T: usize> Default for Bch<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const T: usize> Bch<T> {
+    /// Parity bits per chunk
+    const BITS: usize = M * T;
+
+    pub fn new() -> Self {
+        assert!(T > 0 && T <= BCH_MAX_T);
+        // multiply out the minimal polynomials of alpha, alpha^3 .. alpha^(2T - 1)
+        let mut generator = [0u32; WORDS + 1];
+        generator[0] = 1;
+        let mut degree = 0;
+        for i in (1..2 * T as u32).step_by(2) {
+            // minimal polynomial from the conjugates alpha^(i * 2^j)
+            let mut minimal = [0u32; M + 1];
+            minimal[0] = 1;
+            let mut root = gf_pow(2, i);
+            for j in 0..M {
+                for k in (1..=j + 1).rev() {
+                    minimal[k] = minimal[k - 1] ^ gf_mul(minimal[k], root);
+                }
+                minimal[0] = gf_mul(minimal[0], root);
+                root = gf_mul(root, root);
+            }
+            // the coefficients are 0 or 1, multiply into the generator over GF(2)
+            let mut product = [0u32; WORDS + 1];
+            for (k, coefficient) in minimal.iter().enumerate() {
+                if *coefficient == 1 {
+                    for bit in 0..=degree {
+                        if generator[bit / 32] >> (bit % 32) & 1 == 1 {
+                            let at = bit + k;
+                            product[at / 32] ^= 1 << (at % 32);
+                        }
+                    }
+                }
+            }
+            generator = product;
+            degree += M;
+        }
+        let mut low = [0; WORDS];
+        low.copy_from_slice(&generator[..WORDS]);
+        if degree < WORDS * 32 {
+            low[degree / 32] &= !(1 << (degree % 32));
+        }
+        Self { generator: low }
+    }
+
+    fn bit(register: &[u32; WORDS], k: usize) -> bool {
+        register[k / 32] >> (k % 32) & 1 == 1
+    }
+
+    /// Remainder of the inverted data times `x^BITS` divided by the generator
+    fn remainder(&self, data: &[u8]) -> [u32; WORDS] {
+        let mut register = [0u32; WORDS];
+        let top = Self::BITS - 1;
+        for byte in data {
+            let byte = !byte;
+            for bit in (0..8).rev() {
+                let feedback = (byte >> bit & 1 == 1) != Self::bit(&register, top);
+                for w in (1..WORDS).rev() {
+                    register[w] = register[w] << 1 | register[w - 1] >> 31;
+                }
+                register[0] <<= 1;
+                if Self::BITS < WORDS * 32 {
+                    register[Self::BITS / 32] &= !(1 << (Self::BITS % 32));
+                }
+                if feedback {
+                    for (r, g) in register.iter_mut().zip(&self.generator) {
+                        *r ^= g;
+                    }
+                }
+            }
+        }
+        register
+    }
+
+    /// Byte and bit of the parity holding the overall parity
+    fn extension() -> (usize, usize) {
+        (Self::BITS / 8, Self::BITS % 8)
+    }
+
+    /// Set bits of the inverted data
+    fn ones(data: &[u8]) -> u32 {
+        data.iter().map(|b| (!b).count_ones()).sum()
+    }
+
+    /// Error locator polynomial from the syndromes of the remainder `error`
+    fn locator(&self, error: &[u32; WORDS]) -> ([u32; BCH_MAX_T + 1], usize) {
+        let mut syndromes = [0u32; 2 * BCH_MAX_T];
+        for (j, syndrome) in syndromes[..2 * T].iter_mut().enumerate() {
+            let alpha = gf_pow(2, j as u32 + 1);
+            for k in (0..Self::BITS).rev() {
+                *syndrome = gf_mul(*syndrome, alpha) ^ Self::bit(error, k) as u32;
+            }
+        }
+        // Berlekamp-Massey
+        let (mut c, mut b) = ([0u32; 2 * BCH_MAX_T + 1], [0u32; 2 * BCH_MAX_T + 1]);
+        c[0] = 1;
+        b[0] = 1;
+        let (mut len, mut shift, mut last) = (0, 1, 1);
+        for n in 0..2 * T {
+            let mut discrepancy = syndromes[n];
+            for i in 1..=len {
+                discrepancy ^= gf_mul(c[i], syndromes[n - i]);
+            }
+            if discrepancy == 0 {
+                shift += 1;
+                continue;
+            }
+            let scale = gf_mul(discrepancy, gf_inv(last));
+            let previous = c;
+            for i in shift..c.len() {
+                c[i] ^= gf_mul(scale, b[i - shift]);
+            }
+            if 2 * len <= n {
+                len = n + 1 - len;
+                b = previous;
+                last = discrepancy;
+                shift = 1;
+            } else {
+                shift += 1;
+            }
+        }
+        let mut locator = [0u32; BCH_MAX_T + 1];
+        if len > T {
+            return (locator, len);
+        }
+        locator[..=len].copy_from_slice(&c[..=len]);
+        (locator, len)
+    }
+}
+
+impl<const T: usize> EccCode for Bch<T> {
+    const PARITY: usize = (M * T + 1).div_ceil(8);
+
+    fn encode(&self, data: &[u8], parity: &mut [u8]) {
+        let register = self.remainder(data);
+        for (i, byte) in parity[..Self::PARITY].iter_mut().enumerate() {
+            *byte = !(register[i / 4] >> ((i % 4) * 8)) as u8;
+        }
+        // leave the padding bits erased and make the overall parity even
+        let (byte, bit) = Self::extension();
+        parity[byte] |= 0xFF << bit;
+        let ones = Self::ones(data) + register.iter().map(|w| w.count_ones()).sum::<u32>();
+        if ones % 2 == 1 {
+            parity[byte] &= !(1 << bit);
+        }
+    }
+
+    fn correct(&self, data: &mut [u8], parity: &[u8]) -> Option<u8> {
+        let mut received = [0u32; WORDS];
+        for (i, byte) in parity[..Self::PARITY].iter().enumerate() {
+            received[i / 4] |= (!byte as u32) << ((i % 4) * 8);
+        }
+        if Self::BITS % 32 != 0 {
+            received[Self::BITS / 32] &= (1 << (Self::BITS % 32)) - 1;
+        }
+        for word in &mut received[Self::BITS.div_ceil(32)..] {
+            *word = 0;
+        }
+        let (byte, bit) = Self::extension();
+        let extension = parity[byte] >> bit & 1 == 0;
+        // an odd number of bits flipped, counting the overall parity bit
+        let odd = (Self::ones(data)
+            + received.iter().map(|w| w.count_ones()).sum::<u32>()
+            + extension as u32)
+            % 2
+            == 1;
+        let mut error = self.remainder(data);
+        for (e, r) in error.iter_mut().zip(&received) {
+            *e ^= r;
+        }
+        if error.iter().all(|w| *w == 0) {
+            // only the overall parity bit flipped
+            return Some(odd as u8);
+        }
+        let (locator, errors) = self.locator(&error);
+        if errors > T {
+            return None;
+        }
+        // Chien search over the shortened code, an error at degree p is a root at alpha^-p
+        let length = Self::BITS + data.len() * 8;
+        let mut terms = locator;
+        let steps: [u32; BCH_MAX_T + 1] = core::array::from_fn(|j| gf_pow(2, N - j as u32 % N));
+        let mut found = 0;
+        let mut flips = [0usize; BCH_MAX_T];
+        for degree in 0..length {
+            let sum = terms[1..=errors].iter().fold(terms[0], |sum, t| sum ^ t);
+            if sum == 0 {
+                if found == errors {
+                    return None;
+                }
+                flips[found] = degree;
+                found += 1;
+            }
+            for j in 1..=errors {
+                terms[j] = gf_mul(terms[j], steps[j]);
+            }
+        }
+        // the overall parity bit is wrong as well unless the flips found account for it
+        let total = found + (odd != (found % 2 == 1)) as usize;
+        if found != errors || total > T {
+            return None;
+        }
+        for degree in &flips[..found] {
+            // parity bits are below BITS, data bits count down from the first bit sent
+            if let Some(bit) = degree.checked_sub(Self::BITS) {
+                let index = data.len() * 8 - 1 - bit;
+                data[index / 8] ^= 0x80 >> (index % 8);
+            }
+        }
+        Some(total as u8)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// Too many bit errors to correct in the page at the contained address
+    Uncorrectable(u64),
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+            Error::Uncorrectable(address) => NandFlashErrorKind::BlockFail(Some(*address)),
+        }
+    }
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+/// Software ECC over the raw pages of `F` using the code `C`
+pub struct SoftEcc<F, C> {
+    flash: F,
+    code: C,
+    /// Worst outcome of the last read
+    ecc: EccStatus,
+    page: [u8; PAGE_SIZE],
+    spare: [u8; SPARE_SIZE],
+}
+
+type EResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+impl<F, C> SoftEcc<F, C>
+where
+    F: SpareNandFlash,
+    C: EccCode,
+{
+    /// Data chunks in a page
+    const CHUNKS: usize = F::WRITE_SIZE / CHUNK_SIZE;
+    /// Start of the parity in the spare area, the protected spare bytes end here
+    const PARITY_START: usize = {
+        let parity = C::PARITY * (Self::CHUNKS + 1);
+        assert!(
+            F::SPARE_SIZE > SPARE_META + parity,
+            "the parity of the code does not fit the spare area"
+        );
+        F::SPARE_SIZE - parity
+    };
+
+    pub fn new(flash: F, code: C) -> Self {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::SPARE_SIZE <= SPARE_SIZE);
+        assert!(F::WRITE_SIZE.is_multiple_of(CHUNK_SIZE));
+        // evaluated when `SoftEcc` is built for a device and code, failing the build
+        let _ = Self::PARITY_START;
+        Self {
+            flash,
+            code,
+            ecc: EccStatus::NoErrors,
+            page: [0; PAGE_SIZE],
+            spare: [0; SPARE_SIZE],
+        }
+    }
+
+    fn parity(chunk: usize) -> core::ops::Range<usize> {
+        let start = Self::PARITY_START + chunk * C::PARITY;
+        start..start + C::PARITY
+    }
+
+    /// Read the raw page into the buffers and correct it, the data only if `data`
+    fn load(&mut self, offset: u64, data: bool) -> EResult<(), F> {
+        let spare = &mut self.spare[..F::SPARE_SIZE];
+        let page = if data {
+            &mut self.page[..F::WRITE_SIZE]
+        } else {
+            &mut [][..]
+        };
+        self.flash
+            .read_page(offset, page, spare)
+            .map_err(Error::Flash)?;
+        let (protected, parity) = self.spare[..F::SPARE_SIZE].split_at_mut(Self::PARITY_START);
+        let parity = &parity[Self::CHUNKS * C::PARITY..];
+        let mut worst = self.code.correct(&mut protected[SPARE_META..], parity);
+        if data {
+            for (i, chunk) in self.page[..F::WRITE_SIZE]
+                .chunks_exact_mut(CHUNK_SIZE)
+                .enumerate()
+            {
+                let bits = self.code.correct(chunk, &self.spare[Self::parity(i)]);
+                worst = worst.zip(bits).map(|(a, b)| a.max(b));
+            }
+        }
+        let status = match worst {
+            Some(0) => EccStatus::NoErrors,
+            Some(bits) => EccStatus::Corrected(Some(bits)),
+            None => EccStatus::Uncorrectable,
+        };
+        self.ecc = self.ecc.max(status);
+        match worst {
+            Some(_) => Ok(()),
+            None => Err(Error::Uncorrectable(offset)),
+        }
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F, C> ErrorType for SoftEcc<F, C>
+where
+    F: SpareNandFlash,
+{
+    type Error = Error<F::Error>;
+}
+
+impl<F, C> ReadNandFlash for SoftEcc<F, C>
+where
+    F: SpareNandFlash,
+    C: EccCode,
+{
+    const READ_SIZE: usize = F::WRITE_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.ecc = EccStatus::NoErrors;
+        for (address, page) in (offset..)
+            .step_by(F::WRITE_SIZE)
+            .zip(bytes.chunks_exact_mut(F::WRITE_SIZE))
+        {
+            self.load(address, true)?;
+            page.copy_from_slice(&self.page[..F::WRITE_SIZE]);
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.flash.capacity()
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        self.flash.block_status(address).map_err(Error::Flash)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.ecc
+    }
+}
+
+impl<F, C> NandFlash for SoftEcc<F, C>
+where
+    F: SpareNandFlash,
+    C: EccCode,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        self.flash.erase(from, to).map_err(Error::Flash)
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        for (address, page) in (offset..)
+            .step_by(F::WRITE_SIZE)
+            .zip(bytes.chunks_exact(F::WRITE_SIZE))
+        {
+            self.write_page(address, page, &[])?;
+        }
+        Ok(())
+    }
+}
+
+impl<F, C> SpareNandFlash for SoftEcc<F, C>
+where
+    F: SpareNandFlash,
+    C: EccCode,
+{
+    const SPARE_SIZE: usize = Self::PARITY_START;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.ecc = EccStatus::NoErrors;
+        self.load(offset, !data.is_empty())?;
+        data.copy_from_slice(&self.page[..data.len()]);
+        spare.copy_from_slice(&self.spare[..spare.len()]);
+        Ok(())
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let raw = &mut self.spare[..F::SPARE_SIZE];
+        raw.fill(0xFF);
+        raw[..spare.len()].copy_from_slice(spare);
+        // parity of an erased chunk is erased, only program what is written
+        if spare.len() > SPARE_META {
+            let (protected, parity) = raw.split_at_mut(Self::PARITY_START);
+            let parity = &mut parity[Self::CHUNKS * C::PARITY..];
+            self.code.encode(&protected[SPARE_META..], parity);
+        }
+        for (i, chunk) in data.chunks_exact(CHUNK_SIZE).enumerate() {
+            self.code.encode(chunk, &mut self.spare[Self::parity(i)]);
+        }
+        self.flash
+            .write_page(offset, data, &self.spare[..F::SPARE_SIZE])
+            .map_err(Error::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+
+    /// Chunk of pseudo random data
+    fn chunk(seed: u32) -> [u8; CHUNK_SIZE] {
+        let mut state = seed;
+        core::array::from_fn(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+    }
+
+    /// `count` distinct bit positions below `bits`
+    fn positions(seed: u32, count: usize, bits: usize) -> [usize; BCH_MAX_T + 1] {
+        let mut found = [usize::MAX; BCH_MAX_T + 1];
+        let mut state = seed;
+        let mut n = 0;
+        while n < count {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let bit = (state >> 8) as usize % bits;
+            if !found[..n].contains(&bit) {
+                found[n] = bit;
+                n += 1;
+            }
+        }
+        found
+    }
+
+    /// Flip bit `bit` of the data followed by the parity
+    fn flip(data: &mut [u8], parity: &mut [u8], bit: usize) {
+        let data_bits = data.len() * 8;
+        let (bytes, bit) = if bit < data_bits {
+            (data, bit)
+        } else {
+            (parity, bit - data_bits)
+        };
+        bytes[bit / 8] ^= 1 << (bit % 8);
+    }
+
+    #[test]
+    fn hamming_corrects_any_single_bit() {
+        let data = chunk(1);
+        let mut parity = [0; 3];
+        Hamming.encode(&data, &mut parity);
+        let mut clean = data;
+        assert_eq!(Hamming.correct(&mut clean, &parity), Some(0));
+
+        for bit in 0..(CHUNK_SIZE + 3) * 8 {
+            let (mut bad, mut bad_parity) = (data, parity);
+            flip(&mut bad, &mut bad_parity, bit);
+            assert_eq!(Hamming.correct(&mut bad, &bad_parity), Some(1), "bit {bit}");
+            assert_eq!(bad, data, "bit {bit}");
+        }
+    }
+
+    #[test]
+    fn hamming_detects_double_bits() {
+        let data = chunk(2);
+        let mut parity = [0; 3];
+        Hamming.encode(&data, &mut parity);
+        for seed in 0..500 {
+            let bits = positions(seed, 2, (CHUNK_SIZE + 3) * 8);
+            let (mut bad, mut bad_parity) = (data, parity);
+            for bit in &bits[..2] {
+                flip(&mut bad, &mut bad_parity, *bit);
+            }
+            assert_eq!(Hamming.correct(&mut bad, &bad_parity), None, "{bits:?}");
+        }
+    }
+
+    #[test]
+    fn erased_chunk_reads_clean() {
+        let mut data = [0xFF; CHUNK_SIZE];
+        assert_eq!(Hamming.correct(&mut data, &[0xFF; 3]), Some(0));
+        let bch = Bch::<8>::new();
+        assert_eq!(bch.correct(&mut data, &[0xFF; 14]), Some(0));
+        let mut parity = [0; 14];
+        bch.encode(&data, &mut parity);
+        assert_eq!(parity, [0xFF; 14]);
+    }
+
+    fn bch_corrects_up_to_t<const T: usize>() {
+        let bch = Bch::<T>::new();
+        let parity_len = Bch::<T>::PARITY;
+        // the BCH parity and the overall parity bit, padding bits are not part of the code
+        let bits = CHUNK_SIZE * 8 + M * T + 1;
+        for seed in 0..16 {
+            let data = chunk(seed);
+            let mut parity = [0; (M * BCH_MAX_T + 1).div_ceil(8)];
+            bch.encode(&data, &mut parity[..parity_len]);
+            for count in 1..=T {
+                let flips = positions(seed * 100 + count as u32, count, bits);
+                let (mut bad, mut bad_parity) = (data, parity);
+                for bit in &flips[..count] {
+                    flip(&mut bad, &mut bad_parity[..parity_len], *bit);
+                }
+                let corrected = bch.correct(&mut bad, &bad_parity[..parity_len]);
+                assert_eq!(corrected, Some(count as u8), "T={T} {:?}", &flips[..count]);
+                assert_eq!(bad, data, "T={T} {:?}", &flips[..count]);
+            }
+            // errors only in the parity, the overall parity bit last, leave the data alone
+            let (mut bad, mut bad_parity) = (data, parity);
+            for bit in bits - T..bits {
+                flip(&mut bad, &mut bad_parity[..parity_len], bit);
+            }
+            assert_eq!(
+                bch.correct(&mut bad, &bad_parity[..parity_len]),
+                Some(T as u8)
+            );
+            assert_eq!(bad, data);
+        }
+    }
+
+    fn bch_rejects_t_plus_one<const T: usize>() {
+        let bch = Bch::<T>::new();
+        let parity_len = Bch::<T>::PARITY;
+        let bits = CHUNK_SIZE * 8 + M * T + 1;
+        for seed in 0..16 {
+            let data = chunk(seed);
+            let mut parity = [0; (M * BCH_MAX_T + 1).div_ceil(8)];
+            bch.encode(&data, &mut parity[..parity_len]);
+            let flips = positions(seed + 7000, T + 1, bits);
+            let (mut bad, mut bad_parity) = (data, parity);
+            for bit in &flips[..=T] {
+                flip(&mut bad, &mut bad_parity[..parity_len], *bit);
+            }
+            let corrected = bch.correct(&mut bad, &bad_parity[..parity_len]);
+            assert_eq!(corrected, None, "T={T} {:?}", &flips[..=T]);
+        }
+    }
+
+    #[test]
+    fn bch_corrects_up_to_t_bits() {
+        bch_corrects_up_to_t::<1>();
+        bch_corrects_up_to_t::<4>();
+        bch_corrects_up_to_t::<8>();
+        bch_corrects_up_to_t::<BCH_MAX_T>();
+    }
+
+    #[test]
+    fn bch_reports_t_plus_one_bits_uncorrectable() {
+        bch_rejects_t_plus_one::<1>();
+        bch_rejects_t_plus_one::<4>();
+        bch_rejects_t_plus_one::<8>();
+        bch_rejects_t_plus_one::<BCH_MAX_T>();
+    }
+
+    /// Clear the first set bit at or after `bit` of the raw page at `offset`, the data then
+    /// the spare area, like a bit error. Returns the bit cleared.
+    fn clear_raw_bit(flash: &mut RamFlash, offset: u64, bit: usize) -> usize {
+        let (mut data, mut spare) = ([0; PAGE_SIZE], [0; SPARE_SIZE]);
+        flash.read_page(offset, &mut data, &mut spare).unwrap();
+        let is_set = |bit: usize| match bit.checked_sub(PAGE_SIZE * 8) {
+            None => data[bit / 8] >> (bit % 8) & 1 == 1,
+            Some(bit) => spare[bit / 8] >> (bit % 8) & 1 == 1,
+        };
+        let bit = (bit..).find(|b| is_set(*b)).unwrap();
+        let (mut data, mut spare) = ([0xFF; PAGE_SIZE], [0xFF; SPARE_SIZE]);
+        match bit.checked_sub(PAGE_SIZE * 8) {
+            None => data[bit / 8] &= !(1 << (bit % 8)),
+            Some(b) => spare[b / 8] &= !(1 << (b % 8)),
+        }
+        flash.write_page(offset, &data, &spare).unwrap();
+        bit
+    }
+
+    fn page() -> ([u8; PAGE_SIZE], [u8; 16]) {
+        let mut data = [0; PAGE_SIZE];
+        for (i, c) in data.chunks_exact_mut(CHUNK_SIZE).enumerate() {
+            c.copy_from_slice(&chunk(i as u32 + 10));
+        }
+        let mut spare = [0xA5; 16];
+        spare[..SPARE_META].fill(0xFF);
+        (data, spare)
+    }
+
+    #[test]
+    fn soft_ecc_layout() {
+        type Ecc = SoftEcc<RamFlash, Bch<8>>;
+        // 4 data chunks and the metadata chunk, 14 bytes of parity each
+        assert_eq!(Ecc::PARITY_START, SPARE_SIZE - 5 * 14);
+        assert_eq!(<Ecc as SpareNandFlash>::SPARE_SIZE, Ecc::PARITY_START);
+        assert_eq!(
+            SoftEcc::<RamFlash, Hamming>::PARITY_START,
+            SPARE_SIZE - 5 * 3
+        );
+        assert_eq!(
+            SoftEcc::<RamFlash, Bch<14>>::PARITY_START,
+            SPARE_SIZE - 5 * 23
+        );
+    }
+
+    #[test]
+    fn soft_ecc_corrects_data_and_metadata() {
+        let mut flash = RamFlash::new(1);
+        let mut ecc = SoftEcc::new(&mut flash, Bch::<4>::new());
+        let (data, spare) = page();
+        ecc.write_page(0, &data, &spare).unwrap();
+        let (mut read, mut meta) = ([0; PAGE_SIZE], [0; 16]);
+        ecc.read_page(0, &mut read, &mut meta).unwrap();
+        assert_eq!((read, meta), (data, spare));
+        assert_eq!(ecc.ecc_status(), EccStatus::NoErrors);
+
+        // three errors in the second chunk, one in the metadata and one in the marker bytes
+        for bit in [CHUNK_SIZE * 8, CHUNK_SIZE * 8 + 100, CHUNK_SIZE * 8 + 3000] {
+            clear_raw_bit(&mut flash, 0, bit);
+        }
+        clear_raw_bit(&mut flash, 0, (PAGE_SIZE + SPARE_META + 2) * 8);
+        clear_raw_bit(&mut flash, 0, PAGE_SIZE * 8 + 9);
+        let mut ecc = SoftEcc::new(&mut flash, Bch::<4>::new());
+        ecc.read_page(0, &mut read, &mut meta).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(meta[SPARE_META..], spare[SPARE_META..]);
+        // the marker bytes are not protected
+        assert_eq!(meta[1], 0xFD);
+        assert_eq!(ecc.ecc_status(), EccStatus::Corrected(Some(3)));
+        // reading the spare area alone only corrects the metadata
+        ecc.read_page(0, &mut [], &mut meta).unwrap();
+        assert_eq!(meta[SPARE_META..], spare[SPARE_META..]);
+        assert_eq!(ecc.ecc_status(), EccStatus::Corrected(Some(1)));
+        let mut pages = [0; PAGE_SIZE];
+        ecc.read(0, &mut pages).unwrap();
+        assert_eq!(pages, data);
+        assert_eq!(ecc.ecc_status(), EccStatus::Corrected(Some(3)));
+    }
+
+    #[test]
+    fn soft_ecc_reports_uncorrectable() {
+        let mut flash = RamFlash::new(1);
+        let (data, spare) = page();
+        SoftEcc::new(&mut flash, Hamming)
+            .write_page(PAGE_SIZE as u64, &data, &spare)
+            .unwrap();
+        clear_raw_bit(&mut flash, PAGE_SIZE as u64, 0);
+        clear_raw_bit(&mut flash, PAGE_SIZE as u64, 1000);
+        let mut ecc = SoftEcc::new(&mut flash, Hamming);
+        let mut read = [0; PAGE_SIZE];
+        let err = ecc
+            .read_page(PAGE_SIZE as u64, &mut read, &mut [])
+            .unwrap_err();
+        assert!(matches!(err, Error::Uncorrectable(a) if a == PAGE_SIZE as u64));
+        assert_eq!(
+            err.kind(),
+            NandFlashErrorKind::BlockFail(Some(PAGE_SIZE as u64))
+        );
+        assert_eq!(ecc.ecc_status(), EccStatus::Uncorrectable);
+        // the spare area alone is still good
+        let mut meta = [0; 16];
+        ecc.read_page(PAGE_SIZE as u64, &mut [], &mut meta).unwrap();
+        assert_eq!(meta, spare);
+    }
+
+    #[test]
+    fn soft_ecc_erased_pages() {
+        let mut flash = RamFlash::new(1);
+        let mut ecc = SoftEcc::new(&mut flash, Bch::<8>::new());
+        let (mut read, mut meta) = ([0; PAGE_SIZE], [0; 16]);
+        ecc.read_page(0, &mut read, &mut meta).unwrap();
+        assert!(read.iter().chain(&meta).all(|b| *b == 0xFF));
+        assert_eq!(ecc.ecc_status(), EccStatus::NoErrors);
+
+        // a page written without spare bytes leaves the metadata parity erased
+        let (data, _) = page();
+        ecc.write_page(0, &data, &[]).unwrap();
+        ecc.read_page(0, &mut read, &mut meta).unwrap();
+        assert_eq!(read, data);
+        assert!(meta.iter().all(|b| *b == 0xFF));
+        assert_eq!(ecc.ecc_status(), EccStatus::NoErrors);
+
+        // a bit error in an erased page is corrected back to erased
+        clear_raw_bit(&mut flash, PAGE_SIZE as u64, 77);
+        let mut ecc = SoftEcc::new(&mut flash, Bch::<8>::new());
+        ecc.read_page(PAGE_SIZE as u64, &mut read, &mut meta)
+            .unwrap();
+        assert!(read.iter().chain(&meta).all(|b| *b == 0xFF));
+        assert_eq!(ecc.ecc_status(), EccStatus::Corrected(Some(1)));
+    }
+}
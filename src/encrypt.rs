@@ -0,0 +1,431 @@
+//! Transparent encryption of the pages of a device.
+//!
+//! [`Encrypted`] encrypts every page with a 128 bit block cipher such as AES in XTS mode, using
+//! the page number as the tweak. Each page is one XTS data unit, so pages encrypt independently
+//! and the same data encrypts differently on every page. Nothing is stored besides the
+//! ciphertext, the spare area is passed through untouched and is free for authentication tags.
+//!
+//! Erased pages read back erased and pages written as all `0xFF` are left erased, so the layers
+//! above can still tell which pages are free. This shows which pages are erased but nothing
+//! about the data in the others.
+//!
+//! Ciphertext is tied to its page, [`SpareNandFlash::copy_page`] decrypts and encrypts the page
+//! again rather than using the device's own copy.
+use cipher::{
+    consts::U16, generic_array::GenericArray, BlockCipher, BlockDecrypt, BlockEncrypt,
+    BlockSizeUser, KeyInit,
+};
+
+use crate::{
+    mem::PAGE_SIZE,
+    traits::{
+        check_page, check_read, check_write, BlockStatus, EccStatus, ErrorType, NandFlash,
+        NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+/// Bytes in a cipher block
+const BLOCK: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+        }
+    }
+}
+
+/// Multiply the tweak by the primitive element of GF(2^128), little endian as in XTS
+fn next_tweak(tweak: &mut [u8; BLOCK]) {
+    let mut carry = 0;
+    for byte in tweak.iter_mut() {
+        let next = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
+
+fn xor(block: &mut [u8], tweak: &[u8; BLOCK]) {
+    for (b, t) in block.iter_mut().zip(tweak) {
+        *b ^= t;
+    }
+}
+
+/// XTS mode of `C` with one page as the data unit
+struct Xts<C> {
+    /// Cipher for the data, keyed with the first half of the XTS key
+    data: C,
+    /// Cipher for the tweaks, keyed with the second half of the XTS key
+    tweak: C,
+}
+
+impl<C> Xts<C>
+where
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>,
+{
+    /// Encrypted tweak of the first cipher block of `page`
+    fn page_tweak(&self, page: u64) -> [u8; BLOCK] {
+        let mut tweak = [0; BLOCK];
+        tweak[..8].copy_from_slice(&page.to_le_bytes());
+        self.tweak
+            .encrypt_block(GenericArray::from_mut_slice(&mut tweak));
+        tweak
+    }
+
+    /// Encrypt `data` of `page` in place, leaving an erased page erased
+    fn encrypt(&self, page: u64, data: &mut [u8]) {
+        if data.iter().all(|b| *b == 0xFF) {
+            return;
+        }
+        let mut tweak = self.page_tweak(page);
+        for block in data.chunks_exact_mut(BLOCK) {
+            xor(block, &tweak);
+            self.data.encrypt_block(GenericArray::from_mut_slice(block));
+            xor(block, &tweak);
+            next_tweak(&mut tweak);
+        }
+    }
+
+    /// Decrypt `data` of `page` in place, leaving an erased page erased
+    fn decrypt(&self, page: u64, data: &mut [u8]) {
+        if data.iter().all(|b| *b == 0xFF) {
+            return;
+        }
+        let mut tweak = self.page_tweak(page);
+        for block in data.chunks_exact_mut(BLOCK) {
+            xor(block, &tweak);
+            self.data.decrypt_block(GenericArray::from_mut_slice(block));
+            xor(block, &tweak);
+            next_tweak(&mut tweak);
+        }
+    }
+}
+
+/// `F` with its pages encrypted by `C`
+pub struct Encrypted<F, C> {
+    flash: F,
+    xts: Xts<C>,
+    /// Ciphertext of the page being written
+    buf: [u8; PAGE_SIZE],
+}
+
+impl<F, C> Encrypted<F, C>
+where
+    F: NandFlash,
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>,
+{
+    /// Encrypt `flash` with the ciphers for the data and for the tweaks, which must have
+    /// different keys
+    pub fn new(flash: F, data: C, tweak: C) -> Self {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::WRITE_SIZE.is_multiple_of(BLOCK));
+        assert!(F::WRITE_SIZE.is_multiple_of(F::READ_SIZE));
+        Self {
+            flash,
+            xts: Xts { data, tweak },
+            buf: [0; PAGE_SIZE],
+        }
+    }
+
+    /// Encrypt `flash` with an XTS key, the data key followed by the tweak key, e.g. 64 bytes
+    /// for AES-256
+    pub fn from_key(flash: F, key: &[u8]) -> Self
+    where
+        C: KeyInit,
+    {
+        assert!(key.len() == 2 * C::key_size());
+        let (data, tweak) = key.split_at(C::key_size());
+        let data = C::new_from_slice(data).unwrap();
+        let tweak = C::new_from_slice(tweak).unwrap();
+        Self::new(flash, data, tweak)
+    }
+
+    /// Page number of the page at `offset`
+    fn page(offset: u64) -> u64 {
+        offset / F::WRITE_SIZE as u64
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F, C> ErrorType for Encrypted<F, C>
+where
+    F: NandFlash,
+{
+    type Error = Error<F::Error>;
+}
+
+impl<F, C> ReadNandFlash for Encrypted<F, C>
+where
+    F: NandFlash,
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>,
+{
+    const READ_SIZE: usize = F::WRITE_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.flash.read(offset, bytes).map_err(Error::Flash)?;
+        for (i, page) in bytes.chunks_exact_mut(F::WRITE_SIZE).enumerate() {
+            self.xts.decrypt(Self::page(offset) + i as u64, page);
+        }
+        Ok(())
+    }
+
+    fn read_continuous(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.flash
+            .read_continuous(offset, bytes)
+            .map_err(Error::Flash)?;
+        for (i, page) in bytes.chunks_exact_mut(F::WRITE_SIZE).enumerate() {
+            self.xts.decrypt(Self::page(offset) + i as u64, page);
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.flash.capacity()
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        self.flash.block_status(address).map_err(Error::Flash)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.flash.ecc_status()
+    }
+}
+
+impl<F, C> NandFlash for Encrypted<F, C>
+where
+    F: NandFlash,
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        self.flash.erase(from, to).map_err(Error::Flash)
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let size = F::WRITE_SIZE;
+        for (i, page) in bytes.chunks_exact(size).enumerate() {
+            let address = offset + (i * size) as u64;
+            let buf = &mut self.buf[..size];
+            buf.copy_from_slice(page);
+            self.xts.encrypt(Self::page(address), buf);
+            self.flash.write(address, buf).map_err(Error::Flash)?;
+        }
+        Ok(())
+    }
+}
+
+impl<F, C> SpareNandFlash for Encrypted<F, C>
+where
+    F: SpareNandFlash,
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>,
+{
+    const SPARE_SIZE: usize = F::SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.flash
+            .read_page(offset, data, spare)
+            .map_err(Error::Flash)?;
+        if !data.is_empty() {
+            self.xts.decrypt(Self::page(offset), data);
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let buf = &mut self.buf[..data.len()];
+        buf.copy_from_slice(data);
+        self.xts.encrypt(Self::page(offset), buf);
+        self.flash
+            .write_page(offset, buf, spare)
+            .map_err(Error::Flash)
+    }
+
+    fn mark_bad(&mut self, address: u64) -> Result<(), Self::Error> {
+        self.flash.mark_bad(address).map_err(Error::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{PAGES_PER_BLOCK, SPARE_SIZE};
+    use crate::mock::RamFlash;
+    use aes::Aes128;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        core::array::from_fn(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap())
+    }
+
+    fn xts(key1: &str, key2: &str) -> Xts<Aes128> {
+        Xts {
+            data: Aes128::new(&hex::<16>(key1).into()),
+            tweak: Aes128::new(&hex::<16>(key2).into()),
+        }
+    }
+
+    fn encrypted() -> Encrypted<RamFlash, Aes128> {
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8 * 7);
+        Encrypted::from_key(RamFlash::new(2), &key)
+    }
+
+    fn page(seed: u8) -> [u8; PAGE_SIZE] {
+        core::array::from_fn(|i| (i as u8).wrapping_add(seed))
+    }
+
+    /// XTS-AES-128 vectors 1 to 4 of IEEE 1619-2007, the first 32 bytes of vector 4
+    #[test]
+    fn ieee_1619_vectors() {
+        let zero = "00000000000000000000000000000000";
+        let vectors = [
+            (
+                xts(zero, zero),
+                0,
+                [0; 32],
+                "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e",
+            ),
+            (
+                xts(
+                    "11111111111111111111111111111111",
+                    "22222222222222222222222222222222",
+                ),
+                0x3333333333,
+                [0x44; 32],
+                "c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0",
+            ),
+            (
+                xts(
+                    "fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0",
+                    "22222222222222222222222222222222",
+                ),
+                0x3333333333,
+                [0x44; 32],
+                "af85336b597afc1a900b2eb21ec949d292df4c047e0b21532186a5971a227a89",
+            ),
+            (
+                xts(
+                    "27182818284590452353602874713526",
+                    "31415926535897932384626433832795",
+                ),
+                0,
+                core::array::from_fn(|i| i as u8),
+                "27a7479befa1d476489f308cd4cfa6e2a96e4bbe3208ff25287dd3819616e89c",
+            ),
+        ];
+        for (xts, unit, plain, cipher) in vectors {
+            let mut data = plain;
+            xts.encrypt(unit, &mut data);
+            assert_eq!(data, hex::<32>(cipher));
+            xts.decrypt(unit, &mut data);
+            assert_eq!(data, plain);
+        }
+    }
+
+    #[test]
+    fn pages_round_trip() {
+        let mut flash = encrypted();
+        let data = page(1);
+        let spare = [0x12; 8];
+        flash.write_page(0, &data, &spare).unwrap();
+        flash.write(PAGE_SIZE as u64, &data).unwrap();
+
+        let (mut read, mut meta) = ([0; PAGE_SIZE], [0; 8]);
+        flash.read_page(0, &mut read, &mut meta).unwrap();
+        assert_eq!((read, meta), (data, spare));
+        let mut both = [0; 2 * PAGE_SIZE];
+        flash.read(0, &mut both).unwrap();
+        assert_eq!(
+            (&both[..PAGE_SIZE], &both[PAGE_SIZE..]),
+            (&data[..], &data[..])
+        );
+        both.fill(0);
+        flash.read_continuous(0, &mut both).unwrap();
+        assert_eq!(&both[PAGE_SIZE..], &data[..]);
+
+        // the same data is stored differently on each page, the spare area in the clear
+        let mut raw = flash.into_inner();
+        let (mut first, mut second, mut raw_spare) = ([0; PAGE_SIZE], [0; PAGE_SIZE], [0; 8]);
+        raw.read_page(0, &mut first, &mut raw_spare).unwrap();
+        raw.read(PAGE_SIZE as u64, &mut second).unwrap();
+        assert_ne!(first, data);
+        assert_ne!(first, second);
+        assert_eq!(raw_spare, spare);
+    }
+
+    #[test]
+    fn erased_pages_pass_through() {
+        let mut flash = encrypted();
+        let mut read = [0; PAGE_SIZE];
+        flash.read(0, &mut read).unwrap();
+        assert_eq!(read, [0xFF; PAGE_SIZE]);
+
+        // an erased page written stays erased and can be programmed later
+        flash.write(0, &[0xFF; PAGE_SIZE]).unwrap();
+        flash.write_page(0, &[0xFF; PAGE_SIZE], &[0xFF; 4]).unwrap();
+        flash.into_inner().read(0, &mut read).unwrap();
+        assert_eq!(read, [0xFF; PAGE_SIZE]);
+        let mut flash = encrypted();
+        flash.write(0, &page(2)).unwrap();
+        flash.read(0, &mut read).unwrap();
+        assert_eq!(read, page(2));
+    }
+
+    #[test]
+    fn copy_page_encrypts_for_the_new_page() {
+        let mut flash = encrypted();
+        let data = page(3);
+        let mut spare = [0xFF; SPARE_SIZE];
+        spare[4..8].copy_from_slice(b"tags");
+        flash.write_page(PAGE_SIZE as u64, &data, &spare).unwrap();
+        let to = (PAGES_PER_BLOCK * PAGE_SIZE + 5 * PAGE_SIZE) as u64;
+        flash.copy_page(PAGE_SIZE as u64, to, &[0xFF; 4]).unwrap();
+
+        let (mut read, mut meta) = ([0; PAGE_SIZE], [0; SPARE_SIZE]);
+        flash.read_page(to, &mut read, &mut meta).unwrap();
+        assert_eq!((read, meta), (data, spare));
+
+        // the ciphertext is that of the data under the tweak of the new page
+        let mut expected = data;
+        flash.xts.encrypt(to / PAGE_SIZE as u64, &mut expected);
+        let mut raw = flash.into_inner();
+        raw.read(to, &mut read).unwrap();
+        assert_eq!(read, expected);
+        assert_eq!(raw.copies, 0);
+        raw.read(PAGE_SIZE as u64, &mut read).unwrap();
+        assert_ne!(read, expected);
+    }
+}
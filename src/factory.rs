@@ -0,0 +1,630 @@
+//! Factory images for pre-programming parts on a gang programmer.
+//!
+//! A [`Manifest`] lays out the partitions of the device and the files to put in them, read
+//! from a text file of one statement per line:
+//!
+//! ```text
+//! # comments run to the end of the line
+//! blocks 2048                             # blocks in the device, 2048 if not given
+//! ecc hamming                             # on-chip, hamming, bch4 or bch8
+//! table 0..2                              # store a partition table in blocks 0 and 1
+//! partition boot 2..10 boot.bin read-only
+//! partition rootfs 10..1024 rootfs.bin
+//! partition data 1024..2048 -             # no file, left erased
+//! ```
+//!
+//! [`build`] writes a raw [`NandImage`] with each file at the start of its partition and
+//! everything else erased. With software ECC the spare areas hold the parity of
+//! [`SoftEcc`], and the image is programmed with the on chip ECC disabled. The parity of the on
+//! chip ECC is not documented, so with `on-chip` the parity bytes are left erased and the image
+//! must be programmed with ECC-E set for the device to compute them.
+//!
+//! Parts come with factory bad blocks, so the image is not copied block for block. The
+//! programmer writes the blocks of each partition in order to the good blocks of the same range
+//! on the part, skipping bad ones, and rejects the part if a partition has too few good blocks.
+//! The partition table is [`Placement::Replicated`] instead: its first block holds a copy that
+//! goes to every good block of the table range, and one good block is enough.
+//! [`Build::write_manifest`] describes this for the programmer.
+use std::{
+    fs,
+    io::{self, Read, Seek, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    ecc::{self, Bch, Hamming, SoftEcc},
+    image::{self, NandImage, RAW_BLOCK_SIZE},
+    mem::{BLOCK_COUNT, BLOCK_SIZE, PAGES_PER_BLOCK, PAGE_SIZE, SPARE_SIZE},
+    partition::{self, PartitionTable, READ_ONLY},
+    traits::{NandFlash, NandFlashError, NandFlashErrorKind},
+};
+
+/// Most partitions in a manifest, as in the stored table
+pub const MAX_PARTITIONS: usize = 64;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Errors reading the manifest or the partition files
+    Io(io::Error),
+    /// Errors from the image
+    Image(image::Error),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// Manifest line is not a valid statement
+    Syntax(usize),
+    /// Partition is beyond the device, overlaps another or its name is not valid
+    InvalidPartition(String),
+    /// More partitions than [`MAX_PARTITIONS`]
+    TooManyPartitions,
+    /// Partition file is larger than the partition
+    TooLarge(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+impl From<image::Error> for Error {
+    fn from(value: image::Error) -> Self {
+        Error::Image(value)
+    }
+}
+
+impl From<NandFlashErrorKind> for Error {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl From<ecc::Error<image::Error>> for Error {
+    fn from(value: ecc::Error<image::Error>) -> Self {
+        match value {
+            ecc::Error::Flash(e) => Error::Image(e),
+            e => Error::Nand(e.kind()),
+        }
+    }
+}
+
+impl<E> From<partition::Error<E>> for Error
+where
+    Error: From<E>,
+{
+    fn from(value: partition::Error<E>) -> Self {
+        match value {
+            partition::Error::Flash(e) => e.into(),
+            partition::Error::Nand(kind) => Error::Nand(kind),
+            _ => Error::Nand(NandFlashErrorKind::Other),
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::Image(e) => write!(f, "image error: {e}"),
+            Error::Nand(kind) => write!(f, "nand error: {kind:?}"),
+            Error::Syntax(line) => write!(f, "manifest line {line} is not valid"),
+            Error::InvalidPartition(name) => write!(
+                f,
+                "partition '{name}' is beyond the device, overlaps another or has an invalid name"
+            ),
+            Error::TooManyPartitions => write!(f, "more than {MAX_PARTITIONS} partitions"),
+            Error::TooLarge(name) => write!(f, "file of partition '{name}' does not fit"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// How the spare areas are protected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ecc {
+    /// The on chip ECC computes the parity while programming
+    OnChip,
+    /// [`SoftEcc`] with [`Hamming`]
+    Hamming,
+    /// [`SoftEcc`] with [`Bch<4>`]
+    Bch4,
+    /// [`SoftEcc`] with [`Bch<8>`]
+    Bch8,
+}
+
+impl Ecc {
+    /// Parse the name used in manifests
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "on-chip" => Some(Ecc::OnChip),
+            "hamming" => Some(Ecc::Hamming),
+            "bch4" => Some(Ecc::Bch4),
+            "bch8" => Some(Ecc::Bch8),
+            _ => None,
+        }
+    }
+
+    /// Name used in manifests
+    pub fn name(self) -> &'static str {
+        match self {
+            Ecc::OnChip => "on-chip",
+            Ecc::Hamming => "hamming",
+            Ecc::Bch4 => "bch4",
+            Ecc::Bch8 => "bch8",
+        }
+    }
+}
+
+/// A partition of a [`Manifest`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionSpec {
+    pub name: String,
+    pub blocks: Range<usize>,
+    /// [`READ_ONLY`] and the flags of the application
+    pub flags: u32,
+    /// Contents, the partition is left erased if [None]
+    pub file: Option<PathBuf>,
+}
+
+/// Layout of a factory image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// Blocks in the device
+    pub blocks: usize,
+    pub ecc: Ecc,
+    /// Blocks to store a [`PartitionTable`] of the partitions in
+    pub table: Option<Range<usize>>,
+    pub partitions: Vec<PartitionSpec>,
+}
+
+/// Decimal or 0x prefixed hex number
+fn number(arg: &str) -> Option<usize> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+/// Block range written as first..end
+fn range(arg: &str) -> Option<Range<usize>> {
+    let (first, end) = arg.split_once("..")?;
+    Some(number(first)?..number(end)?)
+}
+
+impl Manifest {
+    /// Read the manifest at `path`, with files relative to its directory
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text, path.parent().unwrap_or(Path::new("")))
+    }
+
+    /// Parse a manifest, with files relative to `dir`
+    pub fn parse(text: &str, dir: &Path) -> Result<Self, Error> {
+        let mut manifest = Self {
+            blocks: BLOCK_COUNT,
+            ecc: Ecc::OnChip,
+            table: None,
+            partitions: Vec::new(),
+        };
+        for (i, line) in text.lines().enumerate() {
+            let syntax = Error::Syntax(i + 1);
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                [] => {}
+                ["blocks", blocks] => manifest.blocks = number(blocks).ok_or(syntax)?,
+                ["ecc", ecc] => manifest.ecc = Ecc::from_name(ecc).ok_or(syntax)?,
+                ["table", blocks] => manifest.table = Some(range(blocks).ok_or(syntax)?),
+                ["partition", name, blocks, ref rest @ ..] => {
+                    let (file, flags) = match rest {
+                        [] | ["-"] => (None, 0),
+                        ["-", "read-only"] => (None, READ_ONLY),
+                        [file] => (Some(dir.join(file)), 0),
+                        [file, "read-only"] => (Some(dir.join(file)), READ_ONLY),
+                        _ => return Err(syntax),
+                    };
+                    manifest.partitions.push(PartitionSpec {
+                        name: name.into(),
+                        blocks: range(blocks).ok_or(syntax)?,
+                        flags,
+                        file,
+                    });
+                }
+                _ => return Err(syntax),
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Table of the partitions, checking they are within the device and do not overlap each
+    /// other or the table
+    fn partition_table(&self) -> Result<PartitionTable<MAX_PARTITIONS>, Error> {
+        let mut table = PartitionTable::new();
+        let table_blocks = self.table.clone().unwrap_or(0..0);
+        if table_blocks.end > self.blocks || (self.table.is_some() && table_blocks.is_empty()) {
+            return Err(Error::InvalidPartition("table".into()));
+        }
+        for spec in &self.partitions {
+            let invalid = || Error::InvalidPartition(spec.name.clone());
+            let blocks = &spec.blocks;
+            if blocks.end > self.blocks
+                || (blocks.start < table_blocks.end && table_blocks.start < blocks.end)
+            {
+                return Err(invalid());
+            }
+            table
+                .add::<()>(&spec.name, blocks.clone(), spec.flags)
+                .map_err(|e| match e {
+                    partition::Error::TableFull => Error::TooManyPartitions,
+                    _ => invalid(),
+                })?;
+        }
+        Ok(table)
+    }
+}
+
+/// How the programmer fills the good blocks of a [`Region`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// The image blocks of the region go to its good blocks in order
+    Sequential,
+    /// The first image block of the region goes to every one of its good blocks
+    Replicated,
+}
+
+impl Placement {
+    /// Name used in programming manifests
+    pub fn name(self) -> &'static str {
+        match self {
+            Placement::Sequential => "sequential",
+            Placement::Replicated => "replicated",
+        }
+    }
+}
+
+/// Where a region of the image goes on the part
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    /// Blocks of the part the region may use
+    pub blocks: Range<usize>,
+    pub placement: Placement,
+    /// Blocks of the image to program, from the start of the region
+    pub program: usize,
+    /// Fewest good blocks the region needs, a part with fewer is rejected
+    pub required: usize,
+}
+
+/// Result of [`build`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Build {
+    /// Blocks in the image
+    pub blocks: usize,
+    pub ecc: Ecc,
+    /// Regions in block order
+    pub regions: Vec<Region>,
+}
+
+impl Build {
+    /// Describe how to program the image, one statement per line
+    pub fn write_manifest(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "# W25N factory programming manifest")?;
+        writeln!(out, "page-size {PAGE_SIZE}")?;
+        writeln!(out, "spare-size {SPARE_SIZE}")?;
+        writeln!(out, "pages-per-block {PAGES_PER_BLOCK}")?;
+        writeln!(out, "blocks {}", self.blocks)?;
+        writeln!(out, "raw-block-size {RAW_BLOCK_SIZE}")?;
+        writeln!(out, "ecc {}", self.ecc.name())?;
+        match self.ecc {
+            Ecc::OnChip => writeln!(out, "# ECC-E set, the part computes the spare parity")?,
+            _ => writeln!(out, "# ECC-E clear, the spare parity is in the image")?,
+        }
+        writeln!(out, "ecc-e {}", u8::from(self.ecc == Ecc::OnChip))?;
+        writeln!(
+            out,
+            "# a block is bad if byte {PAGE_SIZE} of its first page is not 0xff"
+        )?;
+        writeln!(out, "bad-block-marker page 0 byte {PAGE_SIZE}")?;
+        writeln!(out, "bad-block-handling skip")?;
+        writeln!(
+            out,
+            "# sequential: program image blocks first..first+program into the good blocks of"
+        )?;
+        writeln!(out, "#   first..end, in order")?;
+        writeln!(
+            out,
+            "# replicated: program image block first into every good block of first..end"
+        )?;
+        writeln!(
+            out,
+            "# reject the part if first..end has fewer than required good blocks"
+        )?;
+        writeln!(
+            out,
+            "# region <name> <first> <end> <placement> <program> <required>"
+        )?;
+        for region in &self.regions {
+            writeln!(
+                out,
+                "region {} {} {} {} {} {}",
+                region.name,
+                region.blocks.start,
+                region.blocks.end,
+                region.placement.name(),
+                region.program,
+                region.required
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the partitions of `manifest` into `image`, which must be erased and have at least
+/// `manifest.blocks` blocks. Returns the image and how to program it.
+pub fn build<F>(manifest: &Manifest, image: NandImage<F>) -> Result<(NandImage<F>, Build), Error>
+where
+    F: Read + Write + Seek,
+{
+    if image.blocks() < manifest.blocks {
+        return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+    }
+    match manifest.ecc {
+        Ecc::OnChip => build_with(manifest, image),
+        Ecc::Hamming => {
+            let (flash, build) = build_with(manifest, SoftEcc::new(image, Hamming))?;
+            Ok((flash.into_inner(), build))
+        }
+        Ecc::Bch4 => {
+            let (flash, build) = build_with(manifest, SoftEcc::new(image, Bch::<4>::new()))?;
+            Ok((flash.into_inner(), build))
+        }
+        Ecc::Bch8 => {
+            let (flash, build) = build_with(manifest, SoftEcc::new(image, Bch::<8>::new()))?;
+            Ok((flash.into_inner(), build))
+        }
+    }
+}
+
+fn build_with<F>(manifest: &Manifest, mut flash: F) -> Result<(F, Build), Error>
+where
+    F: NandFlash,
+    Error: From<F::Error>,
+{
+    let mut table = manifest.partition_table()?;
+    let mut regions = Vec::new();
+    if let Some(blocks) = manifest.table.clone() {
+        table.store(&mut flash, blocks.clone())?;
+        // every copy is the same, one is enough
+        regions.push(Region {
+            name: "table".into(),
+            blocks,
+            placement: Placement::Replicated,
+            program: 1,
+            required: 1,
+        });
+    }
+    for spec in &manifest.partitions {
+        let mut data = match &spec.file {
+            Some(file) => fs::read(file)?,
+            None => Vec::new(),
+        };
+        data.resize(data.len().next_multiple_of(PAGE_SIZE), 0xFF);
+        let used = data.len().div_ceil(BLOCK_SIZE);
+        if used > spec.blocks.len() {
+            return Err(Error::TooLarge(spec.name.clone()));
+        }
+        flash.write((spec.blocks.start * BLOCK_SIZE) as u64, &data)?;
+        regions.push(Region {
+            name: spec.name.clone(),
+            blocks: spec.blocks.clone(),
+            placement: Placement::Sequential,
+            program: used,
+            required: used,
+        });
+    }
+    regions.sort_by_key(|r| r.blocks.start);
+    let build = Build {
+        blocks: manifest.blocks,
+        ecc: manifest.ecc,
+        regions,
+    };
+    Ok((flash, build))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::RAW_PAGE_SIZE;
+    use crate::traits::{BlockStatus, ReadNandFlash};
+    use std::io::Cursor;
+
+    const MANIFEST: &str = "
+        # test layout
+        blocks 16
+        ecc hamming          # software parity
+        table 0..2
+        partition boot 2..6 boot.bin read-only
+        partition data 0x8..0x10 -
+    ";
+
+    /// Directory for the partition files of a test
+    fn dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("w25n-factory-{}-{test}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn layout(dir: &Path) -> Manifest {
+        Manifest::parse(MANIFEST, dir).unwrap()
+    }
+
+    fn image(blocks: usize) -> NandImage<Cursor<Vec<u8>>> {
+        NandImage::format(Cursor::new(Vec::new()), blocks).unwrap()
+    }
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = layout(Path::new("images"));
+        assert_eq!(manifest.blocks, 16);
+        assert_eq!(manifest.ecc, Ecc::Hamming);
+        assert_eq!(manifest.table, Some(0..2));
+        assert_eq!(
+            manifest.partitions,
+            [
+                PartitionSpec {
+                    name: "boot".into(),
+                    blocks: 2..6,
+                    flags: READ_ONLY,
+                    file: Some(Path::new("images").join("boot.bin")),
+                },
+                PartitionSpec {
+                    name: "data".into(),
+                    blocks: 8..16,
+                    flags: 0,
+                    file: None,
+                },
+            ]
+        );
+        let defaults = Manifest::parse("", Path::new("")).unwrap();
+        assert_eq!(
+            (defaults.blocks, defaults.ecc, defaults.table),
+            (BLOCK_COUNT, Ecc::OnChip, None)
+        );
+
+        for (text, line) in [
+            ("ecc bch16", 1),
+            ("blocks 12\ntable 0-2", 2),
+            ("\n\npartition a 2..4 a.bin writable", 3),
+            ("partition a 2..x", 1),
+            ("blocks", 1),
+            ("erase all", 1),
+        ] {
+            assert!(
+                matches!(Manifest::parse(text, Path::new("")), Err(Error::Syntax(l)) if l == line),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn partition_table_checks_layout() {
+        let invalid = |text: &str| match Manifest::parse(text, Path::new(""))
+            .unwrap()
+            .partition_table()
+        {
+            Err(Error::InvalidPartition(name)) => name,
+            other => panic!("{text}: {other:?}"),
+        };
+        assert_eq!(invalid("blocks 16\ntable 0..2\npartition a 1..4 -"), "a");
+        assert_eq!(invalid("blocks 16\npartition a 8..17 -"), "a");
+        assert_eq!(invalid("blocks 16\ntable 15..17"), "table");
+        assert_eq!(invalid("table 2..2"), "table");
+        assert_eq!(invalid("partition a 2..6 -\npartition b 5..8 -"), "b");
+        assert_eq!(invalid("partition a 2..6 -\npartition a 6..8 -"), "a");
+
+        let mut text = String::new();
+        for i in 0..=MAX_PARTITIONS {
+            text += &format!("partition p{i} {i}..{} -\n", i + 1);
+        }
+        let manifest = Manifest::parse(&text, Path::new("")).unwrap();
+        assert!(matches!(
+            manifest.partition_table(),
+            Err(Error::TooManyPartitions)
+        ));
+
+        let table = layout(Path::new("")).partition_table().unwrap();
+        let names: Vec<&str> = table.entries().iter().map(|e| e.name()).collect();
+        assert_eq!(names, ["boot", "data"]);
+    }
+
+    #[test]
+    fn build_image_and_manifest() {
+        let dir = dir("build");
+        let boot: Vec<u8> = (0..3 * PAGE_SIZE + 10).map(|i| (i % 253) as u8).collect();
+        fs::write(dir.join("boot.bin"), &boot).unwrap();
+        let manifest = layout(&dir);
+        let (image, build) = build(&manifest, image(16)).unwrap();
+
+        let region = |name: &str, blocks, placement, program, required| Region {
+            name: name.into(),
+            blocks,
+            placement,
+            program,
+            required,
+        };
+        assert_eq!(
+            build.regions,
+            [
+                region("table", 0..2, Placement::Replicated, 1, 1),
+                region("boot", 2..6, Placement::Sequential, 1, 1),
+                region("data", 8..16, Placement::Sequential, 0, 0),
+            ]
+        );
+
+        // the spare areas hold the Hamming parity
+        let mut flash = SoftEcc::new(image, Hamming);
+        let mut data = vec![0; boot.len().next_multiple_of(PAGE_SIZE)];
+        flash.read((2 * BLOCK_SIZE) as u64, &mut data).unwrap();
+        assert_eq!(data[..boot.len()], boot[..]);
+        assert!(data[boot.len()..].iter().all(|b| *b == 0xFF));
+        let table = PartitionTable::<MAX_PARTITIONS>::load(&mut flash, 0..2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(table.find("boot").unwrap().flags, READ_ONLY);
+        // both table blocks hold the same copy, as the replicated region says
+        let mut image = flash.into_inner();
+        let (mut first, mut second) = ([0; RAW_PAGE_SIZE], [0; RAW_PAGE_SIZE]);
+        image.read_raw(0, &mut first).unwrap();
+        image.read_raw(PAGES_PER_BLOCK as u32, &mut second).unwrap();
+        assert_eq!(first, second);
+        assert_ne!(first[PAGE_SIZE + 4..], [0xFF; SPARE_SIZE - 4]);
+
+        let mut out = Vec::new();
+        build.write_manifest(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        assert!(lines.contains(&"ecc hamming"));
+        assert!(lines.contains(&"ecc-e 0"));
+        assert!(lines.ends_with(&[
+            "region table 0 2 replicated 1 1",
+            "region boot 2 6 sequential 1 1",
+            "region data 8 16 sequential 0 0",
+        ]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn build_checks_sizes() {
+        let dir = dir("sizes");
+        fs::write(dir.join("boot.bin"), vec![0; 4 * BLOCK_SIZE + 1]).unwrap();
+        let manifest = layout(&dir);
+        assert!(matches!(
+            build(&manifest, image(16)),
+            Err(Error::TooLarge(name)) if name == "boot"
+        ));
+        assert!(matches!(
+            build(&manifest, image(8)),
+            Err(Error::Nand(NandFlashErrorKind::OutOfBounds))
+        ));
+
+        // with the on chip ECC the parity is left to the part
+        fs::write(dir.join("boot.bin"), [0x5A; 100]).unwrap();
+        let mut manifest = manifest;
+        manifest.ecc = Ecc::OnChip;
+        let (mut image, build) = build(&manifest, image(16)).unwrap();
+        assert_eq!(build.ecc, Ecc::OnChip);
+        let mut raw = [0; RAW_PAGE_SIZE];
+        image
+            .read_raw((2 * PAGES_PER_BLOCK) as u32, &mut raw)
+            .unwrap();
+        assert_eq!(raw[..100], [0x5A; 100]);
+        assert!(raw[100..].iter().all(|b| *b == 0xFF));
+        assert_eq!(
+            image.block_status(2 * BLOCK_SIZE as u64).unwrap(),
+            BlockStatus::MarkedOk
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,680 @@
+//! Wear levelling flash translation layer.
+//!
+//! Logical sectors the size of a page are written out of place: each write goes to the next
+//! free page of the head block and the old copy is left behind as garbage. Every page carries
+//! its sector, a sequence number, the erase count of its block and CRCs in the spare area, so
+//! the map is rebuilt on [`Ftl::mount`] by taking the newest copy of each sector. A page torn
+//! by power loss fails its CRC or reads as uncorrectable and the previous copy is used instead.
+//!
+//! Blocks are erased when they are taken for writing, the free block with the fewest erases
+//! is taken first. Garbage collection copies the valid pages out of the block with the least
+//! valid pages, using the device's copy-back where available. Cold blocks holding data that
+//! has not moved for a while are collected too once their erase count falls more than
+//! [`FtlConfig::static_threshold`] behind the most worn block.
+//!
+//! The map is held in RAM, one `u32` per sector, so size `SECTORS` for the region the FTL is
+//! given rather than the whole device.
+use crate::{
+    crc::crc32,
+    mem::{PAGE_SIZE, SPARE_META},
+    traits::{BlockStatus, ErrorType, NandFlashError, NandFlashErrorKind, SpareNandFlash},
+};
+
+/// Identifies pages written by the FTL
+const MAGIC: [u8; 2] = *b"FT";
+const META_LEN: usize = 24;
+/// Spare bytes read to get the metadata of a page
+const SPARE_LEN: usize = SPARE_META + META_LEN;
+
+/// Map entry of a sector that has never been written
+const UNMAPPED: u32 = u32::MAX;
+/// Set in a map entry pointing at a trim record
+const TRIMMED: u32 = 1 << 31;
+/// Erase count of a block that has gone bad
+const BAD: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Sector number beyond the end of the FTL
+    OutOfBounds,
+    /// Buffer is not the size of a sector
+    NotAligned,
+    /// Every block holds valid data, nothing can be collected
+    NoSpace,
+    /// Flash has too few good blocks for the number of sectors
+    TooSmall,
+    /// Sector data does not match its CRC
+    Corrupt(u32),
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::OutOfBounds => NandFlashErrorKind::OutOfBounds,
+            Error::NotAligned => NandFlashErrorKind::NotAligned,
+            Error::Corrupt(_) => NandFlashErrorKind::BlockFail(None),
+            _ => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Flash(e) => write!(f, "flash error: {e:?}"),
+            Error::OutOfBounds => write!(f, "sector beyond the end of the FTL"),
+            Error::NotAligned => write!(f, "buffer is not the size of a sector"),
+            Error::NoSpace => write!(f, "no block left to collect"),
+            Error::TooSmall => write!(f, "too few good blocks for the sectors"),
+            Error::Corrupt(sector) => write!(f, "sector {sector} does not match its CRC"),
+        }
+    }
+}
+
+impl<E: core::fmt::Debug> core::error::Error for Error<E> {}
+
+/// Tuning for the [`Ftl`]
+#[derive(Debug, Clone, Copy)]
+pub struct FtlConfig {
+    /// Blocks kept free beyond those needed for sectors, at least 2 for garbage collection
+    pub spare_blocks: usize,
+    /// Difference in erase counts at which cold data is moved off its block
+    pub static_threshold: u32,
+}
+
+impl Default for FtlConfig {
+    fn default() -> Self {
+        Self {
+            spare_blocks: 4,
+            static_threshold: 1000,
+        }
+    }
+}
+
+/// Metadata stored in the spare area of each page
+#[derive(Debug, Clone, Copy)]
+struct Meta {
+    trim: bool,
+    sector: u32,
+    seq: u32,
+    erases: u32,
+    crc: u32,
+}
+
+impl Meta {
+    fn to_bytes(self) -> [u8; META_LEN] {
+        let mut bytes = [0; META_LEN];
+        bytes[0..2].copy_from_slice(&MAGIC);
+        bytes[2] = self.trim as u8;
+        bytes[3] = 0;
+        bytes[4..8].copy_from_slice(&self.sector.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.erases.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.crc.to_le_bytes());
+        let crc = crc32(&bytes[..20]);
+        bytes[20..24].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        if bytes[0..2] != MAGIC || word(20) != crc32(&bytes[..20]) {
+            return None;
+        }
+        Some(Self {
+            trim: bytes[2] != 0,
+            sector: word(4),
+            seq: word(8),
+            erases: word(12),
+            crc: word(16),
+        })
+    }
+}
+
+/// Page mapped FTL over `F` with `SECTORS` logical sectors on a device of up to `BLOCKS` blocks.
+pub struct Ftl<F, const SECTORS: usize, const BLOCKS: usize> {
+    flash: F,
+    config: FtlConfig,
+    /// Physical page of each sector
+    map: [u32; SECTORS],
+    /// Erase count of each block
+    erases: [u32; BLOCKS],
+    /// Pages in each block holding the current copy of a sector
+    valid: [u16; BLOCKS],
+    /// Block being written and its next free page
+    head: Option<(usize, usize)>,
+    /// Sequence number of the next page written
+    seq: u32,
+    blocks: usize,
+    pages_per_block: usize,
+}
+
+type FResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+/// Turn an uncorrectable read into `Ok(false)`, other errors are passed on
+fn unreadable<E: NandFlashError>(result: Result<(), E>) -> Result<bool, Error<E>> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => Ok(false),
+        Err(e) => Err(Error::Flash(e)),
+    }
+}
+
+impl<F, const SECTORS: usize, const BLOCKS: usize> Ftl<F, SECTORS, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    /// Rebuild the sector map from the metadata of every page
+    pub fn mount(flash: F, config: FtlConfig) -> FResult<Self, F> {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::SPARE_SIZE >= SPARE_LEN);
+        let blocks = (flash.capacity() / F::ERASE_SIZE as u64) as usize;
+        if blocks > BLOCKS {
+            return Err(Error::TooSmall);
+        }
+        let mut ftl = Self {
+            flash,
+            config,
+            map: [UNMAPPED; SECTORS],
+            erases: [0; BLOCKS],
+            valid: [0; BLOCKS],
+            head: None,
+            seq: 0,
+            blocks,
+            pages_per_block: F::ERASE_SIZE / F::WRITE_SIZE,
+        };
+        let mut known = 0;
+        let mut total = 0u64;
+        for block in 0..blocks {
+            if ftl.scan_block(block)? {
+                known += 1;
+                total += ftl.erases[block] as u64;
+            }
+        }
+        // blocks holding no pages get the mean erase count of the rest
+        let mean = total.checked_div(known).unwrap_or(0) as u32;
+        for block in 0..blocks {
+            if ftl.erases[block] == 0 && ftl.valid[block] == 0 {
+                ftl.erases[block] = mean;
+            }
+        }
+        let good = ftl.erases[..blocks].iter().filter(|e| **e != BAD).count();
+        if SECTORS.div_ceil(ftl.pages_per_block) + config.spare_blocks.max(2) > good {
+            return Err(Error::TooSmall);
+        }
+        Ok(ftl)
+    }
+
+    /// Add the pages of `block` to the map, returns true if its erase count was found
+    fn scan_block(&mut self, block: usize) -> FResult<bool, F> {
+        let status = self
+            .flash
+            .block_status(self.address(block, 0))
+            .map_err(Error::Flash)?;
+        if status == BlockStatus::Failed {
+            self.erases[block] = BAD;
+            return Ok(false);
+        }
+        // Pages are written in order, the one before the first erased page was written last
+        let mut spare = [0; SPARE_LEN];
+        let mut found = false;
+        let mut pending: Option<(usize, Meta)> = None;
+        for page in 0..=self.pages_per_block {
+            // an unreadable page was torn by power loss, it is neither erased nor valid
+            let readable = page < self.pages_per_block
+                && self.read_spare(self.address(block, page), &mut spare)?;
+            let erased =
+                page == self.pages_per_block || (readable && spare.iter().all(|b| *b == 0xFF));
+            if let Some((last, meta)) = pending.take() {
+                // Only the last page written can have been torn by power loss
+                if !erased || meta.trim || self.data_ok(self.address(block, last), meta.crc)? {
+                    found = true;
+                    self.add_page(block, last, meta)?;
+                }
+            }
+            if erased {
+                break;
+            }
+            pending = Meta::from_bytes(&spare[SPARE_META..])
+                .filter(|_| readable)
+                .map(|meta| (page, meta));
+        }
+        Ok(found)
+    }
+
+    /// Map the sector of a page found when mounting, unless a newer copy is already mapped
+    fn add_page(&mut self, block: usize, page: usize, meta: Meta) -> FResult<(), F> {
+        self.erases[block] = self.erases[block].max(meta.erases);
+        self.seq = self.seq.max(meta.seq.wrapping_add(1));
+        let sector = meta.sector as usize;
+        if sector >= SECTORS {
+            return Ok(());
+        }
+        if self.map[sector] != UNMAPPED {
+            let old = self.map[sector] & !TRIMMED;
+            let mut spare = [0; SPARE_LEN];
+            let readable = self.read_spare(self.page_address(old), &mut spare)?;
+            match Meta::from_bytes(&spare[SPARE_META..]) {
+                Some(current) if readable && current.seq > meta.seq => return Ok(()),
+                _ => self.valid[old as usize / self.pages_per_block] -= 1,
+            }
+        }
+        self.map[sector] = self.page_index(block, page) | if meta.trim { TRIMMED } else { 0 };
+        self.valid[block] += 1;
+        Ok(())
+    }
+
+    /// Read the metadata of a page when mounting, returns false if the page is unreadable.
+    /// With on chip ECC a page torn by power loss reads as uncorrectable.
+    fn read_spare(&mut self, address: u64, spare: &mut [u8; SPARE_LEN]) -> FResult<bool, F> {
+        unreadable(self.flash.read_page(address, &mut [], spare))
+    }
+
+    /// Check the data of the page at `address` matches `crc`
+    fn data_ok(&mut self, address: u64, crc: u32) -> FResult<bool, F> {
+        let mut data = [0; PAGE_SIZE];
+        let data = &mut data[..F::WRITE_SIZE];
+        Ok(unreadable(self.flash.read_page(address, data, &mut []))? && crc32(data) == crc)
+    }
+
+    fn address(&self, block: usize, page: usize) -> u64 {
+        (block * self.pages_per_block + page) as u64 * F::WRITE_SIZE as u64
+    }
+
+    fn page_index(&self, block: usize, page: usize) -> u32 {
+        (block * self.pages_per_block + page) as u32
+    }
+
+    fn page_address(&self, index: u32) -> u64 {
+        index as u64 * F::WRITE_SIZE as u64
+    }
+
+    /// Blocks that are good, hold no valid pages and are not being written
+    fn is_free(&self, block: usize) -> bool {
+        self.erases[block] != BAD
+            && self.valid[block] == 0
+            && self.head.is_none_or(|(head, _)| head != block)
+    }
+
+    fn free_blocks(&self) -> usize {
+        (0..self.blocks).filter(|b| self.is_free(*b)).count()
+    }
+
+    /// Next page to write, collecting garbage and taking a new block if the head is full
+    fn next_page(&mut self, collecting: bool) -> FResult<(usize, usize), F> {
+        if let Some(head) = self.head {
+            return Ok(head);
+        }
+        if !collecting {
+            while self.free_blocks() < 2 {
+                self.collect()?;
+            }
+            self.level()?;
+            if let Some(head) = self.head {
+                return Ok(head);
+            }
+        }
+        // Dynamic wear levelling, take the least worn free block
+        loop {
+            let block = (0..self.blocks)
+                .filter(|b| self.is_free(*b))
+                .min_by_key(|b| self.erases[*b])
+                .ok_or(Error::NoSpace)?;
+            let address = self.address(block, 0);
+            match self.flash.erase(address, address + F::ERASE_SIZE as u64) {
+                Ok(()) => {
+                    self.erases[block] += 1;
+                    self.head = Some((block, 0));
+                    return Ok((block, 0));
+                }
+                Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => {
+                    self.retire(block)?
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+    }
+
+    /// Write a page for `sector` at the head, returning its page index
+    fn program(&mut self, meta: Meta, data: &[u8], from: Option<u32>) -> FResult<u32, F> {
+        loop {
+            let (block, page) = self.next_page(from.is_some())?;
+            let meta = Meta {
+                seq: self.seq,
+                erases: self.erases[block],
+                ..meta
+            };
+            let mut spare = [0xFF; SPARE_LEN];
+            spare[SPARE_META..].copy_from_slice(&meta.to_bytes());
+            let address = self.address(block, page);
+            let result = match from {
+                Some(from) => self
+                    .flash
+                    .copy_page(self.page_address(from), address, &spare),
+                None => self.flash.write_page(address, data, &spare),
+            };
+            self.seq = self.seq.wrapping_add(1);
+            self.head = (page + 1 < self.pages_per_block).then_some((block, page + 1));
+            match result {
+                Ok(()) => return Ok(self.page_index(block, page)),
+                Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => {
+                    self.head = None;
+                    self.retire(block)?
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+    }
+
+    /// Point `sector` at `entry`, releasing its previous page
+    fn remap(&mut self, sector: usize, entry: u32) {
+        if self.map[sector] != UNMAPPED {
+            let old = (self.map[sector] & !TRIMMED) as usize;
+            self.valid[old / self.pages_per_block] -= 1;
+        }
+        self.map[sector] = entry;
+        self.valid[(entry & !TRIMMED) as usize / self.pages_per_block] += 1;
+    }
+
+    /// Move the valid pages of `block` to the head
+    fn evacuate(&mut self, block: usize) -> FResult<(), F> {
+        let mut spare = [0; SPARE_LEN];
+        for page in 0..self.pages_per_block {
+            if self.valid[block] == 0 {
+                break;
+            }
+            let index = self.page_index(block, page);
+            if !self.read_spare(self.address(block, page), &mut spare)? {
+                continue;
+            }
+            let Some(meta) = Meta::from_bytes(&spare[SPARE_META..]) else {
+                continue;
+            };
+            let sector = meta.sector as usize;
+            if sector >= SECTORS || self.map[sector] & !TRIMMED != index {
+                continue;
+            }
+            let moved = self.program(meta, &[], Some(index))?;
+            self.remap(sector, moved | (self.map[sector] & TRIMMED));
+        }
+        Ok(())
+    }
+
+    /// Free the block with the fewest valid pages
+    fn collect(&mut self) -> FResult<(), F> {
+        let head = self.head.map(|(b, _)| b);
+        let victim = (0..self.blocks)
+            .filter(|b| self.erases[*b] != BAD && self.valid[*b] != 0 && Some(*b) != head)
+            .min_by_key(|b| self.valid[*b])
+            .ok_or(Error::NoSpace)?;
+        if self.valid[victim] as usize == self.pages_per_block {
+            return Err(Error::NoSpace);
+        }
+        self.evacuate(victim)
+    }
+
+    /// Static wear levelling, move data off the least worn block if it falls too far behind
+    fn level(&mut self) -> FResult<(), F> {
+        let good = || (0..self.blocks).filter(|b| self.erases[*b] != BAD);
+        let Some(most) = good().map(|b| self.erases[b]).max() else {
+            return Ok(());
+        };
+        let coldest = good()
+            .filter(|b| self.valid[*b] != 0)
+            .min_by_key(|b| self.erases[*b]);
+        match coldest {
+            Some(block)
+                if most - self.erases[block] > self.config.static_threshold
+                    && self.free_blocks() >= self.config.spare_blocks.max(2) =>
+            {
+                self.evacuate(block)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Move the data off a failing block and mark it bad
+    fn retire(&mut self, block: usize) -> FResult<(), F> {
+        self.evacuate(block)?;
+        self.erases[block] = BAD;
+        let _ = self.flash.mark_bad(self.address(block, 0));
+        Ok(())
+    }
+
+    fn check_sector(&self, sector: u32, len: usize) -> FResult<usize, F> {
+        if sector as usize >= SECTORS {
+            return Err(Error::OutOfBounds);
+        }
+        if len != F::WRITE_SIZE {
+            return Err(Error::NotAligned);
+        }
+        Ok(sector as usize)
+    }
+
+    /// Bytes in a sector
+    pub fn sector_size(&self) -> usize {
+        F::WRITE_SIZE
+    }
+
+    /// Number of logical sectors
+    pub fn sectors(&self) -> u32 {
+        SECTORS as u32
+    }
+
+    /// Read `sector` into `buf`, sectors never written or trimmed read as erased
+    pub fn read(&mut self, sector: u32, buf: &mut [u8]) -> FResult<(), F> {
+        let index = self.check_sector(sector, buf.len())?;
+        let entry = self.map[index];
+        if entry == UNMAPPED || entry & TRIMMED != 0 {
+            buf.fill(0xFF);
+            return Ok(());
+        }
+        let mut spare = [0; SPARE_LEN];
+        self.flash
+            .read_page(self.page_address(entry), buf, &mut spare)
+            .map_err(Error::Flash)?;
+        match Meta::from_bytes(&spare[SPARE_META..]) {
+            Some(meta) if meta.crc == crc32(buf) => Ok(()),
+            _ => Err(Error::Corrupt(sector)),
+        }
+    }
+
+    /// Write `data` to `sector`
+    pub fn write(&mut self, sector: u32, data: &[u8]) -> FResult<(), F> {
+        let index = self.check_sector(sector, data.len())?;
+        let meta = Meta {
+            trim: false,
+            sector,
+            seq: 0,
+            erases: 0,
+            crc: crc32(data),
+        };
+        let page = self.program(meta, data, None)?;
+        self.remap(index, page);
+        Ok(())
+    }
+
+    /// Discard the contents of `sector`, it reads as erased until written again
+    pub fn trim(&mut self, sector: u32) -> FResult<(), F> {
+        let index = self.check_sector(sector, F::WRITE_SIZE)?;
+        if self.map[index] == UNMAPPED || self.map[index] & TRIMMED != 0 {
+            return Ok(());
+        }
+        let meta = Meta {
+            trim: true,
+            sector,
+            seq: 0,
+            erases: 0,
+            crc: 0,
+        };
+        let page = self.program(meta, &[], None)?;
+        self.remap(index, page | TRIMMED);
+        Ok(())
+    }
+
+    /// Erase count of `block`, [`None`] if it has gone bad
+    pub fn erase_count(&self, block: usize) -> Option<u32> {
+        self.erases[..self.blocks]
+            .get(block)
+            .filter(|e| **e != BAD)
+            .copied()
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{RamError, RamFlash};
+
+    const SECTORS: usize = 128;
+    type TestFtl = Ftl<RamFlash, SECTORS, 8>;
+
+    fn mount(flash: RamFlash) -> TestFtl {
+        Ftl::mount(flash, FtlConfig::default()).unwrap()
+    }
+
+    /// Page written as `version` of `sector`
+    fn page(sector: u32, version: u32) -> [u8; PAGE_SIZE] {
+        let mut data = [version as u8; PAGE_SIZE];
+        data[..4].copy_from_slice(&sector.to_le_bytes());
+        data[4..8].copy_from_slice(&version.to_le_bytes());
+        data
+    }
+
+    /// Check every sector holds its expected version, [None] for erased
+    fn check(ftl: &mut TestFtl, expected: &[Option<u32>]) {
+        let mut buf = [0; PAGE_SIZE];
+        for (sector, version) in expected.iter().enumerate() {
+            ftl.read(sector as u32, &mut buf).unwrap();
+            match version {
+                Some(version) => assert_eq!(buf, page(sector as u32, *version), "{sector}"),
+                None => assert!(buf.iter().all(|b| *b == 0xFF), "{sector}"),
+            }
+        }
+    }
+
+    /// Operations of a deterministic workload, a write of the step as version or a trim
+    fn workload(steps: u32) -> impl Iterator<Item = (u32, Option<u32>)> {
+        let mut state = 0x1234_5678u32;
+        (0..steps).map(move |step| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let sector = (state >> 8) % SECTORS as u32;
+            let trim = (state >> 24).is_multiple_of(10);
+            (sector, (!trim).then_some(step))
+        })
+    }
+
+    fn apply(ftl: &mut TestFtl, sector: u32, op: Option<u32>) -> Result<(), Error<RamError>> {
+        match op {
+            Some(version) => ftl.write(sector, &page(sector, version)),
+            None => ftl.trim(sector),
+        }
+    }
+
+    #[test]
+    fn write_read_trim() {
+        let mut ftl = mount(RamFlash::new(8));
+        let mut expected = [None; SECTORS];
+        check(&mut ftl, &expected);
+        for sector in [0, 5, 127] {
+            ftl.write(sector, &page(sector, 1)).unwrap();
+            expected[sector as usize] = Some(1);
+        }
+        ftl.write(5, &page(5, 2)).unwrap();
+        expected[5] = Some(2);
+        ftl.trim(0).unwrap();
+        expected[0] = None;
+        check(&mut ftl, &expected);
+        assert!(matches!(
+            ftl.write(128, &page(128, 1)),
+            Err(Error::OutOfBounds)
+        ));
+        assert!(matches!(ftl.write(1, &[0; 16]), Err(Error::NotAligned)));
+
+        let mut ftl = mount(ftl.into_inner());
+        check(&mut ftl, &expected);
+    }
+
+    #[test]
+    fn garbage_collection_copies_back() {
+        let mut ftl = mount(RamFlash::new(8));
+        let mut expected = [None; SECTORS];
+        for (sector, op) in workload(2000) {
+            apply(&mut ftl, sector, op).unwrap();
+            expected[sector as usize] = op;
+        }
+        check(&mut ftl, &expected);
+        let flash = ftl.into_inner();
+        assert!(flash.copies > 0);
+        let mut ftl = mount(flash);
+        check(&mut ftl, &expected);
+        // collection keeps working after the remount
+        for (sector, op) in workload(500) {
+            apply(&mut ftl, sector, op).unwrap();
+            expected[sector as usize] = op;
+        }
+        check(&mut ftl, &expected);
+    }
+
+    #[test]
+    fn power_loss() {
+        const STEPS: u32 = 700;
+        // programs and erases done by the whole workload
+        let mut flash = RamFlash::new(8);
+        flash.cut_after = Some(usize::MAX);
+        let mut ftl = mount(flash);
+        for (sector, op) in workload(STEPS) {
+            apply(&mut ftl, sector, op).unwrap();
+        }
+        let operations = usize::MAX - ftl.into_inner().cut_after.unwrap();
+
+        for cut in (0..operations).step_by(5) {
+            let mut flash = RamFlash::new(8);
+            flash.cut_after = Some(cut);
+            // alternate between torn pages that read back and ones that are uncorrectable
+            flash.tear_uncorrectable = cut % 2 == 1;
+            let mut ftl = mount(flash);
+            let mut expected = [None; SECTORS];
+            let mut torn = None;
+            for (sector, op) in workload(STEPS) {
+                if apply(&mut ftl, sector, op).is_err() {
+                    torn = Some((sector as usize, op));
+                    break;
+                }
+                expected[sector as usize] = op;
+            }
+            let (sector, op) = torn.expect("power cut");
+
+            let mut flash = ftl.into_inner();
+            flash.power_on();
+            let mut ftl = mount(flash);
+            // the operation cut short either took effect or did not
+            let mut buf = [0; PAGE_SIZE];
+            ftl.read(sector as u32, &mut buf).unwrap();
+            let new = match op {
+                Some(version) => buf == page(sector as u32, version),
+                None => buf.iter().all(|b| *b == 0xFF),
+            };
+            if new {
+                expected[sector] = op;
+            }
+            check(&mut ftl, &expected);
+
+            // and the FTL carries on from there
+            for (sector, op) in workload(200) {
+                apply(&mut ftl, sector, op).unwrap();
+                expected[sector as usize] = op;
+            }
+            let mut ftl = mount(ftl.into_inner());
+            check(&mut ftl, &expected);
+        }
+    }
+}
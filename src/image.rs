@@ -0,0 +1,407 @@
+//! Raw NAND images for host tooling.
+//!
+//! An image stores every page followed by its spare area, exactly as the device does:
+//! [`RAW_PAGE_SIZE`] bytes per page and [`PAGES_PER_BLOCK`] pages per block. Factory bad
+//! blocks are marked by a non 0xFF first spare byte of the first page, as on the device.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    mem::{BLOCK_SIZE, PAGES_PER_BLOCK, PAGE_SIZE, SPARE_SIZE},
+    traits::{
+        check_erase, check_page, check_read, check_write, BlockStatus, ErrorType, NandFlash,
+        NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+/// Bytes of a page including its spare area
+pub const RAW_PAGE_SIZE: usize = PAGE_SIZE + SPARE_SIZE;
+/// Bytes of a block including the spare areas of its pages
+pub const RAW_BLOCK_SIZE: usize = RAW_PAGE_SIZE * PAGES_PER_BLOCK;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Errors from the underlying file
+    Io(io::Error),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// File length is not a whole number of raw blocks
+    Size(u64),
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+impl From<NandFlashErrorKind> for Error {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {e}"),
+            Error::Nand(kind) => write!(f, "nand error: {kind:?}"),
+            Error::Size(len) => write!(
+                f,
+                "image length {len} is not a multiple of the raw block size {RAW_BLOCK_SIZE}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl NandFlashError for Error {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Nand(kind) => *kind,
+            _ => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// A raw NAND image behaving like the device: erase sets all bits, program can only clear them.
+pub struct NandImage<F> {
+    file: F,
+    blocks: usize,
+}
+
+impl NandImage<File> {
+    /// Open an existing image file for reading and writing
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::new(file)
+    }
+
+    /// Create an erased image file of `blocks` blocks, truncating any existing file
+    pub fn create(path: impl AsRef<Path>, blocks: usize) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Self::format(file, blocks)
+    }
+}
+
+impl<F> NandImage<F>
+where
+    F: Read + Write + Seek,
+{
+    /// Use an existing image, the number of blocks is taken from its length
+    pub fn new(mut file: F) -> Result<Self, Error> {
+        let len = file.seek(SeekFrom::End(0))?;
+        if len == 0 || !len.is_multiple_of(RAW_BLOCK_SIZE as u64) {
+            return Err(Error::Size(len));
+        }
+        Ok(Self {
+            file,
+            blocks: (len / RAW_BLOCK_SIZE as u64) as usize,
+        })
+    }
+
+    /// Fill `file` with `blocks` erased blocks
+    pub fn format(mut file: F, blocks: usize) -> Result<Self, Error> {
+        file.seek(SeekFrom::Start(0))?;
+        let erased = [0xFF; RAW_PAGE_SIZE];
+        for _ in 0..blocks * PAGES_PER_BLOCK {
+            file.write_all(&erased)?;
+        }
+        file.flush()?;
+        Ok(Self { file, blocks })
+    }
+
+    /// Number of blocks in the image
+    pub fn blocks(&self) -> usize {
+        self.blocks
+    }
+
+    /// Return the underlying file
+    pub fn into_inner(self) -> F {
+        self.file
+    }
+
+    /// Read page `page` including its spare area into `buf`
+    pub fn read_raw(&mut self, page: u32, buf: &mut [u8; RAW_PAGE_SIZE]) -> Result<(), Error> {
+        self.seek_page(page)?;
+        self.file.read_exact(buf)?;
+        Ok(())
+    }
+
+    /// Overwrite page `page` including its spare area with `buf`.
+    /// Unlike programming this can set bits, e.g. to inject errors or clear bad block markers.
+    pub fn write_raw(&mut self, page: u32, buf: &[u8; RAW_PAGE_SIZE]) -> Result<(), Error> {
+        self.seek_page(page)?;
+        self.file.write_all(buf)?;
+        Ok(())
+    }
+
+    /// Flush buffered writes to the file
+    pub fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.file.flush()?)
+    }
+
+    fn seek_page(&mut self, page: u32) -> Result<(), Error> {
+        if page as usize >= self.blocks * PAGES_PER_BLOCK {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        self.file
+            .seek(SeekFrom::Start(page as u64 * RAW_PAGE_SIZE as u64))?;
+        Ok(())
+    }
+
+    /// Program `data` and `spare` into `page`, only clearing bits like the device does
+    fn program(&mut self, page: u32, data: &[u8], spare: &[u8]) -> Result<(), Error> {
+        let mut raw = [0; RAW_PAGE_SIZE];
+        self.read_raw(page, &mut raw)?;
+        raw.iter_mut()
+            .zip(data)
+            .for_each(|(stored, new)| *stored &= new);
+        raw[PAGE_SIZE..]
+            .iter_mut()
+            .zip(spare)
+            .for_each(|(stored, new)| *stored &= new);
+        self.write_raw(page, &raw)
+    }
+}
+
+/// Page index from byte address
+fn page(offset: u64) -> u32 {
+    (offset / PAGE_SIZE as u64) as u32
+}
+
+impl<F> ErrorType for NandImage<F> {
+    type Error = Error;
+}
+
+impl<F> ReadNandFlash for NandImage<F>
+where
+    F: Read + Write + Seek,
+{
+    const READ_SIZE: usize = PAGE_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.seek_page(page(offset))?;
+        for chunk in bytes.chunks_exact_mut(PAGE_SIZE) {
+            self.file.read_exact(chunk)?;
+            // skip the spare area to the next page
+            self.file.seek(SeekFrom::Current(SPARE_SIZE as i64))?;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        (self.blocks * BLOCK_SIZE) as u64
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        let mut marker = [0];
+        self.read_page(address - address % BLOCK_SIZE as u64, &mut [], &mut marker)?;
+        if marker[0] == 0xFF {
+            Ok(BlockStatus::MarkedOk)
+        } else {
+            Ok(BlockStatus::Failed)
+        }
+    }
+}
+
+impl<F> NandFlash for NandImage<F>
+where
+    F: Read + Write + Seek,
+{
+    const WRITE_SIZE: usize = PAGE_SIZE;
+
+    const ERASE_SIZE: usize = BLOCK_SIZE;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        if from == to {
+            return Ok(());
+        }
+        self.seek_page(page(from))?;
+        let erased = [0xFF; RAW_PAGE_SIZE];
+        for _ in page(from)..page(to) {
+            self.file.write_all(&erased)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        for (i, chunk) in bytes.chunks_exact(PAGE_SIZE).enumerate() {
+            self.program(page(offset) + i as u32, chunk, &[])?;
+        }
+        Ok(())
+    }
+}
+
+impl<F> SpareNandFlash for NandImage<F>
+where
+    F: Read + Write + Seek,
+{
+    const SPARE_SIZE: usize = SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let mut raw = [0; RAW_PAGE_SIZE];
+        self.read_raw(page(offset), &mut raw)?;
+        data.copy_from_slice(&raw[..data.len()]);
+        spare.copy_from_slice(&raw[PAGE_SIZE..PAGE_SIZE + spare.len()]);
+        Ok(())
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.program(page(offset), data, spare)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor, vec, vec::Vec};
+
+    use super::*;
+
+    fn image(blocks: usize) -> NandImage<Cursor<Vec<u8>>> {
+        NandImage::format(Cursor::new(Vec::new()), blocks).unwrap()
+    }
+
+    #[test]
+    fn create_and_open() {
+        let path = std::env::temp_dir().join(format!("w25n-image-{}.bin", std::process::id()));
+        let mut created = NandImage::create(&path, 2).unwrap();
+        created
+            .write(BLOCK_SIZE as u64, &[0x5A; PAGE_SIZE])
+            .unwrap();
+        created.flush().unwrap();
+        drop(created);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            2 * RAW_BLOCK_SIZE as u64
+        );
+
+        let mut opened = NandImage::open(&path).unwrap();
+        assert_eq!(opened.blocks(), 2);
+        assert_eq!(opened.capacity(), 2 * BLOCK_SIZE as u64);
+        let mut page = [0; PAGE_SIZE];
+        opened.read(BLOCK_SIZE as u64, &mut page).unwrap();
+        assert_eq!(page, [0x5A; PAGE_SIZE]);
+        drop(opened);
+
+        // create truncates an existing image
+        let created = NandImage::create(&path, 1).unwrap();
+        assert_eq!(created.blocks(), 1);
+        drop(created);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn length_must_be_whole_blocks() {
+        for len in [0, RAW_PAGE_SIZE, RAW_BLOCK_SIZE + 1] {
+            let file = Cursor::new(vec![0xFF; len]);
+            assert!(matches!(NandImage::new(file), Err(Error::Size(l)) if l == len as u64));
+        }
+        let image = NandImage::new(Cursor::new(vec![0xFF; 3 * RAW_BLOCK_SIZE])).unwrap();
+        assert_eq!(image.blocks(), 3);
+    }
+
+    #[test]
+    fn raw_page_layout() {
+        let mut image = image(1);
+        let data: Vec<u8> = (0..PAGE_SIZE).map(|i| i as u8).collect();
+        let spare: Vec<u8> = (0..SPARE_SIZE).map(|i| !(i as u8)).collect();
+        image
+            .write_page(3 * PAGE_SIZE as u64, &data, &spare)
+            .unwrap();
+
+        let raw = image.into_inner().into_inner();
+        let start = 3 * RAW_PAGE_SIZE;
+        assert_eq!(raw[start..start + PAGE_SIZE], data[..]);
+        assert_eq!(raw[start + PAGE_SIZE..start + RAW_PAGE_SIZE], spare[..]);
+        assert!(raw[..start].iter().all(|b| *b == 0xFF));
+        assert!(raw[start + RAW_PAGE_SIZE..].iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn program_only_clears_bits() {
+        let mut image = image(1);
+        image.write(0, &[0xF0; PAGE_SIZE]).unwrap();
+        image.write(0, &[0x3C; PAGE_SIZE]).unwrap();
+        let mut page = [0; PAGE_SIZE];
+        image.read(0, &mut page).unwrap();
+        assert_eq!(page, [0x30; PAGE_SIZE]);
+
+        // a raw write can set bits again
+        let mut raw = [0xFF; RAW_PAGE_SIZE];
+        raw[0] = 0xA5;
+        image.write_raw(0, &raw).unwrap();
+        image.read(0, &mut page).unwrap();
+        assert_eq!(page[..2], [0xA5, 0xFF]);
+
+        image.erase(0, BLOCK_SIZE as u64).unwrap();
+        image.read_raw(0, &mut raw).unwrap();
+        assert_eq!(raw, [0xFF; RAW_PAGE_SIZE]);
+    }
+
+    #[test]
+    fn bad_block_markers() {
+        let mut image = image(3);
+        let mut raw = [0xFF; RAW_PAGE_SIZE];
+        raw[PAGE_SIZE] = 0x00;
+        // factory marker in the first page of block 1
+        image.write_raw(PAGES_PER_BLOCK as u32, &raw).unwrap();
+        // a marker in any other page does not count
+        image
+            .write_raw(2 * PAGES_PER_BLOCK as u32 + 1, &raw)
+            .unwrap();
+        image.mark_bad(0).unwrap();
+
+        let statuses: Vec<_> = (0..3)
+            .map(|b| image.block_status((b * BLOCK_SIZE) as u64).unwrap())
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                BlockStatus::Failed,
+                BlockStatus::Failed,
+                BlockStatus::MarkedOk
+            ]
+        );
+        // the marker is read from the first page of the block at any address in it
+        let last = (2 * BLOCK_SIZE - PAGE_SIZE) as u64;
+        assert_eq!(image.block_status(last).unwrap(), BlockStatus::Failed);
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut image = image(1);
+        let end = BLOCK_SIZE as u64;
+        let out = |r: Result<(), Error>| matches!(r, Err(e) if e.kind() == NandFlashErrorKind::OutOfBounds);
+        let mut raw = [0; RAW_PAGE_SIZE];
+        assert!(out(image.read_raw(PAGES_PER_BLOCK as u32, &mut raw)));
+        assert!(out(image.write_raw(PAGES_PER_BLOCK as u32, &raw)));
+        assert!(out(image.read(end, &mut [0; PAGE_SIZE])));
+        assert!(out(image.write(end, &[0; PAGE_SIZE])));
+        assert!(out(image.erase(end, end + BLOCK_SIZE as u64)));
+        assert!(out(image.read_page(end, &mut [], &mut [0])));
+        assert!(out(image.write_page(end, &[], &[0])));
+        assert!(image.block_status(end).is_err());
+        // nothing was appended to the file
+        assert_eq!(image.into_inner().into_inner().len(), RAW_BLOCK_SIZE);
+    }
+}
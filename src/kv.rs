@@ -0,0 +1,708 @@
+//! Power-loss-safe key-value store.
+//!
+//! Every set or delete programs one page holding the key and value, with a header in the
+//! spare area carrying a hash of the key, a sequence number and a CRC over the whole entry.
+//! A page is programmed in one operation, so an entry is either fully there or fails its CRC
+//! and is ignored, leaving the previous value in place.
+//!
+//! Entries are appended to the head block. When it fills a free block is taken, and once only
+//! one free block is left the oldest block is compacted into it: the entries still current are
+//! copied across and the old block erased. A block holding nothing but current entries is
+//! moved forward whole and the next oldest compacted instead. Blocks are erased when they are
+//! taken, so a block left half erased by power loss is simply reused.
+//!
+//! Nothing is cached beyond a few bytes per block: lookups read the spare areas from the
+//! newest entry back, so RAM use does not grow with the number of keys.
+use crate::{
+    crc::Crc32,
+    mem::{PAGE_SIZE, SPARE_META},
+    traits::{BlockStatus, ErrorType, NandFlashError, NandFlashErrorKind, SpareNandFlash},
+};
+
+const MAGIC: [u8; 2] = *b"KV";
+const HEADER_LEN: usize = 20;
+const SPARE_LEN: usize = SPARE_META + HEADER_LEN;
+/// Set in the header flags of a delete
+const DELETED: u8 = 1;
+/// Most pages per block, the current entries of a block are tracked one bit per page
+const MAX_PAGES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Key is empty or longer than 255 bytes
+    InvalidKey,
+    /// Key and value do not fit in a page
+    TooLarge,
+    /// Buffer is too small for the value, which needs the contained number of bytes
+    BufferTooSmall(usize),
+    /// Every block holds current entries, nothing can be compacted
+    Full,
+    /// Fewer than two good blocks, or more than the store can track
+    Blocks,
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::TooLarge | Error::BufferTooSmall(_) => NandFlashErrorKind::OutOfBounds,
+            _ => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Header of an entry, stored in the spare area of its page
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    flags: u8,
+    key_len: u8,
+    value_len: u16,
+    hash: u32,
+    seq: u32,
+    crc: u32,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..2].copy_from_slice(&MAGIC);
+        bytes[2] = self.flags;
+        bytes[3] = self.key_len;
+        bytes[4..6].copy_from_slice(&self.value_len.to_le_bytes());
+        bytes[6..8].fill(0);
+        bytes[8..12].copy_from_slice(&self.hash.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes[0..2] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            flags: bytes[2],
+            key_len: bytes[3],
+            value_len: u16::from_le_bytes([bytes[4], bytes[5]]),
+            hash: u32::from_le_bytes(bytes[8..12].try_into().ok()?),
+            seq: u32::from_le_bytes(bytes[12..16].try_into().ok()?),
+            crc: u32::from_le_bytes(bytes[16..20].try_into().ok()?),
+        })
+    }
+
+    /// CRC over the header and the entry's data
+    fn crc(&self, data: &[u8]) -> u32 {
+        let mut crc = Crc32::default();
+        crc.update(&self.to_bytes()[..16]);
+        crc.update(&data[..self.key_len as usize + self.value_len as usize]);
+        crc.finish()
+    }
+}
+
+fn is_block_fail<E: NandFlashError>(e: &E) -> bool {
+    matches!(e.kind(), NandFlashErrorKind::BlockFail(_))
+}
+
+fn hash(key: &[u8]) -> u32 {
+    let mut crc = Crc32::default();
+    crc.update(key);
+    crc.finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Bad,
+    /// Holds no entries, erased before use
+    Free,
+    /// Holds entries from `first` on, `used` pages are programmed
+    Used {
+        first: u32,
+        used: u16,
+    },
+}
+
+/// Key-value store over the blocks of `F`, for devices of up to `BLOCKS` blocks
+pub struct KvStore<F, const BLOCKS: usize> {
+    flash: F,
+    info: [Block; BLOCKS],
+    blocks: usize,
+    pages_per_block: u16,
+    /// Block being appended to
+    head: Option<usize>,
+    /// Sequence number of the next entry
+    seq: u32,
+    page: [u8; PAGE_SIZE],
+}
+
+type KResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+impl<F, const BLOCKS: usize> KvStore<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    /// Open the store, recovering the last consistent state
+    pub fn mount(flash: F) -> KResult<Self, F> {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::SPARE_SIZE >= SPARE_LEN);
+        assert!(F::ERASE_SIZE / F::WRITE_SIZE <= MAX_PAGES);
+        let blocks = (flash.capacity() / F::ERASE_SIZE as u64) as usize;
+        if blocks > BLOCKS {
+            return Err(Error::Blocks);
+        }
+        let mut store = Self {
+            flash,
+            info: [Block::Free; BLOCKS],
+            blocks,
+            pages_per_block: (F::ERASE_SIZE / F::WRITE_SIZE) as u16,
+            head: None,
+            seq: 0,
+            page: [0; PAGE_SIZE],
+        };
+        for block in 0..blocks {
+            store.info[block] = store.scan_block(block)?;
+        }
+        if store.info[..blocks]
+            .iter()
+            .filter(|b| **b != Block::Bad)
+            .count()
+            < 2
+        {
+            return Err(Error::Blocks);
+        }
+        // Continue in the newest block, the sequence from its last entry
+        store.head = store.newest(u32::MAX);
+        if let Some(head) = store.head {
+            let Block::Used { first, used } = store.info[head] else {
+                unreachable!()
+            };
+            store.seq = first;
+            for page in (0..used).rev() {
+                if let Some(header) = store.header(head, page)? {
+                    store.seq = header.seq.wrapping_add(1);
+                    break;
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    fn scan_block(&mut self, block: usize) -> KResult<Block, F> {
+        let address = self.address(block, 0);
+        if self.flash.block_status(address).map_err(Error::Flash)? == BlockStatus::Failed {
+            return Ok(Block::Bad);
+        }
+        let Some(header) = self.header(block, 0)? else {
+            return Ok(Block::Free);
+        };
+        // Pages are programmed in order, find the first erased one
+        let (mut lo, mut hi) = (1, self.pages_per_block);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.erased(block, mid)? {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Ok(Block::Used {
+            first: header.seq,
+            used: lo,
+        })
+    }
+
+    fn address(&self, block: usize, page: u16) -> u64 {
+        (block * self.pages_per_block as usize + page as usize) as u64 * F::WRITE_SIZE as u64
+    }
+
+    /// Spare area of a page, [`None`] if it is uncorrectable. With on chip ECC that is how a
+    /// page torn by power loss reads.
+    fn read_spare(&mut self, block: usize, page: u16) -> KResult<Option<[u8; SPARE_LEN]>, F> {
+        let mut spare = [0; SPARE_LEN];
+        match self
+            .flash
+            .read_page(self.address(block, page), &mut [], &mut spare)
+        {
+            Ok(()) => Ok(Some(spare)),
+            Err(e) if is_block_fail(&e) => Ok(None),
+            Err(e) => Err(Error::Flash(e)),
+        }
+    }
+
+    fn erased(&mut self, block: usize, page: u16) -> KResult<bool, F> {
+        Ok(self
+            .read_spare(block, page)?
+            .is_some_and(|spare| spare.iter().all(|b| *b == 0xFF)))
+    }
+
+    /// Header of an entry without checking its data
+    fn header(&mut self, block: usize, page: u16) -> KResult<Option<Header>, F> {
+        Ok(self
+            .read_spare(block, page)?
+            .and_then(|spare| Header::from_bytes(&spare[SPARE_META..])))
+    }
+
+    /// Used block with the largest first sequence number below `before`
+    fn newest(&self, before: u32) -> Option<usize> {
+        (0..self.blocks)
+            .filter_map(|b| match self.info[b] {
+                Block::Used { first, .. } if first < before => Some((first, b)),
+                _ => None,
+            })
+            .max()
+            .map(|(_, b)| b)
+    }
+
+    /// Find the current entry for `key`, reading its page into the page buffer.
+    /// Torn entries are skipped so the previous one is found instead.
+    fn find(&mut self, key: &[u8]) -> KResult<Option<(usize, u16, Header)>, F> {
+        let wanted = hash(key);
+        let mut before = u32::MAX;
+        while let Some(block) = self.newest(before) {
+            let Block::Used { first, used } = self.info[block] else {
+                unreachable!()
+            };
+            before = first;
+            for page in (0..used).rev() {
+                let Some(header) = self.header(block, page)? else {
+                    continue;
+                };
+                if header.hash != wanted || header.key_len as usize != key.len() {
+                    continue;
+                }
+                if self.read_entry(self.address(block, page))?
+                    && &self.page[..key.len()] == key
+                    && header.crc(&self.page) == header.crc
+                {
+                    return Ok(Some((block, page, header)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Copy the value of `key` into `buf`, returning its length or [`None`] if not set
+    pub fn get(&mut self, key: &[u8], buf: &mut [u8]) -> KResult<Option<usize>, F> {
+        let Some((_, _, header)) = self.find(key)? else {
+            return Ok(None);
+        };
+        if header.flags & DELETED != 0 {
+            return Ok(None);
+        }
+        let (start, len) = (key.len(), header.value_len as usize);
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall(len));
+        }
+        buf[..len].copy_from_slice(&self.page[start..start + len]);
+        Ok(Some(len))
+    }
+
+    /// Set `key` to `value`, replacing any previous value
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> KResult<(), F> {
+        self.append(key, value, 0)
+    }
+
+    /// Delete `key`, does nothing if it is not set
+    pub fn delete(&mut self, key: &[u8]) -> KResult<(), F> {
+        match self.find(key)? {
+            Some((_, _, header)) if header.flags & DELETED == 0 => self.append(key, &[], DELETED),
+            _ => Ok(()),
+        }
+    }
+
+    fn append(&mut self, key: &[u8], value: &[u8], flags: u8) -> KResult<(), F> {
+        if key.is_empty() || key.len() > u8::MAX as usize {
+            return Err(Error::InvalidKey);
+        }
+        if key.len() + value.len() > F::WRITE_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let mut header = Header {
+            flags,
+            key_len: key.len() as u8,
+            value_len: value.len() as u16,
+            hash: hash(key),
+            seq: 0,
+            crc: 0,
+        };
+        loop {
+            let (block, page) = self.next_page(false)?;
+            // compaction uses the page buffer, fill it once the page is known
+            self.page.fill(0xFF);
+            self.page[..key.len()].copy_from_slice(key);
+            self.page[key.len()..key.len() + value.len()].copy_from_slice(value);
+            header.seq = self.seq;
+            header.crc = header.crc(&self.page);
+            match self.program(block, page, header, None) {
+                Ok(()) => return Ok(()),
+                Err(e) if is_block_fail(&e) => self.retire(block)?,
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+    }
+
+    /// Program the entry in the page buffer, or copy the one at `from`, with `header`
+    fn program(
+        &mut self,
+        block: usize,
+        page: u16,
+        header: Header,
+        from: Option<u64>,
+    ) -> Result<(), F::Error> {
+        let mut spare = [0xFF; SPARE_LEN];
+        spare[SPARE_META..].copy_from_slice(&header.to_bytes());
+        let address = self.address(block, page);
+        self.seq = self.seq.wrapping_add(1);
+        if let Block::Used { used, .. } = &mut self.info[block] {
+            *used += 1;
+        }
+        match from {
+            Some(from) => self.flash.copy_page(from, address, &spare),
+            None => self
+                .flash
+                .write_page(address, &self.page[..F::WRITE_SIZE], &spare),
+        }
+    }
+
+    /// Next page to write, taking a free block or compacting when the head is full
+    fn next_page(&mut self, compacting: bool) -> KResult<(usize, u16), F> {
+        if let Some(head) = self.head {
+            if let Block::Used { used, .. } = self.info[head] {
+                if used < self.pages_per_block {
+                    return Ok((head, used));
+                }
+            }
+        }
+        // keep one free block for compaction
+        if !compacting && self.free_blocks() < 2 {
+            self.compact()?;
+            return self.next_page(true);
+        }
+        self.take_free()
+    }
+
+    fn free_blocks(&self) -> usize {
+        self.info[..self.blocks]
+            .iter()
+            .filter(|b| **b == Block::Free)
+            .count()
+    }
+
+    /// Erase a free block and make it the head
+    fn take_free(&mut self) -> KResult<(usize, u16), F> {
+        loop {
+            let block = (0..self.blocks)
+                .find(|b| self.info[*b] == Block::Free)
+                .ok_or(Error::Full)?;
+            let address = self.address(block, 0);
+            match self.flash.erase(address, address + F::ERASE_SIZE as u64) {
+                Ok(()) => {
+                    self.info[block] = Block::Used {
+                        first: self.seq,
+                        used: 0,
+                    };
+                    self.head = Some(block);
+                    return Ok((block, 0));
+                }
+                Err(e) if is_block_fail(&e) => {
+                    self.info[block] = Block::Bad;
+                    let _ = self.flash.mark_bad(address);
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+    }
+
+    /// Copy the current entries of the oldest block into a fresh block and erase it.
+    ///
+    /// A block of nothing but current entries frees no space. It is moved whole to the fresh
+    /// block, which makes it the newest, and the next oldest is tried, so cold entries do not
+    /// keep the garbage behind them from being reclaimed.
+    fn compact(&mut self) -> KResult<(), F> {
+        for _ in 0..self.blocks {
+            let oldest = (0..self.blocks)
+                .filter_map(|b| match self.info[b] {
+                    Block::Used { first, .. } if Some(b) != self.head => Some((first, b)),
+                    _ => None,
+                })
+                .min()
+                .map(|(_, b)| b)
+                .ok_or(Error::Full)?;
+            let live = self.current(oldest, false)?;
+            self.evacuate(oldest, live)?;
+            let address = self.address(oldest, 0);
+            self.info[oldest] = Block::Free;
+            match self.flash.erase(address, address + F::ERASE_SIZE as u64) {
+                Ok(()) => {}
+                Err(e) if is_block_fail(&e) => {
+                    self.info[oldest] = Block::Bad;
+                    let _ = self.flash.mark_bad(address);
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+            if live.count_ones() < self.pages_per_block as u32 {
+                return Ok(());
+            }
+        }
+        // every block is full of current entries
+        Err(Error::Full)
+    }
+
+    /// Copy the pages of `block` set in `live`, see [`KvStore::current`], to the head
+    fn evacuate(&mut self, block: usize, live: u64) -> KResult<(), F> {
+        let Block::Used { used, .. } = self.info[block] else {
+            return Ok(());
+        };
+        for page in 0..used {
+            if live & 1 << page == 0 {
+                continue;
+            }
+            let Some(header) = self.header(block, page)? else {
+                continue;
+            };
+            let address = self.address(block, page);
+            loop {
+                let (to, at) = self.next_page(true)?;
+                if to == block {
+                    return Err(Error::Full);
+                }
+                // the sequence number is part of the CRC, recompute it for the copy
+                if !self.read_entry(address)? {
+                    break;
+                }
+                let mut copy = Header {
+                    seq: self.seq,
+                    ..header
+                };
+                copy.crc = copy.crc(&self.page);
+                match self.program(to, at, copy, Some(address)) {
+                    Ok(()) => break,
+                    Err(e) if is_block_fail(&e) => self.retire(to)?,
+                    Err(e) => return Err(Error::Flash(e)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pages of `block` holding the current entry for their key, one bit per page. Deletes can
+    /// be dropped when the oldest block is compacted as there is nothing older left to hide.
+    ///
+    /// Takes a single pass over the headers of the store: an entry stops being current when a
+    /// valid entry for the same key with a later sequence number turns up.
+    fn current(&mut self, block: usize, deletes: bool) -> KResult<u64, F> {
+        let Block::Used { used, .. } = self.info[block] else {
+            return Ok(0);
+        };
+        let mut headers = [None; MAX_PAGES];
+        let mut live = 0u64;
+        for page in 0..used {
+            let Some(header) = self.header(block, page)? else {
+                continue;
+            };
+            if header.flags & DELETED != 0 && !deletes {
+                continue;
+            }
+            if self.read_entry(self.address(block, page))? && header.crc(&self.page) == header.crc {
+                headers[page as usize] = Some(header);
+                live |= 1 << page;
+            }
+        }
+        let mut key = [0; u8::MAX as usize];
+        for other in 0..self.blocks {
+            let Block::Used { used, .. } = self.info[other] else {
+                continue;
+            };
+            for page in 0..used {
+                if live == 0 {
+                    return Ok(0);
+                }
+                let Some(newer) = self.header(other, page)? else {
+                    continue;
+                };
+                let mut candidates = 0u64;
+                for (i, header) in headers.iter().enumerate() {
+                    if let Some(h) = header {
+                        if live & 1 << i != 0
+                            && (h.hash, h.key_len) == (newer.hash, newer.key_len)
+                            && h.seq < newer.seq
+                        {
+                            candidates |= 1 << i;
+                        }
+                    }
+                }
+                if candidates == 0 {
+                    continue;
+                }
+                // a torn entry hides nothing and a matching hash may be a different key
+                if !self.read_entry(self.address(other, page))?
+                    || newer.crc(&self.page) != newer.crc
+                {
+                    continue;
+                }
+                let key = &mut key[..newer.key_len as usize];
+                key.copy_from_slice(&self.page[..key.len()]);
+                for i in 0..used {
+                    if candidates & 1 << i == 0 {
+                        continue;
+                    }
+                    if self.read_entry(self.address(block, i))? && &self.page[..key.len()] == key {
+                        live &= !(1 << i);
+                    }
+                }
+            }
+        }
+        Ok(live)
+    }
+
+    /// Read the page at `address` into the page buffer, returns false if it is uncorrectable
+    fn read_entry(&mut self, address: u64) -> KResult<bool, F> {
+        match self
+            .flash
+            .read_page(address, &mut self.page[..F::WRITE_SIZE], &mut [])
+        {
+            Ok(()) => Ok(true),
+            Err(e) if is_block_fail(&e) => Ok(false),
+            Err(e) => Err(Error::Flash(e)),
+        }
+    }
+
+    /// Move the entries off a failing block and mark it bad
+    fn retire(&mut self, block: usize) -> KResult<(), F> {
+        if self.head == Some(block) {
+            self.head = None;
+        }
+        let live = self.current(block, true)?;
+        self.evacuate(block, live)?;
+        self.info[block] = Block::Bad;
+        let _ = self.flash.mark_bad(self.address(block, 0));
+        Ok(())
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{format, vec::Vec};
+
+    use super::*;
+    use crate::mock::RamFlash;
+
+    const SETS: u32 = 3000;
+
+    fn value(key: u32, version: u32) -> Vec<u8> {
+        format!("value {version} of key {key}").into_bytes()
+    }
+
+    #[test]
+    fn compaction_keeps_current_entries() {
+        let mut store = KvStore::<_, 8>::mount(RamFlash::new(8)).unwrap();
+        let mut expected: [Option<u32>; 50] = [None; 50];
+        let mut state = 7u32;
+        for version in 0..SETS {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let key = (state >> 8) % expected.len() as u32;
+            let name = format!("key {key}");
+            if (state >> 24).is_multiple_of(8) {
+                store.delete(name.as_bytes()).unwrap();
+                expected[key as usize] = None;
+            } else {
+                store.set(name.as_bytes(), &value(key, version)).unwrap();
+                expected[key as usize] = Some(version);
+            }
+        }
+        // compacting reads each page of the store a bounded number of times, not once per entry
+        let flash = store.into_inner();
+        assert!(flash.reads < SETS as usize * 20, "{} reads", flash.reads);
+
+        let mut store = KvStore::<_, 8>::mount(flash).unwrap();
+        let mut buf = [0; 64];
+        for (key, version) in expected.iter().enumerate() {
+            let name = format!("key {key}");
+            let len = store.get(name.as_bytes(), &mut buf).unwrap();
+            match version {
+                Some(version) => {
+                    assert_eq!(&buf[..len.unwrap()], value(key as u32, *version))
+                }
+                None => assert_eq!(len, None),
+            }
+        }
+    }
+
+    #[test]
+    fn cold_block_does_not_stop_compaction() {
+        let mut store = KvStore::<_, 8>::mount(RamFlash::new(8)).unwrap();
+        // exactly one block of entries that never change
+        for key in 0..64 {
+            store
+                .set(format!("cold {key}").as_bytes(), &value(key, 0))
+                .unwrap();
+        }
+        for version in 0..SETS {
+            store.set(b"hot", &value(64, version)).unwrap();
+        }
+
+        let mut store = KvStore::<_, 8>::mount(store.into_inner()).unwrap();
+        let mut buf = [0; 64];
+        for key in 0..64 {
+            let len = store
+                .get(format!("cold {key}").as_bytes(), &mut buf)
+                .unwrap();
+            assert_eq!(&buf[..len.unwrap()], value(key, 0));
+        }
+        let len = store.get(b"hot", &mut buf).unwrap();
+        assert_eq!(&buf[..len.unwrap()], value(64, SETS - 1));
+    }
+
+    #[test]
+    fn full_store_reports_full() {
+        let mut store = KvStore::<_, 3>::mount(RamFlash::new(3)).unwrap();
+        let mut key = 0;
+        let err = loop {
+            match store.set(format!("key {key}").as_bytes(), &value(key, 0)) {
+                Ok(()) => key += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(err, Error::Full));
+        // two blocks of entries, the third is kept free for compaction
+        assert_eq!(key, 128);
+        let mut buf = [0; 64];
+        for key in 0..key {
+            let len = store
+                .get(format!("key {key}").as_bytes(), &mut buf)
+                .unwrap();
+            assert_eq!(&buf[..len.unwrap()], value(key, 0));
+        }
+    }
+
+    #[test]
+    fn uncorrectable_torn_entry_keeps_previous_value() {
+        let mut flash = RamFlash::new(4);
+        flash.tear_uncorrectable = true;
+        let mut store = KvStore::<_, 4>::mount(flash).unwrap();
+        store.set(b"key", b"old").unwrap();
+        let mut flash = store.into_inner();
+        flash.cut_after = Some(0);
+        let mut store = KvStore::<_, 4>::mount(flash).unwrap();
+        assert!(store.set(b"key", b"new").is_err());
+
+        let mut flash = store.into_inner();
+        flash.power_on();
+        assert_eq!(flash.uncorrectable.len(), 1);
+        let mut store = KvStore::<_, 4>::mount(flash).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(store.get(b"key", &mut buf).unwrap(), Some(3));
+        assert_eq!(&buf[..3], b"old");
+        store.set(b"key", b"newer").unwrap();
+        let mut store = KvStore::<_, 4>::mount(store.into_inner()).unwrap();
+        assert_eq!(store.get(b"key", &mut buf).unwrap(), Some(5));
+        assert_eq!(&buf[..5], b"newer");
+    }
+}
@@ -1,7 +1,44 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
+#[cfg(feature = "authentication")]
+pub mod auth;
+pub mod bad_block;
+pub mod bbt;
+#[cfg(feature = "sdmmc")]
+pub mod block_device;
 mod commands;
+pub mod concat;
+pub mod config;
+pub mod crc;
+pub mod ecc;
+#[cfg(feature = "encryption")]
+pub mod encrypt;
+#[cfg(feature = "std")]
+pub mod factory;
+pub mod ftl;
+pub mod kv;
+#[cfg(feature = "littlefs")]
+pub mod littlefs;
+pub mod log_store;
 pub mod mem;
+pub mod mirror;
+#[cfg(test)]
+mod mock;
+pub mod nor;
+pub mod otp;
+pub mod partition;
 pub mod registers;
+pub mod scrub;
+pub mod selftest;
+#[cfg(feature = "slots")]
+pub mod slots;
+pub mod stripe;
 mod w25n;
-pub use w25n::W25N;
-mod traits;
+pub use config::W25NConfig;
+pub use w25n::{Error, VerifyPolicy, RESET_TIME_US, W25N};
+pub mod traits;
+pub mod wear;
+
+#[cfg(feature = "std")]
+pub mod image;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod spidev;
@@ -0,0 +1,263 @@
+//! Block device for littlefs.
+//!
+//! [`LittleFsStorage`] implements the littlefs2 [`Storage`] trait: the read, program and block
+//! sizes come from the device geometry, the block count is the `BLOCK_COUNT` parameter and
+//! the caches are one W25N page.
+//!
+//! littlefs deals with bad blocks itself: a program or erase that reports
+//! [`io::Error::CORRUPTION`] makes it move the data to another block. The storage reports
+//! blocks marked bad and blocks that fail to program or erase as corrupt, and marks them bad
+//! when littlefs next erases them, once nothing on them is in use.
+//!
+//! Single bit errors corrected by the ECC are routine on NAND and do not retire a block. Once
+//! a block has needed correction on [`LittleFsStorage::with_threshold`] reads its next program
+//! is reported as corrupt too, so littlefs relocates the data before it becomes unreadable.
+//! That block is erased and used again like any other.
+use littlefs2::consts::{U16, U2048};
+use littlefs2::driver::Storage;
+use littlefs2::io;
+
+use crate::traits::{BlockStatus, EccStatus, NandFlashError, NandFlashErrorKind, SpareNandFlash};
+
+/// Blocks remembered as failing until littlefs erases them
+const FAILING: usize = 8;
+
+/// Bytes of the littlefs caches, must match `CACHE_SIZE`
+const CACHE: usize = 2048;
+
+/// Corrected reads of a block before its data is relocated, unless set otherwise
+pub const DEFAULT_THRESHOLD: u8 = 4;
+
+/// littlefs error for a flash error
+fn io_error(kind: NandFlashErrorKind) -> io::Error {
+    match kind {
+        NandFlashErrorKind::BlockFail(_) => io::Error::CORRUPTION,
+        NandFlashErrorKind::NotAligned | NandFlashErrorKind::OutOfBounds => io::Error::INVALID,
+        _ => io::Error::IO,
+    }
+}
+
+/// littlefs storage over the first `BLOCK_COUNT` blocks of `F`
+pub struct LittleFsStorage<F, const BLOCK_COUNT: usize> {
+    flash: F,
+    /// Blocks that failed to program, marked bad on their next erase
+    failing: [Option<u32>; FAILING],
+    next: usize,
+    /// Reads of each block that needed correction since it was erased
+    corrected: [u8; BLOCK_COUNT],
+    threshold: u8,
+}
+
+impl<F, const BLOCK_COUNT: usize> LittleFsStorage<F, BLOCK_COUNT>
+where
+    F: SpareNandFlash,
+{
+    /// Panics if `flash` is smaller than `BLOCK_COUNT` blocks or its pages do not fit the
+    /// littlefs caches
+    pub fn new(flash: F) -> Self {
+        Self::with_threshold(flash, DEFAULT_THRESHOLD)
+    }
+
+    /// Relocate the data of a block once `threshold` reads of it, at least one, needed ECC
+    /// correction
+    pub fn with_threshold(flash: F, threshold: u8) -> Self {
+        assert!(CACHE.is_multiple_of(F::WRITE_SIZE) && F::ERASE_SIZE.is_multiple_of(CACHE));
+        assert!((BLOCK_COUNT * F::ERASE_SIZE) as u64 <= flash.capacity());
+        Self {
+            flash,
+            failing: [None; FAILING],
+            next: 0,
+            corrected: [0; BLOCK_COUNT],
+            threshold: threshold.max(1),
+        }
+    }
+
+    fn block(offset: usize) -> u32 {
+        (offset / F::ERASE_SIZE) as u32
+    }
+
+    fn is_failing(&self, block: u32) -> bool {
+        self.failing.contains(&Some(block))
+    }
+
+    fn set_failing(&mut self, block: u32) {
+        if !self.is_failing(block) {
+            self.failing[self.next] = Some(block);
+            self.next = (self.next + 1) % FAILING;
+        }
+    }
+
+    /// Map flash errors to littlefs errors, remembering blocks that failed
+    fn check<T>(&mut self, block: u32, result: Result<T, F::Error>) -> io::Result<T> {
+        result.map_err(|e| {
+            if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) {
+                self.set_failing(block);
+            }
+            io_error(e.kind())
+        })
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F, const BLOCK_COUNT: usize> Storage for LittleFsStorage<F, BLOCK_COUNT>
+where
+    F: SpareNandFlash,
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const BLOCK_SIZE: usize = F::ERASE_SIZE;
+    const BLOCK_COUNT: usize = BLOCK_COUNT;
+
+    type CACHE_SIZE = U2048;
+    type LOOKAHEAD_SIZE = U16;
+
+    /// Corrected reads succeed and are counted against the block
+    fn read(&mut self, off: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let block = Self::block(off) as usize;
+        self.flash
+            .read(off as u64, buf)
+            .map_err(|e| io_error(e.kind()))?;
+        if let EccStatus::Corrected(_) = self.flash.ecc_status() {
+            self.corrected[block] = self.corrected[block].saturating_add(1);
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, off: usize, data: &[u8]) -> io::Result<usize> {
+        let block = Self::block(off);
+        if self.is_failing(block) || self.corrected[block as usize] >= self.threshold {
+            return Err(io::Error::CORRUPTION);
+        }
+        let result = self.flash.write(off as u64, data);
+        self.check(block, result)?;
+        Ok(data.len())
+    }
+
+    fn erase(&mut self, off: usize, len: usize) -> io::Result<usize> {
+        for start in (off..off + len).step_by(F::ERASE_SIZE) {
+            let block = Self::block(start);
+            let address = start as u64;
+            if let Some(slot) = self.failing.iter_mut().find(|b| **b == Some(block)) {
+                *slot = None;
+                self.flash
+                    .mark_bad(address)
+                    .map_err(|e| io_error(e.kind()))?;
+                return Err(io::Error::CORRUPTION);
+            }
+            let status = self.flash.block_status(address);
+            if self.check(block, status)? == BlockStatus::Failed {
+                return Err(io::Error::CORRUPTION);
+            }
+            match self.flash.erase(address, address + F::ERASE_SIZE as u64) {
+                // the charge is renewed, the block is as good as new
+                Ok(()) => self.corrected[block as usize] = 0,
+                // nothing on the block is in use, retire it straight away
+                Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => {
+                    self.flash
+                        .mark_bad(address)
+                        .map_err(|e| io_error(e.kind()))?;
+                    return Err(io::Error::CORRUPTION);
+                }
+                Err(e) => return Err(io_error(e.kind())),
+            }
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{BLOCK_SIZE, PAGE_SIZE};
+    use crate::mock::RamFlash;
+    use crate::traits::ReadNandFlash;
+
+    #[test]
+    fn failed_program_retires_block_on_erase() {
+        let mut flash = RamFlash::new(4);
+        flash.fail_program = Some((BLOCK_SIZE + PAGE_SIZE) as u64);
+        let mut storage = LittleFsStorage::<_, 4>::new(flash);
+        let page = [0x5A; PAGE_SIZE];
+
+        storage.write(BLOCK_SIZE, &page).unwrap();
+        assert_eq!(
+            storage.write(BLOCK_SIZE + PAGE_SIZE, &page),
+            Err(io::Error::CORRUPTION)
+        );
+        // littlefs moves on, the block stays off limits until it is erased
+        assert_eq!(
+            storage.write(BLOCK_SIZE + 2 * PAGE_SIZE, &page),
+            Err(io::Error::CORRUPTION)
+        );
+        assert_eq!(
+            storage.erase(BLOCK_SIZE, BLOCK_SIZE),
+            Err(io::Error::CORRUPTION)
+        );
+        assert_eq!(
+            storage.erase(BLOCK_SIZE, BLOCK_SIZE),
+            Err(io::Error::CORRUPTION)
+        );
+        assert_eq!(storage.erase(2 * BLOCK_SIZE, BLOCK_SIZE), Ok(BLOCK_SIZE));
+
+        let mut flash = storage.into_inner();
+        assert_eq!(
+            flash.block_status(BLOCK_SIZE as u64).unwrap(),
+            BlockStatus::Failed
+        );
+    }
+
+    #[test]
+    fn corrected_reads_relocate_without_retiring() {
+        let mut flash = RamFlash::new(4);
+        flash.corrected.push(BLOCK_SIZE as u64);
+        let mut storage = LittleFsStorage::<_, 4>::with_threshold(flash, 2);
+        let page = [0x5A; PAGE_SIZE];
+        let mut buf = [0; PAGE_SIZE];
+
+        storage.write(BLOCK_SIZE, &page).unwrap();
+        // one correction is routine
+        storage.read(BLOCK_SIZE, &mut buf).unwrap();
+        assert_eq!(buf, page);
+        storage.write(BLOCK_SIZE + PAGE_SIZE, &page).unwrap();
+        // clean reads do not count
+        storage.read(BLOCK_SIZE + PAGE_SIZE, &mut buf).unwrap();
+        storage.write(BLOCK_SIZE + 2 * PAGE_SIZE, &page).unwrap();
+        // at the threshold littlefs is told to relocate
+        storage.read(BLOCK_SIZE, &mut buf).unwrap();
+        assert_eq!(
+            storage.write(BLOCK_SIZE + 3 * PAGE_SIZE, &page),
+            Err(io::Error::CORRUPTION)
+        );
+
+        // the erase refreshes the block, which stays in use
+        assert_eq!(storage.erase(BLOCK_SIZE, BLOCK_SIZE), Ok(BLOCK_SIZE));
+        storage.write(BLOCK_SIZE, &page).unwrap();
+        let mut flash = storage.into_inner();
+        assert_eq!(
+            flash.block_status(BLOCK_SIZE as u64).unwrap(),
+            BlockStatus::MarkedOk
+        );
+    }
+
+    #[test]
+    fn uncorrectable_read_does_not_retire() {
+        let mut flash = RamFlash::new(4);
+        flash.uncorrectable.push(BLOCK_SIZE as u64);
+        let mut storage = LittleFsStorage::<_, 4>::new(flash);
+        let mut buf = [0; PAGE_SIZE];
+        assert_eq!(
+            storage.read(BLOCK_SIZE, &mut buf),
+            Err(io::Error::CORRUPTION)
+        );
+        assert_eq!(storage.erase(BLOCK_SIZE, BLOCK_SIZE), Ok(BLOCK_SIZE));
+        let mut flash = storage.into_inner();
+        assert_eq!(
+            flash.block_status(BLOCK_SIZE as u64).unwrap(),
+            BlockStatus::MarkedOk
+        );
+    }
+}
@@ -0,0 +1,805 @@
+//! Append-only log of variable length records.
+//!
+//! Records are packed into a page buffer in RAM and each page is programmed once when it
+//! fills or on [`LogStore::flush`], so the log runs at the program speed of the device without
+//! any read-modify-write. Blocks are used as a ring: when the head catches up with the oldest
+//! block, that block is erased and its records are lost.
+//!
+//! Every programmed page has a header in its spare area with the sequence number of its block
+//! and of the first record in the page. As block sequence numbers increase around the ring the
+//! head is found on [`LogStore::open`] with a binary search over the blocks and then over the
+//! pages of the head block, rather than a scan of the device.
+//!
+//! Within a page each record is a 2 byte length, 4 byte sequence number and a 4 byte CRC over
+//! both and the payload. A length of 0xFFFF ends the page. Records never span pages.
+use crate::{
+    crc::Crc32,
+    mem::{PAGE_SIZE, SPARE_META},
+    traits::{ErrorType, NandFlashError, NandFlashErrorKind, SpareNandFlash},
+};
+
+const MAGIC: [u8; 2] = *b"LG";
+const HEADER_LEN: usize = 16;
+const SPARE_LEN: usize = SPARE_META + HEADER_LEN;
+/// Length, sequence and CRC before each record
+const RECORD_HEADER: usize = 10;
+const END: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Record does not fit in a page
+    TooLarge,
+    /// Buffer is too small for the record, which needs the contained number of bytes
+    BufferTooSmall(usize),
+    /// No good blocks left to write to
+    NoSpace,
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::TooLarge | Error::BufferTooSmall(_) => NandFlashErrorKind::OutOfBounds,
+            Error::NoSpace => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Header stored in the spare area of every page
+#[derive(Debug, Clone, Copy)]
+struct PageHeader {
+    block_seq: u32,
+    first_seq: u32,
+}
+
+impl PageHeader {
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..2].copy_from_slice(&MAGIC);
+        bytes[4..8].copy_from_slice(&self.block_seq.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.first_seq.to_le_bytes());
+        let mut crc = Crc32::default();
+        crc.update(&bytes[..12]);
+        bytes[12..16].copy_from_slice(&crc.finish().to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut crc = Crc32::default();
+        crc.update(&bytes[..12]);
+        if bytes[0..2] != MAGIC || word(12) != crc.finish() {
+            return None;
+        }
+        Some(Self {
+            block_seq: word(4),
+            first_seq: word(8),
+        })
+    }
+}
+
+/// What a page's spare area says about it
+enum Page {
+    Erased,
+    Bad,
+    /// Programmed but the header is invalid, torn by power loss
+    Torn,
+    Written(PageHeader),
+}
+
+/// A record returned by [`LogStore::read_next`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// Sequence number of the record
+    pub seq: u32,
+    /// Length of the payload copied into the buffer
+    pub len: usize,
+}
+
+/// Position in the log to read records from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    block: usize,
+    page: usize,
+    offset: usize,
+}
+
+/// Append-only record log over every block of `F`
+pub struct LogStore<F> {
+    flash: F,
+    blocks: usize,
+    pages_per_block: usize,
+    /// Block and page the write buffer will be programmed to
+    head: (usize, usize),
+    /// Oldest block holding records, [`None`] until something is written
+    tail: Option<usize>,
+    /// Sequence number of the head block
+    block_seq: u32,
+    /// Sequence number of the next record appended
+    seq: u32,
+    /// Records waiting to be programmed
+    buf: [u8; PAGE_SIZE],
+    fill: usize,
+    /// Last page read and where it came from
+    cache: [u8; PAGE_SIZE],
+    cached: Option<(usize, usize)>,
+}
+
+type LResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+impl<F> LogStore<F>
+where
+    F: SpareNandFlash,
+{
+    /// Open the log on `flash`, finding the head with a binary search.
+    /// Returns [`Error::NoSpace`] if `flash` holds no whole block.
+    pub fn open(flash: F) -> LResult<Self, F> {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::SPARE_SIZE >= SPARE_LEN);
+        let blocks = (flash.capacity() / F::ERASE_SIZE as u64) as usize;
+        if blocks == 0 {
+            return Err(Error::NoSpace);
+        }
+        let mut log = Self {
+            flash,
+            blocks,
+            pages_per_block: F::ERASE_SIZE / F::WRITE_SIZE,
+            head: (0, 0),
+            tail: None,
+            block_seq: 0,
+            seq: 0,
+            buf: [0xFF; PAGE_SIZE],
+            fill: 0,
+            cache: [0; PAGE_SIZE],
+            cached: None,
+        };
+        log.find_head()?;
+        Ok(log)
+    }
+
+    fn find_head(&mut self) -> LResult<(), F> {
+        let Some(first) = self.next_good(self.blocks - 1)? else {
+            return Err(Error::NoSpace);
+        };
+        let last = self.prev_good(first)?;
+        let head = match (self.block_header(first)?, self.block_header(last)?) {
+            // Nothing written yet
+            (None, None) => {
+                self.head = (first, 0);
+                return Ok(());
+            }
+            // The first block was erased to be reused when power was lost
+            (None, Some(_)) => last,
+            // Blocks still in the current lap since the first have a sequence at least as big
+            (Some(start), _) => {
+                let (mut lo, mut hi) = (first, self.blocks - 1);
+                while lo < hi {
+                    let mid = lo + (hi - lo).div_ceil(2);
+                    // bad blocks are skipped forward, giving up if they run past the range
+                    let probe = match self.next_good(mid - 1)? {
+                        Some(b) if b >= mid && b <= hi => b,
+                        _ => {
+                            hi = mid - 1;
+                            continue;
+                        }
+                    };
+                    match self.block_header(probe)? {
+                        Some(h) if h.block_seq.wrapping_sub(start.block_seq) < u32::MAX / 2 => {
+                            lo = probe
+                        }
+                        _ => hi = mid - 1,
+                    }
+                }
+                lo
+            }
+        };
+        let header = self.block_header(head)?.expect("head block has a header");
+        self.block_seq = header.block_seq;
+        // Binary search for the first erased page of the head block
+        let (mut lo, mut hi) = (1, self.pages_per_block);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.page(head, mid)? {
+                Page::Erased => hi = mid,
+                _ => lo = mid + 1,
+            }
+        }
+        self.head = (head, lo);
+        self.seq = self
+            .last_seq(head, lo)?
+            .map_or(header.first_seq, |s| s.wrapping_add(1));
+        // The oldest block follows the head, or the one after if it was being erased
+        let next = self.next_good(head)?.unwrap_or(head);
+        let after = self.next_good(next)?.unwrap_or(next);
+        self.tail = Some(if next != head && self.block_header(next)?.is_some() {
+            next
+        } else if after != head && self.block_header(after)?.is_some() {
+            after
+        } else {
+            // never wrapped, the log starts at the first block
+            first
+        });
+        Ok(())
+    }
+
+    /// Sequence number of the last valid record in the pages of `block` before `end`
+    fn last_seq(&mut self, block: usize, end: usize) -> LResult<Option<u32>, F> {
+        for page in (0..end).rev() {
+            let mut last = None;
+            if let Page::Written(_) = self.page(block, page)? {
+                if !self.load(block, page)? {
+                    continue;
+                }
+                let mut offset = 0;
+                while let Some((seq, len, valid)) = parse(&self.cache[..F::WRITE_SIZE], offset) {
+                    if valid {
+                        last = Some(seq);
+                    }
+                    offset += RECORD_HEADER + len;
+                }
+            }
+            if last.is_some() {
+                return Ok(last);
+            }
+        }
+        Ok(None)
+    }
+
+    fn address(&self, block: usize, page: usize) -> u64 {
+        (block * self.pages_per_block + page) as u64 * F::WRITE_SIZE as u64
+    }
+
+    fn page(&mut self, block: usize, page: usize) -> LResult<Page, F> {
+        let mut spare = [0; SPARE_LEN];
+        match self
+            .flash
+            .read_page(self.address(block, page), &mut [], &mut spare)
+        {
+            Ok(()) => {}
+            // with on chip ECC a page torn by power loss reads as uncorrectable
+            Err(e) if is_block_fail(&e) => return Ok(Page::Torn),
+            Err(e) => return Err(Error::Flash(e)),
+        }
+        Ok(if page == 0 && spare[0] != 0xFF {
+            Page::Bad
+        } else if spare.iter().all(|b| *b == 0xFF) {
+            Page::Erased
+        } else {
+            match PageHeader::from_bytes(&spare[SPARE_META..]) {
+                Some(header) => Page::Written(header),
+                None => Page::Torn,
+            }
+        })
+    }
+
+    fn block_header(&mut self, block: usize) -> LResult<Option<PageHeader>, F> {
+        match self.page(block, 0)? {
+            Page::Written(header) => Ok(Some(header)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Next good block after `block` around the ring
+    fn next_good(&mut self, block: usize) -> LResult<Option<usize>, F> {
+        for i in 1..=self.blocks {
+            let next = (block + i) % self.blocks;
+            if !matches!(self.page(next, 0)?, Page::Bad) {
+                return Ok(Some(next));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Previous good block before `block` around the ring
+    fn prev_good(&mut self, block: usize) -> LResult<usize, F> {
+        for i in 1..=self.blocks {
+            let prev = (block + self.blocks - i) % self.blocks;
+            if !matches!(self.page(prev, 0)?, Page::Bad) {
+                return Ok(prev);
+            }
+        }
+        Ok(block)
+    }
+
+    /// Read a page into the cache, returns false if it is uncorrectable and was torn
+    fn load(&mut self, block: usize, page: usize) -> LResult<bool, F> {
+        if self.cached != Some((block, page)) {
+            self.cached = None;
+            let address = self.address(block, page);
+            match self
+                .flash
+                .read_page(address, &mut self.cache[..F::WRITE_SIZE], &mut [])
+            {
+                Ok(()) => {}
+                Err(e) if is_block_fail(&e) => return Ok(false),
+                Err(e) => return Err(Error::Flash(e)),
+            }
+            self.cached = Some((block, page));
+        }
+        Ok(true)
+    }
+
+    /// Append a record, returning its sequence number.
+    /// The record is only on flash once its page fills or the log is flushed.
+    pub fn append(&mut self, record: &[u8]) -> LResult<u32, F> {
+        if record.len() + RECORD_HEADER > F::WRITE_SIZE || record.len() >= END as usize {
+            return Err(Error::TooLarge);
+        }
+        if self.fill + RECORD_HEADER + record.len() > F::WRITE_SIZE {
+            self.flush()?;
+        }
+        let seq = self.seq;
+        let mut crc = Crc32::default();
+        crc.update(&seq.to_le_bytes());
+        crc.update(record);
+        let out = &mut self.buf[self.fill..self.fill + RECORD_HEADER + record.len()];
+        out[0..2].copy_from_slice(&(record.len() as u16).to_le_bytes());
+        out[2..6].copy_from_slice(&seq.to_le_bytes());
+        out[6..10].copy_from_slice(&crc.finish().to_le_bytes());
+        out[RECORD_HEADER..].copy_from_slice(record);
+        self.fill += RECORD_HEADER + record.len();
+        self.seq = self.seq.wrapping_add(1);
+        Ok(seq)
+    }
+
+    /// Program the records appended so far, the rest of their page is left unused
+    pub fn flush(&mut self) -> LResult<(), F> {
+        if self.fill == 0 {
+            return Ok(());
+        }
+        let first_seq = u32::from_le_bytes(self.buf[2..6].try_into().unwrap());
+        loop {
+            if self.head.1 == self.pages_per_block || self.tail.is_none() {
+                self.advance()?;
+            }
+            let (block, page) = self.head;
+            let header = PageHeader {
+                block_seq: self.block_seq,
+                first_seq,
+            };
+            let mut spare = [0xFF; SPARE_LEN];
+            spare[SPARE_META..].copy_from_slice(&header.to_bytes());
+            let address = self.address(block, page);
+            let data = &self.buf[..F::WRITE_SIZE];
+            match self.flash.write_page(address, data, &spare) {
+                Ok(()) => break,
+                // continue in the next block, taking the records before along
+                Err(e) if is_block_fail(&e) => self.relocate(block, page)?,
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+        self.head.1 += 1;
+        self.buf.fill(0xFF);
+        self.fill = 0;
+        Ok(())
+    }
+
+    /// Copy the pages of the failing `block` before `pages` to a new head block, then mark it
+    /// bad. Power loss before the marker is written leaves the records in both blocks, where
+    /// they are read twice with the same sequence numbers.
+    fn relocate(&mut self, block: usize, pages: usize) -> LResult<(), F> {
+        'retry: loop {
+            // the only good block left
+            if self.next_good(self.head.0)? == Some(block) {
+                return Err(Error::NoSpace);
+            }
+            self.head.1 = self.pages_per_block;
+            self.advance()?;
+            let target = self.head.0;
+            for page in 0..pages {
+                let Page::Written(header) = self.page(block, page)? else {
+                    continue;
+                };
+                if !self.load(block, page)? {
+                    continue;
+                }
+                let header = PageHeader {
+                    block_seq: self.block_seq,
+                    ..header
+                };
+                let mut spare = [0xFF; SPARE_LEN];
+                spare[SPARE_META..].copy_from_slice(&header.to_bytes());
+                let address = self.address(target, self.head.1);
+                let data = &self.cache[..F::WRITE_SIZE];
+                match self.flash.write_page(address, data, &spare) {
+                    Ok(()) => self.head.1 += 1,
+                    Err(e) if is_block_fail(&e) => {
+                        let _ = self.flash.mark_bad(self.address(target, 0));
+                        continue 'retry;
+                    }
+                    Err(e) => return Err(Error::Flash(e)),
+                }
+            }
+            break;
+        }
+        if self.tail == Some(block) {
+            self.tail = Some(self.head.0);
+        }
+        let _ = self.flash.mark_bad(self.address(block, 0));
+        Ok(())
+    }
+
+    /// Move the head to a freshly erased block, reclaiming the oldest block if needed
+    fn advance(&mut self) -> LResult<(), F> {
+        let mut next = match self.tail {
+            // the very first block
+            None => self.head.0,
+            Some(_) => self.next_good(self.head.0)?.ok_or(Error::NoSpace)?,
+        };
+        for _ in 0..self.blocks {
+            // reclaim the oldest block
+            if self.tail == Some(next) {
+                self.tail = self.next_good(next)?;
+            }
+            if self.cached.is_some_and(|(b, _)| b == next) {
+                self.cached = None;
+            }
+            let address = self.address(next, 0);
+            match self.flash.erase(address, address + F::ERASE_SIZE as u64) {
+                Ok(()) => {
+                    if self.tail.is_none() {
+                        self.tail = Some(next);
+                    } else {
+                        self.block_seq = self.block_seq.wrapping_add(1);
+                    }
+                    self.head = (next, 0);
+                    return Ok(());
+                }
+                Err(e) if is_block_fail(&e) => {
+                    let _ = self.flash.mark_bad(address);
+                    next = self.next_good(next)?.ok_or(Error::NoSpace)?;
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+        Err(Error::NoSpace)
+    }
+
+    /// Sequence number the next appended record will get
+    pub fn next_seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Cursor at the oldest record in the log
+    pub fn oldest(&self) -> Cursor {
+        Cursor {
+            block: self.tail.unwrap_or(self.head.0),
+            page: 0,
+            offset: 0,
+        }
+    }
+
+    /// Cursor at the record with sequence number `seq`, or the first record after it
+    pub fn seek(&mut self, seq: u32) -> LResult<Cursor, F> {
+        let oldest = self.oldest();
+        let Some(tail) = self.tail else {
+            return Ok(oldest);
+        };
+        // true if `s` comes before `base`
+        let before = |s: u32, base: u32| s.wrapping_sub(base) > u32::MAX / 2;
+        match self.block_header(tail)? {
+            Some(start) if !before(seq, start.first_seq) => {}
+            _ => return Ok(oldest),
+        }
+        // Binary search the ring for the last block starting at or before seq
+        let span = (self.head.0 + self.blocks - tail) % self.blocks;
+        let (mut lo, mut hi) = (0, span);
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            // blocks without a header are skipped forward, giving up if they run past the range
+            let mut probe = None;
+            for offset in mid..=hi {
+                if let Some(h) = self.block_header((tail + offset) % self.blocks)? {
+                    probe = Some((offset, h));
+                    break;
+                }
+            }
+            match probe {
+                Some((offset, h)) if !before(seq, h.first_seq) => lo = offset,
+                _ => hi = mid - 1,
+            }
+        }
+        let block = (tail + lo) % self.blocks;
+        // Then the last page starting at or before seq
+        let mut cursor = Cursor {
+            block,
+            page: 0,
+            offset: 0,
+        };
+        for page in 1..self.pages_per_block {
+            if (block, page) == self.head {
+                break;
+            }
+            match self.page(block, page)? {
+                Page::Written(h) if !before(seq, h.first_seq) => cursor.page = page,
+                Page::Written(_) | Page::Erased => break,
+                _ => {}
+            }
+        }
+        // Step over the earlier records in the page
+        while let Some((found, len)) = self.peek(&mut cursor)? {
+            if !before(found, seq) {
+                break;
+            }
+            cursor.offset += RECORD_HEADER + len;
+        }
+        Ok(cursor)
+    }
+
+    /// Find the next valid record at or after the cursor without consuming it
+    fn peek(&mut self, cursor: &mut Cursor) -> LResult<Option<(u32, usize)>, F> {
+        loop {
+            let (block, page) = (cursor.block, cursor.page);
+            if (block, page) == self.head {
+                return Ok(None);
+            }
+            if page == self.pages_per_block {
+                if block == self.head.0 {
+                    return Ok(None);
+                }
+                *cursor = Cursor {
+                    block: (block + 1) % self.blocks,
+                    page: 0,
+                    offset: 0,
+                };
+                continue;
+            }
+            if cursor.offset == 0 {
+                match self.page(block, page)? {
+                    Page::Written(_) => {}
+                    // the rest of the block is bad, being reclaimed or was abandoned
+                    Page::Erased | Page::Bad => {
+                        cursor.page = self.pages_per_block;
+                        continue;
+                    }
+                    Page::Torn => {
+                        cursor.page += 1;
+                        continue;
+                    }
+                }
+            }
+            if !self.load(block, page)? {
+                cursor.page += 1;
+                cursor.offset = 0;
+                continue;
+            }
+            match parse(&self.cache[..F::WRITE_SIZE], cursor.offset) {
+                Some((seq, len, true)) => return Ok(Some((seq, len))),
+                Some((_, len, false)) => cursor.offset += RECORD_HEADER + len,
+                None => {
+                    cursor.page += 1;
+                    cursor.offset = 0;
+                }
+            }
+        }
+    }
+
+    /// Copy the record at the cursor into `buf` and move past it.
+    /// Returns [`None`] at the end of the log, records not yet flushed are not read.
+    pub fn read_next(&mut self, cursor: &mut Cursor, buf: &mut [u8]) -> LResult<Option<Record>, F> {
+        let Some((seq, len)) = self.peek(cursor)? else {
+            return Ok(None);
+        };
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall(len));
+        }
+        let start = cursor.offset + RECORD_HEADER;
+        buf[..len].copy_from_slice(&self.cache[start..start + len]);
+        cursor.offset += RECORD_HEADER + len;
+        Ok(Some(Record { seq, len }))
+    }
+
+    /// Return the underlying flash, records not yet flushed are lost
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+fn is_block_fail<E: NandFlashError>(e: &E) -> bool {
+    matches!(e.kind(), NandFlashErrorKind::BlockFail(_))
+}
+
+/// Parse the record at `offset` into its sequence number, length and whether its CRC matches
+fn parse(page: &[u8], offset: usize) -> Option<(u32, usize, bool)> {
+    let header = page.get(offset..offset + RECORD_HEADER)?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let payload = page.get(offset + RECORD_HEADER..offset + RECORD_HEADER + len as usize)?;
+    if len == END {
+        return None;
+    }
+    let seq = u32::from_le_bytes(header[2..6].try_into().unwrap());
+    let stored = u32::from_le_bytes(header[6..10].try_into().unwrap());
+    let mut crc = Crc32::default();
+    crc.update(&seq.to_le_bytes());
+    crc.update(payload);
+    Some((seq, len as usize, crc.finish() == stored))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{
+        mem::BLOCK_SIZE,
+        mock::RamFlash,
+        traits::{BlockStatus, ReadNandFlash},
+    };
+
+    /// Sequence numbers and payloads of every record from the oldest
+    fn records(log: &mut LogStore<RamFlash>) -> Vec<(u32, Vec<u8>)> {
+        let mut cursor = log.oldest();
+        let mut buf = [0; 64];
+        let mut records = Vec::new();
+        while let Some(record) = log.read_next(&mut cursor, &mut buf).unwrap() {
+            records.push((record.seq, buf[..record.len].to_vec()));
+        }
+        records
+    }
+
+    /// Append and flush `count` records, each to its own page
+    fn append(log: &mut LogStore<RamFlash>, count: u32) -> Vec<(u32, Vec<u8>)> {
+        (0..count)
+            .map(|i| {
+                let record = [i as u8; 16];
+                let seq = log.append(&record).unwrap();
+                log.flush().unwrap();
+                (seq, record.to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn program_failure_keeps_earlier_records() {
+        let mut flash = RamFlash::new(4);
+        flash.fail_program = Some(4 * PAGE_SIZE as u64);
+        let mut log = LogStore::open(flash).unwrap();
+        let expected = append(&mut log, 8);
+        assert_eq!(records(&mut log), expected);
+
+        let mut flash = log.into_inner();
+        assert_eq!(flash.block_status(0).unwrap(), BlockStatus::Failed);
+        let mut log = LogStore::open(flash).unwrap();
+        assert_eq!(records(&mut log), expected);
+        assert_eq!(log.next_seq(), 8);
+    }
+
+    #[test]
+    fn erase_failure_of_first_block() {
+        let mut flash = RamFlash::new(4);
+        flash.fail_erase.push(0);
+        let mut log = LogStore::open(flash).unwrap();
+        let expected = append(&mut log, 3);
+        assert_eq!(records(&mut log), expected);
+
+        let mut flash = log.into_inner();
+        assert_eq!(flash.block_status(0).unwrap(), BlockStatus::Failed);
+        assert_eq!(
+            flash.block_status(BLOCK_SIZE as u64).unwrap(),
+            BlockStatus::MarkedOk
+        );
+        let mut log = LogStore::open(flash).unwrap();
+        assert_eq!(records(&mut log), expected);
+    }
+
+    const PAGES: u32 = (BLOCK_SIZE / PAGE_SIZE) as u32;
+
+    /// Check the records are the newest of `all`, starting at a block boundary
+    fn check_suffix(log: &mut LogStore<RamFlash>, all: &[(u32, Vec<u8>)]) {
+        let found = records(log);
+        let skipped = all.len() - found.len();
+        assert_eq!(found, all[skipped..]);
+        assert_eq!(skipped % PAGES as usize, 0);
+    }
+
+    #[test]
+    fn wraps_reclaiming_the_oldest_block() {
+        let mut log = LogStore::open(RamFlash::new(4)).unwrap();
+        let all = append(&mut log, 2 * 4 * PAGES + 10);
+        let found = records(&mut log);
+        // the head block and the three before it, one of them partly written
+        assert_eq!(found.len(), (3 * PAGES + 10) as usize);
+        check_suffix(&mut log, &all);
+        assert_eq!(found[0].0, 5 * PAGES);
+    }
+
+    #[test]
+    fn finds_head_after_wrapping() {
+        let mut log = LogStore::open(RamFlash::new(5)).unwrap();
+        let mut all = Vec::new();
+        // every position of the head block around the ring and within its block
+        for step in [
+            1,
+            PAGES - 1,
+            PAGES,
+            2 * PAGES + 3,
+            3 * PAGES,
+            5,
+            4 * PAGES - 7,
+        ] {
+            all.extend(append(&mut log, step));
+            let next = log.next_seq();
+            let mut flash = log.into_inner();
+            flash.reads = 0;
+            // the head is found without reading every page
+            let flash = LogStore::open(flash).unwrap().into_inner();
+            assert!(flash.reads < 40, "{} reads", flash.reads);
+            log = LogStore::open(flash).unwrap();
+            assert_eq!(log.next_seq(), next);
+            check_suffix(&mut log, &all);
+        }
+    }
+
+    #[test]
+    fn seek_to_any_record() {
+        let mut log = LogStore::open(RamFlash::new(4)).unwrap();
+        // 40 records to a page, wrapping the ring
+        let mut all = Vec::new();
+        for i in 0..5 * 40 * PAGES {
+            let record = [i as u8; 40];
+            all.push((log.append(&record).unwrap(), record.to_vec()));
+        }
+        log.flush().unwrap();
+        let oldest = records(&mut log)[0].0;
+        assert!(oldest > 0);
+
+        let mut buf = [0; 64];
+        for seq in (oldest..log.next_seq()).step_by(37) {
+            let mut cursor = log.seek(seq).unwrap();
+            let record = log.read_next(&mut cursor, &mut buf).unwrap().unwrap();
+            assert_eq!(record.seq, seq);
+            assert_eq!(buf[..record.len], all[seq as usize].1);
+            let record = log.read_next(&mut cursor, &mut buf).unwrap();
+            assert_eq!(
+                record.map(|r| r.seq),
+                (seq + 1 < log.next_seq()).then_some(seq + 1)
+            );
+        }
+        // records already reclaimed start from the oldest, later ones find the end
+        let mut cursor = log.seek(0).unwrap();
+        assert_eq!(cursor, log.oldest());
+        let record = log.read_next(&mut cursor, &mut buf).unwrap().unwrap();
+        assert_eq!(record.seq, oldest);
+        let mut cursor = log.seek(log.next_seq()).unwrap();
+        assert_eq!(log.read_next(&mut cursor, &mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn uncorrectable_torn_page_ends_the_log() {
+        let mut flash = RamFlash::new(4);
+        flash.tear_uncorrectable = true;
+        let mut log = LogStore::open(flash).unwrap();
+        let expected = append(&mut log, 5 * PAGES + 3);
+
+        let mut flash = log.into_inner();
+        flash.cut_after = Some(0);
+        let mut log = LogStore::open(flash).unwrap();
+        log.append(b"torn").unwrap();
+        assert!(log.flush().is_err());
+
+        let mut flash = log.into_inner();
+        flash.power_on();
+        assert_eq!(flash.uncorrectable.len(), 1);
+        let mut log = LogStore::open(flash).unwrap();
+        check_suffix(&mut log, &expected);
+        // appending carries on after the torn page
+        let seq = log.next_seq();
+        log.append(b"after").unwrap();
+        log.flush().unwrap();
+        let mut log = LogStore::open(log.into_inner()).unwrap();
+        let found = records(&mut log);
+        assert_eq!(found.last().unwrap(), &(seq, b"after".to_vec()));
+    }
+
+    #[test]
+    fn open_rejects_flash_without_blocks() {
+        assert!(matches!(
+            LogStore::open(RamFlash::new(0)),
+            Err(Error::NoSpace)
+        ));
+    }
+}
@@ -1,3 +1,18 @@
+/// Bytes in the main array of a page
+pub const PAGE_SIZE: usize = 2048;
+/// Bytes in the spare area following each page
+pub const SPARE_SIZE: usize = 128;
+/// Spare bytes left to the user with the on chip ECC enabled, the bytes after hold its parity
+pub const SPARE_USER_SIZE: usize = 64;
+/// Pages in an erase block
+pub const PAGES_PER_BLOCK: usize = 64;
+/// Bytes in the main array of an erase block
+pub const BLOCK_SIZE: usize = PAGE_SIZE * PAGES_PER_BLOCK;
+/// First spare byte free for metadata, the bytes before hold the bad block marker
+pub const SPARE_META: usize = 4;
+/// Erase blocks in a W25N02KV
+pub const BLOCK_COUNT: usize = 2048;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub struct PageAddress(u32);
 
@@ -47,7 +62,7 @@ impl From<u32> for PageAddress {
     }
 }
 
-/// Iterates through block addresses
+/// Iterates through the addresses of the blocks from `start` up to but not including `end`
 pub struct BlockAddressIterator {
     end: PageAddress,
     pa: PageAddress,
@@ -55,10 +70,7 @@ pub struct BlockAddressIterator {
 
 impl BlockAddressIterator {
     pub fn new(start: PageAddress, end: PageAddress) -> Self {
-        Self {
-            end,
-            pa: Default::default(),
-        }
+        Self { end, pa: start }
     }
 }
 
@@ -66,7 +78,7 @@ impl Iterator for BlockAddressIterator {
     type Item = PageAddress;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.pa > self.end {
+        if self.pa >= self.end {
             None
         } else {
             Some(self.pa.increment_block())
@@ -0,0 +1,530 @@
+//! Two devices kept identical for data that must survive the loss of a block.
+//!
+//! [`Mirror`] writes and erases every block on both devices, the primary first. Reads come from
+//! the primary and fall back to the secondary when the primary fails or its ECC cannot correct
+//! the data. A block whose copy on one device failed is remembered as divergent and reads of it
+//! go to the good copy until [`Mirror::resync`] rewrites the failed one.
+//!
+//! Divergence is only tracked in RAM. After power loss [`Mirror::scan`] compares the two
+//! devices, taking the copy that reads without errors, or the primary when both do since a write
+//! interrupted between the two devices has already reached it.
+use crate::{
+    mem::PAGE_SIZE,
+    traits::{
+        check_erase, check_read, check_write, BlockStatus, EccStatus, ErrorType, NandFlash,
+        NandFlashError, NandFlashErrorKind, ReadNandFlash,
+    },
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<P, S> {
+    /// Errors from the primary device
+    Primary(P),
+    /// Errors from the secondary device
+    Secondary(S),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// Devices have more blocks than the mirror can track
+    TooManyBlocks,
+}
+
+impl<P, S> From<NandFlashErrorKind> for Error<P, S> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<P: NandFlashError, S: NandFlashError> NandFlashError for Error<P, S> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Primary(e) => e.kind(),
+            Error::Secondary(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+            Error::TooManyBlocks => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// One of the two devices of a [`Mirror`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Primary,
+    Secondary,
+}
+
+impl Side {
+    /// The other device
+    pub fn other(self) -> Self {
+        match self {
+            Side::Primary => Side::Secondary,
+            Side::Secondary => Side::Primary,
+        }
+    }
+}
+
+/// Whether a result means the copy it came from is bad
+fn failed<E: NandFlashError>(result: &Result<(), E>, ecc: EccStatus) -> bool {
+    match result {
+        Err(e) => matches!(e.kind(), NandFlashErrorKind::BlockFail(_)),
+        Ok(()) => ecc == EccStatus::Uncorrectable,
+    }
+}
+
+/// Devices `P` and `S` mirrored, for devices of up to `BLOCKS` blocks
+pub struct Mirror<P, S, const BLOCKS: usize> {
+    primary: P,
+    secondary: S,
+    /// Device holding the failed copy of each block, if any
+    stale: [Option<Side>; BLOCKS],
+    blocks: usize,
+    /// ECC status of the device the last read came from
+    ecc: EccStatus,
+}
+
+type MResult<T, P, S> = Result<T, Error<<P as ErrorType>::Error, <S as ErrorType>::Error>>;
+
+impl<P, S, const BLOCKS: usize> Mirror<P, S, BLOCKS>
+where
+    P: NandFlash,
+    S: NandFlash,
+{
+    /// Mirror `primary` onto `secondary`, which must have the same geometry.
+    /// Both start out assumed to be in sync, see [`Mirror::scan`].
+    pub fn new(primary: P, secondary: S) -> MResult<Self, P, S> {
+        assert!(P::READ_SIZE == S::READ_SIZE);
+        assert!(P::WRITE_SIZE == S::WRITE_SIZE && P::WRITE_SIZE <= PAGE_SIZE);
+        assert!(P::ERASE_SIZE == S::ERASE_SIZE);
+        assert!(primary.capacity() == secondary.capacity());
+        let blocks = (primary.capacity() / P::ERASE_SIZE as u64) as usize;
+        if blocks > BLOCKS {
+            return Err(Error::TooManyBlocks);
+        }
+        Ok(Self {
+            primary,
+            secondary,
+            stale: [None; BLOCKS],
+            blocks,
+            ecc: EccStatus::NoErrors,
+        })
+    }
+
+    /// Remember that the copy of `block` on `side` failed. A block that fails on both devices
+    /// keeps the first failure, the other copy is the better one.
+    fn diverge(&mut self, block: usize, side: Side) {
+        self.stale[block].get_or_insert(side);
+    }
+
+    /// Read from the device on `side`, returning whether its copy failed with the result
+    fn read_from(
+        &mut self,
+        side: Side,
+        offset: u64,
+        bytes: &mut [u8],
+    ) -> (bool, MResult<(), P, S>) {
+        match side {
+            Side::Primary => {
+                let result = self.primary.read(offset, bytes);
+                self.ecc = self.primary.ecc_status();
+                (failed(&result, self.ecc), result.map_err(Error::Primary))
+            }
+            Side::Secondary => {
+                let result = self.secondary.read(offset, bytes);
+                self.ecc = self.secondary.ecc_status();
+                (failed(&result, self.ecc), result.map_err(Error::Secondary))
+            }
+        }
+    }
+
+    /// Split the range from `offset` of `len` bytes at block boundaries, calling `op` with the
+    /// block, its address and the range of bytes it covers
+    fn split(
+        &mut self,
+        offset: u64,
+        len: usize,
+        mut op: impl FnMut(&mut Self, usize, u64, core::ops::Range<usize>) -> MResult<(), P, S>,
+    ) -> MResult<(), P, S> {
+        let mut done = 0;
+        while done < len {
+            let address = offset + done as u64;
+            let block = (address / P::ERASE_SIZE as u64) as usize;
+            let n = (P::ERASE_SIZE - (address % P::ERASE_SIZE as u64) as usize).min(len - done);
+            op(self, block, address, done..done + n)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Combine the results of an operation on both devices, it fails only if no copy succeeded
+    fn mirrored(
+        &mut self,
+        block: usize,
+        primary: Result<(), P::Error>,
+        secondary: Result<(), S::Error>,
+    ) -> MResult<(), P, S> {
+        let primary_failed = failed(&primary, EccStatus::NoErrors);
+        let secondary_failed = failed(&secondary, EccStatus::NoErrors);
+        match (primary, secondary) {
+            (Ok(()), Ok(())) => Ok(()),
+            (Err(e), _) if !primary_failed => Err(Error::Primary(e)),
+            (_, Err(e)) if !secondary_failed => Err(Error::Secondary(e)),
+            (Err(e), Err(_)) => Err(Error::Primary(e)),
+            (Err(_), Ok(())) => {
+                self.diverge(block, Side::Primary);
+                Ok(())
+            }
+            (Ok(()), Err(_)) => {
+                self.diverge(block, Side::Secondary);
+                Ok(())
+            }
+        }
+    }
+
+    /// Blocks with a failed copy and the device holding it
+    pub fn divergent(&self) -> impl Iterator<Item = (usize, Side)> + '_ {
+        self.stale[..self.blocks]
+            .iter()
+            .enumerate()
+            .filter_map(|(block, side)| side.map(|side| (block, side)))
+    }
+
+    /// Whether every block has two good copies
+    pub fn in_sync(&self) -> bool {
+        self.divergent().next().is_none()
+    }
+
+    /// Compare the two copies of every block that is not bad, recording the ones that differ.
+    /// Returns the number of divergent blocks.
+    pub fn scan(&mut self) -> MResult<usize, P, S> {
+        let size = P::WRITE_SIZE;
+        let mut a = [0; PAGE_SIZE];
+        let mut b = [0; PAGE_SIZE];
+        for block in 0..self.blocks {
+            let start = (block * P::ERASE_SIZE) as u64;
+            if self.block_status(start)? == BlockStatus::Failed {
+                continue;
+            }
+            for page in (0..P::ERASE_SIZE).step_by(size) {
+                let address = start + page as u64;
+                let (primary, result) = self.read_from(Side::Primary, address, &mut a[..size]);
+                if !primary {
+                    result?;
+                }
+                let (secondary, result) = self.read_from(Side::Secondary, address, &mut b[..size]);
+                if !secondary {
+                    result?;
+                }
+                if primary {
+                    self.diverge(block, Side::Primary);
+                } else if secondary || a[..size] != b[..size] {
+                    self.diverge(block, Side::Secondary);
+                }
+                if self.stale[block].is_some() {
+                    break;
+                }
+            }
+        }
+        Ok(self.divergent().count())
+    }
+
+    /// Rewrite the failed copy of every divergent block from the good one.
+    /// Returns the number of blocks repaired, blocks whose repair failed stay divergent.
+    pub fn resync(&mut self) -> MResult<usize, P, S> {
+        let mut repaired = 0;
+        for block in 0..self.blocks {
+            let Some(side) = self.stale[block] else {
+                continue;
+            };
+            match self.repair(block, side) {
+                Ok(()) => {
+                    self.stale[block] = None;
+                    repaired += 1;
+                }
+                Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(repaired)
+    }
+
+    /// Erase the copy of `block` on `side` and copy the other device's pages into it
+    fn repair(&mut self, block: usize, side: Side) -> MResult<(), P, S> {
+        let size = P::WRITE_SIZE;
+        let start = (block * P::ERASE_SIZE) as u64;
+        let end = start + P::ERASE_SIZE as u64;
+        match side {
+            Side::Primary => self.primary.erase(start, end).map_err(Error::Primary)?,
+            Side::Secondary => self.secondary.erase(start, end).map_err(Error::Secondary)?,
+        }
+        let mut page = [0; PAGE_SIZE];
+        let page = &mut page[..size];
+        for address in (start..end).step_by(size) {
+            self.read_from(side.other(), address, page).1?;
+            // erased pages are left erased so they can still be programmed
+            if page.iter().all(|b| *b == 0xFF) {
+                continue;
+            }
+            match side {
+                Side::Primary => self.primary.write(address, page).map_err(Error::Primary)?,
+                Side::Secondary => self
+                    .secondary
+                    .write(address, page)
+                    .map_err(Error::Secondary)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The primary device
+    pub fn primary(&mut self) -> &mut P {
+        &mut self.primary
+    }
+
+    /// The secondary device
+    pub fn secondary(&mut self) -> &mut S {
+        &mut self.secondary
+    }
+
+    /// Return the two devices
+    pub fn into_inner(self) -> (P, S) {
+        (self.primary, self.secondary)
+    }
+}
+
+impl<P, S, const BLOCKS: usize> ErrorType for Mirror<P, S, BLOCKS>
+where
+    P: NandFlash,
+    S: NandFlash,
+{
+    type Error = Error<P::Error, S::Error>;
+}
+
+impl<P, S, const BLOCKS: usize> ReadNandFlash for Mirror<P, S, BLOCKS>
+where
+    P: NandFlash,
+    S: NandFlash,
+{
+    const READ_SIZE: usize = P::READ_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let mut ecc = EccStatus::NoErrors;
+        let result = self.split(offset, bytes.len(), |mirror, block, address, range| {
+            let side = match mirror.stale[block] {
+                Some(side) => side.other(),
+                None => Side::Primary,
+            };
+            let (failed, mut result) = mirror.read_from(side, address, &mut bytes[range.clone()]);
+            if failed && mirror.stale[block].is_none() {
+                mirror.diverge(block, side);
+                result = mirror.read_from(side.other(), address, &mut bytes[range]).1;
+            }
+            ecc = ecc.max(mirror.ecc);
+            result
+        });
+        self.ecc = ecc;
+        result
+    }
+
+    fn capacity(&self) -> u64 {
+        self.primary.capacity()
+    }
+
+    /// A block is bad if it is bad on either device
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        let primary = self.primary.block_status(address).map_err(Error::Primary)?;
+        let secondary = self
+            .secondary
+            .block_status(address)
+            .map_err(Error::Secondary)?;
+        Ok(primary.max(secondary))
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.ecc
+    }
+}
+
+impl<P, S, const BLOCKS: usize> NandFlash for Mirror<P, S, BLOCKS>
+where
+    P: NandFlash,
+    S: NandFlash,
+{
+    const WRITE_SIZE: usize = P::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = P::ERASE_SIZE;
+
+    /// Erases that succeed on both devices bring the blocks back in sync
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.split(
+            from,
+            (to - from) as usize,
+            |mirror, block, address, range| {
+                let end = address + range.len() as u64;
+                let primary = mirror.primary.erase(address, end);
+                let secondary = mirror.secondary.erase(address, end);
+                mirror.stale[block] = None;
+                mirror.mirrored(block, primary, secondary)
+            },
+        )
+    }
+
+    /// Writes that fail on one device succeed with the block left divergent
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.split(offset, bytes.len(), |mirror, block, address, range| {
+            let primary = mirror.primary.write(address, &bytes[range.clone()]);
+            let secondary = mirror.secondary.write(address, &bytes[range]);
+            mirror.mirrored(block, primary, secondary)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::mem::BLOCK_SIZE;
+    use crate::mock::RamFlash;
+    use crate::traits::SpareNandFlash;
+    use std::vec::Vec;
+
+    const BLOCKS: usize = 4;
+
+    fn mirror() -> Mirror<RamFlash, RamFlash, BLOCKS> {
+        Mirror::new(RamFlash::new(BLOCKS), RamFlash::new(BLOCKS)).unwrap()
+    }
+
+    fn pages(seed: u8) -> [u8; 2 * PAGE_SIZE] {
+        core::array::from_fn(|i| (i % 251) as u8 ^ seed)
+    }
+
+    fn divergent(mirror: &Mirror<RamFlash, RamFlash, BLOCKS>) -> Vec<(usize, Side)> {
+        mirror.divergent().collect()
+    }
+
+    #[test]
+    fn read_falls_back_to_the_secondary() {
+        let mut mirror = mirror();
+        let data = pages(1);
+        mirror.write(0, &data).unwrap();
+        mirror.primary().corrected.push(0);
+        let mut read = [0; 2 * PAGE_SIZE];
+        mirror.read(0, &mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(mirror.ecc_status(), EccStatus::Corrected(Some(1)));
+        assert!(mirror.in_sync());
+
+        mirror.primary().uncorrectable.push(PAGE_SIZE as u64);
+        read.fill(0);
+        mirror.read(0, &mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(divergent(&mirror), [(0, Side::Primary)]);
+        // later reads of the block go straight to the good copy
+        let reads = mirror.primary().reads;
+        mirror.read(0, &mut read).unwrap();
+        assert_eq!(mirror.primary().reads, reads);
+        assert_eq!(mirror.ecc_status(), EccStatus::NoErrors);
+
+        // with both copies failing the error is returned
+        mirror.write((2 * BLOCK_SIZE) as u64, &data).unwrap();
+        let page = (2 * BLOCK_SIZE) as u64;
+        mirror.primary().uncorrectable.push(page);
+        mirror.secondary().uncorrectable.push(page);
+        let err = mirror.read(page, &mut read).unwrap_err();
+        assert_eq!(err.kind(), NandFlashErrorKind::BlockFail(Some(page)));
+    }
+
+    #[test]
+    fn failed_write_diverges_until_resync() {
+        let mut mirror = mirror();
+        let data = pages(2);
+        let address = (BLOCK_SIZE + PAGE_SIZE) as u64;
+        mirror.secondary().fail_program = Some(address);
+        mirror.write(BLOCK_SIZE as u64, &data).unwrap();
+        assert_eq!(divergent(&mirror), [(1, Side::Secondary)]);
+        let mut read = [0; 2 * PAGE_SIZE];
+        mirror.read(BLOCK_SIZE as u64, &mut read).unwrap();
+        assert_eq!(read, data);
+
+        // a repair that fails leaves the block divergent
+        mirror.secondary().fail_erase.push(1);
+        assert_eq!(mirror.resync().unwrap(), 0);
+        assert_eq!(divergent(&mirror), [(1, Side::Secondary)]);
+        mirror.secondary().fail_erase.clear();
+        assert_eq!(mirror.resync().unwrap(), 1);
+        assert!(mirror.in_sync());
+        mirror
+            .secondary()
+            .read(BLOCK_SIZE as u64, &mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        // erased pages were not programmed by the repair
+        mirror
+            .secondary()
+            .write((BLOCK_SIZE + 2 * PAGE_SIZE) as u64, &data[..PAGE_SIZE])
+            .unwrap();
+
+        // an erase that works on both devices is in sync again
+        mirror.primary().fail_program = Some(0);
+        mirror.write(0, &data).unwrap();
+        assert_eq!(divergent(&mirror), [(0, Side::Primary)]);
+        mirror.erase(0, BLOCK_SIZE as u64).unwrap();
+        assert!(mirror.in_sync());
+    }
+
+    #[test]
+    fn scan_finds_interrupted_writes() {
+        let mut mirror = mirror();
+        let data = pages(3);
+        mirror.write(0, &data).unwrap();
+        // power lost between the two devices
+        mirror.primary().write(BLOCK_SIZE as u64, &data).unwrap();
+        // power lost while programming the primary
+        mirror.primary().tear_uncorrectable = true;
+        mirror.primary().cut_after = Some(0);
+        let address = (2 * BLOCK_SIZE) as u64;
+        assert!(mirror.primary().write(address, &data[..PAGE_SIZE]).is_err());
+        mirror.primary().power_on();
+        mirror
+            .secondary()
+            .write(address, &data[..PAGE_SIZE])
+            .unwrap();
+        // bad blocks are not compared
+        mirror
+            .secondary()
+            .write(3 * BLOCK_SIZE as u64, &data)
+            .unwrap();
+        mirror.secondary().mark_bad(3 * BLOCK_SIZE as u64).unwrap();
+
+        let (primary, secondary) = mirror.into_inner();
+        let mut mirror = Mirror::<_, _, BLOCKS>::new(primary, secondary).unwrap();
+        assert!(mirror.in_sync());
+        assert_eq!(mirror.scan().unwrap(), 2);
+        assert_eq!(
+            divergent(&mirror),
+            [(1, Side::Secondary), (2, Side::Primary)]
+        );
+        assert_eq!(mirror.resync().unwrap(), 2);
+        assert_eq!(mirror.scan().unwrap(), 0);
+        let mut read = [0; 2 * PAGE_SIZE];
+        mirror
+            .secondary()
+            .read(BLOCK_SIZE as u64, &mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        mirror
+            .primary()
+            .read(address, &mut read[..PAGE_SIZE])
+            .unwrap();
+        assert_eq!(read[..PAGE_SIZE], data[..PAGE_SIZE]);
+    }
+
+    #[test]
+    fn too_many_blocks() {
+        assert!(matches!(
+            Mirror::<_, _, 2>::new(RamFlash::new(3), RamFlash::new(3)),
+            Err(Error::TooManyBlocks)
+        ));
+    }
+}
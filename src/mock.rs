@@ -0,0 +1,574 @@
+//! Test doubles for the driver and the layers above
+extern crate std;
+
+use core::convert::Infallible;
+use std::{collections::BTreeMap, vec, vec::Vec};
+
+use embedded_hal::spi::{ErrorType, Operation, SpiDevice};
+
+use crate::{
+    commands::{
+        BLOCK_ERASE, JEDEC, PAGE_DATA_READ, PROGRAM_DATA_LOAD, PROGRAM_EXECUTE,
+        RANDOM_PROGRAM_DATA_LOAD, READ, READ_REG, STATUS_REGISTER_1, STATUS_REGISTER_2,
+        STATUS_REGISTER_3, WRITE_DISABLE, WRITE_ENABLE, WRITE_REG,
+    },
+    mem::{BLOCK_SIZE, PAGES_PER_BLOCK, PAGE_SIZE, SPARE_SIZE, SPARE_USER_SIZE},
+    otp::{crc16, PARAMETER_COPIES, PARAMETER_LEN},
+    registers::{Status1, Status2, Status3},
+    traits::{
+        check_erase, check_page, check_read, check_write, BlockStatus, EccStatus, NandFlash,
+        NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+/// Status Register-3 with WEL set and BUSY clear
+const READY: u8 = 0b10;
+
+/// SPI device that records the bytes written in each transaction. Status Register-3 always
+/// reads as ready with writes enabled, Status Register-2 as `status_2`, other registers as 0
+/// and everything else reads as erased.
+#[derive(Debug, Default)]
+pub struct RecordingSpi {
+    pub transactions: Vec<Vec<u8>>,
+    pub status_2: u8,
+}
+
+impl RecordingSpi {
+    /// Bytes following `command` in each transaction that starts with it
+    pub fn commands(&self, command: u8) -> Vec<&[u8]> {
+        self.transactions
+            .iter()
+            .filter(|t| t.first() == Some(&command))
+            .map(|t| &t[1..])
+            .collect()
+    }
+}
+
+impl ErrorType for RecordingSpi {
+    type Error = Infallible;
+}
+
+impl SpiDevice for RecordingSpi {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        let mut written = Vec::new();
+        for op in operations.iter_mut() {
+            match op {
+                Operation::Write(buf) => written.extend_from_slice(buf),
+                Operation::Read(buf) => buf.fill(0xFF),
+                Operation::Transfer(read, write) => {
+                    written.extend_from_slice(write);
+                    read.fill(0xFF);
+                }
+                Operation::TransferInPlace(buf) => {
+                    written.extend_from_slice(buf);
+                    let value = match buf.get(..2) {
+                        Some([READ_REG, STATUS_REGISTER_3]) => READY,
+                        Some([READ_REG, STATUS_REGISTER_2]) => self.status_2,
+                        _ => 0,
+                    };
+                    buf.fill(0);
+                    if buf.len() > 2 {
+                        buf[2] = value;
+                    }
+                }
+                Operation::DelayNs(_) => {}
+            }
+        }
+        self.transactions.push(written);
+        Ok(())
+    }
+}
+
+/// Bits per sector the on chip ECC of [`ChipSpi`] corrects
+const CHIP_ECC_BITS: u32 = 8;
+
+/// SPI device emulating a W25N02KV in Buffer Read Mode: the status registers, the page buffer,
+/// programs, erases, page reads and the OTP pages. With ECC-E set a program stores an index
+/// into the data it protects as parity, and a page read corrects up to [`CHIP_ECC_BITS`] flipped
+/// bits per sector of the main array against it. Operations complete at once.
+#[derive(Debug)]
+pub struct ChipSpi {
+    pub status_1: u8,
+    pub status_2: u8,
+    wel: bool,
+    e_fail: bool,
+    p_fail: bool,
+    ecc: u8,
+    buffer: Vec<u8>,
+    /// Programmed pages by page address, the others are erased
+    pages: BTreeMap<u32, Vec<u8>>,
+    /// Data covered by each parity written with ECC-E set
+    parities: Vec<Vec<u8>>,
+    /// Pages of the OTP area by page address
+    pub otp: Vec<Vec<u8>>,
+    /// Page address of a page whose programs fail
+    pub fail_program: Option<u32>,
+}
+
+impl ChipSpi {
+    /// Erased device with the status registers after power on: every block protected, ECC
+    /// enabled, Buffer Read Mode, the unique ID and parameter page in the OTP area
+    pub fn new() -> Self {
+        let mut parameters = Vec::new();
+        for _ in 0..PARAMETER_COPIES {
+            parameters.extend_from_slice(&parameter_data());
+        }
+        Self {
+            status_1: Status1::new().with_bp(0b1111).into(),
+            status_2: Status2::new().with_buf(true).with_ecc_e(true).into(),
+            wel: false,
+            e_fail: false,
+            p_fail: false,
+            ecc: 0,
+            buffer: vec![0xFF; RAW_PAGE],
+            pages: BTreeMap::new(),
+            parities: Vec::new(),
+            otp: vec![vec![0xFF; RAW_PAGE], parameters],
+            fail_program: None,
+        }
+    }
+
+    fn register(&self, address: u8) -> u8 {
+        match address {
+            STATUS_REGISTER_1 => self.status_1,
+            STATUS_REGISTER_2 => self.status_2,
+            STATUS_REGISTER_3 => Status3::new()
+                .with_wel(self.wel)
+                .with_e_fail(self.e_fail)
+                .with_p_fail(self.p_fail)
+                .with_ecc(self.ecc)
+                .into(),
+            _ => 0,
+        }
+    }
+
+    fn load(&mut self, column: usize, data: &[u8]) {
+        let end = (column + data.len()).min(RAW_PAGE);
+        self.buffer[column..end].copy_from_slice(&data[..end - column]);
+    }
+
+    fn program(&mut self, page: u32) {
+        self.wel = false;
+        self.p_fail = self.fail_program == Some(page);
+        if self.p_fail {
+            return;
+        }
+        let mut data = self.buffer.clone();
+        if Status2::from_bytes([self.status_2]).ecc_e() {
+            let index = self.parities.len() as u32;
+            self.parities.push(data[..PAGE_SIZE].to_vec());
+            data[PAGE_SIZE + SPARE_USER_SIZE..].fill(0x00);
+            data[PAGE_SIZE + SPARE_USER_SIZE..][..4].copy_from_slice(&index.to_le_bytes());
+        }
+        let stored = self
+            .pages
+            .entry(page)
+            .or_insert_with(|| vec![0xFF; RAW_PAGE]);
+        for (byte, new) in stored.iter_mut().zip(&data) {
+            *byte &= new;
+        }
+    }
+
+    fn erase(&mut self, page: u32) {
+        self.wel = false;
+        self.e_fail = false;
+        let block = page - page % PAGES_PER_BLOCK as u32;
+        self.pages
+            .retain(|p, _| !(block..block + PAGES_PER_BLOCK as u32).contains(p));
+    }
+
+    fn page_read(&mut self, page: u32) {
+        let status = Status2::from_bytes([self.status_2]);
+        self.ecc = 0;
+        if status.otp_e() {
+            let otp = self.otp.get(page as usize);
+            self.buffer.fill(0xFF);
+            self.load(0, &otp.cloned().unwrap_or_default());
+            return;
+        }
+        self.buffer = match self.pages.get(&page) {
+            Some(raw) => raw.clone(),
+            None => vec![0xFF; RAW_PAGE],
+        };
+        let parity = &self.buffer[PAGE_SIZE + SPARE_USER_SIZE..];
+        if !status.ecc_e() || parity.iter().all(|b| *b == 0xFF) {
+            return;
+        }
+        let index = u32::from_le_bytes(parity[..4].try_into().unwrap());
+        let Some(expected) = self.parities.get(index as usize) else {
+            self.ecc = 0b10;
+            return;
+        };
+        let flipped = self.buffer[..PAGE_SIZE]
+            .chunks(512)
+            .zip(expected.chunks(512))
+            .map(|(read, expected)| {
+                read.iter()
+                    .zip(expected)
+                    .map(|(a, b)| (a ^ b).count_ones())
+                    .sum::<u32>()
+            })
+            .max()
+            .unwrap_or(0);
+        if flipped > CHIP_ECC_BITS {
+            self.ecc = 0b10;
+        } else if flipped > 0 {
+            self.ecc = 0b01;
+            self.buffer[..PAGE_SIZE].copy_from_slice(expected);
+        }
+    }
+}
+
+impl Default for ChipSpi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ErrorType for ChipSpi {
+    type Error = Infallible;
+}
+
+impl SpiDevice for ChipSpi {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        let mut written = Vec::new();
+        for op in operations.iter_mut() {
+            match op {
+                Operation::Write(buf) => written.extend_from_slice(buf),
+                Operation::Read(buf) => match written.first() {
+                    Some(&JEDEC) => {
+                        buf.fill(0);
+                        buf[..3].copy_from_slice(&[0xEF, 0xAA, 0x22]);
+                    }
+                    Some(&READ) => {
+                        let column = u16::from_be_bytes([written[1], written[2]]) as usize;
+                        buf.fill(0xFF);
+                        let end = (column + buf.len()).min(RAW_PAGE);
+                        if column < end {
+                            buf[..end - column].copy_from_slice(&self.buffer[column..end]);
+                        }
+                    }
+                    _ => buf.fill(0xFF),
+                },
+                Operation::TransferInPlace(buf) => {
+                    written.extend_from_slice(buf);
+                    if let [READ_REG, address, value, ..] = &mut buf[..] {
+                        *value = self.register(*address);
+                    }
+                }
+                Operation::Transfer(read, write) => {
+                    written.extend_from_slice(write);
+                    read.fill(0xFF);
+                }
+                Operation::DelayNs(_) => {}
+            }
+        }
+        let page = |w: &[u8]| u32::from_be_bytes([0, w[1], w[2], w[3]]);
+        let column = |w: &[u8]| u16::from_be_bytes([w[1], w[2]]) as usize;
+        match written[..] {
+            [WRITE_ENABLE] => self.wel = true,
+            [WRITE_DISABLE] => self.wel = false,
+            [WRITE_REG, STATUS_REGISTER_1, value] => self.status_1 = value,
+            [WRITE_REG, STATUS_REGISTER_2, value] => self.status_2 = value,
+            [BLOCK_ERASE, _, _, _] if self.wel => self.erase(page(&written)),
+            [PROGRAM_EXECUTE, _, _, _] if self.wel => self.program(page(&written)),
+            [PAGE_DATA_READ, _, _, _] => self.page_read(page(&written)),
+            [PROGRAM_DATA_LOAD, _, _, ..] => {
+                self.buffer.fill(0xFF);
+                self.load(column(&written), &written[3..]);
+            }
+            [RANDOM_PROGRAM_DATA_LOAD, _, _, ..] => self.load(column(&written), &written[3..]),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Parameter data laid out like that of the W25N02KV
+pub fn parameter_data() -> [u8; PARAMETER_LEN] {
+    let mut data = [0; PARAMETER_LEN];
+    data[..4].copy_from_slice(b"ONFI");
+    data[4..6].copy_from_slice(&2u16.to_le_bytes());
+    data[32..44].copy_from_slice(b"WINBOND     ");
+    data[44..64].copy_from_slice(b"W25N02KV            ");
+    data[64] = 0xEF;
+    data[80..84].copy_from_slice(&2048u32.to_le_bytes());
+    data[84..86].copy_from_slice(&128u16.to_le_bytes());
+    data[92..96].copy_from_slice(&64u32.to_le_bytes());
+    data[96..100].copy_from_slice(&2048u32.to_le_bytes());
+    data[100] = 1;
+    data[102] = 1;
+    data[103..105].copy_from_slice(&40u16.to_le_bytes());
+    // 1 x 10^5 cycles
+    data[105] = 1;
+    data[106] = 5;
+    data[110] = 4;
+    data[112] = 8;
+    data[133..135].copy_from_slice(&700u16.to_le_bytes());
+    data[135..137].copy_from_slice(&10_000u16.to_le_bytes());
+    data[137..139].copy_from_slice(&60u16.to_le_bytes());
+    let crc = crc16(&data[..254]);
+    data[254..].copy_from_slice(&crc.to_le_bytes());
+    data
+}
+
+/// Error of [`RamFlash`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamError {
+    /// Power was cut, nothing works until [`RamFlash::power_on`]
+    PowerLost,
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+}
+
+impl From<NandFlashErrorKind> for RamError {
+    fn from(value: NandFlashErrorKind) -> Self {
+        RamError::Nand(value)
+    }
+}
+
+impl NandFlashError for RamError {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            RamError::Nand(kind) => *kind,
+            RamError::PowerLost => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Flash held in RAM with the page, spare and block sizes of the W25N. Programs clear bits
+/// like NAND does and power can be cut in the middle of a program or erase.
+#[derive(Debug, Clone)]
+pub struct RamFlash {
+    /// Each page followed by its spare area
+    raw: Vec<u8>,
+    /// Programs and erases left before power is cut, [None] to keep power
+    pub cut_after: Option<usize>,
+    powered: bool,
+    /// Pages moved by [`SpareNandFlash::copy_page`]
+    pub copies: usize,
+    /// Pages read by [`SpareNandFlash::read_page`]
+    pub reads: usize,
+    /// Calls to [`ReadNandFlash::block_status`]
+    pub statuses: usize,
+    /// Address of a page whose next program fails, programming half of it
+    pub fail_program: Option<u64>,
+    /// Blocks that fail to erase
+    pub fail_erase: Vec<usize>,
+    /// Make pages torn by a power cut or a failed program read as uncorrectable until erased,
+    /// like they do with on chip ECC enabled
+    pub tear_uncorrectable: bool,
+    /// Addresses of pages that read as uncorrectable until erased
+    pub uncorrectable: Vec<u64>,
+    /// Addresses of pages whose reads need ECC correction until erased
+    pub corrected: Vec<u64>,
+    ecc: EccStatus,
+}
+
+const RAW_PAGE: usize = PAGE_SIZE + SPARE_SIZE;
+
+impl RamFlash {
+    /// Erased flash of `blocks` blocks
+    pub fn new(blocks: usize) -> Self {
+        Self {
+            raw: vec![0xFF; blocks * PAGES_PER_BLOCK * RAW_PAGE],
+            cut_after: None,
+            powered: true,
+            copies: 0,
+            reads: 0,
+            statuses: 0,
+            fail_program: None,
+            fail_erase: Vec::new(),
+            tear_uncorrectable: false,
+            uncorrectable: Vec::new(),
+            corrected: Vec::new(),
+            ecc: EccStatus::NoErrors,
+        }
+    }
+
+    /// Restore power after a cut
+    pub fn power_on(&mut self) {
+        self.powered = true;
+        self.cut_after = None;
+    }
+
+    fn raw_page(&mut self, offset: u64) -> &mut [u8] {
+        let page = (offset / PAGE_SIZE as u64) as usize;
+        &mut self.raw[page * RAW_PAGE..(page + 1) * RAW_PAGE]
+    }
+
+    /// Count down to the power cut, returns false if this operation is cut short
+    fn operation(&mut self) -> Result<bool, RamError> {
+        if !self.powered {
+            return Err(RamError::PowerLost);
+        }
+        match &mut self.cut_after {
+            Some(0) => {
+                self.powered = false;
+                Ok(false)
+            }
+            Some(n) => {
+                *n -= 1;
+                Ok(true)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Program the page at `offset`. A cut program leaves the second half of the main array
+    /// unprogrammed.
+    fn program(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), RamError> {
+        let complete = self.operation()?;
+        let failed = self.fail_program == Some(offset);
+        if failed {
+            self.fail_program = None;
+        }
+        let raw = self.raw_page(offset);
+        let torn = if complete && !failed {
+            data.len()
+        } else {
+            data.len() / 2
+        };
+        for (byte, new) in raw.iter_mut().zip(&data[..torn]) {
+            *byte &= new;
+        }
+        for (byte, new) in raw[PAGE_SIZE..].iter_mut().zip(spare) {
+            *byte &= new;
+        }
+        if torn < data.len() && self.tear_uncorrectable {
+            self.uncorrectable.push(offset);
+        }
+        if !complete {
+            Err(RamError::PowerLost)
+        } else if failed {
+            Err(NandFlashErrorKind::BlockFail(Some(offset)).into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl crate::traits::ErrorType for RamFlash {
+    type Error = RamError;
+}
+
+impl ReadNandFlash for RamFlash {
+    const READ_SIZE: usize = PAGE_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), RamError> {
+        check_read(self, offset, bytes.len())?;
+        let mut ecc = EccStatus::NoErrors;
+        for (i, page) in bytes.chunks_exact_mut(PAGE_SIZE).enumerate() {
+            self.read_page(offset + (i * PAGE_SIZE) as u64, page, &mut [])?;
+            ecc = ecc.max(self.ecc);
+        }
+        self.ecc = ecc;
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        (self.raw.len() / RAW_PAGE * PAGE_SIZE) as u64
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, RamError> {
+        let block = address - address % BLOCK_SIZE as u64;
+        check_page(self, block, 0, 1)?;
+        self.statuses += 1;
+        if !self.powered {
+            return Err(RamError::PowerLost);
+        }
+        let marker = self.raw_page(block)[PAGE_SIZE];
+        Ok(match marker {
+            0xFF => BlockStatus::MarkedOk,
+            _ => BlockStatus::Failed,
+        })
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.ecc
+    }
+}
+
+impl NandFlash for RamFlash {
+    const WRITE_SIZE: usize = PAGE_SIZE;
+    const ERASE_SIZE: usize = BLOCK_SIZE;
+
+    /// A cut erase only erases the first half of each block
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), RamError> {
+        check_erase(self, from, to)?;
+        for block in (from..to).step_by(BLOCK_SIZE) {
+            let complete = self.operation()?;
+            if self.fail_erase.contains(&(block as usize / BLOCK_SIZE)) {
+                return Err(NandFlashErrorKind::BlockFail(Some(block)).into());
+            }
+            let pages = if complete {
+                PAGES_PER_BLOCK
+            } else {
+                PAGES_PER_BLOCK / 2
+            };
+            let start = (block / PAGE_SIZE as u64) as usize * RAW_PAGE;
+            self.raw[start..start + pages * RAW_PAGE].fill(0xFF);
+            let erased = block..block + (pages * PAGE_SIZE) as u64;
+            self.uncorrectable.retain(|page| !erased.contains(page));
+            self.corrected.retain(|page| !erased.contains(page));
+            if !complete {
+                return Err(RamError::PowerLost);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), RamError> {
+        check_write(self, offset, bytes.len())?;
+        for (i, page) in bytes.chunks_exact(PAGE_SIZE).enumerate() {
+            self.program(offset + (i * PAGE_SIZE) as u64, page, &[])?;
+        }
+        Ok(())
+    }
+}
+
+impl SpareNandFlash for RamFlash {
+    const SPARE_SIZE: usize = SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), RamError> {
+        check_page(self, offset, data.len(), spare.len())?;
+        if !self.powered {
+            return Err(RamError::PowerLost);
+        }
+        self.reads += 1;
+        if self.uncorrectable.contains(&offset) {
+            self.ecc = EccStatus::Uncorrectable;
+            return Err(NandFlashErrorKind::BlockFail(Some(offset)).into());
+        }
+        self.ecc = if self.corrected.contains(&offset) {
+            EccStatus::Corrected(Some(1))
+        } else {
+            EccStatus::NoErrors
+        };
+        let raw = self.raw_page(offset);
+        data.copy_from_slice(&raw[..data.len()]);
+        spare.copy_from_slice(&raw[PAGE_SIZE..PAGE_SIZE + spare.len()]);
+        Ok(())
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), RamError> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.program(offset, data, spare)
+    }
+
+    /// Copy inside the device like the W25N's copy-back, counted in `copies`
+    fn copy_page(&mut self, from: u64, to: u64, spare: &[u8]) -> Result<(), RamError> {
+        check_page(self, from, 0, spare.len())?;
+        check_page(self, to, 0, 0)?;
+        let mut raw = self.raw_page(from).to_vec();
+        raw[PAGE_SIZE..PAGE_SIZE + spare.len()].copy_from_slice(spare);
+        let (data, old) = raw.split_at(PAGE_SIZE);
+        self.copies += 1;
+        self.program(to, data, old)
+    }
+}
@@ -0,0 +1,376 @@
+//! NOR flash partitions over NAND for bootloaders such as embassy-boot.
+//!
+//! [`NorPartition`] maps a range of blocks of the device onto a contiguous
+//! `embedded_storage::nor_flash::NorFlash`, skipping the blocks marked bad when it is created.
+//! The blocks after the ones exposed are spares that take the place of bad blocks in the range.
+//! With the `async` feature the `embedded-storage-async` traits are implemented as well, running
+//! the blocking operations to completion.
+//!
+//! NAND pages can only be programmed a few times per erase, so writes smaller than a page use
+//! partial page programs: the page last written is kept in RAM, each write merges its bytes in
+//! and programs the page again before it returns, so nothing is left to flush. Each `WRITE`
+//! unit of a page is programmed once, which keeps a page within the [`NOP`] partial programs
+//! the W25N allows and each on chip ECC sector to a single program. Writing to a unit that was
+//! programmed before, or to a page left for another one, returns [`Error::Programmed`], which
+//! sequential writes to an erased partition such as a DFU image never do.
+use embedded_storage::nor_flash::{self, NorFlashError, NorFlashErrorKind};
+
+use crate::{
+    mem::PAGE_SIZE,
+    traits::{BlockStatus, ErrorType, NandFlash, NandFlashErrorKind},
+};
+
+/// Page of a block not yet known to be erased
+const UNKNOWN: u8 = u8::MAX;
+
+/// Partial programs allowed per page between erases
+pub const NOP: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// Page or unit was already programmed since its block was erased
+    Programmed,
+    /// Not enough good blocks in the range for the partition
+    NoGoodBlocks,
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E> From<NorFlashErrorKind> for Error<E> {
+    fn from(value: NorFlashErrorKind) -> Self {
+        Error::Nand(match value {
+            NorFlashErrorKind::NotAligned => NandFlashErrorKind::NotAligned,
+            NorFlashErrorKind::OutOfBounds => NandFlashErrorKind::OutOfBounds,
+            _ => NandFlashErrorKind::Other,
+        })
+    }
+}
+
+impl<E: core::fmt::Debug> NorFlashError for Error<E> {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::Nand(NandFlashErrorKind::NotAligned) => NorFlashErrorKind::NotAligned,
+            Error::Nand(NandFlashErrorKind::OutOfBounds) => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// A partition of `F` of up to `BLOCKS` blocks, written in units of `WRITE` bytes
+pub struct NorPartition<F, const BLOCKS: usize, const WRITE: usize> {
+    flash: F,
+    /// Physical block of each block of the partition
+    map: [u16; BLOCKS],
+    blocks: usize,
+    /// First page of each block known to be erased, or [`UNKNOWN`]
+    erased: [u8; BLOCKS],
+    /// Page last written and the `WRITE` units programmed in it, one bit each
+    pending: Option<usize>,
+    units: u8,
+    write_buf: [u8; PAGE_SIZE],
+    /// Page last read for reads of part of a page
+    cached: Option<usize>,
+    read_buf: [u8; PAGE_SIZE],
+}
+
+type NResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+impl<F, const BLOCKS: usize, const WRITE: usize> NorPartition<F, BLOCKS, WRITE>
+where
+    F: NandFlash,
+{
+    /// Expose `blocks` good blocks from the physical blocks in `range` of `flash`.
+    /// `WRITE` must divide the page into at most [`NOP`] units.
+    pub fn new(mut flash: F, range: core::ops::Range<usize>, blocks: usize) -> NResult<Self, F> {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::WRITE_SIZE.is_multiple_of(WRITE));
+        assert!(F::WRITE_SIZE / WRITE <= NOP);
+        assert!(F::WRITE_SIZE.is_multiple_of(F::READ_SIZE));
+        assert!(F::ERASE_SIZE / F::WRITE_SIZE < UNKNOWN as usize);
+        if blocks > BLOCKS || range.end as u64 * F::ERASE_SIZE as u64 > flash.capacity() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        let mut map = [0; BLOCKS];
+        let mut found = 0;
+        for block in range {
+            if found == blocks {
+                break;
+            }
+            let address = (block * F::ERASE_SIZE) as u64;
+            if flash.block_status(address).map_err(Error::Flash)? != BlockStatus::Failed {
+                map[found] = block as u16;
+                found += 1;
+            }
+        }
+        if found < blocks {
+            return Err(Error::NoGoodBlocks);
+        }
+        Ok(Self {
+            flash,
+            map,
+            blocks,
+            erased: [UNKNOWN; BLOCKS],
+            pending: None,
+            units: 0,
+            write_buf: [0xFF; PAGE_SIZE],
+            cached: None,
+            read_buf: [0; PAGE_SIZE],
+        })
+    }
+
+    fn pages_per_block(&self) -> usize {
+        F::ERASE_SIZE / F::WRITE_SIZE
+    }
+
+    /// Device address of a page of the partition
+    fn address(&self, page: usize) -> u64 {
+        let (block, page) = (page / self.pages_per_block(), page % self.pages_per_block());
+        self.map[block] as u64 * F::ERASE_SIZE as u64 + (page * F::WRITE_SIZE) as u64
+    }
+
+    /// Merge `bytes` into `page` at `start` and program the page, the units written must not
+    /// have been programmed before
+    fn program(&mut self, page: usize, start: usize, bytes: &[u8]) -> NResult<(), F> {
+        self.open(page)?;
+        let units = (start / WRITE..(start + bytes.len()) / WRITE).fold(0, |m, u| m | 1 << u);
+        if self.units & units != 0 {
+            return Err(Error::Programmed);
+        }
+        self.write_buf[start..start + bytes.len()].copy_from_slice(bytes);
+        // the units programmed before are programmed again with the same data, leaving them
+        // and their ECC parity as they are
+        self.units |= units;
+        let result = self
+            .flash
+            .write(self.address(page), &self.write_buf[..F::WRITE_SIZE]);
+        if result.is_err() {
+            self.pending = None;
+        }
+        result.map_err(Error::Flash)?;
+        let (block, index) = (page / self.pages_per_block(), page % self.pages_per_block());
+        if self.erased[block] != UNKNOWN {
+            self.erased[block] = self.erased[block].max(index as u8 + 1);
+        }
+        Ok(())
+    }
+
+    /// Make `page` the page written to, it must still be erased unless it is the last one
+    fn open(&mut self, page: usize) -> NResult<(), F> {
+        if self.pending == Some(page) {
+            return Ok(());
+        }
+        self.pending = None;
+        let (block, index) = (page / self.pages_per_block(), page % self.pages_per_block());
+        let address = self.address(page);
+        let buf = &mut self.write_buf[..F::WRITE_SIZE];
+        if self.erased[block] == UNKNOWN || index < self.erased[block] as usize {
+            self.flash.read(address, buf).map_err(Error::Flash)?;
+            if buf.iter().any(|b| *b != 0xFF) {
+                return Err(Error::Programmed);
+            }
+        }
+        buf.fill(0xFF);
+        self.pending = Some(page);
+        self.units = 0;
+        Ok(())
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F, const BLOCKS: usize, const WRITE: usize> nor_flash::ErrorType
+    for NorPartition<F, BLOCKS, WRITE>
+where
+    F: NandFlash,
+{
+    type Error = Error<F::Error>;
+}
+
+impl<F, const BLOCKS: usize, const WRITE: usize> nor_flash::ReadNorFlash
+    for NorPartition<F, BLOCKS, WRITE>
+where
+    F: NandFlash,
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, mut bytes: &mut [u8]) -> Result<(), Self::Error> {
+        nor_flash::check_read(self, offset, bytes.len())?;
+        let size = F::WRITE_SIZE;
+        let mut offset = offset as usize;
+        while !bytes.is_empty() {
+            let (page, start) = (offset / size, offset % size);
+            let len = bytes.len().min(size - start);
+            let (out, rest) = bytes.split_at_mut(len);
+            if self.pending == Some(page) {
+                out.copy_from_slice(&self.write_buf[start..start + len]);
+            } else if len == size {
+                let address = self.address(page);
+                self.flash.read(address, out).map_err(Error::Flash)?;
+            } else {
+                if self.cached != Some(page) {
+                    self.cached = None;
+                    let address = self.address(page);
+                    self.flash
+                        .read(address, &mut self.read_buf[..size])
+                        .map_err(Error::Flash)?;
+                    self.cached = Some(page);
+                }
+                out.copy_from_slice(&self.read_buf[start..start + len]);
+            }
+            bytes = rest;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.blocks * F::ERASE_SIZE
+    }
+}
+
+impl<F, const BLOCKS: usize, const WRITE: usize> nor_flash::NorFlash
+    for NorPartition<F, BLOCKS, WRITE>
+where
+    F: NandFlash,
+{
+    const WRITE_SIZE: usize = WRITE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        nor_flash::check_erase(self, from, to)?;
+        let (first, last) = (from as usize / F::ERASE_SIZE, to as usize / F::ERASE_SIZE);
+        let pages = self.pages_per_block();
+        let erased =
+            |page: Option<usize>| page.is_some_and(|p| (first..last).contains(&(p / pages)));
+        if erased(self.pending) {
+            self.pending = None;
+        }
+        if erased(self.cached) {
+            self.cached = None;
+        }
+        for block in first..last {
+            self.erased[block] = UNKNOWN;
+            let address = self.map[block] as u64 * F::ERASE_SIZE as u64;
+            self.flash
+                .erase(address, address + F::ERASE_SIZE as u64)
+                .map_err(Error::Flash)?;
+            self.erased[block] = 0;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, mut bytes: &[u8]) -> Result<(), Self::Error> {
+        nor_flash::check_write(self, offset, bytes.len())?;
+        let size = F::WRITE_SIZE;
+        let mut offset = offset as usize;
+        while !bytes.is_empty() {
+            let (page, start) = (offset / size, offset % size);
+            let len = bytes.len().min(size - start);
+            if self.cached == Some(page) {
+                self.cached = None;
+            }
+            self.program(page, start, &bytes[..len])?;
+            bytes = &bytes[len..];
+            offset += len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F, const BLOCKS: usize, const WRITE: usize> embedded_storage_async::nor_flash::ReadNorFlash
+    for NorPartition<F, BLOCKS, WRITE>
+where
+    F: NandFlash,
+{
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        nor_flash::ReadNorFlash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        nor_flash::ReadNorFlash::capacity(self)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F, const BLOCKS: usize, const WRITE: usize> embedded_storage_async::nor_flash::NorFlash
+    for NorPartition<F, BLOCKS, WRITE>
+where
+    F: NandFlash,
+{
+    const WRITE_SIZE: usize = WRITE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        nor_flash::NorFlash::erase(self, from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        nor_flash::NorFlash::write(self, offset, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::RamFlash;
+    use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+    const WRITE: usize = PAGE_SIZE / NOP;
+    type TestPartition = NorPartition<RamFlash, 4, WRITE>;
+
+    fn partition(flash: RamFlash) -> TestPartition {
+        NorPartition::new(flash, 0..4, 4).unwrap()
+    }
+
+    #[test]
+    fn partial_page_is_programmed_before_write_returns() {
+        let mut nor = partition(RamFlash::new(4));
+        nor.write(0, &[0x11; WRITE]).unwrap();
+        nor.write(WRITE as u32, &[0x22; WRITE]).unwrap();
+        // dropped without anything left to flush, as embassy-boot does
+        let mut nor = partition(nor.into_inner());
+        let mut buf = [0; 3 * WRITE];
+        nor.read(0, &mut buf).unwrap();
+        assert!(buf[..WRITE].iter().all(|b| *b == 0x11));
+        assert!(buf[WRITE..2 * WRITE].iter().all(|b| *b == 0x22));
+        assert!(buf[2 * WRITE..].iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn each_unit_is_programmed_once() {
+        let mut nor = partition(RamFlash::new(4));
+        for unit in 0..NOP {
+            nor.write((unit * WRITE) as u32, &[unit as u8; WRITE])
+                .unwrap();
+        }
+        assert!(matches!(
+            nor.write(WRITE as u32, &[0; WRITE]),
+            Err(Error::Programmed)
+        ));
+        let mut page = [0; PAGE_SIZE];
+        nor.read(0, &mut page).unwrap();
+        for (unit, bytes) in page.chunks_exact(WRITE).enumerate() {
+            assert!(bytes.iter().all(|b| *b == unit as u8));
+        }
+        // a page left for another is not written again until erased
+        nor.write(PAGE_SIZE as u32, &[0; WRITE]).unwrap();
+        assert!(matches!(nor.write(0, &[0; WRITE]), Err(Error::Programmed)));
+        nor.erase(0, crate::mem::BLOCK_SIZE as u32).unwrap();
+        nor.write(0, &[0; WRITE]).unwrap();
+    }
+}
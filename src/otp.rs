@@ -0,0 +1,219 @@
+//! The OTP area: unique ID, parameter page and the one time programmable pages.
+//!
+//! With the OTP-E bit of Status Register-2 set, page addresses 00h to 0Bh select these pages
+//! instead of the main array, see [`crate::W25N::read_otp`]. The unique ID and parameter pages
+//! are programmed in the factory and hold several copies of their data, the parsers here take
+//! the first intact copy.
+use core::ops::Range;
+
+/// Page holding the unique ID
+pub const UNIQUE_ID_PAGE: u32 = 0x00;
+/// Page holding the parameter page data
+pub const PARAMETER_PAGE: u32 = 0x01;
+/// Pages free for the user, programmable until the area is locked with OTP-L
+pub const OTP_PAGES: Range<u32> = 0x02..0x0C;
+/// Pages in the OTP area
+pub const AREA_PAGES: u32 = 0x0C;
+
+/// Bytes in the unique ID
+pub const UNIQUE_ID_LEN: usize = 16;
+/// Copies of the unique ID and its complement in the unique ID page
+pub const UNIQUE_ID_COPIES: usize = 16;
+/// Bytes in one copy of the parameter data
+pub const PARAMETER_LEN: usize = 256;
+/// Copies of the parameter data in the parameter page
+pub const PARAMETER_COPIES: usize = 3;
+
+const SIGNATURE: [u8; 4] = *b"ONFI";
+
+/// The unique ID from the start of the unique ID page.
+/// Each copy is followed by its complement, returns [None] if no copy matches its complement.
+pub fn unique_id(page: &[u8]) -> Option<[u8; UNIQUE_ID_LEN]> {
+    page.chunks_exact(2 * UNIQUE_ID_LEN)
+        .take(UNIQUE_ID_COPIES)
+        .find(|copy| {
+            let (id, complement) = copy.split_at(UNIQUE_ID_LEN);
+            id.iter().zip(complement).all(|(a, b)| a ^ b == 0xFF)
+        })
+        .map(|copy| copy[..UNIQUE_ID_LEN].try_into().unwrap())
+}
+
+/// CRC-16 of the parameter data: polynomial 8005h, initial value 4F4Eh
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_from(0x4F4E, data)
+}
+
+/// CRC-16 with polynomial 8005h from `crc`, not reflected and without a final XOR
+fn crc16_from(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Device description from the parameter page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParameterPage {
+    /// Revision number
+    pub revision: u16,
+    manufacturer: [u8; 12],
+    model: [u8; 20],
+    /// JEDEC manufacturer ID (0xEF)
+    pub jedec_id: u8,
+    /// Bytes in the main array of a page
+    pub page_size: u32,
+    /// Bytes in the spare area of a page
+    pub spare_size: u16,
+    pub pages_per_block: u32,
+    pub blocks_per_unit: u32,
+    pub units: u8,
+    pub bits_per_cell: u8,
+    /// Most bad blocks a unit can have
+    pub max_bad_blocks: u16,
+    /// Erase cycles a block is guaranteed to endure
+    pub endurance: u32,
+    /// Partial programs allowed per page between erases
+    pub programs_per_page: u8,
+    /// Bits the on chip ECC corrects
+    pub ecc_bits: u8,
+    /// Maximum page program time tPROG in microseconds
+    pub max_program_us: u16,
+    /// Maximum block erase time tBE in microseconds
+    pub max_erase_us: u16,
+    /// Maximum page read time tRD in microseconds
+    pub max_read_us: u16,
+}
+
+impl ParameterPage {
+    /// Parse the first copy of the parameter data in `page` with a matching CRC
+    pub fn parse(page: &[u8]) -> Option<Self> {
+        page.chunks_exact(PARAMETER_LEN)
+            .take(PARAMETER_COPIES)
+            .find_map(Self::parse_copy)
+    }
+
+    fn parse_copy(data: &[u8]) -> Option<Self> {
+        let crc = u16::from_le_bytes([data[254], data[255]]);
+        if data[..4] != SIGNATURE || crc16(&data[..254]) != crc {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        Some(Self {
+            revision: u16_at(4),
+            manufacturer: data[32..44].try_into().unwrap(),
+            model: data[44..64].try_into().unwrap(),
+            jedec_id: data[64],
+            page_size: u32_at(80),
+            spare_size: u16_at(84),
+            pages_per_block: u32_at(92),
+            blocks_per_unit: u32_at(96),
+            units: data[100],
+            bits_per_cell: data[102],
+            max_bad_blocks: u16_at(103),
+            endurance: (data[105] as u32).saturating_mul(10u32.saturating_pow(data[106] as u32)),
+            programs_per_page: data[110],
+            ecc_bits: data[112],
+            max_program_us: u16_at(133),
+            max_erase_us: u16_at(135),
+            max_read_us: u16_at(137),
+        })
+    }
+
+    /// Device manufacturer, e.g. "WINBOND"
+    pub fn manufacturer(&self) -> &str {
+        text(&self.manufacturer)
+    }
+
+    /// Device model, e.g. "W25N02KV"
+    pub fn model(&self) -> &str {
+        text(&self.model)
+    }
+}
+
+/// ASCII field without its padding
+fn text(field: &[u8]) -> &str {
+    core::str::from_utf8(field)
+        .unwrap_or("")
+        .trim_end_matches([' ', '\0'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::parameter_data;
+
+    #[test]
+    fn crc16_check_values() {
+        // the catalogued check value of CRC-16/UMTS, the same CRC from zero
+        assert_eq!(crc16_from(0, b"123456789"), 0xFEE8);
+        assert_eq!(crc16(b"123456789"), 0x2771);
+        assert_eq!(crc16(&[]), 0x4F4E);
+    }
+
+    #[test]
+    fn parameter_page_fields() {
+        let mut page = [0; PARAMETER_LEN * PARAMETER_COPIES];
+        for copy in page.chunks_exact_mut(PARAMETER_LEN) {
+            copy.copy_from_slice(&parameter_data());
+        }
+        let parsed = ParameterPage::parse(&page).unwrap();
+        assert_eq!(parsed.manufacturer(), "WINBOND");
+        assert_eq!(parsed.model(), "W25N02KV");
+        assert_eq!(
+            (
+                parsed.revision,
+                parsed.jedec_id,
+                parsed.page_size,
+                parsed.spare_size
+            ),
+            (2, 0xEF, 2048, 128)
+        );
+        assert_eq!(
+            (parsed.pages_per_block, parsed.blocks_per_unit, parsed.units),
+            (64, 2048, 1)
+        );
+        assert_eq!((parsed.bits_per_cell, parsed.max_bad_blocks), (1, 40));
+        assert_eq!(
+            (parsed.endurance, parsed.programs_per_page, parsed.ecc_bits),
+            (100_000, 4, 8)
+        );
+        assert_eq!(
+            (
+                parsed.max_program_us,
+                parsed.max_erase_us,
+                parsed.max_read_us
+            ),
+            (700, 10_000, 60)
+        );
+
+        // a copy with a bad CRC or signature is passed over
+        page[70] ^= 1;
+        page[PARAMETER_LEN] = b'X';
+        assert_eq!(ParameterPage::parse(&page), Some(parsed));
+        page[2 * PARAMETER_LEN + 254] ^= 1;
+        assert_eq!(ParameterPage::parse(&page), None);
+    }
+
+    #[test]
+    fn unique_id_needs_its_complement() {
+        let id: [u8; UNIQUE_ID_LEN] = core::array::from_fn(|i| i as u8 * 17);
+        let mut page = [0; 2 * UNIQUE_ID_LEN * UNIQUE_ID_COPIES];
+        for copy in page.chunks_exact_mut(2 * UNIQUE_ID_LEN) {
+            copy[..UNIQUE_ID_LEN].copy_from_slice(&id);
+            for (c, b) in copy[UNIQUE_ID_LEN..].iter_mut().zip(&id) {
+                *c = !b;
+            }
+        }
+        page[3] ^= 0x10;
+        assert_eq!(unique_id(&page), Some(id));
+        assert_eq!(unique_id(&[0; 2 * UNIQUE_ID_LEN * UNIQUE_ID_COPIES]), None);
+    }
+}
@@ -0,0 +1,638 @@
+//! Block aligned partitions of a device and an on-flash partition table.
+//!
+//! A [`Partition`] wraps a device over a range of its blocks and is itself a device, with
+//! addresses starting at zero and its own bounds checks, so the layers in this crate can each
+//! be given a part of one chip. Several partitions can share a device by each wrapping a
+//! `&mut` reference to it in turn.
+//!
+//! A [`PartitionTable`] names the partitions and can be stored on the device so the layout
+//! is read at boot instead of compiled in. [`PartitionTable::store`] writes a copy to page 0
+//! of every good block of a range with an increasing sequence number, the block holding the
+//! newest copy last, and [`PartitionTable::load`] takes the newest copy whose CRC matches, so
+//! a copy torn by power loss falls back to the previous table.
+use core::ops::Range;
+
+use crate::{
+    crc::crc32,
+    mem::PAGE_SIZE,
+    traits::{
+        check_erase, check_page, check_read, check_write, BlockStatus, EccStatus, ErrorType,
+        NandFlash, NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+/// Longest partition name in bytes
+pub const NAME_LEN: usize = 16;
+/// Entry flag: the partition is opened read only
+pub const READ_ONLY: u32 = 1 << 0;
+
+const MAGIC: [u8; 4] = *b"PTBL";
+/// Magic, sequence number and entry count before the entries
+const TABLE_HEADER: usize = 12;
+const ENTRY_LEN: usize = NAME_LEN + 12;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// Write or erase of a read only partition
+    ReadOnly,
+    /// No partition with the name in the table
+    NotFound,
+    /// Table has no room for another entry
+    TableFull,
+    /// Entry name is empty, too long or already used, or its blocks overlap another entry
+    InvalidEntry,
+    /// No good block in the range to store the table in
+    NoGoodBlocks,
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+            _ => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// The blocks of `F` in a range, addressed from zero
+pub struct Partition<F> {
+    flash: F,
+    /// Byte address of the first block
+    start: u64,
+    /// Bytes in the partition
+    size: u64,
+    read_only: bool,
+}
+
+type PResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+impl<F> Partition<F>
+where
+    F: NandFlash,
+{
+    /// Partition over the blocks in `blocks` of `flash`
+    pub fn new(flash: F, blocks: Range<usize>) -> PResult<Self, F> {
+        let start = blocks.start as u64 * F::ERASE_SIZE as u64;
+        let end = blocks.end as u64 * F::ERASE_SIZE as u64;
+        if blocks.is_empty() || end > flash.capacity() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        Ok(Self {
+            flash,
+            start,
+            size: end - start,
+            read_only: false,
+        })
+    }
+
+    /// Refuse writes and erases through this partition
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Blocks of the underlying device the partition covers
+    pub fn blocks(&self) -> Range<usize> {
+        let first = (self.start / F::ERASE_SIZE as u64) as usize;
+        first..first + (self.size / F::ERASE_SIZE as u64) as usize
+    }
+
+    fn check_writable(&self) -> PResult<(), F> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F> ErrorType for Partition<F>
+where
+    F: NandFlash,
+{
+    type Error = Error<F::Error>;
+}
+
+impl<F> ReadNandFlash for Partition<F>
+where
+    F: NandFlash,
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.flash
+            .read(self.start + offset, bytes)
+            .map_err(Error::Flash)
+    }
+
+    fn read_continuous(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.flash
+            .read_continuous(self.start + offset, bytes)
+            .map_err(Error::Flash)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.size
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        if address >= self.size {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        self.flash
+            .block_status(self.start + address)
+            .map_err(Error::Flash)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.flash.ecc_status()
+    }
+}
+
+impl<F> NandFlash for Partition<F>
+where
+    F: NandFlash,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.check_writable()?;
+        self.flash
+            .erase(self.start + from, self.start + to)
+            .map_err(Error::Flash)
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.check_writable()?;
+        self.flash
+            .write(self.start + offset, bytes)
+            .map_err(Error::Flash)
+    }
+}
+
+impl<F> SpareNandFlash for Partition<F>
+where
+    F: SpareNandFlash,
+{
+    const SPARE_SIZE: usize = F::SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.flash
+            .read_page(self.start + offset, data, spare)
+            .map_err(Error::Flash)
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.check_writable()?;
+        self.flash
+            .write_page(self.start + offset, data, spare)
+            .map_err(Error::Flash)
+    }
+
+    fn copy_page(&mut self, from: u64, to: u64, spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, from, 0, spare.len())?;
+        check_page(self, to, 0, 0)?;
+        self.check_writable()?;
+        self.flash
+            .copy_page(self.start + from, self.start + to, spare)
+            .map_err(Error::Flash)
+    }
+
+    fn mark_bad(&mut self, address: u64) -> Result<(), Self::Error> {
+        if address >= self.size {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        self.flash
+            .mark_bad(self.start + address)
+            .map_err(Error::Flash)
+    }
+}
+
+/// A named range of blocks in a [`PartitionTable`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    /// Name padded with zeros
+    name: [u8; NAME_LEN],
+    /// First block of the partition
+    pub first: u32,
+    /// Blocks in the partition
+    pub count: u32,
+    /// [`READ_ONLY`] and flags of the application in the upper bits
+    pub flags: u32,
+}
+
+impl Entry {
+    /// Name of the partition, empty if it is not valid UTF-8
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    /// Blocks of the device the partition covers
+    pub fn blocks(&self) -> Range<usize> {
+        self.first as usize..(self.first + self.count) as usize
+    }
+
+    fn to_bytes(self) -> [u8; ENTRY_LEN] {
+        let mut bytes = [0; ENTRY_LEN];
+        bytes[..NAME_LEN].copy_from_slice(&self.name);
+        bytes[NAME_LEN..NAME_LEN + 4].copy_from_slice(&self.first.to_le_bytes());
+        bytes[NAME_LEN + 4..NAME_LEN + 8].copy_from_slice(&self.count.to_le_bytes());
+        bytes[NAME_LEN + 8..].copy_from_slice(&self.flags.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let word = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Self {
+            name: bytes[..NAME_LEN].try_into().unwrap(),
+            first: word(NAME_LEN),
+            count: word(NAME_LEN + 4),
+            flags: word(NAME_LEN + 8),
+        }
+    }
+}
+
+/// Up to `N` named partitions
+#[derive(Debug, Clone)]
+pub struct PartitionTable<const N: usize> {
+    entries: [Entry; N],
+    len: usize,
+    /// Sequence number of the copy on flash
+    seq: u32,
+}
+
+impl<const N: usize> Default for PartitionTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> PartitionTable<N> {
+    /// Bytes of a stored table
+    const LEN: usize = TABLE_HEADER + N * ENTRY_LEN + 4;
+
+    pub fn new() -> Self {
+        Self {
+            entries: [Entry {
+                name: [0; NAME_LEN],
+                first: 0,
+                count: 0,
+                flags: 0,
+            }; N],
+            len: 0,
+            seq: 0,
+        }
+    }
+
+    /// Add a partition named `name` over `blocks`
+    pub fn add<E>(&mut self, name: &str, blocks: Range<usize>, flags: u32) -> Result<(), Error<E>> {
+        if self.len == N {
+            return Err(Error::TableFull);
+        }
+        let overlaps = |e: &Entry| e.blocks().start < blocks.end && blocks.start < e.blocks().end;
+        if name.is_empty()
+            || name.len() > NAME_LEN
+            || name.contains('\0')
+            || blocks.is_empty()
+            || u32::try_from(blocks.end).is_err()
+            || self.find(name).is_some()
+            || self.entries().iter().any(overlaps)
+        {
+            return Err(Error::InvalidEntry);
+        }
+        let mut entry = Entry {
+            name: [0; NAME_LEN],
+            first: blocks.start as u32,
+            count: blocks.len() as u32,
+            flags,
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.entries[self.len] = entry;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// The partitions in the order they were added
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries[..self.len]
+    }
+
+    /// The partition named `name`
+    pub fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries().iter().find(|e| e.name() == name)
+    }
+
+    /// Open the partition named `name` on `flash`, read only if the entry says so
+    pub fn open<F: NandFlash>(&self, flash: F, name: &str) -> PResult<Partition<F>, F> {
+        let entry = self.find(name).ok_or(Error::NotFound)?;
+        let partition = Partition::new(flash, entry.blocks())?;
+        Ok(if entry.flags & READ_ONLY != 0 {
+            partition.read_only()
+        } else {
+            partition
+        })
+    }
+
+    fn to_bytes(&self, page: &mut [u8]) {
+        page.fill(0xFF);
+        page[..4].copy_from_slice(&MAGIC);
+        page[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        page[8..12].copy_from_slice(&(self.len as u32).to_le_bytes());
+        for (i, entry) in self.entries().iter().enumerate() {
+            let at = TABLE_HEADER + i * ENTRY_LEN;
+            page[at..at + ENTRY_LEN].copy_from_slice(&entry.to_bytes());
+        }
+        let end = TABLE_HEADER + self.len * ENTRY_LEN;
+        let crc = crc32(&page[..end]);
+        page[end..end + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn from_bytes(page: &[u8]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes(page[i..i + 4].try_into().unwrap());
+        let len = word(8) as usize;
+        if page[..4] != MAGIC || len > N {
+            return None;
+        }
+        let end = TABLE_HEADER + len * ENTRY_LEN;
+        if word(end) != crc32(&page[..end]) {
+            return None;
+        }
+        let mut table = Self::new();
+        table.seq = word(4);
+        table.len = len;
+        for (i, entry) in table.entries[..len].iter_mut().enumerate() {
+            *entry = Entry::from_bytes(&page[TABLE_HEADER + i * ENTRY_LEN..]);
+        }
+        Some(table)
+    }
+
+    /// Read the table stored in page 0 of `block`, [`None`] if the block is bad or holds no
+    /// valid table
+    fn read_copy<F: NandFlash>(
+        flash: &mut F,
+        block: usize,
+        page: &mut [u8],
+    ) -> PResult<Option<Self>, F> {
+        let address = (block * F::ERASE_SIZE) as u64;
+        if flash.block_status(address).map_err(Error::Flash)? == BlockStatus::Failed {
+            return Ok(None);
+        }
+        match flash.read(address, page) {
+            Ok(()) => Ok(Self::from_bytes(page)),
+            Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => Ok(None),
+            Err(e) => Err(Error::Flash(e)),
+        }
+    }
+
+    /// Block in `blocks` holding the newest valid table and that table
+    fn newest<F: NandFlash>(
+        flash: &mut F,
+        blocks: Range<usize>,
+    ) -> PResult<Option<(usize, Self)>, F> {
+        assert!(Self::LEN <= F::WRITE_SIZE && F::WRITE_SIZE <= PAGE_SIZE);
+        let mut page = [0; PAGE_SIZE];
+        let page = &mut page[..F::WRITE_SIZE];
+        let mut newest: Option<(usize, Self)> = None;
+        for block in blocks {
+            if let Some(table) = Self::read_copy(flash, block, page)? {
+                if newest.as_ref().is_none_or(|(_, n)| table.seq > n.seq) {
+                    newest = Some((block, table));
+                }
+            }
+        }
+        Ok(newest)
+    }
+
+    /// Read the newest valid table stored in `blocks` of `flash`
+    pub fn load<F: NandFlash>(flash: &mut F, blocks: Range<usize>) -> PResult<Option<Self>, F> {
+        Ok(Self::newest(flash, blocks)?.map(|(_, table)| table))
+    }
+
+    /// Erase `block`, write `page` to it and read it back, returning whether it holds the copy
+    fn write_copy<F: NandFlash>(
+        flash: &mut F,
+        block: usize,
+        page: &[u8],
+        check: &mut [u8],
+    ) -> PResult<bool, F> {
+        let address = (block * F::ERASE_SIZE) as u64;
+        if flash.block_status(address).map_err(Error::Flash)? == BlockStatus::Failed {
+            return Ok(false);
+        }
+        let result = flash
+            .erase(address, address + F::ERASE_SIZE as u64)
+            .and_then(|()| flash.write(address, page))
+            .and_then(|()| flash.read(address, check));
+        match result {
+            Ok(()) => Ok(check == page),
+            Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => Ok(false),
+            Err(e) => Err(Error::Flash(e)),
+        }
+    }
+
+    /// Write the table to every good block in `blocks` of `flash`, erasing them first.
+    /// Keep the partitions clear of these blocks.
+    ///
+    /// The block holding the newest stored table is only rewritten once a new copy has been
+    /// written and read back elsewhere, so power loss at any point leaves a valid table. If no
+    /// other block takes a copy it is overwritten in place.
+    pub fn store<F: NandFlash>(&mut self, flash: &mut F, blocks: Range<usize>) -> PResult<(), F> {
+        let newest = Self::newest(flash, blocks.clone())?;
+        let keep = newest.as_ref().map(|(block, _)| *block);
+        // the new copy has to win over any stored one
+        self.seq = newest
+            .map_or(self.seq, |(_, n)| n.seq.max(self.seq))
+            .wrapping_add(1);
+        let (mut page, mut check) = ([0; PAGE_SIZE], [0; PAGE_SIZE]);
+        let (page, check) = (&mut page[..F::WRITE_SIZE], &mut check[..F::WRITE_SIZE]);
+        self.to_bytes(page);
+        let mut stored = false;
+        for block in blocks.filter(|b| Some(*b) != keep) {
+            stored |= Self::write_copy(flash, block, page, check)?;
+        }
+        if let Some(block) = keep {
+            stored |= Self::write_copy(flash, block, page, check)?;
+        }
+        if !stored {
+            return Err(Error::NoGoodBlocks);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{BLOCK_SIZE, PAGE_SIZE};
+    use crate::mock::{RamError, RamFlash};
+
+    type Table = PartitionTable<4>;
+    const TABLE_BLOCKS: Range<usize> = 0..3;
+
+    fn table() -> Table {
+        let mut table = Table::new();
+        table.add::<RamError>("boot", 3..5, READ_ONLY).unwrap();
+        table.add::<RamError>("data", 5..16, 1 << 8).unwrap();
+        table
+    }
+
+    #[test]
+    fn store_and_load() {
+        let mut flash = RamFlash::new(16);
+        assert!(Table::load(&mut flash, TABLE_BLOCKS).unwrap().is_none());
+        let mut stored = table();
+        stored.store(&mut flash, TABLE_BLOCKS).unwrap();
+        let loaded = Table::load(&mut flash, TABLE_BLOCKS).unwrap().unwrap();
+        assert_eq!(loaded.entries(), stored.entries());
+        let data = loaded.find("data").unwrap();
+        assert_eq!(
+            (data.name(), data.blocks(), data.flags),
+            ("data", 5..16, 1 << 8)
+        );
+        assert!(loaded.find("dat").is_none());
+
+        // a table built from scratch still replaces the stored one
+        let mut newer = Table::new();
+        newer.add::<RamError>("all", 3..16, 0).unwrap();
+        newer.store(&mut flash, TABLE_BLOCKS).unwrap();
+        let loaded = Table::load(&mut flash, TABLE_BLOCKS).unwrap().unwrap();
+        assert_eq!(loaded.entries(), newer.entries());
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        let mut table = table();
+        for (name, blocks) in [
+            ("boot", 20..21),
+            ("", 20..21),
+            ("a name that is too long", 20..21),
+            ("nul\0", 20..21),
+            ("empty", 20..20),
+            ("overlap", 4..6),
+            ("inside", 6..7),
+            ("huge", 20..1 << 40),
+        ] {
+            assert!(
+                matches!(
+                    table.add::<RamError>(name, blocks, 0),
+                    Err(Error::InvalidEntry)
+                ),
+                "{name}"
+            );
+        }
+        table.add::<RamError>("c", 16..17, 0).unwrap();
+        table.add::<RamError>("d", 17..18, 0).unwrap();
+        assert!(matches!(
+            table.add::<RamError>("e", 18..19, 0),
+            Err(Error::TableFull)
+        ));
+    }
+
+    #[test]
+    fn partitions_are_bounded() {
+        let mut flash = RamFlash::new(16);
+        let mut table = table();
+        table.add::<RamError>("beyond", 16..17, 0).unwrap();
+        assert!(matches!(
+            table.open(&mut flash, "beyond"),
+            Err(Error::Nand(NandFlashErrorKind::OutOfBounds))
+        ));
+        assert!(matches!(
+            table.open(&mut flash, "none"),
+            Err(Error::NotFound)
+        ));
+
+        let mut boot = table.open(&mut flash, "boot").unwrap();
+        assert_eq!(boot.capacity(), 2 * BLOCK_SIZE as u64);
+        assert!(matches!(
+            boot.erase(0, BLOCK_SIZE as u64),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(
+            boot.write(0, &[0; PAGE_SIZE]),
+            Err(Error::ReadOnly)
+        ));
+        let mut page = [0; PAGE_SIZE];
+        assert!(matches!(
+            boot.read(2 * BLOCK_SIZE as u64, &mut page),
+            Err(Error::Nand(NandFlashErrorKind::OutOfBounds))
+        ));
+
+        let mut data = table.open(&mut flash, "data").unwrap();
+        data.write(0, &[0x5A; PAGE_SIZE]).unwrap();
+        flash.read((5 * BLOCK_SIZE) as u64, &mut page).unwrap();
+        assert_eq!(page, [0x5A; PAGE_SIZE]);
+    }
+
+    #[test]
+    fn bad_table_blocks_are_skipped() {
+        let mut flash = RamFlash::new(16);
+        flash.mark_bad(BLOCK_SIZE as u64).unwrap();
+        flash.fail_erase.push(2);
+        let mut stored = table();
+        stored.store(&mut flash, TABLE_BLOCKS).unwrap();
+        let loaded = Table::load(&mut flash, TABLE_BLOCKS).unwrap().unwrap();
+        assert_eq!(loaded.entries(), stored.entries());
+        // the copy that cannot be read is passed over
+        flash.uncorrectable.push(0);
+        assert!(Table::load(&mut flash, TABLE_BLOCKS).unwrap().is_none());
+
+        flash.fail_erase.push(0);
+        assert!(matches!(
+            stored.store(&mut flash, TABLE_BLOCKS),
+            Err(Error::NoGoodBlocks)
+        ));
+    }
+
+    #[test]
+    fn interrupted_store_keeps_a_table() {
+        let mut flash = RamFlash::new(16);
+        let old = table();
+        old.clone().store(&mut flash, 0..2).unwrap();
+        // only the first block still holds a copy
+        flash.uncorrectable.push(BLOCK_SIZE as u64);
+        let mut new = Table::new();
+        new.add::<RamError>("all", 2..16, 0).unwrap();
+        for cut in 0.. {
+            let mut flash = flash.clone();
+            flash.cut_after = Some(cut);
+            let done = new.clone().store(&mut flash, 0..2).is_ok();
+            flash.power_on();
+            let loaded = Table::load(&mut flash, 0..2).unwrap().unwrap();
+            if done {
+                assert_eq!(loaded.entries(), new.entries());
+                break;
+            }
+            assert!(loaded.entries() == old.entries() || loaded.entries() == new.entries());
+        }
+    }
+}
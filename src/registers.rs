@@ -1,5 +1,8 @@
+#![allow(clippy::new_without_default)]
 use modular_bitfield::prelude::*;
 
+use crate::traits::EccStatus;
+
 #[bitfield]
 pub struct Status1 {
     /// Status Register Protect-1
@@ -56,7 +59,19 @@ pub struct Status3 {
     pub p_fail: bool,
     /// ECC Status
     pub ecc: B2,
-    reserved: B2,
+    #[skip]
+    __: B2,
+}
+
+impl Status3 {
+    /// Decode the ECC bits set by the last page read
+    pub fn ecc_status(&self) -> EccStatus {
+        match self.ecc() {
+            0b00 => EccStatus::NoErrors,
+            0b01 => EccStatus::Corrected(None),
+            _ => EccStatus::Uncorrectable,
+        }
+    }
 }
 
 impl From<Status3> for u8 {
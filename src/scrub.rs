@@ -0,0 +1,771 @@
+//! Background scrubbing and read disturb refresh.
+//!
+//! [`Scrubber`] passes everything through to the device it wraps, counting the reads of each
+//! block and the reads that needed ECC correction. [`Scrubber::step`] is called from an idle
+//! task and reads one page at a time, walking the whole device. A block whose corrected reads
+//! or total reads cross the [`ScrubConfig`] thresholds is refreshed: its pages are copied to a
+//! scratch block, the block is erased and the pages copied back, renewing the charge before
+//! the bit errors become uncorrectable. The refresh is spread over the following steps, each
+//! copying one page or erasing at most two blocks.
+//!
+//! The last [`RESERVED_BLOCKS`] blocks are hidden from the layers above. The scratch block and
+//! a journal block are picked from the good ones, and a reserved block that fails to erase or
+//! program is marked bad and replaced by the next. Once the copy in the scratch block is
+//! complete a record naming the block and the scratch block is written to the journal and it
+//! is only erased after the copy back, so a refresh interrupted by power loss is finished by
+//! [`Scrubber::new`]. The counters are only kept in RAM.
+//!
+//! A write or erase of the block being refreshed cancels a refresh still copying to the
+//! scratch block. Any access to a block being copied back finishes its refresh first.
+use crate::{
+    mem::{PAGE_SIZE, SPARE_META, SPARE_SIZE},
+    traits::{
+        check_erase, check_page, check_read, check_write, BlockStatus, EccStatus, ErrorType,
+        NandFlash, NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+/// Blocks at the end of the device used for refreshing
+pub const RESERVED_BLOCKS: usize = 4;
+
+const MAGIC: [u8; 4] = *b"Scrb";
+/// Spare bytes holding a journal record: magic, then the block and the scratch block, each
+/// followed by its complement
+const RECORD_LEN: usize = SPARE_META + 12;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// Device has more blocks than the counters can hold
+    TooManyBlocks,
+    /// Fewer than two of the reserved blocks are good
+    NoReservedBlocks,
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+            Error::TooManyBlocks | Error::NoReservedBlocks => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Thresholds at which the [`Scrubber`] refreshes a block
+#[derive(Debug, Clone, Copy)]
+pub struct ScrubConfig {
+    /// Reads needing ECC correction since the block was last erased
+    pub corrected_reads: u32,
+    /// Reads of any page of the block since it was last erased
+    pub reads: u32,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        Self {
+            corrected_reads: 4,
+            reads: 100_000,
+        }
+    }
+}
+
+/// Outcome of one [`Scrubber::step`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scrub {
+    /// The page at the address was read with the contained ECC outcome
+    Read(u64, EccStatus),
+    /// The block at the address is marked bad and was skipped
+    Skipped(u64),
+    /// The refresh of the block at the address went one step further
+    Refreshing(u64),
+    /// The block at the address was refreshed
+    Refreshed(u64),
+}
+
+/// Progress of a refresh
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// The scratch block is erased next
+    Erase { block: usize },
+    /// `page` of `block` is copied to the scratch block next
+    Backup { block: usize, page: usize },
+    /// The journal record is written and `block` erased next
+    Commit { block: usize },
+    /// `page` of the scratch block is copied back to `block` next
+    Restore { block: usize, page: usize },
+}
+
+impl Phase {
+    fn block(self) -> usize {
+        match self {
+            Phase::Erase { block }
+            | Phase::Backup { block, .. }
+            | Phase::Commit { block }
+            | Phase::Restore { block, .. } => block,
+        }
+    }
+}
+
+fn is_block_fail<E: NandFlashError>(e: &E) -> bool {
+    matches!(e.kind(), NandFlashErrorKind::BlockFail(_))
+}
+
+/// ECC outcome of a read, counting a failed block as uncorrectable
+fn outcome<F: ReadNandFlash>(flash: &F, result: &Result<(), F::Error>) -> EccStatus {
+    match result {
+        Err(e) if is_block_fail(e) => EccStatus::Uncorrectable,
+        _ => flash.ecc_status(),
+    }
+}
+
+/// Scrubs the blocks of `F`, for devices of up to `BLOCKS` blocks
+pub struct Scrubber<F, const BLOCKS: usize> {
+    flash: F,
+    config: ScrubConfig,
+    reads: [u32; BLOCKS],
+    corrected: [u32; BLOCKS],
+    blocks: usize,
+    /// Next page to scrub
+    cursor: usize,
+    scratch: usize,
+    journal: usize,
+    /// Refresh in progress
+    phase: Option<Phase>,
+    page: [u8; PAGE_SIZE],
+    spare: [u8; SPARE_SIZE],
+}
+
+type SResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+impl<F, const BLOCKS: usize> Scrubber<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    /// Wrap `flash`, finishing a refresh interrupted by power loss
+    pub fn new(flash: F, config: ScrubConfig) -> SResult<Self, F> {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::SPARE_SIZE <= SPARE_SIZE);
+        assert!(F::SPARE_SIZE >= RECORD_LEN);
+        let blocks = (flash.capacity() / F::ERASE_SIZE as u64) as usize;
+        if blocks > BLOCKS || blocks <= RESERVED_BLOCKS {
+            return Err(Error::TooManyBlocks);
+        }
+        let mut scrubber = Self {
+            flash,
+            config,
+            reads: [0; BLOCKS],
+            corrected: [0; BLOCKS],
+            blocks,
+            cursor: 0,
+            scratch: blocks,
+            journal: blocks,
+            phase: None,
+            page: [0; PAGE_SIZE],
+            spare: [0; SPARE_SIZE],
+        };
+        for journal in scrubber.data_blocks()..blocks {
+            if let Some((block, scratch)) = scrubber.read_record(journal)? {
+                (scrubber.journal, scrubber.scratch) = (journal, scratch);
+                scrubber.erase_block(block)?;
+                scrubber.phase = Some(Phase::Restore { block, page: 0 });
+                scrubber.finish()?;
+                return Ok(scrubber);
+            }
+        }
+        scrubber.journal = scrubber.pick(blocks)?;
+        scrubber.scratch = scrubber.pick(scrubber.journal)?;
+        Ok(scrubber)
+    }
+
+    fn pages_per_block(&self) -> usize {
+        F::ERASE_SIZE / F::WRITE_SIZE
+    }
+
+    /// Blocks usable by the layers above
+    fn data_blocks(&self) -> usize {
+        self.blocks - RESERVED_BLOCKS
+    }
+
+    fn block_address(&self, block: usize) -> u64 {
+        (block * F::ERASE_SIZE) as u64
+    }
+
+    fn page_address(&self, block: usize, page: usize) -> u64 {
+        self.block_address(block) + (page * F::WRITE_SIZE) as u64
+    }
+
+    fn erase_block(&mut self, block: usize) -> SResult<(), F> {
+        let address = self.block_address(block);
+        self.flash
+            .erase(address, address + F::ERASE_SIZE as u64)
+            .map_err(Error::Flash)
+    }
+
+    /// First good reserved block other than `other`, the scratch and the journal block
+    fn pick(&mut self, other: usize) -> SResult<usize, F> {
+        for block in self.data_blocks()..self.blocks {
+            if block == other || block == self.scratch || block == self.journal {
+                continue;
+            }
+            let status = self.flash.block_status(self.block_address(block));
+            if status.map_err(Error::Flash)? != BlockStatus::Failed {
+                return Ok(block);
+            }
+        }
+        Err(Error::NoReservedBlocks)
+    }
+
+    /// Mark the scratch block bad and pick another
+    fn retire_scratch(&mut self) -> SResult<(), F> {
+        let failed = self.scratch;
+        self.flash
+            .mark_bad(self.block_address(failed))
+            .map_err(Error::Flash)?;
+        self.scratch = self.pick(failed)?;
+        Ok(())
+    }
+
+    /// Mark the journal block bad and pick another
+    fn retire_journal(&mut self) -> SResult<(), F> {
+        let failed = self.journal;
+        self.flash
+            .mark_bad(self.block_address(failed))
+            .map_err(Error::Flash)?;
+        self.journal = self.pick(failed)?;
+        Ok(())
+    }
+
+    /// Block being refreshed and the scratch block it was copied to, from the journal record
+    /// in `journal`
+    fn read_record(&mut self, journal: usize) -> SResult<Option<(usize, usize)>, F> {
+        let address = self.block_address(journal);
+        if self.flash.block_status(address).map_err(Error::Flash)? == BlockStatus::Failed {
+            return Ok(None);
+        }
+        let mut spare = [0; RECORD_LEN];
+        match self.flash.read_page(address, &mut [], &mut spare) {
+            Err(e) if is_block_fail(&e) => return Ok(None),
+            result => result.map_err(Error::Flash)?,
+        }
+        let record = &spare[SPARE_META..];
+        let field = |i: usize| {
+            let value = u16::from_le_bytes([record[i], record[i + 1]]);
+            let check = u16::from_le_bytes([record[i + 2], record[i + 3]]);
+            (check == !value).then_some(value as usize)
+        };
+        Ok(match (field(4), field(8)) {
+            (Some(block), Some(scratch))
+                if record[..4] == MAGIC
+                    && block < self.data_blocks()
+                    && (self.data_blocks()..self.blocks).contains(&scratch)
+                    && scratch != journal =>
+            {
+                Some((block, scratch))
+            }
+            _ => None,
+        })
+    }
+
+    /// Erase the journal and write the record naming `block`
+    fn write_record(&mut self, block: usize) -> SResult<(), F> {
+        self.erase_block(self.journal)?;
+        let mut spare = [0xFF; RECORD_LEN];
+        let record = &mut spare[SPARE_META..];
+        record[..4].copy_from_slice(&MAGIC);
+        for (i, value) in [(4, block), (8, self.scratch)] {
+            record[i..i + 2].copy_from_slice(&(value as u16).to_le_bytes());
+            record[i + 2..i + 4].copy_from_slice(&(!(value as u16)).to_le_bytes());
+        }
+        self.flash
+            .write_page(self.block_address(self.journal), &[], &spare)
+            .map_err(Error::Flash)
+    }
+
+    /// Read the page at `address`, returning whether it is programmed
+    fn read_raw(&mut self, address: u64) -> SResult<bool, F> {
+        let (data, spare) = (
+            &mut self.page[..F::WRITE_SIZE],
+            &mut self.spare[..F::SPARE_SIZE],
+        );
+        self.flash
+            .read_page(address, data, spare)
+            .map_err(Error::Flash)?;
+        // programming an erased page would keep it from being written later
+        Ok(data.iter().chain(spare.iter()).any(|b| *b != 0xFF))
+    }
+
+    /// Program the page last read to `address`
+    fn write_raw(&mut self, address: u64) -> SResult<(), F> {
+        self.flash
+            .write_page(
+                address,
+                &self.page[..F::WRITE_SIZE],
+                &self.spare[..F::SPARE_SIZE],
+            )
+            .map_err(Error::Flash)
+    }
+
+    /// Carry out the next step of a refresh, returning the phase that follows
+    fn advance(&mut self, phase: Phase) -> SResult<Option<Phase>, F> {
+        let pages = self.pages_per_block();
+        Ok(match phase {
+            Phase::Erase { block } => match self.erase_block(self.scratch) {
+                Err(e) if is_block_fail(&e) => {
+                    self.retire_scratch()?;
+                    Some(phase)
+                }
+                result => {
+                    result?;
+                    Some(Phase::Backup { block, page: 0 })
+                }
+            },
+            Phase::Backup { block, page } => {
+                if self.read_raw(self.page_address(block, page))? {
+                    match self.write_raw(self.page_address(self.scratch, page)) {
+                        Err(e) if is_block_fail(&e) => {
+                            self.retire_scratch()?;
+                            return Ok(Some(Phase::Erase { block }));
+                        }
+                        result => result?,
+                    }
+                }
+                Some(match page + 1 {
+                    page if page < pages => Phase::Backup { block, page },
+                    _ => Phase::Commit { block },
+                })
+            }
+            Phase::Commit { block } => match self.write_record(block) {
+                Err(e) if is_block_fail(&e) => {
+                    self.retire_journal()?;
+                    Some(phase)
+                }
+                result => {
+                    result?;
+                    self.erase_block(block)?;
+                    Some(Phase::Restore { block, page: 0 })
+                }
+            },
+            Phase::Restore { block, page } => {
+                if self.read_raw(self.page_address(self.scratch, page))? {
+                    self.write_raw(self.page_address(block, page))?;
+                }
+                if page + 1 < pages {
+                    return Ok(Some(Phase::Restore {
+                        block,
+                        page: page + 1,
+                    }));
+                }
+                match self.erase_block(self.journal) {
+                    Err(e) if is_block_fail(&e) => self.retire_journal()?,
+                    result => result?,
+                }
+                self.reads[block] = 0;
+                self.corrected[block] = 0;
+                None
+            }
+        })
+    }
+
+    /// Complete the refresh in progress. A refresh that fails is abandoned.
+    fn finish(&mut self) -> SResult<(), F> {
+        while let Some(phase) = self.phase.take() {
+            self.phase = self.advance(phase)?;
+        }
+        Ok(())
+    }
+
+    /// Before an access to the blocks from `first` to `last`, finish a refresh copying back
+    /// to one of them or cancel one not committed yet if they are `written`
+    fn settle(&mut self, first: usize, last: usize, written: bool) -> SResult<(), F> {
+        match self.phase {
+            Some(phase) if (first..last).contains(&phase.block()) => match phase {
+                Phase::Restore { .. } => self.finish(),
+                _ if written => {
+                    self.phase = None;
+                    Ok(())
+                }
+                _ => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Rewrite the pages of `block` so they hold their charge again, finishing any refresh
+    /// in progress first
+    pub fn refresh(&mut self, block: usize) -> SResult<(), F> {
+        if block >= self.data_blocks() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        self.finish()?;
+        self.phase = Some(Phase::Erase { block });
+        self.finish()
+    }
+
+    /// Count a read of `block` and whether it needed correction
+    fn count(&mut self, block: usize, ecc: EccStatus) {
+        self.reads[block] = self.reads[block].saturating_add(1);
+        if ecc != EccStatus::NoErrors {
+            self.corrected[block] = self.corrected[block].saturating_add(1);
+        }
+    }
+
+    fn needs_refresh(&self, block: usize) -> bool {
+        self.corrected[block] >= self.config.corrected_reads
+            || self.reads[block] >= self.config.reads
+    }
+
+    /// Carry a refresh in progress one step further, or scrub the next page and start
+    /// refreshing its block once the last page has been read if it crossed a threshold. Wraps
+    /// around to the first block at the end of the device.
+    pub fn step(&mut self) -> SResult<Scrub, F> {
+        if let Some(phase) = self.phase.take() {
+            let address = self.block_address(phase.block());
+            self.phase = self.advance(phase)?;
+            return Ok(match self.phase {
+                Some(_) => Scrub::Refreshing(address),
+                None => Scrub::Refreshed(address),
+            });
+        }
+        let pages = self.pages_per_block();
+        let (block, page) = (self.cursor / pages, self.cursor % pages);
+        let address = (self.cursor * F::WRITE_SIZE) as u64;
+        self.cursor = (self.cursor + 1) % (self.data_blocks() * pages);
+        if page == 0
+            && self.flash.block_status(address).map_err(Error::Flash)? == BlockStatus::Failed
+        {
+            self.cursor = ((block + 1) * pages) % (self.data_blocks() * pages);
+            return Ok(Scrub::Skipped(address));
+        }
+        let result = self.flash.read_page(
+            address,
+            &mut self.page[..F::WRITE_SIZE],
+            &mut self.spare[..F::SPARE_SIZE],
+        );
+        let ecc = outcome(&self.flash, &result);
+        match result {
+            Err(e) if ecc != EccStatus::Uncorrectable => return Err(Error::Flash(e)),
+            _ => {}
+        }
+        self.count(block, ecc);
+        // a page that can no longer be read cannot be refreshed, leave it to the layers above
+        if ecc != EccStatus::Uncorrectable && page == pages - 1 && self.needs_refresh(block) {
+            self.phase = Some(Phase::Erase { block });
+        }
+        Ok(Scrub::Read(address, ecc))
+    }
+
+    /// Reads of `block` since it was last erased or refreshed
+    pub fn reads(&self, block: usize) -> u32 {
+        self.reads[block]
+    }
+
+    /// Reads of `block` that needed correction since it was last erased or refreshed
+    pub fn corrected_reads(&self, block: usize) -> u32 {
+        self.corrected[block]
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F, const BLOCKS: usize> ErrorType for Scrubber<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    type Error = Error<F::Error>;
+}
+
+impl<F, const BLOCKS: usize> ReadNandFlash for Scrubber<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let first = offset as usize / F::ERASE_SIZE;
+        let last = (offset as usize + bytes.len()).div_ceil(F::ERASE_SIZE);
+        self.settle(first, last, false)?;
+        let result = self.flash.read(offset, bytes);
+        let ecc = outcome(&self.flash, &result);
+        for block in first..last {
+            self.count(block, ecc);
+        }
+        result.map_err(Error::Flash)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.block_address(self.data_blocks())
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        if address >= self.capacity() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        let block = address as usize / F::ERASE_SIZE;
+        self.settle(block, block + 1, false)?;
+        self.flash.block_status(address).map_err(Error::Flash)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.flash.ecc_status()
+    }
+}
+
+impl<F, const BLOCKS: usize> NandFlash for Scrubber<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        let (first, last) = (from as usize / F::ERASE_SIZE, to as usize / F::ERASE_SIZE);
+        self.settle(first, last, true)?;
+        self.reads[first..last].fill(0);
+        self.corrected[first..last].fill(0);
+        self.flash.erase(from, to).map_err(Error::Flash)
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let first = offset as usize / F::ERASE_SIZE;
+        let last = (offset as usize + bytes.len()).div_ceil(F::ERASE_SIZE);
+        self.settle(first, last, true)?;
+        self.flash.write(offset, bytes).map_err(Error::Flash)
+    }
+}
+
+impl<F, const BLOCKS: usize> SpareNandFlash for Scrubber<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    const SPARE_SIZE: usize = F::SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let block = offset as usize / F::ERASE_SIZE;
+        self.settle(block, block + 1, false)?;
+        let result = self.flash.read_page(offset, data, spare);
+        let ecc = outcome(&self.flash, &result);
+        self.count(block, ecc);
+        result.map_err(Error::Flash)
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let block = offset as usize / F::ERASE_SIZE;
+        self.settle(block, block + 1, true)?;
+        self.flash
+            .write_page(offset, data, spare)
+            .map_err(Error::Flash)
+    }
+
+    fn copy_page(&mut self, from: u64, to: u64, spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, from, 0, spare.len())?;
+        check_page(self, to, 0, spare.len())?;
+        let (from_block, to_block) = (from as usize / F::ERASE_SIZE, to as usize / F::ERASE_SIZE);
+        self.settle(from_block, from_block + 1, false)?;
+        self.settle(to_block, to_block + 1, true)?;
+        self.count(from_block, EccStatus::NoErrors);
+        self.flash.copy_page(from, to, spare).map_err(Error::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::mem::{BLOCK_SIZE, PAGES_PER_BLOCK};
+    use crate::mock::RamFlash;
+    use std::vec::Vec;
+
+    const BLOCKS: usize = 8;
+
+    /// Refresh a block after one corrected read
+    const CONFIG: ScrubConfig = ScrubConfig {
+        corrected_reads: 1,
+        reads: u32::MAX,
+    };
+
+    fn page(seed: u8) -> [u8; PAGE_SIZE] {
+        core::array::from_fn(|i| (i as u8).wrapping_mul(seed) ^ seed)
+    }
+
+    /// Flash with the first pages of block 0 written, the first needing correction
+    fn flash() -> RamFlash {
+        let mut flash = RamFlash::new(BLOCKS);
+        for i in 0..3 {
+            let spare = [0xFF, 0xFF, 0xFF, 0xFF, i];
+            flash
+                .write_page((i as usize * PAGE_SIZE) as u64, &page(i + 1), &spare)
+                .unwrap();
+        }
+        flash.corrected.push(0);
+        flash
+    }
+
+    fn check_block(flash: &mut impl SpareNandFlash) {
+        let (mut data, mut spare) = ([0; PAGE_SIZE], [0; 5]);
+        for i in 0..3 {
+            flash
+                .read_page((i as usize * PAGE_SIZE) as u64, &mut data, &mut spare)
+                .unwrap();
+            assert_eq!((data, spare[4]), (page(i + 1), i));
+        }
+        flash
+            .read_page(3 * PAGE_SIZE as u64, &mut data, &mut [])
+            .unwrap();
+        assert!(data.iter().all(|b| *b == 0xFF));
+    }
+
+    /// Step until the scrubber returns a result other than a read
+    fn step_past_reads<F: SpareNandFlash>(scrubber: &mut Scrubber<F, BLOCKS>) -> Scrub {
+        loop {
+            match scrubber.step().unwrap() {
+                Scrub::Read(..) => continue,
+                other => return other,
+            }
+        }
+    }
+
+    #[test]
+    fn refresh_is_spread_over_steps() {
+        let mut scrubber = Scrubber::<_, BLOCKS>::new(flash(), CONFIG).unwrap();
+        assert_eq!(
+            scrubber.capacity(),
+            ((BLOCKS - RESERVED_BLOCKS) * BLOCK_SIZE) as u64
+        );
+        let mut steps = Vec::new();
+        for _ in 0..PAGES_PER_BLOCK {
+            steps.push(scrubber.step().unwrap());
+        }
+        assert_eq!(steps[0], Scrub::Read(0, EccStatus::Corrected(Some(1))));
+        assert_eq!(scrubber.corrected_reads(0), 1);
+        // erasing the scratch block, a page each way and the commit
+        let mut refreshing = 0;
+        while scrubber.step().unwrap() == Scrub::Refreshing(0) {
+            refreshing += 1;
+        }
+        assert_eq!(refreshing, 2 * PAGES_PER_BLOCK + 1);
+        assert_eq!(scrubber.corrected_reads(0), 0);
+        assert_eq!(scrubber.reads(0), 0);
+        check_block(&mut scrubber);
+
+        let mut flash = scrubber.into_inner();
+        assert!(flash.corrected.is_empty());
+        // the journal is clear and the erased pages can still be written
+        assert!(Scrubber::<_, BLOCKS>::new(&mut flash, CONFIG).is_ok());
+        flash
+            .write_page(3 * PAGE_SIZE as u64, &page(9), &[])
+            .unwrap();
+    }
+
+    #[test]
+    fn interrupted_refresh_is_finished_on_mount() {
+        for cut in 0..16 {
+            let mut flash = flash();
+            let mut scrubber = Scrubber::<_, BLOCKS>::new(&mut flash, CONFIG).unwrap();
+            scrubber.flash.cut_after = Some(cut);
+            let finished = loop {
+                match scrubber.step() {
+                    Ok(Scrub::Refreshed(_)) => break true,
+                    Ok(_) => continue,
+                    Err(_) => break false,
+                }
+            };
+            flash.power_on();
+            let mut scrubber = Scrubber::<_, BLOCKS>::new(&mut flash, CONFIG).unwrap();
+            check_block(&mut scrubber);
+            if finished {
+                // 4 erases, 6 page copies and the journal record
+                assert_eq!(cut, 11);
+                return;
+            }
+        }
+        panic!("the refresh never completed");
+    }
+
+    #[test]
+    fn access_while_copying_back_finishes_refresh() {
+        let mut scrubber = Scrubber::<_, BLOCKS>::new(flash(), CONFIG).unwrap();
+        assert_eq!(step_past_reads(&mut scrubber), Scrub::Refreshing(0));
+        while scrubber.phase != Some(Phase::Restore { block: 0, page: 1 }) {
+            scrubber.step().unwrap();
+        }
+        // the second page is still only in the scratch block
+        check_block(&mut scrubber);
+        assert_eq!(scrubber.phase, None);
+        assert_eq!(scrubber.corrected_reads(0), 0);
+    }
+
+    #[test]
+    fn write_while_copying_cancels_refresh() {
+        let mut scrubber = Scrubber::<_, BLOCKS>::new(flash(), CONFIG).unwrap();
+        assert_eq!(step_past_reads(&mut scrubber), Scrub::Refreshing(0));
+        scrubber.step().unwrap();
+        scrubber.step().unwrap();
+        scrubber
+            .write_page(3 * PAGE_SIZE as u64, &page(4), &[])
+            .unwrap();
+        assert_eq!(scrubber.phase, None);
+        // the block is refreshed again on the next pass, with the new page
+        assert_eq!(step_past_reads(&mut scrubber), Scrub::Refreshing(0));
+        while scrubber.step().unwrap() != Scrub::Refreshed(0) {}
+        let mut data = [0; PAGE_SIZE];
+        scrubber
+            .read_page(3 * PAGE_SIZE as u64, &mut data, &mut [])
+            .unwrap();
+        assert_eq!(data, page(4));
+    }
+
+    #[test]
+    fn reserved_blocks_skip_bad_ones() {
+        let mut flash = flash();
+        let reserved = BLOCKS - RESERVED_BLOCKS;
+        flash.mark_bad((reserved * BLOCK_SIZE) as u64).unwrap();
+        // the scratch block fails to erase and is replaced by the last good one
+        flash.fail_erase.push(reserved + 2);
+        let mut scrubber = Scrubber::<_, BLOCKS>::new(&mut flash, CONFIG).unwrap();
+        assert_eq!(
+            (scrubber.journal, scrubber.scratch),
+            (reserved + 1, reserved + 2)
+        );
+        scrubber.refresh(0).unwrap();
+        assert_eq!(scrubber.scratch, reserved + 3);
+        check_block(&mut scrubber);
+        let status = flash.block_status(((reserved + 2) * BLOCK_SIZE) as u64);
+        assert_eq!(status.unwrap(), BlockStatus::Failed);
+
+        // with a single good reserved block left refreshing is impossible
+        flash.fail_erase.push(reserved + 3);
+        let mut scrubber = Scrubber::<_, BLOCKS>::new(&mut flash, CONFIG).unwrap();
+        assert!(matches!(scrubber.refresh(0), Err(Error::NoReservedBlocks)));
+        check_block(&mut scrubber);
+        flash
+            .mark_bad(((reserved + 1) * BLOCK_SIZE) as u64)
+            .unwrap();
+        assert!(matches!(
+            Scrubber::<_, BLOCKS>::new(&mut flash, CONFIG),
+            Err(Error::NoReservedBlocks)
+        ));
+    }
+}
@@ -0,0 +1,724 @@
+//! Manufacturing self-test of a W25N on its board.
+//!
+//! [`run`] goes through the checks below and collects the outcome of each in a [`Report`], so a
+//! test station can log every result rather than stopping at the first failure:
+//!
+//! - the JEDEC ID and the factory parameter page match a W25N02KV
+//! - Status Register-1 and -2 read back what is written to them
+//! - the OTP area is locked or not as expected
+//! - the tested blocks erase, program and verify with a checkerboard, the inverse checkerboard
+//!   and each word holding its own address, skipping factory bad blocks
+//! - the on chip ECC corrects bits flipped by programming a page raw with ECC-E clear, and
+//!   reports a page with one bit more than it can correct as uncorrectable
+//! - page program and block erase times are within tPROG and tBE
+//!
+//! Testing the whole array erases it and takes several minutes, [`Config::blocks`] limits the
+//! blocks tested. Tested blocks are left erased and the status registers as they were found.
+use core::ops::Range;
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::{
+    mem::{
+        ColumnAddress, PageAddress, BLOCK_COUNT, BLOCK_SIZE, PAGES_PER_BLOCK, PAGE_SIZE,
+        SPARE_META, SPARE_SIZE,
+    },
+    otp::ParameterPage,
+    traits::{BlockStatus, EccStatus, NandFlashError, NandFlashErrorKind, ReadNandFlash},
+    Error, W25N,
+};
+
+/// Bytes of a sector checked by the on chip ECC
+const SECTOR_SIZE: usize = 512;
+
+/// Outcome of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// The device did not behave as expected
+    Fail,
+    /// The check could not run, e.g. without a good block to test on
+    Skipped,
+    /// The device returned an error, [`NandFlashErrorKind::Other`] for bus errors
+    Error(NandFlashErrorKind),
+}
+
+impl Outcome {
+    /// Whether the check did not fail
+    pub fn passed(self) -> bool {
+        matches!(self, Outcome::Pass | Outcome::Skipped)
+    }
+}
+
+impl<SPI> From<Error<SPI>> for Outcome
+where
+    SPI: SpiDevice + core::fmt::Debug,
+{
+    fn from(value: Error<SPI>) -> Self {
+        Outcome::Error(value.kind())
+    }
+}
+
+/// Data written by an array test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// 0x55 on even pages and 0xAA on odd ones, so neighbouring cells hold opposite bits
+    Checkerboard,
+    /// 0xAA on even pages and 0x55 on odd ones
+    InverseCheckerboard,
+    /// Each 32 bit word holds its byte address, little endian
+    AddressInData,
+}
+
+impl Pattern {
+    /// All patterns in the order they are tested
+    pub const ALL: [Pattern; 3] = [
+        Pattern::Checkerboard,
+        Pattern::InverseCheckerboard,
+        Pattern::AddressInData,
+    ];
+
+    /// Fill `page` with the pattern for the page at `address`
+    pub fn fill(self, address: u64, page: &mut [u8]) {
+        let odd = (address / PAGE_SIZE as u64) % 2 == 1;
+        match self {
+            Pattern::Checkerboard => page.fill(if odd { 0xAA } else { 0x55 }),
+            Pattern::InverseCheckerboard => page.fill(if odd { 0x55 } else { 0xAA }),
+            Pattern::AddressInData => {
+                for (i, word) in page.chunks_exact_mut(4).enumerate() {
+                    let at = address as u32 + 4 * i as u32;
+                    word.copy_from_slice(&at.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Outcome of testing the array with one [`Pattern`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatternReport {
+    pub pattern: Pattern,
+    pub outcome: Outcome,
+    /// Blocks that failed to erase, program or verify, and are skipped by later patterns
+    pub failed_blocks: u32,
+    /// Address of the first page or block that failed
+    pub first_failure: Option<u64>,
+    /// Pages the on chip ECC had to correct
+    pub corrected_pages: u32,
+}
+
+/// Times of an operation measured during the array tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// [`Outcome::Fail`] if the longest time exceeds the limit
+    pub outcome: Outcome,
+    /// Limit in microseconds, from the [`Config`] or the parameter page
+    pub limit_us: Option<u64>,
+    /// Operations timed
+    pub samples: u32,
+    pub max_us: u64,
+    pub total_us: u64,
+}
+
+impl Timing {
+    fn new() -> Self {
+        Self {
+            outcome: Outcome::Skipped,
+            limit_us: None,
+            samples: 0,
+            max_us: 0,
+            total_us: 0,
+        }
+    }
+
+    fn add(&mut self, us: u64) {
+        self.samples += 1;
+        self.max_us = self.max_us.max(us);
+        self.total_us += us;
+    }
+
+    /// Mean time in microseconds
+    pub fn average_us(&self) -> u64 {
+        self.total_us.checked_div(self.samples as u64).unwrap_or(0)
+    }
+
+    fn finish(&mut self, limit_us: Option<u64>) {
+        self.limit_us = limit_us;
+        self.outcome = match limit_us {
+            _ if self.samples == 0 => Outcome::Skipped,
+            Some(limit) if self.max_us > limit => Outcome::Fail,
+            _ => Outcome::Pass,
+        };
+    }
+}
+
+/// What the device is expected to be and what to test
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Blocks to run the array tests on
+    pub blocks: Range<usize>,
+    /// Expected JEDEC manufacturer ID
+    pub manufacturer: u8,
+    /// Expected JEDEC device ID
+    pub device: u16,
+    /// Expected state of OTP-L, [None] to only report it
+    pub otp_locked: Option<bool>,
+    /// Most factory bad blocks in [`Config::blocks`], taken from the parameter page if [None]
+    pub max_bad_blocks: Option<u32>,
+    /// Bits per sector the on chip ECC corrects, the parameter page leaves this as 0
+    pub ecc_bits: usize,
+    /// Limit of tPROG in microseconds, taken from the parameter page if [None]
+    pub max_program_us: Option<u64>,
+    /// Limit of tBE in microseconds, taken from the parameter page if [None]
+    pub max_erase_us: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            blocks: 0..BLOCK_COUNT,
+            manufacturer: 0xEF,
+            device: 0xAA22,
+            otp_locked: None,
+            max_bad_blocks: None,
+            ecc_bits: 8,
+            max_program_us: None,
+            max_erase_us: None,
+        }
+    }
+}
+
+/// Outcome of every check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub jedec: Outcome,
+    /// Manufacturer and device ID read
+    pub jedec_id: Option<(u8, u16)>,
+    pub parameter_page: Outcome,
+    pub status_registers: Outcome,
+    pub otp_lock: Outcome,
+    /// State of OTP-L read
+    pub otp_locked: Option<bool>,
+    /// [`Outcome::Fail`] if there are more factory bad blocks than allowed
+    pub bad_blocks: Outcome,
+    /// Factory bad blocks in the tested blocks
+    pub factory_bad_blocks: u32,
+    pub patterns: [PatternReport; 3],
+    pub ecc_injection: Outcome,
+    pub program_time: Timing,
+    pub erase_time: Timing,
+}
+
+impl Report {
+    /// Whether no check failed
+    pub fn passed(&self) -> bool {
+        [
+            self.jedec,
+            self.parameter_page,
+            self.status_registers,
+            self.otp_lock,
+            self.bad_blocks,
+            self.ecc_injection,
+            self.program_time.outcome,
+            self.erase_time.outcome,
+        ]
+        .into_iter()
+        .chain(self.patterns.iter().map(|p| p.outcome))
+        .all(Outcome::passed)
+    }
+
+    /// Record the array tests as not run because of `outcome`
+    fn abort_array(&mut self, outcome: Outcome) {
+        self.bad_blocks = outcome;
+        self.ecc_injection = outcome;
+        for pattern in self.patterns.iter_mut() {
+            pattern.outcome = outcome;
+        }
+    }
+}
+
+/// Blocks not to test, one bit each
+struct BlockSet([u32; BLOCK_COUNT / 32]);
+
+impl BlockSet {
+    fn contains(&self, block: usize) -> bool {
+        self.0[block / 32] & (1 << (block % 32)) != 0
+    }
+
+    fn insert(&mut self, block: usize) {
+        self.0[block / 32] |= 1 << (block % 32);
+    }
+}
+
+/// Run every check on `flash` and report the outcomes.
+/// `now` returns a monotonic time in microseconds for timing program and erase.
+pub fn run<SPI>(flash: &mut W25N<SPI>, config: &Config, mut now: impl FnMut() -> u64) -> Report
+where
+    SPI: SpiDevice + core::fmt::Debug,
+{
+    assert!(config.blocks.end <= BLOCK_COUNT);
+    let mut report = Report {
+        jedec: Outcome::Skipped,
+        jedec_id: None,
+        parameter_page: Outcome::Skipped,
+        status_registers: Outcome::Skipped,
+        otp_lock: Outcome::Skipped,
+        otp_locked: None,
+        bad_blocks: Outcome::Skipped,
+        factory_bad_blocks: 0,
+        patterns: Pattern::ALL.map(|pattern| PatternReport {
+            pattern,
+            outcome: Outcome::Skipped,
+            failed_blocks: 0,
+            first_failure: None,
+            corrected_pages: 0,
+        }),
+        ecc_injection: Outcome::Skipped,
+        program_time: Timing::new(),
+        erase_time: Timing::new(),
+    };
+
+    match flash.jedec() {
+        Ok(jedec) => {
+            report.jedec_id = Some((jedec.manufacturer, jedec.device));
+            let expected = (config.manufacturer, config.device);
+            report.jedec = outcome((jedec.manufacturer, jedec.device) == expected);
+        }
+        Err(e) => report.jedec = e.into(),
+    }
+
+    let parameters = flash.parameter_page();
+    report.parameter_page = match &parameters {
+        Ok(p) => outcome(
+            p.jedec_id == config.manufacturer
+                && p.page_size as usize == PAGE_SIZE
+                && p.spare_size as usize == SPARE_SIZE
+                && p.pages_per_block as usize == PAGES_PER_BLOCK
+                && (p.blocks_per_unit * p.units as u32) as usize == BLOCK_COUNT,
+        ),
+        Err(Error::InvalidParameterPage) => Outcome::Fail,
+        Err(e) => Outcome::Error(e.kind()),
+    };
+    let parameters = parameters.ok();
+
+    // restored at the end, the array tests need the blocks unprotected and ECC enabled
+    let saved = match (flash.read_status_1(), flash.read_status_2()) {
+        (Ok(status_1), Ok(status_2)) => (status_1, status_2),
+        (Err(e), _) | (_, Err(e)) => {
+            report.status_registers = e.into();
+            return report;
+        }
+    };
+    report.status_registers = status_registers(flash).unwrap_or_else(Outcome::from);
+
+    report.otp_locked = Some(saved.1.otp_l());
+    report.otp_lock = match config.otp_locked {
+        Some(locked) => outcome(locked == saved.1.otp_l()),
+        None => Outcome::Pass,
+    };
+
+    let setup = flash
+        .disable_block_protect()
+        .and_then(|()| flash.set_ecc(true));
+    if let Err(e) = setup {
+        report.abort_array(e.into());
+    } else {
+        array(flash, config, parameters.as_ref(), &mut now, &mut report);
+    }
+
+    let limit =
+        |configured: Option<u64>, parameter: Option<u16>| configured.or(parameter.map(u64::from));
+    report.program_time.finish(limit(
+        config.max_program_us,
+        parameters.map(|p| p.max_program_us),
+    ));
+    report.erase_time.finish(limit(
+        config.max_erase_us,
+        parameters.map(|p| p.max_erase_us),
+    ));
+
+    let restore = flash
+        .write_status_1(saved.0)
+        .and_then(|()| flash.write_status_2(saved.1));
+    if let Err(e) = restore {
+        report.status_registers = e.into();
+    }
+    report
+}
+
+fn outcome(pass: bool) -> Outcome {
+    if pass {
+        Outcome::Pass
+    } else {
+        Outcome::Fail
+    }
+}
+
+/// Write values to the protection bits of Status Register-1 and the BUF bit of Status
+/// Register-2 and read them back. The caller restores the registers.
+fn status_registers<SPI>(flash: &mut W25N<SPI>) -> Result<Outcome, Error<SPI>>
+where
+    SPI: SpiDevice + core::fmt::Debug,
+{
+    for (bp, tb) in [(0b1010, true), (0b0101, false)] {
+        let status = flash.read_status_1()?.with_bp(bp).with_tb(tb);
+        flash.write_status_1(status)?;
+        let read = flash.read_status_1()?;
+        if (read.bp(), read.tb()) != (bp, tb) {
+            return Ok(Outcome::Fail);
+        }
+    }
+    for buf in [false, true] {
+        let status = flash.read_status_2()?.with_buf(buf);
+        flash.write_status_2(status)?;
+        if flash.read_status_2()?.buf() != buf {
+            return Ok(Outcome::Fail);
+        }
+    }
+    Ok(Outcome::Pass)
+}
+
+/// Array, ECC and timing tests on the good blocks of `config.blocks`
+fn array<SPI>(
+    flash: &mut W25N<SPI>,
+    config: &Config,
+    parameters: Option<&ParameterPage>,
+    now: &mut impl FnMut() -> u64,
+    report: &mut Report,
+) where
+    SPI: SpiDevice + core::fmt::Debug,
+{
+    let mut factory_bad = BlockSet([0; BLOCK_COUNT / 32]);
+    for block in config.blocks.clone() {
+        match flash.block_status((block * BLOCK_SIZE) as u64) {
+            Ok(BlockStatus::Failed) => {
+                factory_bad.insert(block);
+                report.factory_bad_blocks += 1;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                report.abort_array(e.into());
+                return;
+            }
+        }
+    }
+    let max_bad = config
+        .max_bad_blocks
+        .or(parameters.map(|p| p.max_bad_blocks as u32));
+    report.bad_blocks = outcome(max_bad.is_none_or(|max| report.factory_bad_blocks <= max));
+
+    let mut test = Test {
+        flash,
+        now,
+        program: &mut report.program_time,
+        erase: &mut report.erase_time,
+        page: [0; PAGE_SIZE],
+        read: [0; PAGE_SIZE],
+    };
+    let mut failed = BlockSet([0; BLOCK_COUNT / 32]);
+    let tested = config.blocks.clone().filter(|b| !factory_bad.contains(*b));
+    for result in report.patterns.iter_mut() {
+        result.outcome = Outcome::Pass;
+        for block in tested.clone() {
+            if failed.contains(block) {
+                continue;
+            }
+            match test.pattern(block, result) {
+                Ok(true) => {}
+                Ok(false) => {
+                    failed.insert(block);
+                    result.failed_blocks += 1;
+                    result.outcome = Outcome::Fail;
+                }
+                Err(e) => {
+                    result.outcome = e.into();
+                    break;
+                }
+            }
+        }
+    }
+
+    report.ecc_injection = match tested.clone().find(|b| !failed.contains(*b)) {
+        Some(block) => test
+            .ecc(block, config.ecc_bits)
+            .unwrap_or_else(Outcome::from),
+        None => Outcome::Skipped,
+    };
+
+    // leave the tested blocks erased, failed ones as far as they erase
+    for block in tested {
+        let _ = test.erase(block);
+    }
+}
+
+/// State of the array tests
+struct Test<'a, SPI, N> {
+    flash: &'a mut W25N<SPI>,
+    now: N,
+    program: &'a mut Timing,
+    erase: &'a mut Timing,
+    page: [u8; PAGE_SIZE],
+    read: [u8; PAGE_SIZE],
+}
+
+impl<SPI, N> Test<'_, SPI, N>
+where
+    SPI: SpiDevice + core::fmt::Debug,
+    N: FnMut() -> u64,
+{
+    /// Erase `block`, returning whether it erased
+    fn erase(&mut self, block: usize) -> Result<bool, Error<SPI>> {
+        let pa = PageAddress::from_byte_address((block * BLOCK_SIZE) as u64);
+        self.flash.start_block_erase(pa)?;
+        let start = (self.now)();
+        let result = self.flash.finish_erase(pa);
+        self.erase.add((self.now)().wrapping_sub(start));
+        completed(result)
+    }
+
+    /// Program the page buffer to `address`, returning whether it programmed
+    fn program(&mut self, address: u64, spare: &[u8]) -> Result<bool, Error<SPI>> {
+        let pa = PageAddress::from_byte_address(address);
+        self.flash.load_program_data(0.into(), &self.page)?;
+        if !spare.is_empty() {
+            self.flash
+                .random_load_program_data((PAGE_SIZE as u16).into(), spare)?;
+        }
+        self.flash.start_program_execute(pa)?;
+        let start = (self.now)();
+        let result = self.flash.finish_program(pa);
+        self.program.add((self.now)().wrapping_sub(start));
+        completed(result)
+    }
+
+    /// Erase, program and verify `block` with the pattern of `result`, returning whether it
+    /// passed
+    fn pattern(&mut self, block: usize, result: &mut PatternReport) -> Result<bool, Error<SPI>> {
+        let start = (block * BLOCK_SIZE) as u64;
+        let pages = (start..start + BLOCK_SIZE as u64).step_by(PAGE_SIZE);
+        let mut fail = |address| {
+            result.first_failure.get_or_insert(address);
+            Ok(false)
+        };
+        if !self.erase(block)? {
+            return fail(start);
+        }
+        for address in pages.clone() {
+            result.pattern.fill(address, &mut self.page);
+            if !self.program(address, &[])? {
+                return fail(address);
+            }
+        }
+        for address in pages {
+            result.pattern.fill(address, &mut self.page);
+            match ReadNandFlash::read(self.flash, address, &mut self.read) {
+                Err(Error::Nand(NandFlashErrorKind::BlockFail(_))) => return fail(address),
+                result => result?,
+            }
+            if self.read != self.page {
+                return fail(address);
+            }
+            if self.flash.ecc_status() != EccStatus::NoErrors {
+                result.corrected_pages += 1;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Program page 0 of the erased `block` with ECC, then copy it raw to the following pages
+    /// with 1, `bits` and `bits + 1` bits of its first sector flipped and check the ECC
+    /// corrects the first two and reports the last as uncorrectable
+    fn ecc(&mut self, block: usize, bits: usize) -> Result<Outcome, Error<SPI>> {
+        let start = (block * BLOCK_SIZE) as u64;
+        if !self.erase(block)? {
+            return Ok(Outcome::Fail);
+        }
+        Pattern::AddressInData.fill(start, &mut self.page);
+        let mut spare = [0xFF; SPARE_SIZE];
+        spare[SPARE_META..].fill(0x5A);
+        if !self.program(start, &spare)? {
+            return Ok(Outcome::Fail);
+        }
+        let expected = self.page;
+
+        // raw copy of the page with the parity the device computed
+        self.flash.set_ecc(false)?;
+        let result = self.flip(start, bits, &mut spare);
+        self.flash.set_ecc(true)?;
+        if !result? {
+            return Ok(Outcome::Fail);
+        }
+
+        for (i, want) in [
+            EccStatus::Corrected(None),
+            EccStatus::Corrected(None),
+            EccStatus::Uncorrectable,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let address = start + ((i + 1) * PAGE_SIZE) as u64;
+            let read = ReadNandFlash::read(self.flash, address, &mut self.read);
+            let status = self.flash.ecc_status();
+            let pass = match want {
+                EccStatus::Uncorrectable => status == want,
+                _ => read.is_ok() && status == want && self.read == expected,
+            };
+            if !pass {
+                return Ok(Outcome::Fail);
+            }
+        }
+        Ok(Outcome::Pass)
+    }
+
+    /// With ECC disabled, read the page at `start` raw and program it to the next pages with
+    /// 1, `bits` and `bits + 1` flipped bits, returning whether they programmed
+    fn flip(
+        &mut self,
+        start: u64,
+        bits: usize,
+        spare: &mut [u8; SPARE_SIZE],
+    ) -> Result<bool, Error<SPI>> {
+        let pa = PageAddress::from_byte_address(start);
+        self.flash.page_data_read(pa)?;
+        self.flash.read_data(0.into(), &mut self.read)?;
+        self.flash
+            .read_data(ColumnAddress::new(PAGE_SIZE as u16), spare)?;
+        for (i, flips) in [1, bits, bits + 1].into_iter().enumerate() {
+            self.page = self.read;
+            // spread the flips over the first sector
+            for bit in 0..flips {
+                let at = bit * 8 * SECTOR_SIZE / (bits + 1) + bit % 8;
+                self.page[at / 8] ^= 1 << (at % 8);
+            }
+            let address = start + ((i + 1) * PAGE_SIZE) as u64;
+            if !self.program(address, spare)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Whether an operation succeeded, a failure reported by the device is not an error
+fn completed<SPI>(result: Result<(), Error<SPI>>) -> Result<bool, Error<SPI>>
+where
+    SPI: SpiDevice,
+{
+    match result {
+        Ok(()) => Ok(true),
+        Err(Error::Nand(NandFlashErrorKind::BlockFail(_))) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        commands::RANDOM_PROGRAM_DATA_LOAD,
+        mock::{ChipSpi, RecordingSpi},
+        registers::{Status1, Status2},
+        traits::SpareNandFlash,
+    };
+
+    fn config() -> Config {
+        Config {
+            blocks: 0..3,
+            ..Config::default()
+        }
+    }
+
+    /// Device with registers `run` changes: ECC disabled and a few blocks protected
+    fn chip() -> ChipSpi {
+        let mut spi = ChipSpi::new();
+        spi.status_1 = Status1::new().with_bp(0b0011).with_tb(true).into();
+        spi.status_2 = Status2::new().with_buf(true).into();
+        spi
+    }
+
+    #[test]
+    fn healthy_device_passes() {
+        let mut spi = chip();
+        let saved = (spi.status_1, spi.status_2);
+        let mut flash = W25N::new(
+            &mut spi,
+            (BLOCK_COUNT as u32 * PAGES_PER_BLOCK as u32).into(),
+        );
+        let report = run(&mut flash, &config(), || 0);
+        assert!(report.passed(), "{report:?}");
+        assert_eq!(report.jedec_id, Some((0xEF, 0xAA22)));
+        assert_eq!(report.otp_locked, Some(false));
+        assert_eq!(report.factory_bad_blocks, 0);
+        assert_eq!(report.ecc_injection, Outcome::Pass);
+        assert_eq!((spi.status_1, spi.status_2), saved);
+    }
+
+    #[test]
+    fn failed_program_fails_the_pattern() {
+        let mut spi = chip();
+        let saved = (spi.status_1, spi.status_2);
+        let page = PAGES_PER_BLOCK as u32 + 5;
+        spi.fail_program = Some(page);
+        let mut flash = W25N::new(
+            &mut spi,
+            (BLOCK_COUNT as u32 * PAGES_PER_BLOCK as u32).into(),
+        );
+        let report = run(&mut flash, &config(), || 0);
+        assert!(!report.passed());
+        let first = &report.patterns[0];
+        assert_eq!(first.outcome, Outcome::Fail);
+        assert_eq!(first.failed_blocks, 1);
+        assert_eq!(first.first_failure, Some(page as u64 * PAGE_SIZE as u64));
+        // the failed block is not tested again
+        for pattern in &report.patterns[1..] {
+            assert_eq!((pattern.outcome, pattern.failed_blocks), (Outcome::Pass, 0));
+        }
+        assert_eq!(report.status_registers, Outcome::Pass);
+        assert_eq!(report.ecc_injection, Outcome::Pass);
+        assert_eq!((spi.status_1, spi.status_2), saved);
+    }
+
+    #[test]
+    fn factory_bad_blocks_are_skipped() {
+        let mut spi = ChipSpi::new();
+        let mut flash = W25N::new(
+            &mut spi,
+            (BLOCK_COUNT as u32 * PAGES_PER_BLOCK as u32).into(),
+        );
+        flash.disable_block_protect().unwrap();
+        flash.mark_bad(0).unwrap();
+        let report = run(&mut flash, &config(), || 0);
+        assert!(report.passed(), "{report:?}");
+        assert_eq!(report.factory_bad_blocks, 1);
+        let config = Config {
+            max_bad_blocks: Some(0),
+            ..config()
+        };
+        let report = run(&mut flash, &config, || 0);
+        assert_eq!(report.bad_blocks, Outcome::Fail);
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn ecc_spare_keeps_bad_block_marker() {
+        let mut spi = RecordingSpi::default();
+        let mut flash = W25N::new(&mut spi, (2 * PAGES_PER_BLOCK as u32).into());
+        let (mut program, mut erase) = (Timing::new(), Timing::new());
+        let mut test = Test {
+            flash: &mut flash,
+            now: || 0,
+            program: &mut program,
+            erase: &mut erase,
+            page: [0; PAGE_SIZE],
+            read: [0; PAGE_SIZE],
+        };
+        // the mock reads back erased, only the first program matters here
+        let _ = test.ecc(1, 4);
+        let loads = spi.commands(RANDOM_PROGRAM_DATA_LOAD);
+        let (column, spare) = loads[0].split_at(2);
+        assert_eq!(column, (PAGE_SIZE as u16).to_be_bytes());
+        assert_eq!(spare[..SPARE_META], [0xFF; SPARE_META]);
+        assert!(spare[SPARE_META..].iter().all(|b| *b == 0x5A));
+    }
+}
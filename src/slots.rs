@@ -0,0 +1,902 @@
+//! A/B firmware slots for bootloaders.
+//!
+//! The device is split into two slots of equal size, [`Slot::A`] in the first half and
+//! [`Slot::B`] in the second. The first two blocks of a slot hold its header and the image is
+//! written to the good blocks after them, skipping blocks marked bad and marking blocks that
+//! fail while it is written.
+//!
+//! Every header change is a new record in the spare area of the next page of the header blocks:
+//! the image version, length and SHA-256, a generation that orders the images and the
+//! [`SlotState`] with a count of trial boots. When one header block fills the other is erased
+//! and used, so the latest record is on flash at any point power is lost.
+//!
+//! A new image goes to the slot not holding the newest confirmed image and starts out
+//! [`SlotState::Pending`]. [`SlotManager::boot`], called by the bootloader, picks the newest
+//! image that verifies. Every boot of a pending image is counted, and once it has used up
+//! [`SlotConfig::max_tries`] without the application calling [`SlotManager::confirm`] it is
+//! marked failed and the confirmed image in the other slot boots again.
+use sha2::{Digest, Sha256};
+
+use crate::{
+    crc::Crc32,
+    mem::{PAGE_SIZE, SPARE_META},
+    traits::{BlockStatus, ErrorType, NandFlashError, NandFlashErrorKind, SpareNandFlash},
+};
+
+/// Blocks at the start of each slot holding its header records
+pub const HEADER_BLOCKS: usize = 2;
+
+const MAGIC: [u8; 2] = *b"AB";
+const HEADER_LEN: usize = 56;
+const SPARE_LEN: usize = SPARE_META + HEADER_LEN;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// Not enough good blocks left in the slot
+    NoSpace,
+    /// No update in progress, or no confirmed image to roll back to
+    State,
+    /// Buffer is smaller than the contained number of bytes, one page
+    BufferTooSmall(usize),
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+            Error::BufferTooSmall(_) => NandFlashErrorKind::OutOfBounds,
+            Error::NoSpace | Error::State => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// One of the two firmware slots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    /// The slot that is not this one
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+/// State of the image in a slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotState {
+    /// Written but not yet confirmed by the application
+    Pending,
+    /// Confirmed good by the application
+    Confirmed,
+    /// Failed verification or ran out of trial boots
+    Failed,
+}
+
+impl SlotState {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(SlotState::Pending),
+            2 => Some(SlotState::Confirmed),
+            3 => Some(SlotState::Failed),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            SlotState::Pending => 1,
+            SlotState::Confirmed => 2,
+            SlotState::Failed => 3,
+        }
+    }
+}
+
+/// Header of the image in a slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotInfo {
+    pub state: SlotState,
+    /// Version given when the image was written
+    pub version: u32,
+    /// Image length in bytes
+    pub length: u32,
+    /// SHA-256 of the image
+    pub hash: [u8; 32],
+    /// Increases with each image written, the newest image has the largest
+    pub generation: u32,
+    /// Boots of the image while pending
+    pub tries: u8,
+}
+
+/// A header record and its sequence number among the records of its slot
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    seq: u32,
+    info: SlotInfo,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let info = &self.info;
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..2].copy_from_slice(&MAGIC);
+        bytes[2] = info.state.to_u8();
+        bytes[3] = info.tries;
+        bytes[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[8..12].copy_from_slice(&info.generation.to_le_bytes());
+        bytes[12..16].copy_from_slice(&info.version.to_le_bytes());
+        bytes[16..20].copy_from_slice(&info.length.to_le_bytes());
+        bytes[20..52].copy_from_slice(&info.hash);
+        let mut crc = Crc32::default();
+        crc.update(&bytes[..52]);
+        bytes[52..56].copy_from_slice(&crc.finish().to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut crc = Crc32::default();
+        crc.update(&bytes[..52]);
+        if bytes[0..2] != MAGIC || word(52) != crc.finish() {
+            return None;
+        }
+        Some(Self {
+            seq: word(4),
+            info: SlotInfo {
+                state: SlotState::from_u8(bytes[2])?,
+                version: word(12),
+                length: word(16),
+                hash: bytes[20..52].try_into().unwrap(),
+                generation: word(8),
+                tries: bytes[3],
+            },
+        })
+    }
+}
+
+/// What a header page's spare area holds
+enum Record {
+    Erased,
+    /// Programmed but the header is invalid, torn by power loss
+    Torn,
+    Written(Header),
+}
+
+/// Behaviour of the [`SlotManager`]
+#[derive(Debug, Clone, Copy)]
+pub struct SlotConfig {
+    /// Boots of a pending image before it is marked failed
+    pub max_tries: u8,
+}
+
+impl Default for SlotConfig {
+    fn default() -> Self {
+        Self { max_tries: 3 }
+    }
+}
+
+/// An image being written
+struct Update {
+    slot: Slot,
+    version: u32,
+    length: u32,
+    hasher: Sha256,
+    /// Block of the slot being written and the next page in it
+    block: usize,
+    page: usize,
+    /// Bytes waiting in the page buffer
+    fill: usize,
+}
+
+/// A/B firmware slots over the two halves of `F`
+pub struct SlotManager<F> {
+    flash: F,
+    config: SlotConfig,
+    /// Blocks in each slot
+    slot_blocks: usize,
+    pages_per_block: usize,
+    /// Latest header record of each slot
+    headers: [Option<Header>; 2],
+    /// Header block and page the next record of each slot goes to, [`None`] if both are bad
+    heads: [Option<(usize, usize)>; 2],
+    update: Option<Update>,
+    /// Image block last read and the block of its slot holding it, for reading on from there
+    cursor: Option<(Slot, usize, usize)>,
+    /// Image data waiting to be programmed
+    page: [u8; PAGE_SIZE],
+}
+
+type SResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+fn is_block_fail<E: NandFlashError>(e: &E) -> bool {
+    matches!(e.kind(), NandFlashErrorKind::BlockFail(_))
+}
+
+impl<F> SlotManager<F>
+where
+    F: SpareNandFlash,
+{
+    /// Read the slot headers from `flash`
+    pub fn mount(flash: F, config: SlotConfig) -> SResult<Self, F> {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::SPARE_SIZE >= SPARE_LEN);
+        let slot_blocks = (flash.capacity() / F::ERASE_SIZE as u64) as usize / 2;
+        if slot_blocks <= HEADER_BLOCKS {
+            return Err(Error::NoSpace);
+        }
+        let mut manager = Self {
+            flash,
+            config,
+            slot_blocks,
+            pages_per_block: F::ERASE_SIZE / F::WRITE_SIZE,
+            headers: [None; 2],
+            heads: [None; 2],
+            update: None,
+            cursor: None,
+            page: [0xFF; PAGE_SIZE],
+        };
+        for slot in [Slot::A, Slot::B] {
+            manager.load_header(slot)?;
+        }
+        Ok(manager)
+    }
+
+    /// Find the latest header record of `slot` and where the next one goes
+    fn load_header(&mut self, slot: Slot) -> SResult<(), F> {
+        let mut latest: Option<(Header, usize, usize)> = None;
+        let mut head = None;
+        for h in 0..HEADER_BLOCKS {
+            let block = self.block(slot, h);
+            if self.is_bad(block)? {
+                continue;
+            }
+            // Records are appended in page order, binary search for the first erased page
+            let (mut lo, mut hi) = (0, self.pages_per_block);
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+                match self.record(block, mid)? {
+                    Record::Erased => hi = mid,
+                    _ => lo = mid + 1,
+                }
+            }
+            head = head.or(Some((h, lo)));
+            // the last record may be torn by power loss, take the one before
+            for page in (0..lo).rev() {
+                if let Record::Written(header) = self.record(block, page)? {
+                    if latest.is_none_or(|(l, _, _)| header.seq > l.seq) {
+                        latest = Some((header, h, lo));
+                    }
+                    break;
+                }
+            }
+        }
+        self.headers[slot as usize] = latest.map(|(header, _, _)| header);
+        self.heads[slot as usize] = latest.map(|(_, h, page)| (h, page)).or(head);
+        Ok(())
+    }
+
+    /// Block of the device from a block within `slot`
+    fn block(&self, slot: Slot, block: usize) -> usize {
+        slot as usize * self.slot_blocks + block
+    }
+
+    fn address(&self, block: usize, page: usize) -> u64 {
+        (block * self.pages_per_block + page) as u64 * F::WRITE_SIZE as u64
+    }
+
+    fn is_bad(&mut self, block: usize) -> SResult<bool, F> {
+        let status = self.flash.block_status(self.address(block, 0));
+        Ok(status.map_err(Error::Flash)? == BlockStatus::Failed)
+    }
+
+    fn erase_block(&mut self, block: usize) -> Result<(), F::Error> {
+        let address = self.address(block, 0);
+        self.flash.erase(address, address + F::ERASE_SIZE as u64)
+    }
+
+    fn record(&mut self, block: usize, page: usize) -> SResult<Record, F> {
+        let mut spare = [0; SPARE_LEN];
+        match self
+            .flash
+            .read_page(self.address(block, page), &mut [], &mut spare)
+        {
+            Ok(()) => {}
+            Err(e) if is_block_fail(&e) => return Ok(Record::Torn),
+            Err(e) => return Err(Error::Flash(e)),
+        }
+        let header = &spare[SPARE_META..];
+        Ok(if header.iter().all(|b| *b == 0xFF) {
+            Record::Erased
+        } else {
+            match Header::from_bytes(header) {
+                Some(header) => Record::Written(header),
+                None => Record::Torn,
+            }
+        })
+    }
+
+    /// Append a header record to `slot`
+    fn write_record(&mut self, slot: Slot, info: SlotInfo) -> SResult<(), F> {
+        let index = slot as usize;
+        let header = Header {
+            seq: self.headers[index].map_or(0, |h| h.seq.wrapping_add(1)),
+            info,
+        };
+        let mut spare = [0xFF; SPARE_LEN];
+        spare[SPARE_META..].copy_from_slice(&header.to_bytes());
+        loop {
+            let (h, page) = self.heads[index].ok_or(Error::NoSpace)?;
+            if page == self.pages_per_block {
+                self.next_header_block(slot, h)?;
+                continue;
+            }
+            let block = self.block(slot, h);
+            match self
+                .flash
+                .write_page(self.address(block, page), &[], &spare)
+            {
+                Ok(()) => {
+                    self.heads[index] = Some((h, page + 1));
+                    break;
+                }
+                Err(e) if is_block_fail(&e) => {
+                    let _ = self.flash.mark_bad(self.address(block, 0));
+                    self.heads[index] = Some((h, self.pages_per_block));
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+        self.headers[index] = Some(header);
+        Ok(())
+    }
+
+    /// Erase the header block after the full block `h` to take the next records
+    fn next_header_block(&mut self, slot: Slot, h: usize) -> SResult<(), F> {
+        for i in 1..=HEADER_BLOCKS {
+            let next = (h + i) % HEADER_BLOCKS;
+            let block = self.block(slot, next);
+            if self.is_bad(block)? {
+                continue;
+            }
+            match self.erase_block(block) {
+                Ok(()) => {
+                    self.heads[slot as usize] = Some((next, 0));
+                    return Ok(());
+                }
+                Err(e) if is_block_fail(&e) => {
+                    let _ = self.flash.mark_bad(self.address(block, 0));
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+        self.heads[slot as usize] = None;
+        Err(Error::NoSpace)
+    }
+
+    /// Erase the header blocks of `slot`, leaving it empty
+    fn clear_header(&mut self, slot: Slot) -> SResult<(), F> {
+        self.headers[slot as usize] = None;
+        self.heads[slot as usize] = None;
+        for h in (0..HEADER_BLOCKS).rev() {
+            let block = self.block(slot, h);
+            if self.is_bad(block)? {
+                continue;
+            }
+            match self.erase_block(block) {
+                Ok(()) => self.heads[slot as usize] = Some((h, 0)),
+                Err(e) if is_block_fail(&e) => {
+                    let _ = self.flash.mark_bad(self.address(block, 0));
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+        self.heads[slot as usize].ok_or(Error::NoSpace).map(|_| ())
+    }
+
+    /// First good image block of `slot` from `block` on
+    fn next_image_block(&mut self, slot: Slot, block: usize) -> SResult<Option<usize>, F> {
+        for b in block.max(HEADER_BLOCKS)..self.slot_blocks {
+            if !self.is_bad(self.block(slot, b))? {
+                return Ok(Some(b));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Erase the first good image block of `slot` from `block` on for writing
+    fn erase_next(&mut self, slot: Slot, mut block: usize) -> SResult<usize, F> {
+        loop {
+            block = self.next_image_block(slot, block)?.ok_or(Error::NoSpace)?;
+            match self.erase_block(self.block(slot, block)) {
+                Ok(()) => return Ok(block),
+                Err(e) if is_block_fail(&e) => {
+                    self.cursor = None;
+                    let _ = self
+                        .flash
+                        .mark_bad(self.address(self.block(slot, block), 0));
+                    block += 1;
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+    }
+
+    /// Header of the image in `slot`, [`None`] if the slot is empty
+    pub fn info(&self, slot: Slot) -> Option<SlotInfo> {
+        self.headers[slot as usize].map(|h| h.info)
+    }
+
+    /// Slot of the newest image in one of the `states`
+    fn newest(&self, states: &[SlotState]) -> Option<Slot> {
+        [Slot::A, Slot::B]
+            .into_iter()
+            .filter_map(|slot| self.info(slot).map(|info| (slot, info)))
+            .filter(|(_, info)| states.contains(&info.state))
+            .max_by_key(|(_, info)| info.generation)
+            .map(|(slot, _)| slot)
+    }
+
+    /// Slot the next update is written to, the one not holding the newest confirmed image
+    pub fn update_slot(&self) -> Slot {
+        match self.newest(&[SlotState::Confirmed]) {
+            Some(slot) => slot.other(),
+            None => self
+                .newest(&[SlotState::Pending])
+                .map_or(Slot::A, Slot::other),
+        }
+    }
+
+    /// Start writing a new image to [`SlotManager::update_slot`], erasing the old image there.
+    /// Any update already in progress is abandoned.
+    pub fn begin_update(&mut self, version: u32) -> SResult<Slot, F> {
+        self.update = None;
+        let slot = self.update_slot();
+        self.cursor = None;
+        self.clear_header(slot)?;
+        self.page.fill(0xFF);
+        self.update = Some(Update {
+            slot,
+            version,
+            length: 0,
+            hasher: Sha256::new(),
+            block: HEADER_BLOCKS - 1,
+            page: self.pages_per_block,
+            fill: 0,
+        });
+        Ok(slot)
+    }
+
+    /// Append `data` to the image being written. An error abandons the update.
+    pub fn write_image(&mut self, mut data: &[u8]) -> SResult<(), F> {
+        let mut update = self.update.take().ok_or(Error::State)?;
+        update.length = u32::try_from(data.len())
+            .ok()
+            .and_then(|len| update.length.checked_add(len))
+            .ok_or(Error::NoSpace)?;
+        update.hasher.update(data);
+        while !data.is_empty() {
+            let n = data.len().min(F::WRITE_SIZE - update.fill);
+            self.page[update.fill..update.fill + n].copy_from_slice(&data[..n]);
+            update.fill += n;
+            data = &data[n..];
+            if update.fill == F::WRITE_SIZE {
+                self.program(&mut update)?;
+            }
+        }
+        self.update = Some(update);
+        Ok(())
+    }
+
+    /// Program the page buffer to the next page of the image
+    fn program(&mut self, update: &mut Update) -> SResult<(), F> {
+        loop {
+            if update.page == self.pages_per_block {
+                update.block = self.erase_next(update.slot, update.block + 1)?;
+                update.page = 0;
+            }
+            let block = self.block(update.slot, update.block);
+            let address = self.address(block, update.page);
+            match self
+                .flash
+                .write_page(address, &self.page[..F::WRITE_SIZE], &[])
+            {
+                Ok(()) => break,
+                // move the pages already written to the failing block to a fresh one
+                Err(e) if is_block_fail(&e) => {
+                    let failed = block;
+                    update.block = self.erase_next(update.slot, update.block + 1)?;
+                    let block = self.block(update.slot, update.block);
+                    for page in 0..update.page {
+                        self.flash
+                            .copy_page(self.address(failed, page), self.address(block, page), &[])
+                            .map_err(Error::Flash)?;
+                    }
+                    self.cursor = None;
+                    let _ = self.flash.mark_bad(self.address(failed, 0));
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+        update.page += 1;
+        update.fill = 0;
+        self.page.fill(0xFF);
+        Ok(())
+    }
+
+    /// Program the rest of the image and mark it pending, returning its header
+    pub fn finish_update(&mut self) -> SResult<SlotInfo, F> {
+        let mut update = self.update.take().ok_or(Error::State)?;
+        if update.fill > 0 {
+            self.program(&mut update)?;
+        }
+        let generation = self
+            .headers
+            .iter()
+            .flatten()
+            .map(|h| h.info.generation.wrapping_add(1))
+            .max()
+            .unwrap_or(0);
+        let info = SlotInfo {
+            state: SlotState::Pending,
+            version: update.version,
+            length: update.length,
+            hash: update.hasher.finalize().into(),
+            generation,
+            tries: 0,
+        };
+        self.write_record(update.slot, info)?;
+        Ok(info)
+    }
+
+    /// Read the image in `slot` from `offset`, which must be page aligned like the length of
+    /// `buf`. Runs of pages are read with [`ReadNandFlash::read_continuous`].
+    ///
+    /// [`ReadNandFlash::read_continuous`]: crate::traits::ReadNandFlash::read_continuous
+    pub fn read_image(&mut self, slot: Slot, offset: u64, mut buf: &mut [u8]) -> SResult<(), F> {
+        let size = F::WRITE_SIZE;
+        if !offset.is_multiple_of(size as u64) || !buf.len().is_multiple_of(size) {
+            return Err(Error::Nand(NandFlashErrorKind::NotAligned));
+        }
+        let page = offset as usize / size;
+        let index = page / self.pages_per_block;
+        // carry on from the block last read rather than skipping bad blocks from the start
+        let (mut i, mut block) = match self.cursor {
+            Some((s, i, block)) if s == slot && i <= index => (i, block),
+            _ => (
+                0,
+                self.next_image_block(slot, HEADER_BLOCKS)?
+                    .ok_or(NandFlashErrorKind::OutOfBounds)?,
+            ),
+        };
+        while i < index {
+            block = self
+                .next_image_block(slot, block + 1)?
+                .ok_or(NandFlashErrorKind::OutOfBounds)?;
+            i += 1;
+        }
+        let mut page = page % self.pages_per_block;
+        loop {
+            self.cursor = Some((slot, i, block));
+            let count = (buf.len() / size).min(self.pages_per_block - page);
+            let (run, rest) = buf.split_at_mut(count * size);
+            let address = self.address(self.block(slot, block), page);
+            self.flash
+                .read_continuous(address, run)
+                .map_err(Error::Flash)?;
+            buf = rest;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            block = self
+                .next_image_block(slot, block + 1)?
+                .ok_or(NandFlashErrorKind::OutOfBounds)?;
+            i += 1;
+            page = 0;
+        }
+    }
+
+    /// Check the image in `slot` against the hash in its header, streaming it through `buf`
+    /// which must hold at least a page. The larger the buffer the longer the continuous reads.
+    pub fn verify(&mut self, slot: Slot, buf: &mut [u8]) -> SResult<bool, F> {
+        let Some(info) = self.info(slot) else {
+            return Ok(false);
+        };
+        let chunk = buf.len() - buf.len() % F::WRITE_SIZE;
+        if chunk == 0 {
+            return Err(Error::BufferTooSmall(F::WRITE_SIZE));
+        }
+        let mut hasher = Sha256::new();
+        let (mut offset, length) = (0, info.length as usize);
+        while offset < length {
+            let len = chunk.min((length - offset).next_multiple_of(F::WRITE_SIZE));
+            match self.read_image(slot, offset as u64, &mut buf[..len]) {
+                Ok(()) => {}
+                // an uncorrectable page or missing blocks
+                Err(e)
+                    if matches!(e.kind(), NandFlashErrorKind::BlockFail(_))
+                        || matches!(e, Error::Nand(NandFlashErrorKind::OutOfBounds)) =>
+                {
+                    return Ok(false)
+                }
+                Err(e) => return Err(e),
+            }
+            hasher.update(&buf[..len.min(length - offset)]);
+            offset += len;
+        }
+        Ok(hasher.finalize()[..] == info.hash)
+    }
+
+    /// Pick the image to boot, for the bootloader.
+    ///
+    /// Tries the newest pending or confirmed image first. A pending image has the boot counted
+    /// and is marked failed once it used up its tries, and any image that does not verify is
+    /// marked failed, falling back to the other slot. Returns [`None`] if neither slot holds a
+    /// bootable image. `buf` is used to verify the image, see [`SlotManager::verify`].
+    pub fn boot(&mut self, buf: &mut [u8]) -> SResult<Option<(Slot, SlotInfo)>, F> {
+        while let Some(slot) = self.newest(&[SlotState::Pending, SlotState::Confirmed]) {
+            let mut info = self.info(slot).expect("slot has an image");
+            if info.state == SlotState::Pending {
+                if info.tries >= self.config.max_tries {
+                    info.state = SlotState::Failed;
+                    self.write_record(slot, info)?;
+                    continue;
+                }
+                info.tries += 1;
+                self.write_record(slot, info)?;
+            }
+            if self.verify(slot, buf)? {
+                return Ok(Some((slot, info)));
+            }
+            info.state = SlotState::Failed;
+            self.write_record(slot, info)?;
+        }
+        Ok(None)
+    }
+
+    /// Confirm the image on trial, for the application once it has booted and runs well.
+    /// Does nothing if the newest image is not a pending image that has been booted.
+    pub fn confirm(&mut self) -> SResult<(), F> {
+        let Some(slot) = self.newest(&[SlotState::Pending, SlotState::Confirmed]) else {
+            return Ok(());
+        };
+        let mut info = self.info(slot).expect("slot has an image");
+        if info.state == SlotState::Pending && info.tries > 0 {
+            info.state = SlotState::Confirmed;
+            self.write_record(slot, info)?;
+        }
+        Ok(())
+    }
+
+    /// Mark the newest image failed so the next boot goes back to the confirmed image in the
+    /// other slot. Returns [`Error::State`] if there is no confirmed image to go back to.
+    pub fn rollback(&mut self) -> SResult<Slot, F> {
+        let slot = self
+            .newest(&[SlotState::Pending, SlotState::Confirmed])
+            .ok_or(Error::State)?;
+        if self.info(slot.other()).map(|info| info.state) != Some(SlotState::Confirmed) {
+            return Err(Error::State);
+        }
+        let mut info = self.info(slot).expect("slot has an image");
+        info.state = SlotState::Failed;
+        self.write_record(slot, info)?;
+        Ok(slot.other())
+    }
+
+    /// Return the underlying flash
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::mem::{BLOCK_SIZE, PAGES_PER_BLOCK, SPARE_SIZE};
+    use crate::mock::RamFlash;
+    use crate::traits::ReadNandFlash;
+    use std::vec::Vec;
+
+    /// Blocks of the device, 8 in each slot
+    const BLOCKS: usize = 16;
+    const SLOT_BLOCKS: usize = BLOCKS / 2;
+
+    fn image(seed: u8, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    fn install(manager: &mut SlotManager<&mut RamFlash>, version: u32, data: &[u8]) -> Slot {
+        let slot = manager.begin_update(version).unwrap();
+        // uneven writes across page boundaries
+        for part in data.chunks(1000) {
+            manager.write_image(part).unwrap();
+        }
+        manager.finish_update().unwrap();
+        slot
+    }
+
+    /// Boot and confirm the newest image
+    fn boot_confirmed(manager: &mut SlotManager<&mut RamFlash>) -> Slot {
+        let mut buf = [0; PAGE_SIZE];
+        let (slot, _) = manager.boot(&mut buf).unwrap().unwrap();
+        manager.confirm().unwrap();
+        slot
+    }
+
+    /// Address of the last header record written to `slot`
+    fn last_record(flash: &mut RamFlash, slot: Slot) -> u64 {
+        let mut last = None;
+        for h in 0..HEADER_BLOCKS {
+            for page in 0..PAGES_PER_BLOCK {
+                let block = slot as usize * SLOT_BLOCKS + h;
+                let address = (block * BLOCK_SIZE + page * PAGE_SIZE) as u64;
+                let mut spare = [0; SPARE_SIZE];
+                // an unreadable page was programmed
+                let read = flash.read_page(address, &mut [], &mut spare);
+                if read.is_err() || spare[SPARE_META..SPARE_LEN].iter().any(|b| *b != 0xFF) {
+                    last = Some(address);
+                }
+            }
+        }
+        last.unwrap()
+    }
+
+    #[test]
+    fn update_is_confirmed_after_trial_boot() {
+        let mut flash = RamFlash::new(BLOCKS);
+        let mut manager = SlotManager::mount(&mut flash, SlotConfig::default()).unwrap();
+        let data = image(1, 3 * PAGE_SIZE + 100);
+        assert_eq!(install(&mut manager, 1, &data), Slot::A);
+        let info = manager.info(Slot::A).unwrap();
+        assert_eq!(
+            (info.state, info.length, info.tries),
+            (SlotState::Pending, 3 * PAGE_SIZE as u32 + 100, 0)
+        );
+        assert_eq!(info.hash[..], Sha256::digest(&data)[..]);
+        // confirming before the image booted does nothing
+        manager.confirm().unwrap();
+        assert_eq!(manager.info(Slot::A).unwrap().state, SlotState::Pending);
+
+        assert_eq!(boot_confirmed(&mut manager), Slot::A);
+        let mut manager = SlotManager::mount(&mut flash, SlotConfig::default()).unwrap();
+        let info = manager.info(Slot::A).unwrap();
+        assert_eq!((info.state, info.tries), (SlotState::Confirmed, 1));
+        // confirmed images boot without counting tries
+        let mut buf = [0; 2 * PAGE_SIZE];
+        assert_eq!(manager.boot(&mut buf).unwrap(), Some((Slot::A, info)));
+        assert_eq!(manager.info(Slot::A), Some(info));
+        assert_eq!(manager.update_slot(), Slot::B);
+        assert!(manager.info(Slot::B).is_none());
+    }
+
+    #[test]
+    fn unconfirmed_update_expires_and_rolls_back() {
+        let mut flash = RamFlash::new(BLOCKS);
+        let config = SlotConfig { max_tries: 2 };
+        let mut manager = SlotManager::mount(&mut flash, config).unwrap();
+        install(&mut manager, 1, &image(1, 5000));
+        boot_confirmed(&mut manager);
+        assert_eq!(install(&mut manager, 2, &image(2, 7000)), Slot::B);
+
+        let mut buf = [0; PAGE_SIZE];
+        for tries in 1..=2 {
+            // every boot is a reset, the count survives remounting
+            let mut manager = SlotManager::mount(&mut flash, config).unwrap();
+            let (slot, info) = manager.boot(&mut buf).unwrap().unwrap();
+            assert_eq!(
+                (slot, info.state, info.tries),
+                (Slot::B, SlotState::Pending, tries)
+            );
+        }
+        let mut manager = SlotManager::mount(&mut flash, config).unwrap();
+        let (slot, info) = manager.boot(&mut buf).unwrap().unwrap();
+        assert_eq!((slot, info.version), (Slot::A, 1));
+        assert_eq!(manager.info(Slot::B).unwrap().state, SlotState::Failed);
+
+        // a confirmed update can still be rolled back to the other confirmed image
+        assert_eq!(install(&mut manager, 3, &image(3, 100)), Slot::B);
+        assert_eq!(boot_confirmed(&mut manager), Slot::B);
+        assert_eq!(manager.rollback().unwrap(), Slot::A);
+        let (slot, info) = manager.boot(&mut buf).unwrap().unwrap();
+        assert_eq!((slot, info.version), (Slot::A, 1));
+        // with the other image failed there is nothing to go back to
+        assert!(matches!(manager.rollback(), Err(Error::State)));
+    }
+
+    #[test]
+    fn torn_state_record_keeps_the_previous_one() {
+        let mut flash = RamFlash::new(BLOCKS);
+        let mut manager = SlotManager::mount(&mut flash, SlotConfig::default()).unwrap();
+        install(&mut manager, 1, &image(1, 5000));
+        boot_confirmed(&mut manager);
+        install(&mut manager, 2, &image(2, 5000));
+        let mut buf = [0; PAGE_SIZE];
+        manager.boot(&mut buf).unwrap().unwrap();
+        let trial = manager.info(Slot::B).unwrap();
+        manager.confirm().unwrap();
+
+        // the confirm record fails ECC after power loss, the one before stands
+        let confirm = last_record(&mut flash, Slot::B);
+        flash.uncorrectable.push(confirm);
+        let mut manager = SlotManager::mount(&mut flash, SlotConfig::default()).unwrap();
+        assert_eq!(manager.info(Slot::B), Some(trial));
+
+        // or only part of it was programmed
+        manager.confirm().unwrap();
+        let torn = last_record(&mut flash, Slot::B) + PAGE_SIZE as u64;
+        let mut spare = [0xFF; SPARE_LEN];
+        spare[SPARE_META..SPARE_META + 4].copy_from_slice(b"AB\x03\x01");
+        flash.write_page(torn, &[], &spare).unwrap();
+        let mut manager = SlotManager::mount(&mut flash, SlotConfig::default()).unwrap();
+        assert_eq!(manager.info(Slot::B).unwrap().state, SlotState::Confirmed);
+
+        // later records go after the torn one
+        manager.rollback().unwrap();
+        assert_eq!(last_record(&mut flash, Slot::B), torn + PAGE_SIZE as u64);
+        let manager = SlotManager::mount(&mut flash, SlotConfig::default()).unwrap();
+        assert_eq!(manager.info(Slot::B).unwrap().state, SlotState::Failed);
+    }
+
+    #[test]
+    fn image_spans_bad_blocks() {
+        let mut flash = RamFlash::new(BLOCKS);
+        let block = |b: usize| (b * BLOCK_SIZE) as u64;
+        flash.mark_bad(block(3)).unwrap();
+        flash.fail_program = Some(block(4) + 10 * PAGE_SIZE as u64);
+        flash.fail_erase.push(6);
+        let mut manager = SlotManager::mount(&mut flash, SlotConfig::default()).unwrap();
+        // blocks 2, 5 and 7 after 3 was bad, 4 failed to program and 6 to erase
+        let data = image(7, 2 * BLOCK_SIZE + 5 * PAGE_SIZE);
+        assert_eq!(install(&mut manager, 1, &data), Slot::A);
+        let mut buf = [0; 3 * PAGE_SIZE];
+        assert!(manager.verify(Slot::A, &mut buf).unwrap());
+        for offset in [0, BLOCK_SIZE - PAGE_SIZE, 2 * BLOCK_SIZE, PAGE_SIZE] {
+            manager
+                .read_image(Slot::A, offset as u64, &mut buf)
+                .unwrap();
+            assert_eq!(buf[..], data[offset..offset + buf.len()]);
+        }
+        // beyond the last good block of the slot
+        let err = manager.read_image(Slot::A, 5 * BLOCK_SIZE as u64, &mut buf);
+        assert!(matches!(
+            err,
+            Err(Error::Nand(NandFlashErrorKind::OutOfBounds))
+        ));
+        let mut flash = manager.into_inner();
+        for b in [3, 4, 6] {
+            assert_eq!(flash.block_status(block(b)).unwrap(), BlockStatus::Failed);
+        }
+
+        // verifying a page at a time finds each block once
+        let mut manager = SlotManager::mount(&mut flash, SlotConfig::default()).unwrap();
+        let mut page = [0; PAGE_SIZE];
+        manager.read_image(Slot::B, 0, &mut page).unwrap();
+        let statuses = manager.flash.statuses;
+        assert!(manager.verify(Slot::A, &mut page).unwrap());
+        assert!(manager.flash.statuses - statuses < SLOT_BLOCKS);
+        // a page that cannot be read fails verification
+        manager
+            .flash
+            .uncorrectable
+            .push(block(5) + PAGE_SIZE as u64);
+        assert!(!manager.verify(Slot::A, &mut buf).unwrap());
+    }
+}
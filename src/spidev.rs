@@ -0,0 +1,187 @@
+//! SPI device over Linux spidev for running the driver on a host.
+//!
+//! [`Spidev`] implements [`SpiDevice`] on a `/dev/spidevB.C` character device, so [`crate::W25N`]
+//! and everything built on it can drive a real chip from e.g. a Raspberry Pi test jig.
+//!
+//! The spidev driver rejects messages longer than its buffer, `bufsiz` in the module parameters
+//! and 4096 bytes by default. Operations are split into transfers of at most that size and
+//! messages into several ioctls, keeping chip select asserted between them with `cs_change` so
+//! the device still sees a single transaction, like a page read of [`crate::mem::PAGE_SIZE`]
+//! plus spare or a continuous read of many pages.
+use std::{fs, io, path::Path};
+
+use ::spidev::{SpiModeFlags, SpidevOptions, SpidevTransfer};
+use embedded_hal::spi::{self, ErrorKind, Operation, SpiDevice};
+
+/// spidev buffer size when it cannot be read from the module parameters
+pub const DEFAULT_MAX_TRANSFER: usize = 4096;
+/// Most transfers in one ioctl, the size of the request must fit in 14 bits
+const MAX_TRANSFERS: usize = (1 << 14) / core::mem::size_of::<SpidevTransfer>() - 1;
+
+const BUFSIZ: &str = "/sys/module/spidev/parameters/bufsiz";
+
+#[derive(Debug)]
+pub struct Error(pub io::Error);
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "spidev error: {}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl spi::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// A spidev device with its own chip select
+#[derive(Debug)]
+pub struct Spidev {
+    spi: ::spidev::Spidev,
+    /// Most bytes in one ioctl
+    max_transfer: usize,
+}
+
+impl Spidev {
+    /// Open `path` in SPI mode 0 with 8 bit words at `speed_hz`, the W25N supports modes 0 and 3
+    pub fn open(path: impl AsRef<Path>, speed_hz: u32) -> Result<Self, Error> {
+        let mut spi = ::spidev::Spidev::open(path).map_err(Error)?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(speed_hz)
+            .mode(SpiModeFlags::SPI_MODE_0)
+            .build();
+        spi.configure(&options).map_err(Error)?;
+        Ok(Self::new(spi))
+    }
+
+    /// Use an already configured device, with the buffer size of the loaded spidev module
+    pub fn new(spi: ::spidev::Spidev) -> Self {
+        let max_transfer = fs::read_to_string(BUFSIZ)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(DEFAULT_MAX_TRANSFER);
+        Self { spi, max_transfer }
+    }
+
+    /// Limit each ioctl to `max_transfer` bytes, for controllers with smaller limits than spidev
+    pub fn with_max_transfer(mut self, max_transfer: usize) -> Self {
+        assert!(max_transfer > 0);
+        self.max_transfer = max_transfer;
+        self
+    }
+
+    /// Most bytes in one ioctl
+    pub fn max_transfer(&self) -> usize {
+        self.max_transfer
+    }
+
+    /// Return the underlying spidev device
+    pub fn into_inner(self) -> ::spidev::Spidev {
+        self.spi
+    }
+}
+
+/// Transfers of one transaction, sent in ioctls of at most `max_transfer` bytes
+struct Message<'a, 'b> {
+    spi: &'a ::spidev::Spidev,
+    max_transfer: usize,
+    transfers: Vec<SpidevTransfer<'b, 'b>>,
+    /// Bytes in `transfers`
+    len: usize,
+}
+
+impl<'b> Message<'_, 'b> {
+    fn push(&mut self, transfer: SpidevTransfer<'b, 'b>, len: usize) -> Result<(), Error> {
+        if self.len + len > self.max_transfer || self.transfers.len() == MAX_TRANSFERS {
+            // more follows, keep the device selected until the next ioctl
+            if let Some(last) = self.transfers.last_mut() {
+                last.cs_change = 1;
+            }
+            self.flush()?;
+        }
+        self.transfers.push(transfer);
+        self.len += len;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.transfers.is_empty() {
+            self.spi
+                .transfer_multiple(&mut self.transfers)
+                .map_err(Error)?;
+        }
+        self.transfers.clear();
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl spi::ErrorType for Spidev {
+    type Error = Error;
+}
+
+impl SpiDevice for Spidev {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        // spidev transfers cannot read into the buffer they write from
+        let sources: Vec<Vec<u8>> = operations
+            .iter()
+            .filter_map(|op| match op {
+                Operation::TransferInPlace(buf) => Some(buf.to_vec()),
+                _ => None,
+            })
+            .collect();
+        let mut sources = sources.iter();
+        let size = self.max_transfer;
+        let mut message = Message {
+            spi: &self.spi,
+            max_transfer: size,
+            transfers: Vec::new(),
+            len: 0,
+        };
+        for op in operations.iter_mut() {
+            match op {
+                Operation::Read(buf) => {
+                    for chunk in buf.chunks_mut(size) {
+                        let len = chunk.len();
+                        message.push(SpidevTransfer::read(chunk), len)?;
+                    }
+                }
+                Operation::Write(buf) => {
+                    for chunk in buf.chunks(size) {
+                        message.push(SpidevTransfer::write(chunk), chunk.len())?;
+                    }
+                }
+                Operation::Transfer(read, write) => {
+                    let common = read.len().min(write.len());
+                    let (read, rest_read) = read.split_at_mut(common);
+                    let (write, rest_write) = write.split_at(common);
+                    for (rx, tx) in read.chunks_mut(size).zip(write.chunks(size)) {
+                        message.push(SpidevTransfer::read_write(tx, rx), tx.len())?;
+                    }
+                    for chunk in rest_read.chunks_mut(size) {
+                        let len = chunk.len();
+                        message.push(SpidevTransfer::read(chunk), len)?;
+                    }
+                    for chunk in rest_write.chunks(size) {
+                        message.push(SpidevTransfer::write(chunk), chunk.len())?;
+                    }
+                }
+                Operation::TransferInPlace(buf) => {
+                    let source = sources.next().unwrap();
+                    for (rx, tx) in buf.chunks_mut(size).zip(source.chunks(size)) {
+                        message.push(SpidevTransfer::read_write(tx, rx), tx.len())?;
+                    }
+                }
+                Operation::DelayNs(ns) => {
+                    let us = ns.div_ceil(1000).min(u16::MAX as u32) as u16;
+                    message.push(SpidevTransfer::delay(us), 0)?;
+                }
+            }
+        }
+        message.flush()
+    }
+}
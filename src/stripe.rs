@@ -0,0 +1,313 @@
+//! Several chips on independent buses with their pages interleaved.
+//!
+//! [`Stripe`] spreads consecutive pages over its chips in turn, page `p` lives on chip `p % N` at
+//! page `p / N`. A multi page write loads the next chip's buffer while the chips before it are
+//! still programming, and an erase starts on every chip before waiting for any, so the busy time
+//! of the chips overlaps and writes run up to `N` times faster than on a single chip. For that
+//! each chip needs its own bus, chips sharing a bus only save the status polling.
+//!
+//! An erase block of the stripe is the block with the same number on every chip, it is bad if
+//! it is bad on any of them.
+use embedded_hal::spi::SpiDevice;
+
+use crate::{
+    mem::{PageAddress, BLOCK_SIZE, PAGE_SIZE, SPARE_SIZE},
+    traits::{
+        check_erase, check_page, check_read, check_write, BlockStatus, EccStatus, ErrorType,
+        NandFlash, NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+    W25N,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from one of the chips
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+        }
+    }
+}
+
+/// `N` chips with their pages interleaved
+pub struct Stripe<SPI, const N: usize> {
+    chips: [W25N<SPI>; N],
+    /// Worst ECC status over the chips of the last read
+    ecc: EccStatus,
+}
+
+type SResult<T, SPI> = Result<T, Error<crate::Error<SPI>>>;
+
+impl<SPI, const N: usize> Stripe<SPI, N>
+where
+    SPI: SpiDevice + core::fmt::Debug,
+{
+    pub fn new(chips: [W25N<SPI>; N]) -> Self {
+        assert!(N > 0);
+        Self {
+            chips,
+            ecc: EccStatus::NoErrors,
+        }
+    }
+
+    /// Chip holding the page at `offset` and the page address within it
+    fn locate(offset: u64) -> (usize, PageAddress) {
+        let page = offset / PAGE_SIZE as u64;
+        let chip_page = page / N as u64;
+        (
+            (page % N as u64) as usize,
+            PageAddress::from_byte_address(chip_page * PAGE_SIZE as u64),
+        )
+    }
+
+    /// Wait for every operation started in `pending`, returning the first error
+    fn finish(
+        &mut self,
+        pending: &mut [Option<PageAddress>; N],
+        erase: bool,
+        mut result: SResult<(), SPI>,
+    ) -> SResult<(), SPI> {
+        for (chip, pa) in self.chips.iter_mut().zip(pending.iter_mut()) {
+            if let Some(pa) = pa.take() {
+                let done = if erase {
+                    chip.finish_erase(pa)
+                } else {
+                    chip.finish_program(pa)
+                };
+                if result.is_ok() {
+                    result = done.map_err(Error::Flash);
+                }
+            }
+        }
+        result
+    }
+
+    /// Program the pages of `bytes` from `offset`, only waiting for a chip when its next page
+    /// is due
+    fn write_pages(
+        &mut self,
+        offset: u64,
+        bytes: &[u8],
+        pending: &mut [Option<PageAddress>; N],
+    ) -> SResult<(), SPI> {
+        for (i, page) in bytes.chunks_exact(PAGE_SIZE).enumerate() {
+            let (index, pa) = Self::locate(offset + (i * PAGE_SIZE) as u64);
+            let chip = &mut self.chips[index];
+            if let Some(busy) = pending[index].take() {
+                chip.finish_program(busy).map_err(Error::Flash)?;
+            }
+            chip.load_program_data(0.into(), page)
+                .map_err(Error::Flash)?;
+            chip.start_program_execute(pa).map_err(Error::Flash)?;
+            pending[index] = Some(pa);
+        }
+        Ok(())
+    }
+
+    /// Start erasing the blocks from `first` to `last` on every chip, only waiting for a chip
+    /// when its next block is due
+    fn erase_blocks(
+        &mut self,
+        first: u64,
+        last: u64,
+        pending: &mut [Option<PageAddress>; N],
+    ) -> SResult<(), SPI> {
+        for block in first..last {
+            let pa = PageAddress::from_byte_address(block * BLOCK_SIZE as u64);
+            for (chip, busy) in self.chips.iter_mut().zip(pending.iter_mut()) {
+                if let Some(busy) = busy.take() {
+                    chip.finish_erase(busy).map_err(Error::Flash)?;
+                }
+                chip.start_block_erase(pa).map_err(Error::Flash)?;
+                *busy = Some(pa);
+            }
+        }
+        Ok(())
+    }
+
+    /// The chips
+    pub fn chips(&mut self) -> &mut [W25N<SPI>; N] {
+        &mut self.chips
+    }
+
+    /// Return the chips
+    pub fn into_inner(self) -> [W25N<SPI>; N] {
+        self.chips
+    }
+}
+
+impl<SPI, const N: usize> ErrorType for Stripe<SPI, N>
+where
+    SPI: SpiDevice + core::fmt::Debug,
+{
+    type Error = Error<crate::Error<SPI>>;
+}
+
+impl<SPI, const N: usize> ReadNandFlash for Stripe<SPI, N>
+where
+    SPI: SpiDevice + core::fmt::Debug,
+{
+    const READ_SIZE: usize = PAGE_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.ecc = EccStatus::NoErrors;
+        for (i, page) in bytes.chunks_exact_mut(PAGE_SIZE).enumerate() {
+            let (index, pa) = Self::locate(offset + (i * PAGE_SIZE) as u64);
+            let chip = &mut self.chips[index];
+            let result = ReadNandFlash::read(chip, pa.to_byte_address(), page);
+            self.ecc = self.ecc.max(chip.ecc_status());
+            result.map_err(Error::Flash)?;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.chips.iter().map(|c| c.capacity()).sum()
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        if address >= self.capacity() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        let block = address / Self::ERASE_SIZE as u64 * BLOCK_SIZE as u64;
+        let mut status = BlockStatus::Ok;
+        for chip in &mut self.chips {
+            status = status.max(chip.block_status(block).map_err(Error::Flash)?);
+        }
+        Ok(status)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.ecc
+    }
+}
+
+impl<SPI, const N: usize> NandFlash for Stripe<SPI, N>
+where
+    SPI: SpiDevice + core::fmt::Debug,
+{
+    const WRITE_SIZE: usize = PAGE_SIZE;
+
+    const ERASE_SIZE: usize = BLOCK_SIZE * N;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        let mut pending = [None; N];
+        let (first, last) = (from / Self::ERASE_SIZE as u64, to / Self::ERASE_SIZE as u64);
+        let result = self.erase_blocks(first, last, &mut pending);
+        self.finish(&mut pending, true, result)
+    }
+
+    /// Pages are verified by the policy of their chip, see [`W25N::set_verify`], once every
+    /// program has finished so the verify reads do not hold up the other chips
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let mut pending = [None; N];
+        let result = self.write_pages(offset, bytes, &mut pending);
+        self.finish(&mut pending, false, result)?;
+        for (i, page) in bytes.chunks_exact(PAGE_SIZE).enumerate() {
+            let (index, pa) = Self::locate(offset + (i * PAGE_SIZE) as u64);
+            self.chips[index]
+                .verify_program(pa, page, &[])
+                .map_err(Error::Flash)?;
+        }
+        Ok(())
+    }
+}
+
+impl<SPI, const N: usize> SpareNandFlash for Stripe<SPI, N>
+where
+    SPI: SpiDevice + core::fmt::Debug,
+{
+    const SPARE_SIZE: usize = SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let (index, pa) = Self::locate(offset);
+        let chip = &mut self.chips[index];
+        let result = chip.read_page(pa.to_byte_address(), data, spare);
+        self.ecc = chip.ecc_status();
+        result.map_err(Error::Flash)
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        let (index, pa) = Self::locate(offset);
+        self.chips[index]
+            .write_page(pa.to_byte_address(), data, spare)
+            .map_err(Error::Flash)
+    }
+
+    /// Copies within a chip use its page buffer, between chips the page goes through a buffer
+    fn copy_page(&mut self, from: u64, to: u64, spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, from, 0, spare.len())?;
+        check_page(self, to, 0, 0)?;
+        let ((src, from), (dst, to)) = (Self::locate(from), Self::locate(to));
+        if src == dst {
+            return self.chips[src]
+                .copy_page(from.to_byte_address(), to.to_byte_address(), spare)
+                .map_err(Error::Flash);
+        }
+        let mut data = [0; PAGE_SIZE];
+        let mut old = [0; SPARE_SIZE];
+        self.chips[src]
+            .read_page(from.to_byte_address(), &mut data, &mut old)
+            .map_err(Error::Flash)?;
+        old[..spare.len()].copy_from_slice(spare);
+        self.chips[dst]
+            .write_page(to.to_byte_address(), &data, &old)
+            .map_err(Error::Flash)
+    }
+
+    /// Mark the block bad on every chip
+    fn mark_bad(&mut self, address: u64) -> Result<(), Self::Error> {
+        if address >= self.capacity() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        let block = address / Self::ERASE_SIZE as u64 * BLOCK_SIZE as u64;
+        for chip in &mut self.chips {
+            chip.mark_bad(block).map_err(Error::Flash)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{commands::PAGE_DATA_READ, mock::RecordingSpi, VerifyPolicy};
+
+    #[test]
+    fn write_verifies_each_page_on_its_chip() {
+        let mut spis = [RecordingSpi::default(), RecordingSpi::default()];
+        let chips = spis.each_mut().map(|spi| {
+            let mut chip = W25N::new(spi, 64.into());
+            chip.set_verify(Some(VerifyPolicy::Error));
+            chip
+        });
+        let mut stripe = Stripe::<_, 2>::new(chips);
+        stripe.write(0, &[0xFF; 3 * PAGE_SIZE]).unwrap();
+        let [a, b] = &spis;
+        assert_eq!(a.commands(PAGE_DATA_READ), [[0, 0, 0], [0, 0, 1]]);
+        assert_eq!(b.commands(PAGE_DATA_READ), [[0, 0, 0]]);
+    }
+}
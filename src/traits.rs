@@ -1,3 +1,5 @@
+use crate::mem::{PAGE_SIZE, SPARE_SIZE};
+
 pub trait NandFlashError: core::fmt::Debug {
     /// Convert a specific NAND flash error into a generic error kind
     fn kind(&self) -> NandFlashErrorKind;
@@ -30,6 +32,10 @@ pub enum NandFlashErrorKind {
     /// Contains byte address of failing block, or [None] if specific block unknown
     BlockFailing(Option<u64>),
 
+    /// Data failed authentication, it was changed other than through the driver.
+    /// Contains byte address of the page, or [None] if specific page unknown
+    Integrity(Option<u64>),
+
     /// Error specific to the implementation.
     Other,
 }
@@ -49,11 +55,39 @@ pub trait ReadNandFlash: ErrorType {
     ///
     fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error>;
 
+    /// Read consecutive pages as one stream, for large sequential reads such as verifying an
+    /// image.
+    ///
+    /// The default is [`ReadNandFlash::read`], devices that can stream across page boundaries
+    /// without setting up each page read override it.
+    fn read_continuous(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.read(offset, bytes)
+    }
+
     /// The capacity of the peripheral in bytes.
     fn capacity(&self) -> u64;
 
     /// Check if the block is marked as bad
     fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error>;
+
+    /// ECC result of the most recent read, the worst over its pages.
+    ///
+    /// Devices without on chip ECC always report [`EccStatus::NoErrors`].
+    fn ecc_status(&self) -> EccStatus {
+        EccStatus::NoErrors
+    }
+}
+
+/// Outcome of the ECC check on data read from the device
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum EccStatus {
+    /// Data read without bit errors
+    NoErrors,
+    /// Bit errors were corrected, the data is good but the block is wearing out.
+    /// Contains the most bits corrected in one ECC chunk, or [None] if the device does not say
+    Corrected(Option<u8>),
+    /// Too many bit errors to correct, the data is bad
+    Uncorrectable,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -112,7 +146,7 @@ pub fn check_erase<T: NandFlash>(flash: &T, from: u64, to: u64) -> Result<(), Na
     if from > to || to > flash.capacity() {
         return Err(NandFlashErrorKind::OutOfBounds);
     }
-    if from % T::ERASE_SIZE as u64 != 0 || to % T::ERASE_SIZE as u64 != 0 {
+    if !from.is_multiple_of(T::ERASE_SIZE as u64) || !to.is_multiple_of(T::ERASE_SIZE as u64) {
         return Err(NandFlashErrorKind::NotAligned);
     }
     Ok(())
@@ -136,8 +170,150 @@ fn check_slice<T: ReadNandFlash>(
     if length as u64 > flash.capacity() || offset > (flash.capacity() - (length as u64)) {
         return Err(NandFlashErrorKind::OutOfBounds);
     }
-    if offset % align as u64 != 0 || length % align != 0 {
+    if !offset.is_multiple_of(align as u64) || !length.is_multiple_of(align) {
+        return Err(NandFlashErrorKind::NotAligned);
+    }
+    Ok(())
+}
+
+/// NAND flash with access to the spare area that follows each page.
+///
+/// The spare area holds the factory bad block marker in its first byte and is otherwise free
+/// for ECC parity and metadata of the layers above.
+///
+/// `SPARE_SIZE` is the physical spare area. Devices with on chip ECC may store its parity in
+/// part of it while the ECC is enabled, on the W25N everything from
+/// [`crate::mem::SPARE_USER_SIZE`] on. Bytes written there are then replaced by parity, so
+/// keep data that must survive below it.
+pub trait SpareNandFlash: NandFlash {
+    /// The number of spare bytes following each page, including any used by on chip ECC
+    const SPARE_SIZE: usize;
+
+    /// Read a single page, filling `data` from the start of the main array and `spare` from the
+    /// start of the spare area.
+    ///
+    /// `data` must be empty or `WRITE_SIZE` bytes long, `spare` at most `SPARE_SIZE` bytes long.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the arguments are not aligned or out of bounds. The implementation
+    /// can use the [`check_page`] helper function.
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error>;
+
+    /// Program a single page and its spare area in one operation.
+    /// Either slice can be empty to leave that part of the page erased.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the arguments are not aligned or out of bounds. The implementation
+    /// can use the [`check_page`] helper function.
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error>;
+
+    /// Copy the page at `from` to `to`, replacing the start of its spare area with `spare`.
+    ///
+    /// The default moves the page through a buffer, devices with an internal page buffer can
+    /// copy without moving the data over the bus.
+    fn copy_page(&mut self, from: u64, to: u64, spare: &[u8]) -> Result<(), Self::Error> {
+        let mut data = [0; PAGE_SIZE];
+        let mut old = [0; SPARE_SIZE];
+        let (data, old) = (&mut data[..Self::WRITE_SIZE], &mut old[..Self::SPARE_SIZE]);
+        self.read_page(from, data, old)?;
+        old[..spare.len()].copy_from_slice(spare);
+        self.write_page(to, data, old)
+    }
+
+    /// Mark the block containing `address` as bad by clearing the marker in the spare area
+    /// of its first page.
+    fn mark_bad(&mut self, address: u64) -> Result<(), Self::Error> {
+        let block = address - address % Self::ERASE_SIZE as u64;
+        self.write_page(block, &[], &[0x00])
+    }
+}
+
+/// Return whether a single page access is aligned and within bounds.
+pub fn check_page<T: SpareNandFlash>(
+    flash: &T,
+    offset: u64,
+    data: usize,
+    spare: usize,
+) -> Result<(), NandFlashErrorKind> {
+    if offset >= flash.capacity() || spare > T::SPARE_SIZE {
+        return Err(NandFlashErrorKind::OutOfBounds);
+    }
+    if !offset.is_multiple_of(T::WRITE_SIZE as u64) || (data != 0 && data != T::WRITE_SIZE) {
         return Err(NandFlashErrorKind::NotAligned);
     }
     Ok(())
 }
+
+impl<T: ErrorType> ErrorType for &mut T {
+    type Error = T::Error;
+}
+
+impl<T: ReadNandFlash> ReadNandFlash for &mut T {
+    const READ_SIZE: usize = T::READ_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        T::read(self, offset, bytes)
+    }
+
+    fn read_continuous(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        T::read_continuous(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> u64 {
+        T::capacity(self)
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        T::block_status(self, address)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        T::ecc_status(self)
+    }
+}
+
+impl<T: NandFlash> NandFlash for &mut T {
+    const WRITE_SIZE: usize = T::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = T::ERASE_SIZE;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        T::erase(self, from, to)
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        T::write(self, offset, bytes)
+    }
+}
+
+impl<T: SpareNandFlash> SpareNandFlash for &mut T {
+    const SPARE_SIZE: usize = T::SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        T::read_page(self, offset, data, spare)
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        T::write_page(self, offset, data, spare)
+    }
+
+    fn copy_page(&mut self, from: u64, to: u64, spare: &[u8]) -> Result<(), Self::Error> {
+        T::copy_page(self, from, to, spare)
+    }
+
+    fn mark_bad(&mut self, address: u64) -> Result<(), Self::Error> {
+        T::mark_bad(self, address)
+    }
+}
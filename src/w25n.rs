@@ -1,33 +1,75 @@
-use core::default;
-
 use embedded_hal::spi::{self};
 
 use crate::{
     commands::{
-        BLOCK_ERASE, DEEP_POWER_DOWN, JEDEC, PAGE_DATA_READ, PROGRAM_DATA_LOAD, PROGRAM_EXECUTE,
-        RANDOM_PROGRAM_DATA_LOAD, READ, READ_REG, RELEASE_POWER_DOWN, RESET, STATUS_REGISTER_1,
-        STATUS_REGISTER_2, STATUS_REGISTER_3, WRITE_DISABLE, WRITE_ENABLE, WRITE_REG,
+        BLOCK_ERASE, DEEP_POWER_DOWN, ENABLE_RESET, JEDEC, PAGE_DATA_READ, PROGRAM_DATA_LOAD,
+        PROGRAM_EXECUTE, RANDOM_PROGRAM_DATA_LOAD, READ, READ_REG, RELEASE_POWER_DOWN, RESET,
+        RESET_DEVICE, STATUS_REGISTER_1, STATUS_REGISTER_2, STATUS_REGISTER_3, WRITE_DISABLE,
+        WRITE_ENABLE, WRITE_REG,
+    },
+    crc::Crc32,
+    mem::{
+        BlockAddressIterator, ColumnAddress, PageAddress, BLOCK_SIZE, PAGE_SIZE, SPARE_SIZE,
+        SPARE_USER_SIZE,
     },
-    mem::{BlockAddressIterator, ColumnAddress, PageAddress},
+    otp,
     registers::{Jedec, Status1, Status2, Status3},
     traits::{
-        self, check_erase, check_read, check_write, ErrorType, NandFlash, NandFlashError,
-        NandFlashErrorKind,
+        self, check_erase, check_page, check_read, check_write, EccStatus, ErrorType, NandFlash,
+        NandFlashError, NandFlashErrorKind, SpareNandFlash,
     },
 };
 
+/// Longest time tRST the device takes to reset
+pub const RESET_TIME_US: u32 = 500;
+
 pub struct W25N<SPI> {
     spi: SPI,
     page_count: PageAddress,
+    /// Worst ECC status of the pages loaded by the current read
+    ecc: EccStatus,
+    /// Read back programmed pages and what to do when they differ, [None] to trust P-FAIL
+    verify: Option<VerifyPolicy>,
 }
 
 impl<SPI> W25N<SPI> {
     pub fn new(spi: SPI, page_count: PageAddress) -> Self {
-        Self { spi, page_count }
+        Self {
+            spi,
+            page_count,
+            ecc: EccStatus::NoErrors,
+            verify: None,
+        }
+    }
+
+    /// Read back every page programmed by [`NandFlash::write`], [`SpareNandFlash::write_page`]
+    /// and [`SpareNandFlash::copy_page`] and compare it with what was written, handling a
+    /// mismatch with `policy`. With the on chip ECC enabled the spare bytes holding its parity
+    /// are not compared. [None], the default, trusts the P-FAIL flag alone.
+    pub fn set_verify(&mut self, policy: Option<VerifyPolicy>) {
+        self.verify = policy;
+    }
+
+    /// Policy for pages that read back differently, [None] if writes are not verified
+    pub fn verify_policy(&self) -> Option<VerifyPolicy> {
+        self.verify
     }
 }
 
+/// What to do when a programmed page reads back differently, see [`W25N::set_verify`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyPolicy {
+    /// Return [`Error::VerifyFailure`], which the layers above treat as a plain error
+    Error,
+    /// Return [`NandFlashErrorKind::BlockFail`] like a program failure, so the layers above
+    /// retire the block and write the data to another one
+    Relocate,
+    /// Mark the block bad, then return [`NandFlashErrorKind::BlockFail`]
+    MarkBad,
+}
+
 #[derive(Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Error<SPI>
 where
     SPI: spi::SpiDevice,
@@ -44,6 +86,12 @@ where
     EraseFailure,
     ProgramFailure,
     BlockProtect(u8),
+    /// No copy of the unique ID matches its complement
+    InvalidUniqueId,
+    /// No copy of the parameter page has a matching CRC
+    InvalidParameterPage,
+    /// Page at the contained address read back differently than it was programmed
+    VerifyFailure(u64),
 }
 
 impl<SPI> From<NandFlashErrorKind> for Error<SPI>
@@ -65,10 +113,12 @@ where
     fn write(&mut self, buf: &[u8]) -> WResult<(), SPI> {
         self.spi.write(buf).map_err(|e| Error::SPI(e))
     }
+    #[allow(dead_code)]
     fn read(&mut self, buf: &mut [u8]) -> WResult<(), SPI> {
         self.spi.read(buf).map_err(|e| Error::SPI(e))
     }
 
+    #[allow(dead_code)]
     fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> WResult<(), SPI> {
         self.spi.transfer(read, write).map_err(|e| Error::SPI(e))
     }
@@ -87,6 +137,13 @@ where
         self.write(&[RESET])
     }
 
+    /// Send the Enable Reset and Reset Device commands. The device ignores commands for up to
+    /// [`RESET_TIME_US`] afterwards, wait that long before [`W25N::wait_for_operation`].
+    pub fn reset_device(&mut self) -> WResult<(), SPI> {
+        self.write(&[ENABLE_RESET])?;
+        self.write(&[RESET_DEVICE])
+    }
+
     /// Return the JEDEC id of the device
     pub fn jedec(&mut self) -> WResult<Jedec, SPI> {
         let mut result = [0; 3];
@@ -121,10 +178,20 @@ where
 
     /// Wait until the busy flag is cleared
     pub fn wait_for_operation(&mut self) -> WResult<(), SPI> {
-        while self.read_status_3()?.busy() {}
+        self.wait_for_status()?;
         Ok(())
     }
 
+    /// Wait until the busy flag is cleared, returning the final status
+    fn wait_for_status(&mut self) -> WResult<Status3, SPI> {
+        loop {
+            let status = self.read_status_3()?;
+            if !status.busy() {
+                return Ok(status);
+            }
+        }
+    }
+
     /// Read the protection register
     pub fn read_status_1(&mut self) -> WResult<Status1, SPI> {
         let mut data = [READ_REG, STATUS_REGISTER_1, 0x00];
@@ -159,22 +226,69 @@ where
     /// Remove all the block protection to allow erase and writes
     pub fn disable_block_protect(&mut self) -> WResult<(), SPI> {
         let status = self.read_status_1()?.with_bp(0);
-        self.write_status_1(status);
+        self.write_status_1(status)?;
         match self.read_status_1()?.bp() {
             0x0 => Ok(()),
             x => Err(Error::BlockProtect(x)),
         }
     }
+    /// Enable or disable the on chip ECC.
+    /// With ECC disabled the whole spare area is free, see [`crate::ecc::SoftEcc`]
+    pub fn set_ecc(&mut self, enable: bool) -> WResult<(), SPI> {
+        let status = self.read_status_2()?.with_ecc_e(enable);
+        self.write_status_2(status)
+    }
+
+    /// Read from page `page` of the OTP area (see [`crate::otp`]) starting at column ca.
+    /// OTP-E is set for the read and the previous mode restored afterwards.
+    pub fn read_otp(&mut self, page: u32, ca: ColumnAddress, buf: &mut [u8]) -> WResult<(), SPI> {
+        let status = self.read_status_2()?;
+        let (otp, buffered) = (status.otp_e(), status.buf());
+        self.write_status_2(status.with_otp_e(true).with_buf(true))?;
+        let result = self
+            .page_data_read(page.into())
+            .and_then(|_| self.read_data(ca, buf));
+        // restore the mode even if the read failed
+        let status = self.read_status_2()?.with_otp_e(otp).with_buf(buffered);
+        self.write_status_2(status)?;
+        result
+    }
+
+    /// Read the factory programmed unique ID of the device
+    pub fn unique_id(&mut self) -> WResult<[u8; otp::UNIQUE_ID_LEN], SPI> {
+        let mut page = [0; 2 * otp::UNIQUE_ID_LEN * otp::UNIQUE_ID_COPIES];
+        self.read_otp(otp::UNIQUE_ID_PAGE, 0.into(), &mut page)?;
+        otp::unique_id(&page).ok_or(Error::InvalidUniqueId)
+    }
+
+    /// Read and parse the factory programmed parameter page
+    pub fn parameter_page(&mut self) -> WResult<otp::ParameterPage, SPI> {
+        let mut page = [0; otp::PARAMETER_LEN * otp::PARAMETER_COPIES];
+        self.read_otp(otp::PARAMETER_PAGE, 0.into(), &mut page)?;
+        otp::ParameterPage::parse(&page).ok_or(Error::InvalidParameterPage)
+    }
+
     /// Erase the block at ca
     /// Returns error if e-fail flag is set
     pub fn block_erase(&mut self, pa: PageAddress) -> WResult<(), SPI> {
+        self.start_block_erase(pa)?;
+        self.finish_erase(pa)
+    }
+
+    /// Start erasing the block at pa without waiting for it to finish, the device is busy until
+    /// [`W25N::finish_erase`] returns
+    pub fn start_block_erase(&mut self, pa: PageAddress) -> WResult<(), SPI> {
         self.write_enable()?;
         self.transaction(&mut [
             spi::Operation::Write(&[BLOCK_ERASE]),
             spi::Operation::Write(&pa.to_array()),
-        ])?;
-        self.wait_for_operation()?;
-        if self.read_status_3()?.e_fail() {
+        ])
+    }
+
+    /// Wait for the erase of the block at pa to finish
+    /// Returns error if e-fail flag is set
+    pub fn finish_erase(&mut self, pa: PageAddress) -> WResult<(), SPI> {
+        if self.wait_for_status()?.e_fail() {
             Err(Error::Nand(NandFlashErrorKind::BlockFail(Some(
                 pa.to_byte_address(),
             ))))
@@ -210,25 +324,139 @@ where
     /// Write data from the buffer to the page at pa
     /// Returns error if p-fail flag is set
     pub fn program_execute(&mut self, pa: PageAddress) -> WResult<(), SPI> {
+        self.start_program_execute(pa)?;
+        self.finish_program(pa)
+    }
+
+    /// Start writing data from the buffer to the page at pa without waiting for it to finish,
+    /// the device is busy until [`W25N::finish_program`] returns
+    pub fn start_program_execute(&mut self, pa: PageAddress) -> WResult<(), SPI> {
         self.transaction(&mut [
             spi::Operation::Write(&[PROGRAM_EXECUTE]),
             spi::Operation::Write(&pa.to_array()),
-        ])?;
-        self.wait_for_operation()?;
-        if self.read_status_3()?.p_fail() {
-            Err(Error::ProgramFailure)
+        ])
+    }
+
+    /// Wait for the program of the page at pa to finish
+    /// Returns error if p-fail flag is set
+    pub fn finish_program(&mut self, pa: PageAddress) -> WResult<(), SPI> {
+        if self.wait_for_status()?.p_fail() {
+            Err(Error::Nand(NandFlashErrorKind::BlockFail(Some(
+                pa.to_byte_address(),
+            ))))
         } else {
             Ok(())
         }
     }
 
+    /// Read back the page at pa after it was programmed and handle a mismatch with `data` and
+    /// `spare` by the verify policy. Does nothing if writes are not verified.
+    pub(crate) fn verify_program(
+        &mut self,
+        pa: PageAddress,
+        data: &[u8],
+        spare: &[u8],
+    ) -> WResult<(), SPI> {
+        self.verify_with(pa, |flash| flash.compare_page(pa, data, spare))
+    }
+
+    /// Apply the verify policy to the page at pa if `compare` finds it differs
+    fn verify_with(
+        &mut self,
+        pa: PageAddress,
+        compare: impl FnOnce(&mut Self) -> WResult<bool, SPI>,
+    ) -> WResult<(), SPI> {
+        let Some(policy) = self.verify else {
+            return Ok(());
+        };
+        // the read back is not a read of the caller's, keep its ECC status
+        let ecc = self.ecc;
+        let matches = compare(self);
+        self.ecc = ecc;
+        if matches? {
+            return Ok(());
+        }
+        let address = pa.to_byte_address();
+        match policy {
+            VerifyPolicy::Error => Err(Error::VerifyFailure(address)),
+            VerifyPolicy::Relocate => {
+                Err(Error::Nand(NandFlashErrorKind::BlockFail(Some(address))))
+            }
+            VerifyPolicy::MarkBad => {
+                let block = PageAddress::from_byte_address(address - address % BLOCK_SIZE as u64);
+                self.load_program_data((PAGE_SIZE as u16).into(), &[0x00])?;
+                self.program_execute(block)?;
+                Err(Error::Nand(NandFlashErrorKind::BlockFail(Some(address))))
+            }
+        }
+    }
+
+    /// Spare bytes a program stores as written: with the on chip ECC enabled the bytes from
+    /// [`SPARE_USER_SIZE`] on are replaced by its parity
+    fn stored_spare(&mut self) -> WResult<usize, SPI> {
+        Ok(if self.read_status_2()?.ecc_e() {
+            SPARE_USER_SIZE
+        } else {
+            SPARE_SIZE
+        })
+    }
+
+    /// Whether the page at pa holds `data` followed by `spare`, comparing a chunk at a time.
+    /// A page the ECC cannot correct does not match.
+    fn compare_page(&mut self, pa: PageAddress, data: &[u8], spare: &[u8]) -> WResult<bool, SPI> {
+        let spare = &spare[..spare.len().min(self.stored_spare()?)];
+        match self.page_data_read(pa) {
+            Err(Error::Nand(NandFlashErrorKind::BlockFail(_))) => return Ok(false),
+            result => result?,
+        }
+        let mut buf = [0; 64];
+        let size = buf.len();
+        for (start, expected) in [(0, data), (PAGE_SIZE, spare)] {
+            for (i, chunk) in expected.chunks(size).enumerate() {
+                let read = &mut buf[..chunk.len()];
+                self.read_data(((start + i * size) as u16).into(), read)?;
+                if read != chunk {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// CRC of the first `spare` bytes of the spare area and the data before them in the page
+    /// buffer, a chunk at a time
+    fn buffer_crc(&mut self, spare: usize) -> WResult<u32, SPI> {
+        let mut buf = [0; 64];
+        let mut crc = Crc32::default();
+        for start in (0..PAGE_SIZE + spare).step_by(buf.len()) {
+            let read = &mut buf[..(PAGE_SIZE + spare - start).min(64)];
+            self.read_data((start as u16).into(), read)?;
+            crc.update(read);
+        }
+        Ok(crc.finish())
+    }
+
+    /// Whether a program, erase or page read is still in progress
+    pub fn is_busy(&mut self) -> WResult<bool, SPI> {
+        Ok(self.read_status_3()?.busy())
+    }
+
     /// Read data from page at pa into the buffer
+    /// Returns error if the ECC could not correct the page
     pub fn page_data_read(&mut self, pa: PageAddress) -> WResult<(), SPI> {
         self.transaction(&mut [
             spi::Operation::Write(&[PAGE_DATA_READ]),
             spi::Operation::Write(&pa.to_array()),
         ])?;
-        self.wait_for_operation()
+        let ecc = self.wait_for_status()?.ecc_status();
+        self.ecc = self.ecc.max(ecc);
+        if ecc == EccStatus::Uncorrectable {
+            Err(Error::Nand(NandFlashErrorKind::BlockFail(Some(
+                pa.to_byte_address(),
+            ))))
+        } else {
+            Ok(())
+        }
     }
 
     pub fn read_data(&mut self, ca: ColumnAddress, buf: &mut [u8]) -> WResult<(), SPI> {
@@ -240,6 +468,21 @@ where
         ])
     }
 
+    /// Load the page at `offset` and read it and the pages after it into `buf`.
+    /// Only streams across pages in Continuous Read Mode (BUF=0).
+    fn stream_pages(&mut self, offset: u64, buf: &mut [u8]) -> WResult<(), SPI> {
+        self.page_data_read(PageAddress::from_byte_address(offset))?;
+        // in continuous mode the column address bytes are the dummy clocks
+        self.read_data(0.into(), buf)?;
+        let ecc = self.read_status_3()?.ecc_status();
+        self.ecc = self.ecc.max(ecc);
+        if ecc == EccStatus::Uncorrectable {
+            Err(Error::Nand(NandFlashErrorKind::BlockFail(None)))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Go to deep power down state
     pub fn deep_power_down(&mut self) -> WResult<(), SPI> {
         self.write(&[DEEP_POWER_DOWN])
@@ -301,6 +544,7 @@ where
     fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
         // check the read aligns with pages and doesnt got beyond end of storage
         check_read(self, offset, bytes.len())?;
+        self.ecc = EccStatus::NoErrors;
         // Get page address from byte address
         let mut pa = PageAddress::from_byte_address(offset);
         // Go through each page requested
@@ -313,22 +557,50 @@ where
         Ok(())
     }
 
+    /// Continuous Read Mode (BUF=0): after the first page is loaded the device keeps streaming
+    /// the following pages, leaving out their spare areas. The ECC bits then cover every page
+    /// read.
+    fn read_continuous(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        if bytes.len() <= Self::READ_SIZE {
+            return traits::ReadNandFlash::read(self, offset, bytes);
+        }
+        check_read(self, offset, bytes.len())?;
+        self.ecc = EccStatus::NoErrors;
+        let status = self.read_status_2()?;
+        let buffered = status.buf();
+        self.write_status_2(status.with_buf(false))?;
+        let result = self.stream_pages(offset, bytes);
+        // restore the mode even if the read failed
+        let status = self.read_status_2()?.with_buf(buffered);
+        self.write_status_2(status)?;
+        result
+    }
+
     fn capacity(&self) -> u64 {
         // Page size * page count
         (1 << 11) * (1 << 17)
     }
 
     fn block_status(&mut self, address: u64) -> Result<traits::BlockStatus, Self::Error> {
-        self.page_data_read(PageAddress::from_byte_address(address))?;
+        self.ecc = EccStatus::NoErrors;
+        match self.page_data_read(PageAddress::from_byte_address(address)) {
+            Err(Error::Nand(NandFlashErrorKind::BlockFail(_))) => {
+                return Ok(traits::BlockStatus::Failed)
+            }
+            result => result?,
+        }
         let mut marker = [0];
         self.read_data(2048.into(), &mut marker)?;
-        // TODO: Check ECC
         if marker[0] == 0xFF {
             Ok(traits::BlockStatus::MarkedOk)
         } else {
             Ok(traits::BlockStatus::Failed)
         }
     }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.ecc
+    }
 }
 
 impl<SPI> NandFlash for W25N<SPI>
@@ -361,7 +633,9 @@ where
             // load the page into the buffer
             self.load_program_data(0.into(), page)?;
             // Write the data from buffer
-            self.program_execute(pa.increment_page())?;
+            let page_address = pa.increment_page();
+            self.program_execute(page_address)?;
+            self.verify_program(page_address, page, &[])?;
         }
         Ok(())
     }
@@ -385,3 +659,166 @@ where
         }
     }
 }
+
+impl<SPI> SpareNandFlash for W25N<SPI>
+where
+    SPI: spi::SpiDevice + core::fmt::Debug,
+{
+    /// The whole spare area. With ECC-E=1, the power on default, the bytes from
+    /// [`crate::mem::SPARE_USER_SIZE`] on hold the on chip ECC parity and anything written
+    /// there is lost.
+    const SPARE_SIZE: usize = SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.ecc = EccStatus::NoErrors;
+        self.page_data_read(PageAddress::from_byte_address(offset))?;
+        if !data.is_empty() {
+            self.read_data(0.into(), data)?;
+        }
+        if !spare.is_empty() {
+            self.read_data((PAGE_SIZE as u16).into(), spare)?;
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        // The first load resets the rest of the buffer, the spare is then loaded alongside
+        if data.is_empty() {
+            self.load_program_data((PAGE_SIZE as u16).into(), spare)?;
+        } else {
+            self.load_program_data(0.into(), data)?;
+            if !spare.is_empty() {
+                self.random_load_program_data((PAGE_SIZE as u16).into(), spare)?;
+            }
+        }
+        let pa = PageAddress::from_byte_address(offset);
+        self.program_execute(pa)?;
+        self.verify_program(pa, data, spare)
+    }
+
+    /// Copy through the device's page buffer without reading the data out. When writes are
+    /// verified the buffer is read out once to checksum it, and the copy is checked against it.
+    fn copy_page(&mut self, from: u64, to: u64, spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, from, 0, spare.len())?;
+        check_page(self, to, 0, 0)?;
+        self.page_data_read(PageAddress::from_byte_address(from))?;
+        if spare.is_empty() {
+            self.write_enable()?;
+        } else {
+            self.random_load_program_data((PAGE_SIZE as u16).into(), spare)?;
+        }
+        let expected = match self.verify {
+            Some(_) => {
+                let stored = self.stored_spare()?;
+                Some((stored, self.buffer_crc(stored)?))
+            }
+            None => None,
+        };
+        let pa = PageAddress::from_byte_address(to);
+        self.program_execute(pa)?;
+        let Some((stored, crc)) = expected else {
+            return Ok(());
+        };
+        self.verify_with(pa, |flash| {
+            match flash.page_data_read(pa) {
+                Err(Error::Nand(NandFlashErrorKind::BlockFail(_))) => return Ok(false),
+                result => result?,
+            }
+            Ok(flash.buffer_crc(stored)? == crc)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{mem::SPARE_META, mock::RecordingSpi, traits::ReadNandFlash};
+
+    /// Page addresses of the BLOCK_ERASE commands sent by `erase(from, to)`
+    fn erased(from: u64, to: u64) -> Vec<[u8; 3]> {
+        let mut flash = W25N::new(RecordingSpi::default(), 0.into());
+        NandFlash::erase(&mut flash, from, to).unwrap();
+        flash
+            .spi
+            .commands(BLOCK_ERASE)
+            .iter()
+            .map(|pa| (*pa).try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn erase_end_is_exclusive() {
+        let block = BLOCK_SIZE as u64;
+        assert_eq!(erased(0, 0), Vec::<[u8; 3]>::new());
+        assert_eq!(erased(block, block), Vec::<[u8; 3]>::new());
+        assert_eq!(erased(0, block), [[0, 0, 0]]);
+        assert_eq!(erased(block, 3 * block), [[0, 0, 0x40], [0, 0, 0x80]]);
+    }
+
+    #[test]
+    fn erase_last_block_stays_on_device() {
+        let flash = W25N::new(RecordingSpi::default(), 0.into());
+        let end = flash.capacity();
+        assert_eq!(erased(end - BLOCK_SIZE as u64, end), [[0x01, 0xFF, 0xC0]]);
+    }
+
+    #[test]
+    fn block_status_iter_stops_before_page_count() {
+        let mut flash = W25N::new(RecordingSpi::default(), (2 * 64).into());
+        assert_eq!(flash.block_status_iter().count(), 2);
+    }
+
+    /// W25N that verifies writes, with ECC-E as given and BUF=1
+    fn verifying(ecc: bool) -> W25N<RecordingSpi> {
+        let spi = RecordingSpi {
+            status_2: if ecc { 0x18 } else { 0x08 },
+            ..Default::default()
+        };
+        let mut flash = W25N::new(spi, 64.into());
+        flash.set_verify(Some(VerifyPolicy::Error));
+        flash
+    }
+
+    #[test]
+    fn verify_skips_ecc_parity() {
+        // the mock reads back erased, unlike the parity bytes written
+        let mut spare = [0xFF; SPARE_SIZE];
+        spare[SPARE_USER_SIZE..].fill(0);
+        assert!(verifying(true).write_page(0, &[], &spare).is_ok());
+        assert!(matches!(
+            verifying(false).write_page(0, &[], &spare),
+            Err(Error::VerifyFailure(0))
+        ));
+        spare[SPARE_USER_SIZE - 1] = 0;
+        assert!(matches!(
+            verifying(true).write_page(0, &[], &spare),
+            Err(Error::VerifyFailure(0))
+        ));
+    }
+
+    #[test]
+    fn copy_page_reads_back_copy() {
+        let mut flash = verifying(true);
+        flash
+            .copy_page(0, PAGE_SIZE as u64, &[0xFF; SPARE_META])
+            .unwrap();
+        assert_eq!(flash.spi.commands(PAGE_DATA_READ), [[0, 0, 0], [0, 0, 1]]);
+
+        let mut flash = W25N::new(RecordingSpi::default(), 64.into());
+        flash
+            .copy_page(0, PAGE_SIZE as u64, &[0xFF; SPARE_META])
+            .unwrap();
+        assert_eq!(flash.spi.commands(PAGE_DATA_READ), [[0, 0, 0]]);
+    }
+}
@@ -0,0 +1,666 @@
+//! Per block erase counters and wear statistics.
+//!
+//! [`WearTracker`] passes everything through to the device it wraps and counts each block
+//! erase. The counts are kept in RAM, one `u32` per block, and saved as a snapshot to the last
+//! [`META_BLOCKS`] blocks, which are hidden from the layers above. Snapshots are appended to one
+//! metadata block until it is full and then to the other, so a snapshot torn by power loss
+//! leaves the previous one in place. Erases since the last snapshot are lost on power loss, so
+//! the counts can fall slightly behind; [`WearConfig::sync_interval`] bounds by how much.
+use core::fmt;
+
+use crate::{
+    crc::Crc32,
+    mem::{PAGE_SIZE, SPARE_META},
+    traits::{
+        check_erase, check_page, check_read, check_write, BlockStatus, EccStatus, ErrorType,
+        NandFlash, NandFlashError, NandFlashErrorKind, ReadNandFlash, SpareNandFlash,
+    },
+};
+
+/// Blocks at the end of the device holding the snapshots
+pub const META_BLOCKS: usize = 2;
+/// Buckets of [`WearStats::histogram`], each a tenth of the rated endurance
+pub const HISTOGRAM_BUCKETS: usize = 10;
+
+const MAGIC: [u8; 2] = *b"WC";
+const HEADER_LEN: usize = 12;
+const SPARE_LEN: usize = SPARE_META + HEADER_LEN;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error<E> {
+    /// Errors from the underlying flash
+    Flash(E),
+    /// Errors that map to NandFlashErrorKind
+    Nand(NandFlashErrorKind),
+    /// Device has more blocks than the counters can hold
+    TooManyBlocks,
+    /// Every metadata block has failed
+    NoSpace,
+}
+
+impl<E> From<NandFlashErrorKind> for Error<E> {
+    fn from(value: NandFlashErrorKind) -> Self {
+        Error::Nand(value)
+    }
+}
+
+impl<E: NandFlashError> NandFlashError for Error<E> {
+    fn kind(&self) -> NandFlashErrorKind {
+        match self {
+            Error::Flash(e) => e.kind(),
+            Error::Nand(kind) => *kind,
+            Error::TooManyBlocks | Error::NoSpace => NandFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Tuning for the [`WearTracker`]
+#[derive(Debug, Clone, Copy)]
+pub struct WearConfig {
+    /// Erase cycles each block is rated for
+    pub rated_cycles: u32,
+    /// Erases after which the counts are saved, 0 to only save on [`WearTracker::sync`]
+    pub sync_interval: u32,
+}
+
+impl Default for WearConfig {
+    fn default() -> Self {
+        Self {
+            // W25N02KV datasheet endurance
+            rated_cycles: 100_000,
+            sync_interval: 64,
+        }
+    }
+}
+
+/// Erase count statistics over all blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WearStats {
+    pub min: u32,
+    pub max: u32,
+    pub mean: u32,
+    pub total: u64,
+    /// Blocks by erase count, bucket `i` holds counts from `i` tenths of the rated endurance
+    /// and the last bucket everything beyond
+    pub histogram: [u32; HISTOGRAM_BUCKETS],
+}
+
+/// Summary of the wear of the device for telemetry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthReport {
+    pub stats: WearStats,
+    pub rated_cycles: u32,
+    /// Life left in percent, from the mean erase count
+    pub remaining_percent: u8,
+    /// Erases left on the most worn block
+    pub remaining_cycles: u32,
+    /// Blocks past their rated endurance
+    pub worn_out: u32,
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = &self.stats;
+        write!(
+            f,
+            "erases min={} max={} mean={} total={} rated={} remaining={}% cycles_left={} worn_out={} histogram=",
+            stats.min,
+            stats.max,
+            stats.mean,
+            stats.total,
+            self.rated_cycles,
+            self.remaining_percent,
+            self.remaining_cycles,
+            self.worn_out,
+        )?;
+        for (i, count) in stats.histogram.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(f, "{sep}{count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot page header, stored in the spare area
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    part: u16,
+    seq: u32,
+    crc: u32,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..2].copy_from_slice(&MAGIC);
+        bytes[2..4].copy_from_slice(&self.part.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes[0..2] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            part: u16::from_le_bytes([bytes[2], bytes[3]]),
+            seq: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            crc: u32::from_le_bytes(bytes[8..12].try_into().ok()?),
+        })
+    }
+
+    /// CRC over the page data, the part and sequence number
+    fn checksum(part: u16, seq: u32, data: &[u8]) -> u32 {
+        let mut crc = Crc32::default();
+        crc.update(&part.to_le_bytes());
+        crc.update(&seq.to_le_bytes());
+        crc.update(data);
+        crc.finish()
+    }
+}
+
+/// Counts the erases of each block of `F`, for devices of up to `BLOCKS` blocks
+pub struct WearTracker<F, const BLOCKS: usize> {
+    flash: F,
+    config: WearConfig,
+    counts: [u32; BLOCKS],
+    blocks: usize,
+    /// Metadata block snapshots are appended to and its next free page
+    active: usize,
+    next: usize,
+    /// Sequence number of the next snapshot
+    seq: u32,
+    /// Erases since the last snapshot
+    unsaved: u32,
+    page: [u8; PAGE_SIZE],
+}
+
+type WResult<T, F> = Result<T, Error<<F as ErrorType>::Error>>;
+
+impl<F, const BLOCKS: usize> WearTracker<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    /// Load the newest snapshot of the counts, starting from zero if there is none
+    pub fn new(flash: F, config: WearConfig) -> WResult<Self, F> {
+        assert!(F::WRITE_SIZE <= PAGE_SIZE && F::SPARE_SIZE >= SPARE_LEN);
+        let blocks = (flash.capacity() / F::ERASE_SIZE as u64) as usize;
+        if blocks > BLOCKS || blocks <= META_BLOCKS {
+            return Err(Error::TooManyBlocks);
+        }
+        let mut tracker = Self {
+            flash,
+            config,
+            counts: [0; BLOCKS],
+            blocks,
+            active: blocks - META_BLOCKS,
+            next: 0,
+            seq: 0,
+            unsaved: 0,
+            page: [0; PAGE_SIZE],
+        };
+        assert!(tracker.parts() <= tracker.pages_per_block());
+        tracker.load()?;
+        Ok(tracker)
+    }
+
+    fn pages_per_block(&self) -> usize {
+        F::ERASE_SIZE / F::WRITE_SIZE
+    }
+
+    /// Pages in a snapshot
+    fn parts(&self) -> usize {
+        (self.blocks * 4).div_ceil(F::WRITE_SIZE)
+    }
+
+    fn page_address(&self, block: usize, page: usize) -> u64 {
+        (block * F::ERASE_SIZE + page * F::WRITE_SIZE) as u64
+    }
+
+    /// Header of a snapshot page, [None] if the page is erased.
+    /// Torn pages, which read as uncorrectable with on chip ECC, get a header that matches no
+    /// snapshot so they still count as used.
+    fn header(&mut self, block: usize, page: usize) -> WResult<Option<Header>, F> {
+        let torn = Header {
+            part: u16::MAX,
+            seq: 0,
+            crc: 0,
+        };
+        let mut spare = [0; SPARE_LEN];
+        match self
+            .flash
+            .read_page(self.page_address(block, page), &mut [], &mut spare)
+        {
+            Ok(()) => {}
+            Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => {
+                return Ok(Some(torn))
+            }
+            Err(e) => return Err(Error::Flash(e)),
+        }
+        if spare[SPARE_META..].iter().all(|b| *b == 0xFF) {
+            return Ok(None);
+        }
+        Ok(Some(
+            Header::from_bytes(&spare[SPARE_META..]).unwrap_or(torn),
+        ))
+    }
+
+    /// Find the newest complete snapshot and the end of the active metadata block
+    fn load(&mut self) -> WResult<(), F> {
+        let parts = self.parts();
+        let mut newest: Option<(u32, usize, usize)> = None;
+        for block in self.blocks - META_BLOCKS..self.blocks {
+            if self
+                .flash
+                .block_status(self.page_address(block, 0))
+                .map_err(Error::Flash)?
+                == BlockStatus::Failed
+            {
+                continue;
+            }
+            let mut end = 0;
+            for page in 0..self.pages_per_block() {
+                let Some(header) = self.header(block, page)? else {
+                    continue;
+                };
+                end = page + 1;
+                let last = page + 1 >= parts && header.part as usize == parts - 1;
+                if !last || newest.is_some_and(|(seq, _, _)| seq >= header.seq) {
+                    continue;
+                }
+                let start = page + 1 - parts;
+                if self.check_snapshot(block, start, header.seq)? {
+                    newest = Some((header.seq, block, start));
+                }
+            }
+            if newest.is_some_and(|(_, b, _)| b == block) {
+                self.active = block;
+                self.next = end;
+            }
+        }
+        let Some((seq, block, start)) = newest else {
+            // no snapshot, the first one is written to a freshly erased block
+            self.next = self.pages_per_block();
+            return Ok(());
+        };
+        self.seq = seq.wrapping_add(1);
+        for part in 0..parts {
+            self.read_part(block, start + part)?;
+            let counts = self.page[..F::WRITE_SIZE].chunks_exact(4);
+            let first = part * F::WRITE_SIZE / 4;
+            for (count, bytes) in self.counts[first..self.blocks].iter_mut().zip(counts) {
+                *count = u32::from_le_bytes(bytes.try_into().unwrap());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a snapshot page into the page buffer, [None] if it has no header or is
+    /// uncorrectable
+    fn read_part(&mut self, block: usize, page: usize) -> WResult<Option<Header>, F> {
+        let mut spare = [0; SPARE_LEN];
+        let address = self.page_address(block, page);
+        match self
+            .flash
+            .read_page(address, &mut self.page[..F::WRITE_SIZE], &mut spare)
+        {
+            Ok(()) => Ok(Header::from_bytes(&spare[SPARE_META..])),
+            Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => Ok(None),
+            Err(e) => Err(Error::Flash(e)),
+        }
+    }
+
+    /// Check the snapshot `seq` is complete and intact at `start`
+    fn check_snapshot(&mut self, block: usize, start: usize, seq: u32) -> WResult<bool, F> {
+        for part in 0..self.parts() {
+            match self.read_part(block, start + part)? {
+                Some(header)
+                    if header.part as usize == part
+                        && header.seq == seq
+                        && header.crc
+                            == Header::checksum(part as u16, seq, &self.page[..F::WRITE_SIZE]) => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Move to a freshly erased metadata block. Blocks marked bad are skipped and blocks that
+    /// fail to erase are marked bad. The active block is only reused when every other one has
+    /// failed, a power loss before the next snapshot is written then loses the counts.
+    fn switch(&mut self) -> WResult<(), F> {
+        let first = self.blocks - META_BLOCKS;
+        for i in 1..=META_BLOCKS {
+            let block = first + (self.active - first + i) % META_BLOCKS;
+            let address = self.page_address(block, 0);
+            if self.flash.block_status(address).map_err(Error::Flash)? == BlockStatus::Failed {
+                continue;
+            }
+            self.counts[block] += 1;
+            match self.flash.erase(address, address + F::ERASE_SIZE as u64) {
+                Ok(()) => {
+                    self.active = block;
+                    self.next = 0;
+                    return Ok(());
+                }
+                Err(e) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => {
+                    self.flash.mark_bad(address).map_err(Error::Flash)?
+                }
+                Err(e) => return Err(Error::Flash(e)),
+            }
+        }
+        Err(Error::NoSpace)
+    }
+
+    /// Save the counts to the metadata blocks
+    pub fn sync(&mut self) -> WResult<(), F> {
+        // each failed attempt retires a metadata block
+        for _ in 0..=META_BLOCKS {
+            if self.next + self.parts() > self.pages_per_block() {
+                // the snapshot on the next metadata block is no longer needed
+                self.switch()?;
+            }
+            match self.write_snapshot() {
+                Ok(()) => {
+                    self.seq = self.seq.wrapping_add(1);
+                    self.unsaved = 0;
+                    return Ok(());
+                }
+                Err(Error::Flash(e)) if matches!(e.kind(), NandFlashErrorKind::BlockFail(_)) => {
+                    let address = self.page_address(self.active, 0);
+                    self.flash.mark_bad(address).map_err(Error::Flash)?;
+                    self.next = self.pages_per_block();
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::NoSpace)
+    }
+
+    /// Append a snapshot of the counts to the active metadata block
+    fn write_snapshot(&mut self) -> WResult<(), F> {
+        let parts = self.parts();
+        for part in 0..parts {
+            self.page.fill(0xFF);
+            let first = part * F::WRITE_SIZE / 4;
+            let last = (first + F::WRITE_SIZE / 4).min(self.blocks);
+            for (bytes, count) in self.page.chunks_exact_mut(4).zip(&self.counts[first..last]) {
+                bytes.copy_from_slice(&count.to_le_bytes());
+            }
+            let data = &self.page[..F::WRITE_SIZE];
+            let header = Header {
+                part: part as u16,
+                seq: self.seq,
+                crc: Header::checksum(part as u16, self.seq, data),
+            };
+            let mut spare = [0xFF; SPARE_LEN];
+            spare[SPARE_META..].copy_from_slice(&header.to_bytes());
+            let address = self.page_address(self.active, self.next);
+            self.next += 1;
+            self.flash
+                .write_page(address, data, &spare)
+                .map_err(Error::Flash)?;
+        }
+        Ok(())
+    }
+
+    /// Erase count of `block`
+    pub fn erase_count(&self, block: usize) -> Option<u32> {
+        self.counts[..self.blocks].get(block).copied()
+    }
+
+    /// Erase counts of every block, the metadata blocks last
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.counts[..self.blocks]
+    }
+
+    pub fn stats(&self) -> WearStats {
+        let counts = self.erase_counts();
+        let total: u64 = counts.iter().map(|c| *c as u64).sum();
+        let mut histogram = [0; HISTOGRAM_BUCKETS];
+        let rated = self.config.rated_cycles.max(1) as u64;
+        for count in counts {
+            let bucket = (*count as u64 * HISTOGRAM_BUCKETS as u64 / rated) as usize;
+            histogram[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+        }
+        WearStats {
+            min: counts.iter().copied().min().unwrap_or(0),
+            max: counts.iter().copied().max().unwrap_or(0),
+            mean: (total / counts.len() as u64) as u32,
+            total,
+            histogram,
+        }
+    }
+
+    /// Life left in percent against the rated endurance, from the mean erase count
+    pub fn remaining_life(&self) -> u8 {
+        let rated = self.config.rated_cycles.max(1) as u64;
+        let used = self.stats().mean as u64;
+        (rated.saturating_sub(used) * 100 / rated) as u8
+    }
+
+    pub fn health_report(&self) -> HealthReport {
+        let stats = self.stats();
+        let rated = self.config.rated_cycles;
+        HealthReport {
+            stats,
+            rated_cycles: rated,
+            remaining_percent: self.remaining_life(),
+            remaining_cycles: rated.saturating_sub(stats.max),
+            worn_out: self.erase_counts().iter().filter(|c| **c >= rated).count() as u32,
+        }
+    }
+
+    /// Save the counts and return the underlying flash
+    pub fn into_inner(mut self) -> WResult<F, F> {
+        if self.unsaved > 0 {
+            self.sync()?;
+        }
+        Ok(self.flash)
+    }
+}
+
+impl<F, const BLOCKS: usize> ErrorType for WearTracker<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    type Error = Error<F::Error>;
+}
+
+impl<F, const BLOCKS: usize> ReadNandFlash for WearTracker<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.flash.read(offset, bytes).map_err(Error::Flash)
+    }
+
+    fn capacity(&self) -> u64 {
+        ((self.blocks - META_BLOCKS) * F::ERASE_SIZE) as u64
+    }
+
+    fn block_status(&mut self, address: u64) -> Result<BlockStatus, Self::Error> {
+        if address >= self.capacity() {
+            return Err(Error::Nand(NandFlashErrorKind::OutOfBounds));
+        }
+        self.flash.block_status(address).map_err(Error::Flash)
+    }
+
+    fn ecc_status(&self) -> EccStatus {
+        self.flash.ecc_status()
+    }
+}
+
+impl<F, const BLOCKS: usize> NandFlash for WearTracker<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u64, to: u64) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        for address in (from..to).step_by(F::ERASE_SIZE) {
+            // a failed erase still wore the block
+            self.counts[address as usize / F::ERASE_SIZE] += 1;
+            self.unsaved += 1;
+            self.flash
+                .erase(address, address + F::ERASE_SIZE as u64)
+                .map_err(Error::Flash)?;
+        }
+        if self.config.sync_interval != 0 && self.unsaved >= self.config.sync_interval {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.flash.write(offset, bytes).map_err(Error::Flash)
+    }
+}
+
+impl<F, const BLOCKS: usize> SpareNandFlash for WearTracker<F, BLOCKS>
+where
+    F: SpareNandFlash,
+{
+    const SPARE_SIZE: usize = F::SPARE_SIZE;
+
+    fn read_page(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        spare: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.flash
+            .read_page(offset, data, spare)
+            .map_err(Error::Flash)
+    }
+
+    fn write_page(&mut self, offset: u64, data: &[u8], spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, offset, data.len(), spare.len())?;
+        self.flash
+            .write_page(offset, data, spare)
+            .map_err(Error::Flash)
+    }
+
+    fn copy_page(&mut self, from: u64, to: u64, spare: &[u8]) -> Result<(), Self::Error> {
+        check_page(self, from, 0, spare.len())?;
+        check_page(self, to, 0, spare.len())?;
+        self.flash.copy_page(from, to, spare).map_err(Error::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mem::BLOCK_SIZE, mock::RamFlash};
+
+    const BLOCKS: usize = 8;
+    type TestTracker = WearTracker<RamFlash, BLOCKS>;
+
+    fn tracker(flash: RamFlash) -> TestTracker {
+        WearTracker::new(flash, WearConfig::default()).unwrap()
+    }
+
+    fn meta(block: usize) -> u64 {
+        (block * BLOCK_SIZE) as u64
+    }
+
+    /// Erase block 0 three times and sync, returning the flash
+    fn wear(flash: RamFlash) -> RamFlash {
+        let mut tracker = tracker(flash);
+        for _ in 0..3 {
+            tracker.erase(0, BLOCK_SIZE as u64).unwrap();
+        }
+        tracker.into_inner().unwrap()
+    }
+
+    #[test]
+    fn counts_survive_reload() {
+        let mut tracker = tracker(wear(RamFlash::new(BLOCKS)));
+        assert_eq!(tracker.erase_count(0), Some(3));
+        // fill the metadata block so the next sync moves to the other one
+        for _ in 0..70 {
+            tracker.sync().unwrap();
+        }
+        let tracker = self::tracker(tracker.into_inner().unwrap());
+        assert_eq!(tracker.erase_count(0), Some(3));
+        assert_eq!(tracker.erase_count(BLOCKS - 1), Some(1));
+        assert_eq!(tracker.erase_count(BLOCKS - 2), Some(1));
+    }
+
+    #[test]
+    fn bad_metadata_block_is_skipped() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.mark_bad(meta(BLOCKS - 1)).unwrap();
+        let mut flash = wear(flash);
+        assert_eq!(
+            flash.block_status(meta(BLOCKS - 1)),
+            Ok(BlockStatus::Failed)
+        );
+        let tracker = tracker(flash);
+        assert_eq!(tracker.erase_count(0), Some(3));
+        assert_eq!(tracker.erase_count(BLOCKS - 1), Some(0));
+    }
+
+    #[test]
+    fn erase_failure_retires_metadata_block() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.fail_erase.push(BLOCKS - 1);
+        let mut flash = wear(flash);
+        flash.fail_erase.clear();
+        assert_eq!(
+            flash.block_status(meta(BLOCKS - 1)),
+            Ok(BlockStatus::Failed)
+        );
+        assert_eq!(tracker(flash).erase_count(0), Some(3));
+    }
+
+    #[test]
+    fn program_failure_retires_metadata_block() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.fail_program = Some(meta(BLOCKS - 1));
+        let mut flash = wear(flash);
+        assert_eq!(
+            flash.block_status(meta(BLOCKS - 1)),
+            Ok(BlockStatus::Failed)
+        );
+        assert_eq!(tracker(flash).erase_count(0), Some(3));
+    }
+
+    #[test]
+    fn no_metadata_block_left() {
+        let mut flash = RamFlash::new(BLOCKS);
+        flash.mark_bad(meta(BLOCKS - 1)).unwrap();
+        flash.fail_erase.push(BLOCKS - 2);
+        let mut tracker = tracker(flash);
+        assert!(matches!(tracker.sync(), Err(Error::NoSpace)));
+    }
+
+    #[test]
+    fn uncorrectable_snapshot_is_skipped() {
+        let mut flash = wear(RamFlash::new(BLOCKS));
+        flash.tear_uncorrectable = true;
+        // the erase goes through, the program of the snapshot after it is cut
+        flash.cut_after = Some(1);
+        let mut torn = WearTracker::<_, BLOCKS>::new(&mut flash, WearConfig::default()).unwrap();
+        torn.erase(0, BLOCK_SIZE as u64).unwrap();
+        assert!(torn.sync().is_err());
+
+        flash.power_on();
+        assert_eq!(flash.uncorrectable.len(), 1);
+        let mut tracker = tracker(flash);
+        assert_eq!(tracker.erase_count(0), Some(3));
+        // and the next snapshot goes after the torn page
+        tracker.erase(0, BLOCK_SIZE as u64).unwrap();
+        let tracker = self::tracker(tracker.into_inner().unwrap());
+        assert_eq!(tracker.erase_count(0), Some(4));
+    }
+}